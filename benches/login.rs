@@ -0,0 +1,111 @@
+//! Login latency and throughput of the in-memory backends.
+//!
+//! `cargo bench --bench login` measures, `cargo test` runs every benchmark once as a smoke test.
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+#[cfg(feature = "dashmap")]
+use model_testing::concurrent_db;
+use model_testing::{
+    db::Db, in_memory_db, login, register, Argon2Hasher, AuthHeader, EnteredPassword,
+    PasswordHasher, UserId,
+};
+
+const PASSWORD: &str = "correct horse battery staple";
+
+fn auth_header(user: &UserId) -> AuthHeader {
+    AuthHeader::basic(user, &EnteredPassword::new(PASSWORD.to_string()))
+}
+
+/// A db with `count` registered users, and their auth headers.
+fn db_with_users<D: Db>(db: D, count: usize) -> (D, Vec<AuthHeader>) {
+    let headers = (0..count)
+        .map(|i| {
+            let user = UserId(format!("User{}", i));
+            register(
+                &db,
+                user.clone(),
+                EnteredPassword::new(PASSWORD.to_string()),
+            )
+            .unwrap();
+            auth_header(&user)
+        })
+        .collect();
+    (db, headers)
+}
+
+fn verify(c: &mut Criterion) {
+    let pass = EnteredPassword::new(PASSWORD.to_string());
+    let encoded = Argon2Hasher::default().hash(&pass).unwrap();
+    c.bench_function("verify", |b| {
+        b.iter(|| assert!(Argon2Hasher::default().verify(&encoded, &pass).unwrap()))
+    });
+}
+
+fn login_latency(c: &mut Criterion) {
+    let (db, headers) = db_with_users(in_memory_db::init_db(), 1);
+    c.bench_function("login", |b| b.iter(|| login(&db, &headers[0]).unwrap()));
+}
+
+/// Logins per second with several threads logging in different users on one shared db.
+fn login_throughput(c: &mut Criterion) {
+    throughput_on(c, "login_throughput", in_memory_db::init_db);
+}
+
+/// Like `login_throughput`, on the `DashMap`s of `concurrent_db` instead of locked `HashMap`s.
+#[cfg(feature = "dashmap")]
+fn concurrent_login_throughput(c: &mut Criterion) {
+    throughput_on(c, "concurrent_login_throughput", concurrent_db::init_db);
+}
+
+#[cfg(not(feature = "dashmap"))]
+fn concurrent_login_throughput(_c: &mut Criterion) {}
+
+fn throughput_on<D: Db + Send + Sync + 'static>(
+    c: &mut Criterion,
+    name: &str,
+    new_db: impl Fn() -> D,
+) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(1));
+    for &threads in &[1, 2, 4, 8] {
+        let (db, headers) = db_with_users(new_db(), threads);
+        let db = Arc::new(db);
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+            b.iter_custom(|iters| {
+                let per_thread = (iters as usize + threads - 1) / threads;
+                let start = Instant::now();
+                let handles = headers
+                    .iter()
+                    .map(|header| {
+                        let db = Arc::clone(&db);
+                        let header = header.clone();
+                        thread::spawn(move || {
+                            for _ in 0..per_thread {
+                                login(&db, &header).unwrap();
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // argon2 dominates, so fewer samples are still precise enough
+    config = Criterion::default().sample_size(20).measurement_time(Duration::from_secs(10));
+    targets = verify, login_latency, login_throughput, concurrent_login_throughput
+}
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Headers arrive as strings, invalid UTF-8 gets replaced so every input reaches the parser
+fuzz_target!(|data: &[u8]| {
+    let header = String::from_utf8_lossy(data);
+    let _ = model_testing::domain::fuzz_parse_auth(&header);
+});
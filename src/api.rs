@@ -1,33 +1,1508 @@
-use crate::domain::{self, UserId};
+use crate::{
+    body_limit::BodyLimit,
+    domain::{self, DomainConfig, SessionToken, UserId},
+    idempotency::Idempotency,
+    rate_limit::RateLimit,
+};
 use anyhow::anyhow;
-use tide::{http::headers::AUTHORIZATION, Request, Response, StatusCode};
+use async_std::task;
+use futures_lite::FutureExt;
+use std::{
+    any::Any,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tide::{
+    http::{
+        headers::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE},
+        mime, Cookie,
+    },
+    Body, Endpoint, Middleware, Next, Request, Response, Server, StatusCode,
+};
+use uuid::Uuid;
 
-pub async fn secret(req: Request<impl domain::db::Db>) -> tide::Result {
-    let user = UserId(req.param("user")?.to_string());
+/// Name of the cookie that carries the session token set by `login`.
+pub const SESSION_COOKIE: &str = "session";
 
-    if domain::can_access_secret(req.state(), &user)? {
-        Ok(Response::builder(StatusCode::Ok)
-            .body(format!("Secrets for user {:?}", user))
-            .build())
-    } else {
-        Err(tide::Error::new(
+/// Sent with every `401 Unauthorized`, so that browsers prompt for credentials.
+pub const BASIC_CHALLENGE: &str = r#"Basic realm="simulation-testing""#;
+
+/// Header that carries the id of a request, see `AssignRequestId`.
+pub const REQUEST_ID: &str = "X-Request-Id";
+
+/// Builds the app with all routes registered, ready to `listen`.
+pub fn build_app<D>(db: D) -> Server<D>
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    build_app_with(db, ApiConfig::default())
+}
+
+/// Settings of the HTTP layer, see `build_app_with`.
+#[derive(Clone, Debug, Default)]
+pub struct ApiConfig {
+    pub rate_limit: RateLimit,
+    /// Remembers the responses to `/register` requests with an `Idempotency-Key`.
+    pub idempotency: Idempotency,
+    /// `/login` doesn't respond before this much time has passed, whatever the outcome, so that
+    /// network observers can't tell fast failures from slow ones. Zero by default.
+    pub min_login_time: Duration,
+    /// Applies to `/register` and `/login`, which may read a login form from the body, and to
+    /// `/secret`, which stores the body.
+    pub body_limit: BodyLimit,
+    /// What the handlers call the domain logic with.
+    pub domain: DomainConfig,
+}
+
+/// Like `build_app`, but configured by `config`.
+pub fn build_app_with<D>(db: D, config: ApiConfig) -> Server<D>
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let mut app = tide::with_state(db);
+    app.with(AssignRequestId);
+    app.with(CatchPanic);
+    app.with(ProvideDomainConfig(Arc::new(config.domain)));
+    app.with(config.rate_limit);
+    app.with(BasicChallenge);
+    app.at("/register")
+        .with(config.body_limit)
+        .with(config.idempotency)
+        .with(ParseAuthorization)
+        .post(register);
+    app.at("/login")
+        .with(MinResponseTime(config.min_login_time))
+        .with(config.body_limit)
+        .with(ParseAuthorization)
+        .post(login);
+    app.at("/logout").with(ParseAuthorization).post(logout);
+    app.at("/secret")
+        .with(config.body_limit)
+        .get(secret)
+        .put(put_secret);
+    app.at("/secret/:user")
+        .with(config.body_limit)
+        .get(secret)
+        .put(put_secret);
+    app.at("/session").get(session_info);
+    app.at("/session/touch").post(heartbeat);
+    app.at("/metrics").get(metrics);
+    app.at("/health").get(health);
+    app.at("/admin/secret/:user").get(admin_secret);
+    app.at("/openapi.json").get(openapi);
+    app
+}
+
+/// Holds back responses until the given time has passed since the request came in.
+/// Waits on an async timer, so the executor can serve other requests meanwhile.
+#[derive(Clone, Copy, Debug)]
+struct MinResponseTime(Duration);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for MinResponseTime {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let res = next.run(req).await;
+        if let Some(rest) = self.0.checked_sub(start.elapsed()) {
+            task::sleep(rest).await;
+        }
+        Ok(res)
+    }
+}
+
+/// The id of a request, in its extensions. Goes into the log lines about the request, so that
+/// they can be told apart from those of concurrent requests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Gives every request a `RequestId` and echoes it in the `REQUEST_ID` response header. Requests
+/// that already have one, e.g. from a proxy, keep it, so that its logs can be matched with ours.
+/// Failed requests are logged with their id.
+#[derive(Clone, Copy, Debug, Default)]
+struct AssignRequestId;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AssignRequestId {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let id = match req.header(REQUEST_ID) {
+            Some(id) => id.last().as_str().to_string(),
+            None => Uuid::new_v4().to_string(),
+        };
+        req.set_ext(RequestId(id.clone()));
+        let mut res = next.run(req).await;
+        if let Some(e) = res.error() {
+            tide::log::warn!("request failed", {
+                request_id: id,
+                status: u16::from(res.status()),
+                error: e.to_string(),
+            });
+        }
+        res.insert_header(REQUEST_ID, id.as_str());
+        Ok(res)
+    }
+}
+
+/// The id that `AssignRequestId` gave the request.
+fn request_id<State>(req: &Request<State>) -> &str {
+    req.ext::<RequestId>().map_or("", |id| id.0.as_str())
+}
+
+/// Answers requests whose handling panicked, e.g. on a poisoned lock in the db, with `500
+/// Internal Server Error` and a JSON body with the request id, instead of dropping the
+/// connection. Runs inside `AssignRequestId`, so the response still carries the id header.
+#[derive(Clone, Copy, Debug, Default)]
+struct CatchPanic;
+
+#[derive(serde::Serialize)]
+struct PanicJson<'a> {
+    error: &'a str,
+    request_id: &'a str,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CatchPanic {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let id = request_id(&req).to_string();
+        match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+            Ok(res) => Ok(res),
+            Err(panic) => {
+                tide::log::error!("request handler panicked", {
+                    request_id: id,
+                    panic: panic_message(&*panic),
+                });
+                Ok(Response::builder(StatusCode::InternalServerError)
+                    .body(Body::from_json(&PanicJson {
+                        error: "Internal Server Error",
+                        request_id: &id,
+                    })?)
+                    .build())
+            }
+        }
+    }
+}
+
+/// The message of a `panic!`, which is a `&str` or a `String` unless panicked with a payload.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("", String::as_str),
+    }
+}
+
+/// Puts the `ApiConfig::domain` into the request extensions, for `domain_config`.
+#[derive(Clone, Debug)]
+struct ProvideDomainConfig(Arc<DomainConfig>);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProvideDomainConfig {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(Arc::clone(&self.0));
+        Ok(next.run(req).await)
+    }
+}
+
+/// The config that `ProvideDomainConfig` put into the request, or the default for handlers that
+/// are served without it.
+fn domain_config<State>(req: &Request<State>) -> Arc<DomainConfig> {
+    req.ext::<Arc<DomainConfig>>().cloned().unwrap_or_default()
+}
+
+/// Runs `f` with the db and the `domain_config` of the request on async-std's pool for blocking
+/// work. `Db` calls may block their thread, on I/O or in decorators that sleep like `LatencyDb`
+/// and `RetryDb`, which would hold up every other request on the executor thread.
+async fn blocking<D, T>(
+    req: &Request<D>,
+    f: impl FnOnce(&D, &DomainConfig) -> T + Send + 'static,
+) -> T
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let (db, config) = (req.state().clone(), domain_config(req));
+    task::spawn_blocking(move || f(&db, &config)).await
+}
+
+/// What `ParseAuthorization` found in the `Authorization` header. Handlers behind the middleware
+/// find it in the request extensions.
+#[derive(Clone)]
+enum Authorization {
+    Missing,
+    Basic(domain::Credentials),
+}
+
+/// Parses the `Authorization` header once for the handlers behind it, as the `domain_config`
+/// says. Malformed or overlong headers are recorded in the audit trail and rejected with
+/// `400 Bad Request` before they reach a handler.
+///
+/// So are requests with more than one `Authorization` header, even if they agree: proxies and
+/// servers may pick different ones, which lets requests be smuggled past checks in front of us.
+#[derive(Clone, Copy, Debug, Default)]
+struct ParseAuthorization;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ParseAuthorization {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let headers = req
+            .header(AUTHORIZATION)
+            .map(|values| values.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let authorization = match headers.as_slice() {
+            [] => Authorization::Missing,
+            [header] => match domain::AuthHeader::new(header.as_str().to_string())
+                .parse_audited(&domain_config(&req))
+            {
+                Ok(credentials) => Authorization::Basic(credentials),
+                Err(e) => return Err(tide::Error::new(StatusCode::BadRequest, e)),
+            },
+            _ => {
+                return Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    anyhow!("Multiple Authorization headers"),
+                ))
+            }
+        };
+        req.set_ext(authorization);
+        Ok(next.run(req).await)
+    }
+}
+
+/// Adds the `BASIC_CHALLENGE` to `401 Unauthorized` responses that don't have a challenge yet.
+#[derive(Clone, Copy, Debug, Default)]
+struct BasicChallenge;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BasicChallenge {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        if res.status() == StatusCode::Unauthorized && res.header(WWW_AUTHENTICATE).is_none() {
+            res.insert_header(WWW_AUTHENTICATE, BASIC_CHALLENGE);
+        }
+        Ok(res)
+    }
+}
+
+/// The credentials parsed by `ParseAuthorization`, `None` if the request had none.
+fn credentials<State>(req: &Request<State>) -> Option<domain::Credentials> {
+    match req.ext::<Authorization>() {
+        Some(Authorization::Basic(credentials)) => Some(credentials.clone()),
+        Some(Authorization::Missing) | None => None,
+    }
+}
+
+/// How `secret` responds to an authorized user that hasn't stored a secret yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmptySecret {
+    /// `204 No Content`
+    NoContent,
+    /// `404 Not Found`
+    NotFound,
+    /// `200 OK` with an empty JSON object as body
+    EmptyJson,
+}
+
+impl Default for EmptySecret {
+    fn default() -> Self {
+        EmptySecret::NotFound
+    }
+}
+
+pub async fn secret<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    secret_or(req, EmptySecret::default()).await
+}
+
+/// Like `secret`, but responds as configured by `empty` when no secret is stored for the user.
+pub fn secret_with<D>(empty: EmptySecret) -> impl Endpoint<D>
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    move |req: Request<D>| secret_or(req, empty)
+}
+
+/// Reads the secret of the user with the session cookie, `401 Unauthorized` without one.
+/// Users can only read their own secret, so a `:user` in the path has to match the session, like
+/// for `put_secret`. A `404` means they have no secret, with the default `EmptySecret`.
+///
+/// Each secret returned counts as a read, see `domain::secret_access_count`.
+async fn secret_or<D>(req: Request<D>, empty: EmptySecret) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let user = session_user(&req).await?;
+    if let Ok(path_user) = req.param("user") {
+        if path_user != user.0 {
+            return Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("Not allowed"),
+            ));
+        }
+    }
+
+    let reader = user.clone();
+    let allowed = blocking(&req, move |db, config| {
+        domain::can_access_secret_with(db, config, &reader, domain::Role::User)
+    })
+    .await?;
+    if !allowed {
+        return Err(tide::Error::new(
             StatusCode::Forbidden,
             anyhow!("Not allowed"),
-        ))
+        ));
+    }
+
+    // Before reading the secret, so that unacceptable requests don't count as reads
+    let format = secret_format(&req)?;
+    let reader = user.clone();
+    match blocking(&req, move |db, _| domain::get_secret(db, &reader)).await? {
+        Some(secret) => secret_response(format, &user, &secret),
+        None => Ok(match empty {
+            EmptySecret::NoContent => Response::new(StatusCode::NoContent),
+            EmptySecret::NotFound => Response::new(StatusCode::NotFound),
+            EmptySecret::EmptyJson => Response::builder(StatusCode::Ok)
+                .body("{}")
+                .content_type(mime::JSON)
+                .build(),
+        }),
     }
 }
 
-pub async fn login(req: Request<impl domain::db::Db>) -> tide::Result {
-    if let Some(auth) = req.header(AUTHORIZATION) {
-        domain::login(req.state(), auth.as_str())?;
+/// Stores the request body as the secret of the user with the session cookie.
+/// Users can only write their own secret, so a `:user` in the path has to match the session.
+pub async fn put_secret<D>(mut req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let user = session_user(&req).await?;
+    if let Ok(path_user) = req.param("user") {
+        if path_user != user.0 {
+            return Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("Not allowed"),
+            ));
+        }
     }
 
+    let writer = user.clone();
+    let allowed = blocking(&req, move |db, config| {
+        domain::can_access_secret_with(db, config, &writer, domain::Role::User)
+    })
+    .await?;
+    if !allowed {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("Not allowed"),
+        ));
+    }
+
+    let secret = req.body_string().await?;
+    blocking(&req, move |db, _| domain::set_secret(db, &user, secret)).await?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// The representations of a secret, picked by the `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SecretFormat {
+    /// `{"user": "...", "secret": "..."}`
+    Json,
+    /// Just the secret
+    Text,
+}
+
+impl SecretFormat {
+    /// The format with the highest quality in `accept`, the first one on ties.
+    /// Clients without a preference get JSON, `None` means the client accepts neither format.
+    fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Some(SecretFormat::Json),
+        };
+        let mut best: Option<(f32, Self)> = None;
+        for range in accept.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let format = match parts.next().unwrap_or("").to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => SecretFormat::Json,
+                "text/plain" | "text/*" => SecretFormat::Text,
+                _ => continue,
+            };
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if quality > 0.0 && best.map_or(true, |(best, _)| quality > best) {
+                best = Some((quality, format));
+            }
+        }
+        best.map(|(_, format)| format)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SecretJson<'a> {
+    user: &'a str,
+    secret: &'a str,
+}
+
+/// The format the request accepts, `406 Not Acceptable` if neither.
+fn secret_format<State>(req: &Request<State>) -> tide::Result<SecretFormat> {
+    let accept = req.header(ACCEPT).map(|accept| accept.as_str());
+    SecretFormat::negotiate(accept).ok_or_else(|| {
+        tide::Error::new(
+            StatusCode::NotAcceptable,
+            anyhow!("Secrets are only available as JSON or plain text"),
+        )
+    })
+}
+
+fn secret_response(format: SecretFormat, user: &UserId, secret: &str) -> tide::Result {
+    match format {
+        SecretFormat::Json => Ok(Response::builder(StatusCode::Ok)
+            .body(Body::from_json(&SecretJson {
+                user: &user.0,
+                secret,
+            })?)
+            .build()),
+        SecretFormat::Text => Ok(Response::builder(StatusCode::Ok)
+            .body(secret)
+            .content_type(mime::PLAIN)
+            .build()),
+    }
+}
+
+/// Reads the secret of any user. Only for admins, who are identified by their session cookie.
+pub async fn admin_secret<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let admin = session_user(&req).await?;
+    let allowed = blocking(&req, move |db, config| {
+        domain::can_access_secret_with(db, config, &admin, domain::Role::Admin)
+    })
+    .await?;
+    if !allowed {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("Not allowed"),
+        ));
+    }
+
+    let user = UserId(req.param("user")?.to_string());
+    match blocking(&req, move |db, _| domain::get_secret(db, &user)).await? {
+        Some(secret) => Ok(Response::builder(StatusCode::Ok).body(secret).build()),
+        None => Ok(Response::new(StatusCode::NotFound)),
+    }
+}
+
+/// Resolves the user from the session cookie, for requests that don't name the user.
+async fn session_user<D>(req: &Request<D>) -> tide::Result<UserId>
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let token = req
+        .cookie(SESSION_COOKIE)
+        .map(|cookie| SessionToken::new(cookie.value().to_string()))
+        .ok_or_else(|| tide::Error::new(StatusCode::Unauthorized, anyhow!("No session")))?;
+    blocking(req, move |db, config| {
+        domain::session_user_with(db, config, &token)
+    })
+    .await?
+    .ok_or_else(|| tide::Error::new(StatusCode::Unauthorized, anyhow!("Invalid session")))
+}
+
+/// Keeps the session of the cookie alive, `401 Unauthorized` if it has none or it expired.
+pub async fn heartbeat<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let user = session_user(&req).await?;
+    if !blocking(&req, move |db, config| {
+        domain::heartbeat_with(db, config, &user)
+    })
+    .await?
+    {
+        return Err(tide::Error::new(
+            StatusCode::Unauthorized,
+            anyhow!("Session expired"),
+        ));
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+#[derive(serde::Serialize)]
+struct SessionJson<'a> {
+    user: &'a str,
+    /// `None` for sessions that never expire.
+    expires_in_secs: Option<u64>,
+}
+
+/// Whose session the cookie is for and how long until it expires, so that clients can log in
+/// again in time. `401 Unauthorized` if there is none or it expired.
+pub async fn session_info<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let user = session_user(&req).await?;
+    let owner = user.clone();
+    let remaining = blocking(&req, move |db, config| {
+        domain::session_ttl_remaining_with(db, config, &owner)
+    })
+    .await?
+    .ok_or_else(|| tide::Error::new(StatusCode::Unauthorized, anyhow!("Session expired")))?;
+    let expires_in_secs = Some(remaining)
+        .filter(|&remaining| remaining != Duration::MAX)
+        .map(|remaining| remaining.as_secs());
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&SessionJson {
+            user: &user.0,
+            expires_in_secs,
+        })?)
+        .build())
+}
+
+/// Exposes the domain metrics in the Prometheus text format.
+pub async fn metrics<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let metrics = blocking(&req, |db, _| domain::metrics(db)).await?;
+    let body = format!(
+        "# TYPE users_total gauge\nusers_total {}\n# TYPE sessions_active gauge\nsessions_active {}\n",
+        metrics.users, metrics.sessions
+    );
+    Ok(Response::builder(StatusCode::Ok)
+        .body(body)
+        .content_type(mime::PLAIN)
+        .build())
+}
+
+/// Describes all routes of `build_app` as an OpenAPI 3 document, which is written by hand in
+/// `openapi.json` and has to be kept up to date with the routes.
+pub async fn openapi(_req: Request<impl domain::db::Db>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(include_str!("openapi.json"))
+        .content_type(mime::JSON)
+        .build())
+}
+
+/// Up as long as the db answers a ping.
+pub async fn health<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    blocking(&req, |db, _| db.ping())
+        .await
+        .map_err(|e| tide::Error::new(StatusCode::ServiceUnavailable, e))?;
+    Ok(Response::new(StatusCode::Ok))
+}
+
+/// Hashes on the blocking pool along with the `Db` calls, see `blocking`.
+pub async fn register<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let credentials = credentials(&req)
+        .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("Missing credentials")))?;
+    blocking(&req, move |db, config| {
+        domain::register_with(db, config, credentials.user_id, credentials.password)
+    })
+    .await
+    .map_err(register_error)?;
+
     Ok(Response::new(StatusCode::Ok))
 }
 
-pub async fn logout(req: Request<impl domain::db::Db>) -> tide::Result {
-    if let Some(auth) = req.header(AUTHORIZATION) {
-        domain::logout(req.state(), auth.as_str())?;
+/// Fields of a login form, for browsers that post one instead of sending a Basic auth header.
+#[derive(serde::Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+/// The credentials of a urlencoded or JSON login form in the body, if there is one.
+async fn form_credentials<State>(
+    req: &mut Request<State>,
+) -> tide::Result<Option<domain::Credentials>> {
+    let essence = match req.content_type() {
+        Some(mime) => mime.essence().to_string(),
+        None => return Ok(None),
+    };
+    let form: tide::Result<LoginForm> = if essence == mime::FORM.essence() {
+        req.body_form().await
+    } else if essence == mime::JSON.essence() {
+        req.body_json().await
+    } else {
+        return Ok(None);
+    };
+    let form = form.map_err(|e| tide::Error::new(StatusCode::BadRequest, e.into_inner()))?;
+    Ok(Some(domain::Credentials {
+        user_id: UserId(form.username),
+        password: domain::EnteredPassword::new(form.password),
+    }))
+}
+
+/// Takes the credentials from the Authorization header, or else from a login form in the body.
+/// Verifies on the blocking pool along with the `Db` calls, see `blocking`.
+pub async fn login<D>(mut req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    let credentials = match credentials(&req) {
+        Some(credentials) => Some(credentials),
+        None => form_credentials(&mut req).await?,
+    };
+    let credentials = credentials.ok_or_else(|| {
+        tide::Error::new(StatusCode::Unauthorized, anyhow!("Missing credentials"))
+    })?;
+    let outcome = blocking(&req, move |db, config| {
+        domain::login_with_credentials_with(db, config, credentials, false)
+    })
+    .await
+    .map_err(login_error)?;
+    tide::log::info!("login", { user: outcome.user.0, request_id: request_id(&req) });
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_cookie(
+        Cookie::build(SESSION_COOKIE, outcome.token.as_str().to_string())
+            .path("/")
+            .http_only(true)
+            .finish(),
+    );
+    Ok(res)
+}
+
+/// Taken user names are `409 Conflict`. Malformed input is `400 Bad Request` and well-formed input
+/// that the policies reject, like a weak password, `422 Unprocessable Entity`.
+fn register_error(e: domain::RegisterError) -> tide::Error {
+    use domain::RegisterError::*;
+    let status = match e {
+        AlreadyExists => StatusCode::Conflict,
+        ParseAuthError(_) | PasswordTooLong | InvalidHash(_) => StatusCode::BadRequest,
+        WeakPassword(_) | ReservedName | InvalidEmail(_) | HashTooExpensive => {
+            StatusCode::UnprocessableEntity
+        }
+        HashError(_) | DbError(_) => StatusCode::InternalServerError,
+    };
+    tide::Error::new(status, e)
+}
+
+/// Wrong credentials are `401 Unauthorized`, without revealing whether the user exists, and so are
+/// missing or wrong two-factor codes. Passwords over the policy's limit are `400 Bad Request`.
+/// Anything else, like a corrupt stored hash, is the server's fault.
+fn login_error(e: domain::LoginError) -> tide::Error {
+    use domain::LoginError::*;
+    let status = match e {
+        InvalidCredentials | NotRegistered | TotpRequired | InvalidTotp | InvalidRefreshToken => {
+            StatusCode::Unauthorized
+        }
+        ParseAuthError(_) | PasswordTooLong => StatusCode::BadRequest,
+        HashError(_) | DbError(_) | CorruptStoredHash => StatusCode::InternalServerError,
+    };
+    tide::Error::new(status, e)
+}
+
+/// Deliberately tolerant: without credentials there is no session to end, so the request still
+/// succeeds. Logging out is idempotent, and clients shouldn't have to know whether they were
+/// logged in.
+pub async fn logout<D>(req: Request<D>) -> tide::Result
+where
+    D: domain::db::Db + Clone + Send + Sync + 'static,
+{
+    if let Some(credentials) = credentials(&req) {
+        blocking(&req, move |db, config| {
+            domain::logout_user_with(db, config, &credentials.user_id)
+        })
+        .await?;
     }
     Ok(Response::new(StatusCode::Ok))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::ManualClock, db::Db, idempotency::IDEMPOTENCY_KEY, in_memory_db,
+        latency_db::LatencyDb,
+    };
+    use tide::http::{self, Method, Url};
+
+    fn request(method: Method, path: &str) -> http::Request {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        http::Request::new(method, url)
+    }
+
+    fn with_auth(mut req: http::Request, user: &str, pass: &str) -> http::Request {
+        let encoded = base64::encode(format!("{user}:{pass}"));
+        req.insert_header(AUTHORIZATION, format!("Basic {encoded}"));
+        req
+    }
+
+    #[async_std::test]
+    async fn register_login_and_access_secret() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+
+        let res: http::Response = app
+            .respond(with_auth(request(Method::Post, "/register"), "Alice", "pw"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let res: http::Response = app
+            .respond(request(Method::Get, "/secret/Alice"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let res: http::Response = app
+            .respond(with_auth(request(Method::Post, "/login"), "Alice", "pw"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let set_cookie = res.header("set-cookie").unwrap().as_str();
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+
+        db.set_secret(&UserId("Alice".to_string()), "swordfish".to_string())
+            .unwrap();
+        let mut req = request(Method::Get, "/secret/Alice");
+        req.insert_header("cookie", cookie);
+        req.insert_header(ACCEPT, "text/plain");
+        let mut res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "swordfish");
+    }
+
+    #[async_std::test]
+    async fn session_cookie_grants_access_to_own_secret() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let register = with_auth(request(Method::Post, "/register"), "Alice", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+        db.set_secret(&UserId("Alice".to_string()), "swordfish".to_string())
+            .unwrap();
+
+        let res: http::Response = app
+            .respond(with_auth(request(Method::Post, "/login"), "Alice", "pw"))
+            .await
+            .unwrap();
+        let set_cookie = res.header("set-cookie").unwrap().as_str();
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        assert!(cookie.starts_with("session="), "{}", cookie);
+
+        let mut req = request(Method::Get, "/secret");
+        req.insert_header("cookie", cookie);
+        req.insert_header(ACCEPT, "text/plain");
+        let mut res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "swordfish");
+    }
+
+    /// Registers and logs in the user, returning the session cookie to send with requests.
+    async fn login_cookie(app: &Server<in_memory_db::Db>, user: &str, pass: &str) -> String {
+        let register = with_auth(request(Method::Post, "/register"), user, pass);
+        app.respond::<_, http::Response>(register).await.unwrap();
+        let login = with_auth(request(Method::Post, "/login"), user, pass);
+        let res: http::Response = app.respond(login).await.unwrap();
+        let set_cookie = res.header("set-cookie").unwrap().as_str();
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[async_std::test]
+    async fn only_admins_can_read_other_secrets() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let alice = login_cookie(&app, "Alice", "pw").await;
+        let bob = login_cookie(&app, "Bob", "pw").await;
+        db.set_role(&UserId("Alice".to_string()), domain::Role::Admin)
+            .unwrap();
+        db.set_secret(&UserId("Bob".to_string()), "swordfish".to_string())
+            .unwrap();
+
+        let mut req = request(Method::Get, "/admin/secret/Bob");
+        req.insert_header("cookie", alice);
+        let mut res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "swordfish");
+
+        let mut req = request(Method::Get, "/admin/secret/Alice");
+        req.insert_header("cookie", bob);
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+    }
+
+    #[async_std::test]
+    async fn heartbeats_need_a_live_session() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let alice = login_cookie(&app, "Alice", "pw").await;
+
+        let mut req = request(Method::Post, "/session/touch");
+        req.insert_header("cookie", alice.as_str());
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+        let alice_id = UserId("Alice".to_string());
+        assert!(db.get_session_times(&alice_id).unwrap().is_some());
+
+        db.remove_session(&alice_id).unwrap();
+        let mut req = request(Method::Post, "/session/touch");
+        req.insert_header("cookie", alice.as_str());
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let res: http::Response = app
+            .respond(request(Method::Post, "/session/touch"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn session_info_names_the_user_of_a_live_session() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let alice = login_cookie(&app, "Alice", "pw").await;
+
+        let mut req = request(Method::Get, "/session");
+        req.insert_header("cookie", alice.as_str());
+        let mut res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        // The default policy never expires sessions
+        assert_eq!(
+            body,
+            serde_json::json!({ "user": "Alice", "expires_in_secs": null })
+        );
+
+        db.remove_session(&UserId("Alice".to_string())).unwrap();
+        let mut req = request(Method::Get, "/session");
+        req.insert_header("cookie", alice.as_str());
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn users_can_only_store_their_own_secret() {
+        let app = build_app(in_memory_db::init_db());
+        let alice = login_cookie(&app, "Alice", "pw").await;
+        let bob = login_cookie(&app, "Bob", "pw").await;
+
+        let mut req = request(Method::Put, "/secret/Alice");
+        req.insert_header("cookie", alice.as_str());
+        req.set_body("swordfish");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NoContent);
+
+        let mut req = request(Method::Put, "/secret/Alice");
+        req.insert_header("cookie", bob.as_str());
+        req.set_body("hijacked");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let mut req = request(Method::Get, "/secret");
+        req.insert_header("cookie", alice.as_str());
+        req.insert_header(ACCEPT, "text/plain");
+        let mut res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "swordfish");
+
+        let mut req = request(Method::Get, "/secret/Alice");
+        req.insert_header("cookie", bob.as_str());
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        let res: http::Response = app
+            .respond(request(Method::Get, "/secret/Alice"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut req = request(Method::Get, "/secret");
+        req.insert_header("cookie", bob.as_str());
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let mut req = request(Method::Put, "/secret");
+        req.set_body("anonymous");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn oversized_secrets_are_rejected() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let alice = login_cookie(&app, "Alice", "pw").await;
+
+        let mut req = request(Method::Put, "/secret");
+        req.insert_header("cookie", alice.as_str());
+        req.set_body("x".repeat(8 * 1024 + 1));
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        assert_eq!(db.get_secret(&UserId("Alice".to_string())).unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn secret_without_valid_session_cookie_is_unauthorized() {
+        let app = build_app(in_memory_db::init_db());
+
+        let res: http::Response = app.respond(request(Method::Get, "/secret")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut req = request(Method::Get, "/secret");
+        req.insert_header("cookie", "session=made-up");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn login_is_rate_limited() {
+        let config = ApiConfig {
+            rate_limit: RateLimit::new(0.1, 5).with_clock(Arc::new(ManualClock::default())),
+            ..ApiConfig::default()
+        };
+        let app = build_app_with(in_memory_db::init_db(), config);
+        let mut statuses = vec![];
+        for _ in 0..6 {
+            let login = with_auth(request(Method::Post, "/login"), "Alice", "pw");
+            let res: http::Response = app.respond(login).await.unwrap();
+            statuses.push(res.status());
+        }
+        assert_eq!(statuses[5], StatusCode::TooManyRequests, "{:?}", statuses);
+    }
+
+    #[async_std::test]
+    async fn failing_logins_take_at_least_the_min_login_time() {
+        let config = ApiConfig {
+            min_login_time: Duration::from_millis(250),
+            ..ApiConfig::default()
+        };
+        let app = build_app_with(in_memory_db::init_db(), config);
+
+        let start = Instant::now();
+        let login = with_auth(request(Method::Post, "/login"), "Alice", "pw");
+        let res: http::Response = app.respond(login).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        // Malformed headers are rejected just as late
+        let start = Instant::now();
+        let mut login = request(Method::Post, "/login");
+        login.insert_header(AUTHORIZATION, "Bearer token");
+        let res: http::Response = app.respond(login).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[async_std::test]
+    async fn retried_registrations_get_the_original_response() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let register = |key: &str| {
+            let mut req = with_auth(request(Method::Post, "/register"), "Alice", "pw");
+            req.insert_header(IDEMPOTENCY_KEY, key);
+            req
+        };
+
+        let mut first: http::Response = app.respond(register("key-1")).await.unwrap();
+        let mut retry: http::Response = app.respond(register("key-1")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::Ok);
+        assert_eq!(retry.status(), StatusCode::Ok);
+        assert_eq!(
+            first.body_string().await.unwrap(),
+            retry.body_string().await.unwrap()
+        );
+        assert_eq!(db.count_users().unwrap(), 1);
+
+        let other: http::Response = app.respond(register("key-2")).await.unwrap();
+        assert_eq!(other.status(), StatusCode::Conflict);
+    }
+
+    #[async_std::test]
+    async fn metrics_count_users_and_sessions() {
+        let app = build_app(in_memory_db::init_db());
+        for user in &["Alice", "Bob", "Carol"] {
+            let req = with_auth(request(Method::Post, "/register"), user, "pw");
+            app.respond::<_, http::Response>(req).await.unwrap();
+        }
+        for user in &["Alice", "Bob"] {
+            let req = with_auth(request(Method::Post, "/login"), user, "pw");
+            app.respond::<_, http::Response>(req).await.unwrap();
+        }
+
+        let mut res: http::Response = app.respond(request(Method::Get, "/metrics")).await.unwrap();
+        let body = res.body_string().await.unwrap();
+        assert!(body.lines().any(|line| line == "users_total 3"), "{}", body);
+        assert!(
+            body.lines().any(|line| line == "sessions_active 2"),
+            "{body}"
+        );
+    }
+
+    #[async_std::test]
+    async fn openapi_describes_every_route() {
+        let app = build_app(in_memory_db::init_db());
+        let mut res: http::Response = app
+            .respond(request(Method::Get, "/openapi.json"))
+            .await
+            .unwrap();
+        assert_eq!(res.content_type(), Some(mime::JSON));
+        let document: serde_json::Value =
+            serde_json::from_str(&res.body_string().await.unwrap()).unwrap();
+
+        let paths = document["paths"].as_object().unwrap();
+        let mut described = paths.keys().map(String::as_str).collect::<Vec<_>>();
+        described.sort_unstable();
+        // The routes of `build_app`, with `:param` written as `{param}`
+        let mut routes = vec![
+            "/register",
+            "/login",
+            "/logout",
+            "/secret",
+            "/secret/{user}",
+            "/session",
+            "/session/touch",
+            "/metrics",
+            "/health",
+            "/admin/secret/{user}",
+            "/openapi.json",
+        ];
+        routes.sort_unstable();
+        assert_eq!(described, routes);
+        assert_eq!(
+            document["components"]["securitySchemes"]["basic"]["scheme"],
+            "basic"
+        );
+    }
+
+    #[test]
+    fn slow_db_calls_leave_the_executor_thread_to_other_tasks() {
+        let slow = Duration::from_millis(20);
+        let db = Arc::new(LatencyDb::new(in_memory_db::init_db(), slow, slow, 0));
+        let app = build_app(db);
+
+        // Everything runs on this thread, so the ticker only gets to run while the ping waits
+        let ticks = std::cell::Cell::new(0);
+        let ticker = async {
+            loop {
+                task::sleep(Duration::from_millis(1)).await;
+                ticks.set(ticks.get() + 1);
+            }
+        };
+        let health = app.respond::<_, http::Response>(request(Method::Get, "/health"));
+        let res = futures_lite::future::block_on(futures_lite::future::or(
+            async { Some(health.await) },
+            async {
+                ticker.await;
+                None
+            },
+        ))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(ticks.get() > 0, "the ping blocked the thread");
+    }
+
+    #[async_std::test]
+    async fn health_is_ok() {
+        let app = build_app(in_memory_db::init_db());
+        let res: http::Response = app.respond(request(Method::Get, "/health")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn register_requires_credentials() {
+        let app = build_app(in_memory_db::init_db());
+        let res: http::Response = app
+            .respond(request(Method::Post, "/register"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn rejected_registrations_are_the_clients_fault() {
+        let mut config = ApiConfig::default();
+        config.domain.password_policy.min_len = 4;
+        config.domain.password_policy.max_len = 16;
+        config.domain.reserved_names = vec![UserId("admin".to_string())].into_iter().collect();
+        let app = build_app_with(in_memory_db::init_db(), config);
+
+        let too_long = "x".repeat(17);
+        let cases = [
+            ("Alice", "pw", StatusCode::UnprocessableEntity),
+            ("admin", "password", StatusCode::UnprocessableEntity),
+            ("Alice", too_long.as_str(), StatusCode::BadRequest),
+        ];
+        for (user, pass, status) in cases.iter() {
+            let register = with_auth(request(Method::Post, "/register"), user, pass);
+            let res: http::Response = app.respond(register).await.unwrap();
+            assert_eq!(res.status(), *status, "{}:{}", user, pass);
+        }
+    }
+
+    #[async_std::test]
+    async fn overlong_login_passwords_are_bad_requests() {
+        let mut config = ApiConfig::default();
+        config.domain.password_policy.max_len = 16;
+        let app = build_app_with(in_memory_db::init_db(), config);
+        let login = with_auth(request(Method::Post, "/login"), "Alice", &"x".repeat(17));
+        let res: http::Response = app.respond(login).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[cfg(feature = "totp")]
+    #[async_std::test]
+    async fn logins_without_the_second_factor_are_unauthorized() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let register = with_auth(request(Method::Post, "/register"), "Alice", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+        domain::totp::enable_totp(&db, &UserId("Alice".to_string())).unwrap();
+
+        let login = with_auth(request(Method::Post, "/login"), "Alice", "pw");
+        let res: http::Response = app.respond(login).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(res.header("set-cookie").is_none());
+    }
+
+    #[async_std::test]
+    async fn malformed_authorization_is_rejected_before_the_handler() {
+        let db = in_memory_db::init_db();
+        let mut app = tide::with_state(db.clone());
+        app.at("/reached")
+            .with(ParseAuthorization)
+            .post(|_req: Request<in_memory_db::Db>| async { Ok("reached") });
+        let mut req = request(Method::Post, "/reached");
+        req.insert_header(AUTHORIZATION, "Basic not base64!");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let app = build_app(db.clone());
+        for path in &["/register", "/login", "/logout"] {
+            let mut req = request(Method::Post, path);
+            req.insert_header(AUTHORIZATION, "Bearer token");
+            let res: http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BadRequest, "{}", path);
+        }
+        assert_eq!(db.count_users().unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn malformed_authorization_is_audited() {
+        let audit = Arc::new(domain::InMemoryAuditSink::default());
+        let config = ApiConfig {
+            domain: DomainConfig {
+                audit: audit.clone(),
+                ..DomainConfig::default()
+            },
+            ..ApiConfig::default()
+        };
+        let app = build_app_with(in_memory_db::init_db(), config);
+        let mut req = request(Method::Post, "/login");
+        req.insert_header(AUTHORIZATION, "Bearer token");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let events = audit.events();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0].action, domain::AuditAction::Authenticate);
+        assert_eq!(events[0].user, None);
+    }
+
+    #[async_std::test]
+    async fn multiple_authorization_headers_are_rejected() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let register = with_auth(request(Method::Post, "/register"), "Alice", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+
+        let alice = format!("Basic {}", base64::encode("Alice:pw"));
+        let mallory = format!("Basic {}", base64::encode("Mallory:pw"));
+        for second in &[&mallory, &alice] {
+            let mut req = request(Method::Post, "/login");
+            req.append_header(AUTHORIZATION, alice.as_str());
+            req.append_header(AUTHORIZATION, second.as_str());
+            let res: http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BadRequest);
+        }
+        assert_eq!(db.count_sessions().unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn responses_carry_the_request_id() {
+        let app = build_app(in_memory_db::init_db());
+        let res: http::Response = app.respond(request(Method::Get, "/health")).await.unwrap();
+        let generated = res.header(REQUEST_ID).unwrap().as_str().to_string();
+        assert!(Uuid::parse_str(&generated).is_ok(), "{}", generated);
+        let res: http::Response = app.respond(request(Method::Get, "/health")).await.unwrap();
+        assert_ne!(res.header(REQUEST_ID).unwrap().as_str(), generated);
+
+        let mut req = request(Method::Post, "/login");
+        req.insert_header(REQUEST_ID, "from-the-proxy");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res.header(REQUEST_ID).unwrap().as_str(), "from-the-proxy");
+    }
+
+    #[async_std::test]
+    async fn missing_authorization_is_left_to_the_handler() {
+        let app = build_app(in_memory_db::init_db());
+        let res: http::Response = app.respond(request(Method::Post, "/logout")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let res: http::Response = app.respond(request(Method::Post, "/login")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn login_forms_are_accepted_without_authorization() {
+        let app = build_app(in_memory_db::init_db());
+        let register = with_auth(request(Method::Post, "/register"), "Alice", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+
+        let bodies = [
+            (mime::FORM, "username=Alice&password=pw"),
+            (mime::JSON, r#"{"username": "Alice", "password": "pw"}"#),
+        ];
+        for (content_type, body) in bodies.iter().cloned() {
+            let mut req = request(Method::Post, "/login");
+            req.set_body(body);
+            req.set_content_type(content_type);
+            let res: http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok, "{}", body);
+            assert!(res.header("set-cookie").is_some(), "{}", body);
+        }
+
+        let mut req = request(Method::Post, "/login");
+        req.set_body("username=Alice&password=wrong");
+        req.set_content_type(mime::FORM);
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut req = request(Method::Post, "/login");
+        req.set_body("username=Alice");
+        req.set_content_type(mime::FORM);
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn oversized_login_forms_are_rejected() {
+        let app = build_app(in_memory_db::init_db());
+        let register = with_auth(request(Method::Post, "/register"), "Alice", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+
+        let mut req = request(Method::Post, "/login");
+        req.set_body(format!(
+            "username=Alice&password=pw&padding={}",
+            "x".repeat(8 * 1024)
+        ));
+        req.set_content_type(mime::FORM);
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[async_std::test]
+    async fn logins_without_credentials_never_succeed() {
+        let app = build_app(in_memory_db::init_db());
+        for header in &["", " ", "Basic", "Basic ", "Basic Og=="] {
+            let mut req = request(Method::Post, "/login");
+            req.insert_header(AUTHORIZATION, *header);
+            let res: http::Response = app.respond(req).await.unwrap();
+            assert!(
+                res.status().is_client_error(),
+                "{:?} got {}",
+                header,
+                res.status()
+            );
+            assert!(res.header("set-cookie").is_none(), "{:?}", header);
+        }
+    }
+
+    fn assert_basic_challenge(res: &http::Response) {
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(
+            res.header(WWW_AUTHENTICATE).map(|h| h.as_str()),
+            Some(BASIC_CHALLENGE)
+        );
+    }
+
+    #[async_std::test]
+    async fn unauthenticated_requests_get_a_basic_challenge() {
+        let app = build_app(in_memory_db::init_db());
+        let res: http::Response = app.respond(request(Method::Get, "/secret")).await.unwrap();
+        assert_basic_challenge(&res);
+
+        let res: http::Response = app.respond(request(Method::Post, "/login")).await.unwrap();
+        assert_basic_challenge(&res);
+
+        let login = with_auth(request(Method::Post, "/login"), "Alice", "pw");
+        let res: http::Response = app.respond(login).await.unwrap();
+        assert_basic_challenge(&res);
+
+        let res: http::Response = app.respond(request(Method::Get, "/health")).await.unwrap();
+        assert!(res.header(WWW_AUTHENTICATE).is_none());
+    }
+
+    async fn get_secret_accepting(accept: Option<&str>) -> http::Response {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let cookie = login_cookie(&app, "Alice", "pw").await;
+        db.set_secret(&UserId("Alice".to_string()), "swordfish".to_string())
+            .unwrap();
+
+        let mut req = request(Method::Get, "/secret");
+        req.insert_header("cookie", cookie);
+        if let Some(accept) = accept {
+            req.insert_header(ACCEPT, accept);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn secret_as_json_or_plain_text() {
+        let json = r#"{"user":"Alice","secret":"swordfish"}"#;
+        for accept in &[None, Some("*/*"), Some("application/json")] {
+            let mut res = get_secret_accepting(*accept).await;
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res.content_type(), Some(mime::JSON), "{:?}", accept);
+            assert_eq!(res.body_string().await.unwrap(), json);
+        }
+
+        for accept in &["text/plain", "application/json;q=0.5, text/plain"] {
+            let mut res = get_secret_accepting(Some(*accept)).await;
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res.content_type(), Some(mime::PLAIN), "{}", accept);
+            assert_eq!(res.body_string().await.unwrap(), "swordfish");
+        }
+    }
+
+    #[async_std::test]
+    async fn secret_in_other_formats_is_not_acceptable() {
+        let res = get_secret_accepting(Some("text/html")).await;
+        assert_eq!(res.status(), StatusCode::NotAcceptable);
+    }
+
+    #[async_std::test]
+    async fn secrets_of_other_users_are_forbidden() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let alice = login_cookie(&app, "Alice", "pw").await;
+        let register = with_auth(request(Method::Post, "/register"), "Bob", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+        db.set_secret(&UserId("Alice".to_string()), "swordfish".to_string())
+            .unwrap();
+
+        // Unknown users look like any other user, so the session can't probe for names
+        let expected = [
+            ("/secret/Carol", StatusCode::Forbidden),
+            ("/secret/Bob", StatusCode::Forbidden),
+            ("/secret/Alice", StatusCode::Ok),
+        ];
+        for (path, status) in expected.iter() {
+            let mut req = request(Method::Get, path);
+            req.insert_header("cookie", alice.as_str());
+            let res: http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), *status, "{}", path);
+
+            let res: http::Response = app.respond(request(Method::Get, path)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Unauthorized, "{}", path);
+        }
+    }
+
+    #[async_std::test]
+    async fn only_returned_secrets_count_as_reads() {
+        let db = in_memory_db::init_db();
+        let app = build_app(db.clone());
+        let cookie = login_cookie(&app, "Alice", "pw").await;
+        let register = with_auth(request(Method::Post, "/register"), "Bob", "pw");
+        app.respond::<_, http::Response>(register).await.unwrap();
+        let (alice, bob) = (UserId("Alice".to_string()), UserId("Bob".to_string()));
+        db.set_secret(&alice, "swordfish".to_string()).unwrap();
+        db.set_secret(&bob, "hunter2".to_string()).unwrap();
+
+        for _ in 0..3 {
+            let mut req = request(Method::Get, "/secret/Alice");
+            req.insert_header("cookie", cookie.as_str());
+            let res: http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+        let res: http::Response = app
+            .respond(request(Method::Get, "/secret/Alice"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let mut req = request(Method::Get, "/secret/Bob");
+        req.insert_header("cookie", cookie.as_str());
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        let mut req = request(Method::Get, "/secret/Alice");
+        req.insert_header("cookie", cookie.as_str());
+        req.insert_header(ACCEPT, "image/png");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotAcceptable);
+
+        assert_eq!(domain::secret_access_count(&db, &alice).unwrap(), 3);
+        assert_eq!(domain::secret_access_count(&db, &bob).unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn panicking_handlers_get_a_500_with_the_request_id() {
+        async fn panics(_: Request<in_memory_db::Db>) -> tide::Result {
+            panic!("poisoned")
+        }
+        let mut app = build_app(in_memory_db::init_db());
+        app.at("/panic").get(panics);
+
+        let mut req = request(Method::Get, "/panic");
+        req.insert_header(REQUEST_ID, "req-1");
+        let mut res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        assert_eq!(res.header(REQUEST_ID).unwrap().as_str(), "req-1");
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "Internal Server Error", "request_id": "req-1" })
+        );
+
+        // The app keeps serving
+        let res: http::Response = app.respond(request(Method::Get, "/health")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    async fn get_empty_secret(empty: EmptySecret) -> http::Response {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        db.add_session(alice.clone()).unwrap();
+        db.add_session_token(SessionToken::new("token".to_string()), alice)
+            .unwrap();
+        let mut app = tide::with_state(db);
+        app.at("/secret/:user").get(secret_with(empty));
+
+        let mut req = request(Method::Get, "/secret/Alice");
+        req.insert_header("cookie", format!("{SESSION_COOKIE}=token"));
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn empty_secret_as_no_content() {
+        let res = get_empty_secret(EmptySecret::NoContent).await;
+        assert_eq!(res.status(), StatusCode::NoContent);
+    }
+
+    #[async_std::test]
+    async fn empty_secret_as_not_found() {
+        let res = get_empty_secret(EmptySecret::NotFound).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn empty_secret_as_empty_json() {
+        let mut res = get_empty_secret(EmptySecret::EmptyJson).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.content_type(), Some(mime::JSON));
+        assert_eq!(res.body_string().await.unwrap(), "{}");
+    }
+}
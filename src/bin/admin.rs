@@ -0,0 +1,88 @@
+//! Manages the users in the server's sled database, see `AppConfig::db_path`, with the same
+//! configuration as the server. Run it while the server is stopped, as sled only lets one process
+//! open a database.
+//!
+//! ```text
+//! admin add-user <id>
+//! admin delete-user <id>
+//! admin list-users
+//! admin reset-password <id>
+//! ```
+//!
+//! Passwords are read from the first line of stdin, so that they can be piped in.
+
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    path::Path,
+    process,
+};
+
+use anyhow::Context;
+use model_testing::{
+    config::AppConfig, list_users, register_with, reset_password, sled_db::SledDb, unregister,
+    EnteredPassword, UserId,
+};
+
+const USAGE: &str =
+    "Usage: admin add-user <id> | delete-user <id> | list-users | reset-password <id>";
+
+enum Command {
+    AddUser(UserId),
+    DeleteUser(UserId),
+    ListUsers,
+    ResetPassword(UserId),
+}
+
+fn parse_args(args: &[String]) -> Option<Command> {
+    let user_id = |id: &String| UserId(id.clone());
+    match args {
+        [command, id] if command == "add-user" => Some(Command::AddUser(user_id(id))),
+        [command, id] if command == "delete-user" => Some(Command::DeleteUser(user_id(id))),
+        [command] if command == "list-users" => Some(Command::ListUsers),
+        [command, id] if command == "reset-password" => Some(Command::ResetPassword(user_id(id))),
+        _ => None,
+    }
+}
+
+fn read_password() -> anyhow::Result<EnteredPassword> {
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    Ok(EnteredPassword::new(password.to_string()))
+}
+
+fn main() -> anyhow::Result<()> {
+    let command = match parse_args(&env::args().skip(1).collect::<Vec<_>>()) {
+        Some(command) => command,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    // See `AppConfig` for the settings and the variables that override them
+    let config = AppConfig::load(env::var_os("SIMTEST_CONFIG_FILE").as_deref().map(Path::new))?;
+    let path = config
+        .db_path
+        .as_ref()
+        .context("No database configured, set SIMTEST_DB_PATH or db_path in the config file")?;
+    let db = SledDb::open(path)?;
+    let domain_config = config.domain_config();
+
+    match command {
+        Command::AddUser(user_id) => register_with(&db, &domain_config, user_id, read_password()?)?,
+        Command::DeleteUser(user_id) => unregister(&db, &user_id)?,
+        Command::ListUsers => {
+            for user_id in list_users(&db)? {
+                println!("{}", user_id.0);
+            }
+        }
+        Command::ResetPassword(user_id) => {
+            reset_password(&db, &domain_config, &user_id, read_password()?)?
+        }
+    }
+    db.flush()?;
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use anyhow::anyhow;
+use futures_lite::AsyncReadExt;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+/// Rejects requests with a body over `max_len` bytes with `413 Payload Too Large`, so that
+/// handlers that buffer the body, like the login form, can't be made to buffer an arbitrary
+/// amount of it.
+///
+/// Bodies of a declared length are checked before any of them is read. Bodies of unknown length,
+/// like chunked ones, are read up to one byte over the limit and handed on from memory.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimit {
+    max_len: usize,
+}
+
+impl BodyLimit {
+    pub fn new(max_len: usize) -> Self {
+        Self { max_len }
+    }
+
+    fn too_large(&self) -> tide::Error {
+        tide::Error::new(
+            StatusCode::PayloadTooLarge,
+            anyhow!("Request body over {} bytes", self.max_len),
+        )
+    }
+}
+
+/// 8 KiB, plenty for credentials.
+impl Default for BodyLimit {
+    fn default() -> Self {
+        Self::new(8 * 1024)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimit {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match req.len() {
+            Some(len) if len > self.max_len => return Err(self.too_large()),
+            Some(_) => {}
+            None => {
+                let body = req.take_body();
+                let mime = body.mime().clone();
+                let mut bytes = Vec::new();
+                body.take(self.max_len as u64 + 1)
+                    .read_to_end(&mut bytes)
+                    .await?;
+                if bytes.len() > self.max_len {
+                    return Err(self.too_large());
+                }
+                let mut body = Body::from_bytes(bytes);
+                body.set_mime(mime);
+                req.set_body(body);
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::io::Cursor;
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+
+    fn app() -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(BodyLimit::new(4));
+        app.at("/echo")
+            .post(|mut req: Request<()>| async move { Ok(req.body_string().await?) });
+        app
+    }
+
+    fn post(body: Body) -> http::Request {
+        let url = Url::parse("http://localhost/echo").unwrap();
+        let mut req = http::Request::new(Method::Post, url);
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn bodies_over_the_limit_are_rejected() {
+        let app = app();
+        for len in 0..=4 {
+            let body = "x".repeat(len);
+            let mut res: http::Response =
+                app.respond(post(Body::from(body.as_str()))).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res.body_string().await.unwrap(), body);
+        }
+
+        let res: http::Response = app.respond(post(Body::from("xxxxx"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+
+    #[async_std::test]
+    async fn bodies_of_unknown_length_are_only_read_up_to_the_limit() {
+        let app = app();
+        let unknown_len = |body: &'static str| Body::from_reader(Cursor::new(body), None);
+
+        let mut res: http::Response = app.respond(post(unknown_len("xxxx"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "xxxx");
+
+        let res: http::Response = app.respond(post(unknown_len("xxxxx"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+}
@@ -0,0 +1,644 @@
+use std::{
+    collections::VecDeque,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::anyhow;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+
+use crate::domain::{
+    db::{self, Db as _, DbError, DbErrorKind, DbResult, SessionTimes},
+    EncodedPassword, RefreshToken, Role, SessionToken, UserId, UserProfile,
+};
+
+/// An in-memory `Db` like `in_memory_db::Db`, but on `DashMap`s, whose entries are locked
+/// separately, so that calls for different users practically never wait for each other. Meant for
+/// benchmarks under high concurrency, a drop-in for `in_memory_db::Db` elsewhere.
+///
+/// Calls that touch several maps take their locks one after the other, never at the same time,
+/// so they can't deadlock, but other calls can see them half done. `rename_user` registers the
+/// new name before it unregisters the old one, so both are taken for a moment. Readonly views
+/// work like those of `in_memory_db::Db`, transactions don't lock out other callers, see
+/// `transaction`. There is no `DbConfig`, users can have any number of session tokens.
+#[derive(Default, Clone)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Db {
+    users: Arc<DashMap<UserId, EncodedPassword>>,
+    sessions: Arc<DashSet<UserId>>,
+    secrets: Arc<DashMap<UserId, String>>,
+    /// How often each secret was read. Only changed while holding the secret's entry.
+    secret_reads: Arc<DashMap<UserId, u64>>,
+    last_activity: Arc<DashMap<UserId, Instant>>,
+    session_tokens: Arc<DashMap<SessionToken, UserId>>,
+    /// The session tokens of each user, to revoke them with the session.
+    tokens_by_user: Arc<DashMap<UserId, Vec<SessionToken>>>,
+    profiles: Arc<DashMap<UserId, UserProfile>>,
+    totp_secrets: Arc<DashMap<UserId, Vec<u8>>>,
+    /// The last time step a TOTP code of each user was accepted for.
+    totp_steps: Arc<DashMap<UserId, u64>>,
+    roles: Arc<DashMap<UserId, Role>>,
+    session_times: Arc<DashMap<UserId, SessionTimes>>,
+    refresh_tokens: Arc<DashMap<RefreshToken, UserId>>,
+    /// The former passwords of each user, most recent first.
+    password_history: Arc<DashMap<UserId, VecDeque<EncodedPassword>>>,
+    /// Held for the duration of a transaction, so that transactions don't interleave.
+    transaction: Arc<Mutex<()>>,
+}
+
+pub fn init_db() -> Db {
+    Db::default()
+}
+
+impl Db {
+    /// A deep copy of all data, to restore if a transaction fails or to read from in
+    /// `with_readonly_view`.
+    fn snapshot(&self) -> Db {
+        fn copy<T: Clone>(map: &Arc<T>) -> Arc<T> {
+            Arc::new(T::clone(map))
+        }
+        Db {
+            users: copy(&self.users),
+            sessions: copy(&self.sessions),
+            secrets: copy(&self.secrets),
+            last_activity: copy(&self.last_activity),
+            session_tokens: copy(&self.session_tokens),
+            tokens_by_user: copy(&self.tokens_by_user),
+            profiles: copy(&self.profiles),
+            totp_secrets: copy(&self.totp_secrets),
+            totp_steps: copy(&self.totp_steps),
+            roles: copy(&self.roles),
+            session_times: copy(&self.session_times),
+            refresh_tokens: copy(&self.refresh_tokens),
+            password_history: copy(&self.password_history),
+            secret_reads: copy(&self.secret_reads),
+            transaction: Arc::default(),
+        }
+    }
+
+    /// Applies the changes that `after` has over `before` to the live data, entry by entry, so
+    /// that entries that changed only in `after` are written and those removed in `after` are
+    /// removed. All other entries are left as they are now.
+    fn apply_changes(&self, before: &Db, after: &Db) {
+        fn apply<K: Eq + Hash + Clone, V: Clone>(
+            map: &DashMap<K, V>,
+            before: &DashMap<K, V>,
+            after: &DashMap<K, V>,
+            same: impl Fn(&V, &V) -> bool,
+        ) {
+            for entry in after.iter() {
+                let unchanged = before
+                    .get(entry.key())
+                    .map_or(false, |old| same(&old, entry.value()));
+                if !unchanged {
+                    map.insert(entry.key().clone(), entry.value().clone());
+                }
+            }
+            for entry in before.iter() {
+                if !after.contains_key(entry.key()) {
+                    map.remove(entry.key());
+                }
+            }
+        }
+        fn eq<V: PartialEq>(a: &V, b: &V) -> bool {
+            a == b
+        }
+        fn same_pw(a: &EncodedPassword, b: &EncodedPassword) -> bool {
+            a.as_str() == b.as_str()
+        }
+        apply(&self.users, &before.users, &after.users, same_pw);
+        for user_id in after.sessions.iter() {
+            self.sessions.insert(user_id.clone());
+        }
+        for user_id in before.sessions.iter() {
+            if !after.sessions.contains(&*user_id) {
+                self.sessions.remove(&*user_id);
+            }
+        }
+        apply(&self.secrets, &before.secrets, &after.secrets, eq);
+        apply(
+            &self.last_activity,
+            &before.last_activity,
+            &after.last_activity,
+            eq,
+        );
+        apply(
+            &self.session_tokens,
+            &before.session_tokens,
+            &after.session_tokens,
+            eq,
+        );
+        apply(
+            &self.tokens_by_user,
+            &before.tokens_by_user,
+            &after.tokens_by_user,
+            eq,
+        );
+        apply(&self.profiles, &before.profiles, &after.profiles, eq);
+        apply(
+            &self.totp_secrets,
+            &before.totp_secrets,
+            &after.totp_secrets,
+            eq,
+        );
+        apply(&self.totp_steps, &before.totp_steps, &after.totp_steps, eq);
+        apply(&self.roles, &before.roles, &after.roles, eq);
+        apply(
+            &self.session_times,
+            &before.session_times,
+            &after.session_times,
+            eq,
+        );
+        apply(
+            &self.refresh_tokens,
+            &before.refresh_tokens,
+            &after.refresh_tokens,
+            eq,
+        );
+        apply(
+            &self.password_history,
+            &before.password_history,
+            &after.password_history,
+            |a, b| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_pw(a, b)),
+        );
+        apply(
+            &self.secret_reads,
+            &before.secret_reads,
+            &after.secret_reads,
+            eq,
+        );
+    }
+
+    /// Revokes the user's session tokens, found with `tokens_by_user`.
+    fn remove_session_tokens(&self, user_id: &UserId) {
+        if let Some((_, tokens)) = self.tokens_by_user.remove(user_id) {
+            for token in tokens {
+                self.session_tokens.remove(&token);
+            }
+        }
+    }
+}
+
+impl db::Db for Db {
+    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+        self.register_with_profile(user_id, password, UserProfile::default())
+    }
+
+    fn add_session(&self, user_id: UserId) -> DbResult {
+        self.sessions.insert(user_id);
+        Ok(())
+    }
+
+    fn remove_session(&self, user_id: &UserId) -> DbResult {
+        self.sessions.remove(user_id);
+        self.session_times.remove(user_id);
+        self.remove_session_tokens(user_id);
+        Ok(())
+    }
+
+    fn remove_all_sessions(&self, user_id: &UserId) -> DbResult {
+        // Users only ever have a single session for now
+        self.remove_session(user_id)
+    }
+
+    fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>> {
+        Ok(self.users.get(user_id).map(|pw| pw.clone()))
+    }
+
+    fn has_session(&self, user_id: &UserId) -> DbResult<bool> {
+        Ok(self.sessions.contains(user_id))
+    }
+
+    fn get_secret(&self, user_id: &UserId) -> DbResult<Option<String>> {
+        let secret = match self.secrets.get(user_id) {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+        *self.secret_reads.entry(user_id.clone()).or_insert(0) += 1;
+        Ok(Some(secret.clone()))
+    }
+
+    fn set_secret(&self, user_id: &UserId, secret: String) -> DbResult {
+        self.secrets.insert(user_id.clone(), secret);
+        Ok(())
+    }
+
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.last_activity.insert(user_id.clone(), at);
+        Ok(())
+    }
+
+    fn last_activity_before(&self, cutoff: Instant) -> DbResult<Vec<UserId>> {
+        Ok(self
+            .last_activity
+            .iter()
+            .filter(|last| *last.value() < cutoff)
+            .map(|last| last.key().clone())
+            .collect())
+    }
+
+    fn count_users(&self) -> DbResult<usize> {
+        Ok(self.users.len())
+    }
+
+    fn count_sessions(&self) -> DbResult<usize> {
+        Ok(self.sessions.len())
+    }
+
+    fn add_session_token(&self, token: SessionToken, user_id: UserId) -> DbResult {
+        self.tokens_by_user
+            .entry(user_id.clone())
+            .or_default()
+            .push(token.clone());
+        self.session_tokens.insert(token, user_id);
+        Ok(())
+    }
+
+    fn get_session_token(&self, token: &SessionToken) -> DbResult<Option<UserId>> {
+        Ok(self.session_tokens.get(token).map(|owner| owner.clone()))
+    }
+
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult {
+        match self.users.entry(user_id.clone()) {
+            Entry::Occupied(_) => {
+                return Err(DbError::new(
+                    DbErrorKind::Conflict,
+                    anyhow!("{:?} is already registered", user_id),
+                ))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(password);
+            }
+        }
+        self.profiles.insert(user_id, profile);
+        Ok(())
+    }
+
+    fn get_profile(&self, user_id: &UserId) -> DbResult<Option<UserProfile>> {
+        Ok(self.profiles.get(user_id).map(|profile| profile.clone()))
+    }
+
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> DbResult {
+        self.totp_secrets.insert(user_id.clone(), secret);
+        Ok(())
+    }
+
+    fn get_totp_secret(&self, user_id: &UserId) -> DbResult<Option<Vec<u8>>> {
+        Ok(self.totp_secrets.get(user_id).map(|secret| secret.clone()))
+    }
+
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> DbResult<bool> {
+        match self.totp_steps.entry(user_id.clone()) {
+            Entry::Occupied(entry) if *entry.get() >= step => Ok(false),
+            Entry::Occupied(mut entry) => {
+                entry.insert(step);
+                Ok(true)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(step);
+                Ok(true)
+            }
+        }
+    }
+
+    fn set_role(&self, user_id: &UserId, role: Role) -> DbResult {
+        self.roles.insert(user_id.clone(), role);
+        Ok(())
+    }
+
+    fn get_role(&self, user_id: &UserId) -> DbResult<Role> {
+        Ok(self
+            .roles
+            .get(user_id)
+            .map(|role| *role)
+            .unwrap_or_default())
+    }
+
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.session_times
+            .entry(user_id.clone())
+            .and_modify(|times| times.last_seen = at)
+            .or_insert(SessionTimes {
+                started: at,
+                last_seen: at,
+            });
+        Ok(())
+    }
+
+    fn get_session_times(&self, user_id: &UserId) -> DbResult<Option<SessionTimes>> {
+        Ok(self.session_times.get(user_id).map(|times| *times))
+    }
+
+    fn unregister(&self, user_id: &UserId) -> DbResult {
+        if self.users.remove(user_id).is_none() {
+            return Err(not_registered(user_id));
+        }
+        self.sessions.remove(user_id);
+        self.secrets.remove(user_id);
+        self.last_activity.remove(user_id);
+        self.remove_session_tokens(user_id);
+        self.profiles.remove(user_id);
+        self.totp_secrets.remove(user_id);
+        self.totp_steps.remove(user_id);
+        self.roles.remove(user_id);
+        self.session_times.remove(user_id);
+        self.refresh_tokens.retain(|_, owner| owner != user_id);
+        self.password_history.remove(user_id);
+        self.secret_reads.remove(user_id);
+        Ok(())
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult {
+        match self.users.get_mut(user_id) {
+            Some(mut stored) => {
+                *stored = password;
+                Ok(())
+            }
+            None => Err(not_registered(user_id)),
+        }
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool> {
+        match self.users.get_mut(user_id) {
+            Some(mut stored) if stored.as_str() == expected.as_str() => {
+                *stored = new;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(not_registered(user_id)),
+        }
+    }
+
+    fn list_users(&self) -> DbResult<Vec<UserId>> {
+        Ok(self.users.iter().map(|user| user.key().clone()).collect())
+    }
+
+    fn user_exists(&self, user_id: &UserId) -> DbResult<bool> {
+        Ok(self.users.contains_key(user_id))
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken, user_id: UserId) -> DbResult {
+        self.refresh_tokens.insert(token, user_id);
+        Ok(())
+    }
+
+    fn take_refresh_token(&self, token: &RefreshToken) -> DbResult<Option<UserId>> {
+        Ok(self.refresh_tokens.remove(token).map(|(_, owner)| owner))
+    }
+
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> DbResult {
+        self.refresh_tokens.retain(|_, owner| owner != user_id);
+        Ok(())
+    }
+
+    /// Registers `new` with the password of `old` first, so that no one else can take the new
+    /// name, then moves the other entries and unregisters `old`.
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult {
+        let password = self
+            .users
+            .get(old)
+            .map(|pw| pw.clone())
+            .ok_or_else(|| not_registered(old))?;
+        match self.users.entry(new.clone()) {
+            Entry::Occupied(_) => {
+                return Err(DbError::new(
+                    DbErrorKind::Conflict,
+                    anyhow!("{:?} is already registered", new),
+                ))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(password);
+            }
+        }
+
+        fn rename<V>(map: &DashMap<UserId, V>, old: &UserId, new: &UserId) {
+            if let Some((_, value)) = map.remove(old) {
+                map.insert(new.clone(), value);
+            }
+        }
+        fn reassign<K: Eq + std::hash::Hash>(map: &DashMap<K, UserId>, old: &UserId, new: &UserId) {
+            for mut owner in map.iter_mut() {
+                if owner.value() == old {
+                    *owner = new.clone();
+                }
+            }
+        }
+        if self.sessions.remove(old).is_some() {
+            self.sessions.insert(new.clone());
+        }
+        rename(&self.secrets, old, &new);
+        rename(&self.last_activity, old, &new);
+        rename(&self.profiles, old, &new);
+        rename(&self.totp_secrets, old, &new);
+        rename(&self.totp_steps, old, &new);
+        rename(&self.roles, old, &new);
+        rename(&self.session_times, old, &new);
+        reassign(&self.session_tokens, old, &new);
+        rename(&self.tokens_by_user, old, &new);
+        reassign(&self.refresh_tokens, old, &new);
+        rename(&self.password_history, old, &new);
+        rename(&self.secret_reads, old, &new);
+        // Takes a password set under the old name meanwhile along
+        if let Some((_, password)) = self.users.remove(old) {
+            self.users.insert(new, password);
+        }
+        Ok(())
+    }
+
+    /// Always reachable.
+    fn ping(&self) -> DbResult {
+        Ok(())
+    }
+
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> DbResult {
+        let mut history = self.password_history.entry(user_id.clone()).or_default();
+        history.push_front(password);
+        history.truncate(keep);
+        Ok(())
+    }
+
+    fn get_password_history(&self, user_id: &UserId) -> DbResult<Vec<EncodedPassword>> {
+        Ok(self
+            .password_history
+            .get(user_id)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn secret_access_count(&self, user_id: &UserId) -> DbResult<u64> {
+        Ok(self.secret_reads.get(user_id).map_or(0, |reads| *reads))
+    }
+
+    fn iter_sessions(&self) -> DbResult<Vec<(UserId, Option<SessionTimes>)>> {
+        let users: Vec<UserId> = self.sessions.iter().map(|user| user.clone()).collect();
+        Ok(users
+            .into_iter()
+            .map(|user_id| {
+                let times = self.session_times.get(&user_id).map(|times| *times);
+                (user_id, times)
+            })
+            .collect())
+    }
+
+    /// Runs `f` against a snapshot. Copying takes each map's locks in turn, so a write that
+    /// races with the copy may show up in some maps of the snapshot but not in others.
+    fn with_readonly_view(&self, f: &mut dyn FnMut(&dyn db::Db) -> DbResult) -> DbResult {
+        f(&db::ReadOnlyView::new(&self.snapshot()))
+    }
+
+    /// Runs `f` against a snapshot and, if it succeeds, applies what `f` changed in it to the
+    /// live data, entry by entry. A failed transaction changes nothing, and other callers' writes
+    /// are only overwritten in entries that the transaction changed too. It isn't isolated
+    /// though: others can see its changes half applied. Transactions don't interleave.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn db::Db) -> DbResult) -> DbResult {
+        let _transaction = self.transaction.lock().unwrap();
+        let before = self.snapshot();
+        let after = before.snapshot();
+        let result = f(&after);
+        if result.is_ok() {
+            self.apply_changes(&before, &after);
+        }
+        result
+    }
+}
+
+fn not_registered(user_id: &UserId) -> DbError {
+    DbError::new(
+        DbErrorKind::NotFound,
+        anyhow!("{:?} is not registered", user_id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{domain::EnteredPassword, login, logout, register, AuthHeader};
+
+    #[test]
+    fn concurrent_logins_of_different_users_all_succeed() {
+        let db = init_db();
+        let pass = EnteredPassword::new("pw".to_string());
+        let users = (0..8)
+            .map(|i| UserId(format!("User{}", i)))
+            .collect::<Vec<_>>();
+        let handles = users
+            .iter()
+            .map(|user| {
+                let (db, user, pass) = (db.clone(), user.clone(), pass.clone());
+                thread::spawn(move || {
+                    register(&db, user.clone(), pass.clone()).unwrap();
+                    let header = AuthHeader::basic(&user, &pass);
+                    for _ in 0..3 {
+                        login(&db, &header).unwrap();
+                        logout(&db, &header).unwrap();
+                    }
+                    login(&db, &header).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(db.count_users().unwrap(), users.len());
+        assert_eq!(db.count_sessions().unwrap(), users.len());
+        for user in &users {
+            assert!(db.has_session(user).unwrap());
+        }
+    }
+
+    #[test]
+    fn session_tokens_end_with_the_session() {
+        let db = init_db();
+        let (alice, bob) = (UserId("Alice".to_string()), UserId("Bob".to_string()));
+        let tokens = [SessionToken::generate(), SessionToken::generate()];
+        db.add_session_token(tokens[0].clone(), alice.clone())
+            .unwrap();
+        db.add_session_token(tokens[1].clone(), bob.clone())
+            .unwrap();
+
+        db.remove_session(&alice).unwrap();
+        assert_eq!(db.get_session_token(&tokens[0]).unwrap(), None);
+        assert_eq!(db.get_session_token(&tokens[1]).unwrap(), Some(bob));
+        assert!(!db.tokens_by_user.contains_key(&alice));
+    }
+
+    #[test]
+    fn concurrent_registrations_of_one_name_conflict() {
+        let db = init_db();
+        let user = UserId("Alice".to_string());
+        let pw = EnteredPassword::new("pw".to_string()).encode().unwrap();
+        let handles = (0..8)
+            .map(|_| {
+                let (db, user, pw) = (db.clone(), user.clone(), pw.clone());
+                thread::spawn(move || db.register(user, pw))
+            })
+            .collect::<Vec<_>>();
+        let registered = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(Result::is_ok)
+            .count();
+
+        assert_eq!(registered, 1);
+        assert_eq!(db.count_users().unwrap(), 1);
+    }
+
+    #[test]
+    fn failed_transactions_leave_the_db_unchanged() {
+        let db = init_db();
+        let alice = UserId("Alice".to_string());
+        register(&db, alice.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        db.add_session(alice.clone()).unwrap();
+
+        let result = db.transaction(&mut |tx| {
+            tx.set_secret(&alice, "swordfish".to_string())?;
+            tx.remove_all_sessions(&alice)?;
+            tx.unregister(&UserId("Bob".to_string()))
+        });
+        assert_eq!(result.unwrap_err().kind(), DbErrorKind::NotFound);
+        assert_eq!(db.get_secret(&alice).unwrap(), None);
+        assert!(db.has_session(&alice).unwrap());
+    }
+
+    #[test]
+    fn transactions_keep_the_writes_of_others() {
+        let db = init_db();
+        let (alice, bob) = (UserId("Alice".to_string()), UserId("Bob".to_string()));
+        db.set_secret(&bob, "old".to_string()).unwrap();
+
+        for fail in &[true, false] {
+            db.transaction(&mut |tx| {
+                tx.set_secret(&alice, "swordfish".to_string())?;
+                db.set_secret(&bob, format!("written meanwhile, {}", fail))?;
+                if *fail {
+                    tx.unregister(&bob)
+                } else {
+                    Ok(())
+                }
+            })
+            .unwrap_or(());
+            let bobs = db.get_secret(&bob).unwrap();
+            assert_eq!(bobs, Some(format!("written meanwhile, {}", fail)));
+        }
+        assert_eq!(db.get_secret(&alice).unwrap().as_deref(), Some("swordfish"));
+    }
+}
@@ -0,0 +1,445 @@
+//! The settings of the server binary, see `AppConfig`.
+
+#[cfg(feature = "sled")]
+use std::path::PathBuf;
+#[cfg(feature = "signed-tokens")]
+use std::{convert::TryFrom, fmt};
+use std::{env, fs, net::IpAddr, path::Path, str::FromStr, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+
+#[cfg(feature = "signed-tokens")]
+use crate::domain::SignedTokens;
+use crate::{
+    api::ApiConfig,
+    body_limit::BodyLimit,
+    domain::{
+        Argon2Params, CredentialsCharset, DomainConfig, OpaqueTokens, PasswordPolicy,
+        SessionPolicy, TokenGenerator,
+    },
+    idempotency::Idempotency,
+    in_memory_db::DbConfig,
+    rate_limit::RateLimit,
+};
+
+/// Everything the server binary can be configured with, handed to the subsystems with
+/// `api_config`, `domain_config` and `db_config`.
+///
+/// `load` starts from the defaults, overrides them with a TOML file with the same keys as the
+/// fields, and overrides that with environment variables, named like the fields in upper case
+/// with the prefix `SIMTEST_`, e.g. `SIMTEST_LISTEN_ADDR`. Optional settings are unset by an empty
+/// variable.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub listen_addr: String,
+    /// Sessions never go idle if unset, the default.
+    pub session_idle_timeout_secs: Option<u64>,
+    /// Sessions last until logout if unset, the default.
+    pub session_ttl_secs: Option<u64>,
+    pub password_min_len: usize,
+    pub password_require_digit: bool,
+    pub password_max_len: usize,
+    pub password_history_len: usize,
+    /// The cost of new password hashes, see `Argon2Params`.
+    pub argon2_mem_cost_kib: u32,
+    pub argon2_time_cost: u32,
+    pub argon2_lanes: u32,
+    pub max_auth_header_len: usize,
+    /// `utf8`, the default, or `latin1_fallback`, see `CredentialsCharset`.
+    pub credentials_charset: CredentialsCharset,
+    /// Requests per second and client.
+    pub rate_limit_per_sec: f64,
+    pub rate_limit_burst: u32,
+    /// The proxies whose forwarding headers name the client to rate limit, none by default. A
+    /// comma-separated list in the environment.
+    pub rate_limit_trusted_proxies: Vec<IpAddr>,
+    pub idempotency_ttl_secs: u64,
+    pub idempotency_max_entries: usize,
+    pub min_login_time_ms: u64,
+    pub body_limit_bytes: usize,
+    /// Unlimited if unset, the default.
+    pub max_sessions_per_user: Option<usize>,
+    /// Operations that take longer are logged as slow. None are if unset, the default.
+    pub slow_op_threshold_ms: Option<u64>,
+    /// Session tokens are signed with this key, see `SignedTokens`, if set. They are random if
+    /// unset, the default.
+    #[cfg(feature = "signed-tokens")]
+    pub session_token_key: Option<SessionTokenKey>,
+    /// Users and sessions are kept in a sled database at this path if set, see `SledDb`, so that
+    /// they survive restarts. The `admin` binary opens the same one. They are kept in memory if
+    /// unset, the default.
+    #[cfg(feature = "sled")]
+    pub db_path: Option<PathBuf>,
+}
+
+/// The defaults of the configured types, so that the binary behaves like `build_app` and
+/// `init_db` without any configuration.
+impl Default for AppConfig {
+    fn default() -> Self {
+        let domain = DomainConfig::default();
+        Self {
+            listen_addr: "127.0.0.1:8080".to_string(),
+            session_idle_timeout_secs: None,
+            session_ttl_secs: None,
+            password_min_len: domain.password_policy.min_len,
+            password_require_digit: domain.password_policy.require_digit,
+            password_max_len: domain.password_policy.max_len,
+            password_history_len: domain.password_history_len,
+            argon2_mem_cost_kib: domain.argon2.mem_cost,
+            argon2_time_cost: domain.argon2.time_cost,
+            argon2_lanes: domain.argon2.lanes,
+            max_auth_header_len: domain.max_auth_header_len,
+            credentials_charset: domain.credentials_charset,
+            rate_limit_per_sec: 10.0,
+            rate_limit_burst: 30,
+            rate_limit_trusted_proxies: vec![],
+            idempotency_ttl_secs: 24 * 60 * 60,
+            idempotency_max_entries: 10_000,
+            min_login_time_ms: 0,
+            body_limit_bytes: 8 * 1024,
+            max_sessions_per_user: None,
+            slow_op_threshold_ms: None,
+            #[cfg(feature = "signed-tokens")]
+            session_token_key: None,
+            #[cfg(feature = "sled")]
+            db_path: None,
+        }
+    }
+}
+
+/// The prefix of the environment variables of `AppConfig`.
+pub const ENV_PREFIX: &str = "SIMTEST_";
+
+/// The key of `SignedTokens`, in base64 in the file and the environment. At least 32 bytes.
+#[cfg(feature = "signed-tokens")]
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SessionTokenKey(Vec<u8>);
+
+#[cfg(feature = "signed-tokens")]
+#[derive(thiserror::Error, Debug)]
+#[error("The session token key has to be at least 32 bytes in base64")]
+pub struct InvalidTokenKey;
+
+#[cfg(feature = "signed-tokens")]
+impl FromStr for SessionTokenKey {
+    type Err = InvalidTokenKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match base64::decode(s) {
+            Ok(key) if key.len() >= 32 => Ok(Self(key)),
+            _ => Err(InvalidTokenKey),
+        }
+    }
+}
+
+#[cfg(feature = "signed-tokens")]
+impl TryFrom<String> for SessionTokenKey {
+    type Error = InvalidTokenKey;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Doesn't show the key, so that configs can be logged.
+#[cfg(feature = "signed-tokens")]
+impl fmt::Debug for SessionTokenKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionTokenKey(..)")
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Can't read config file: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid value {value:?} of {name}")]
+    InvalidVar { name: String, value: String },
+}
+
+impl AppConfig {
+    /// The defaults, overridden by the TOML file at `path` if there is one, overridden by the
+    /// environment of the process.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::from_toml(&fs::read_to_string(path)?)?,
+            None => Self::default(),
+        };
+        config.apply_env(env::vars())?;
+        Ok(config)
+    }
+
+    /// The defaults, overridden by the keys in `toml`.
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Overrides the settings that have a variable in `vars`, e.g. `env::vars()`. Other
+    /// variables are ignored, including unprefixed ones.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            let setting = match name.strip_prefix(ENV_PREFIX) {
+                Some(setting) => setting,
+                None => continue,
+            };
+            match setting {
+                "LISTEN_ADDR" => self.listen_addr = value,
+                "SESSION_IDLE_TIMEOUT_SECS" => {
+                    self.session_idle_timeout_secs = parse_optional(&name, &value)?
+                }
+                "SESSION_TTL_SECS" => self.session_ttl_secs = parse_optional(&name, &value)?,
+                "PASSWORD_MIN_LEN" => self.password_min_len = parse(&name, &value)?,
+                "PASSWORD_REQUIRE_DIGIT" => self.password_require_digit = parse(&name, &value)?,
+                "PASSWORD_MAX_LEN" => self.password_max_len = parse(&name, &value)?,
+                "PASSWORD_HISTORY_LEN" => self.password_history_len = parse(&name, &value)?,
+                "ARGON2_MEM_COST_KIB" => self.argon2_mem_cost_kib = parse(&name, &value)?,
+                "ARGON2_TIME_COST" => self.argon2_time_cost = parse(&name, &value)?,
+                "ARGON2_LANES" => self.argon2_lanes = parse(&name, &value)?,
+                "MAX_AUTH_HEADER_LEN" => self.max_auth_header_len = parse(&name, &value)?,
+                "CREDENTIALS_CHARSET" => self.credentials_charset = parse(&name, &value)?,
+                "RATE_LIMIT_PER_SEC" => self.rate_limit_per_sec = parse(&name, &value)?,
+                "RATE_LIMIT_BURST" => self.rate_limit_burst = parse(&name, &value)?,
+                "RATE_LIMIT_TRUSTED_PROXIES" => {
+                    self.rate_limit_trusted_proxies = parse_list(&name, &value)?
+                }
+                "IDEMPOTENCY_TTL_SECS" => self.idempotency_ttl_secs = parse(&name, &value)?,
+                "IDEMPOTENCY_MAX_ENTRIES" => self.idempotency_max_entries = parse(&name, &value)?,
+                "MIN_LOGIN_TIME_MS" => self.min_login_time_ms = parse(&name, &value)?,
+                "BODY_LIMIT_BYTES" => self.body_limit_bytes = parse(&name, &value)?,
+                "MAX_SESSIONS_PER_USER" => {
+                    self.max_sessions_per_user = parse_optional(&name, &value)?
+                }
+                "SLOW_OP_THRESHOLD_MS" => {
+                    self.slow_op_threshold_ms = parse_optional(&name, &value)?
+                }
+                #[cfg(feature = "signed-tokens")]
+                "SESSION_TOKEN_KEY" => self.session_token_key = parse_optional(&name, &value)?,
+                #[cfg(feature = "sled")]
+                "DB_PATH" => self.db_path = parse_optional(&name, &value)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn domain_config(&self) -> DomainConfig {
+        DomainConfig {
+            password_policy: PasswordPolicy {
+                min_len: self.password_min_len,
+                require_digit: self.password_require_digit,
+                max_len: self.password_max_len,
+                ..PasswordPolicy::default()
+            },
+            session_policy: SessionPolicy {
+                idle_timeout: self.session_idle_timeout_secs.map(Duration::from_secs),
+                ttl: self.session_ttl_secs.map(Duration::from_secs),
+            },
+            argon2: Argon2Params {
+                mem_cost: self.argon2_mem_cost_kib,
+                time_cost: self.argon2_time_cost,
+                lanes: self.argon2_lanes,
+            },
+            password_history_len: self.password_history_len,
+            max_auth_header_len: self.max_auth_header_len,
+            credentials_charset: self.credentials_charset,
+            session_tokens: self.session_tokens(),
+            slow_op_threshold: self.slow_op_threshold_ms.map(Duration::from_millis),
+            ..DomainConfig::default()
+        }
+    }
+
+    /// `SignedTokens` with the `session_token_key` if there is one, random tokens otherwise.
+    fn session_tokens(&self) -> Arc<dyn TokenGenerator> {
+        #[cfg(feature = "signed-tokens")]
+        if let Some(key) = &self.session_token_key {
+            return Arc::new(SignedTokens::new(key.0.clone()));
+        }
+        Arc::new(OpaqueTokens)
+    }
+
+    /// Includes the `domain_config`.
+    pub fn api_config(&self) -> ApiConfig {
+        ApiConfig {
+            rate_limit: RateLimit::new(self.rate_limit_per_sec, self.rate_limit_burst)
+                .with_trusted_proxies(self.rate_limit_trusted_proxies.iter().copied()),
+            idempotency: Idempotency::new(Duration::from_secs(self.idempotency_ttl_secs))
+                .with_max_entries(self.idempotency_max_entries),
+            min_login_time: Duration::from_millis(self.min_login_time_ms),
+            body_limit: BodyLimit::new(self.body_limit_bytes),
+            domain: self.domain_config(),
+        }
+    }
+
+    /// For `in_memory_db::init_db_with`, the sled database at `db_path` has no such settings.
+    pub fn db_config(&self) -> DbConfig {
+        DbConfig {
+            max_sessions_per_user: self.max_sessions_per_user,
+        }
+    }
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidVar {
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+fn parse_optional<T: FromStr>(name: &str, value: &str) -> Result<Option<T>, ConfigError> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        parse(name, value).map(Some)
+    }
+}
+
+/// Comma-separated values, none if `value` is empty.
+fn parse_list<T: FromStr>(name: &str, value: &str) -> Result<Vec<T>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| parse(name, item))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_overrides_the_file_which_overrides_the_defaults() {
+        let mut config = AppConfig::from_toml(
+            r#"
+            listen_addr = "0.0.0.0:80"
+            session_ttl_secs = 3600
+            password_min_len = 8
+            "#,
+        )
+        .unwrap();
+        config
+            .apply_env(vars(&[
+                ("SIMTEST_PASSWORD_MIN_LEN", "12"),
+                ("SIMTEST_SESSION_TTL_SECS", ""),
+                ("SIMTEST_RATE_LIMIT_TRUSTED_PROXIES", "10.0.0.1, ::1"),
+                ("PASSWORD_REQUIRE_DIGIT", "true"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+
+        assert_eq!(
+            config,
+            AppConfig {
+                listen_addr: "0.0.0.0:80".to_string(),
+                session_ttl_secs: None,
+                password_min_len: 12,
+                rate_limit_trusted_proxies: vec![
+                    "10.0.0.1".parse().unwrap(),
+                    "::1".parse().unwrap()
+                ],
+                ..AppConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(matches!(
+            AppConfig::from_toml("listen_adr = \"0.0.0.0:80\""),
+            Err(ConfigError::Parse(_))
+        ));
+        let result = AppConfig::default().apply_env(vars(&[("SIMTEST_RATE_LIMIT_BURST", "lots")]));
+        assert!(
+            matches!(&result, Err(ConfigError::InvalidVar { name, .. }) if name == "SIMTEST_RATE_LIMIT_BURST"),
+            "{:?}",
+            result
+        );
+        let result =
+            AppConfig::default().apply_env(vars(&[("SIMTEST_CREDENTIALS_CHARSET", "ascii")]));
+        assert!(
+            matches!(&result, Err(ConfigError::InvalidVar { name, .. }) if name == "SIMTEST_CREDENTIALS_CHARSET"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn settings_reach_the_domain_config() {
+        let mut config = AppConfig::default();
+        config
+            .apply_env(vars(&[
+                ("SIMTEST_SESSION_IDLE_TIMEOUT_SECS", "60"),
+                ("SIMTEST_PASSWORD_REQUIRE_DIGIT", "true"),
+                ("SIMTEST_PASSWORD_HISTORY_LEN", "2"),
+                ("SIMTEST_ARGON2_MEM_COST_KIB", "8192"),
+                ("SIMTEST_ARGON2_LANES", "2"),
+                ("SIMTEST_CREDENTIALS_CHARSET", "latin1_fallback"),
+                ("SIMTEST_SLOW_OP_THRESHOLD_MS", "250"),
+            ]))
+            .unwrap();
+
+        let domain = config.api_config().domain;
+        assert_eq!(
+            domain.session_policy.idle_timeout,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(domain.session_policy.ttl, None);
+        assert!(domain.password_policy.require_digit);
+        assert_eq!(domain.password_history_len, 2);
+        assert_eq!(
+            domain.argon2,
+            Argon2Params {
+                mem_cost: 8192,
+                lanes: 2,
+                ..Argon2Params::default()
+            }
+        );
+        assert_eq!(
+            domain.credentials_charset,
+            CredentialsCharset::Latin1Fallback
+        );
+        assert_eq!(domain.slow_op_threshold, Some(Duration::from_millis(250)));
+    }
+
+    #[cfg(feature = "signed-tokens")]
+    #[test]
+    fn a_session_token_key_signs_the_tokens() {
+        use crate::domain::UserId;
+
+        let config = AppConfig::from_toml(&format!(
+            "session_token_key = {:?}",
+            base64::encode([7u8; 32])
+        ))
+        .unwrap();
+        let token = config
+            .domain_config()
+            .session_tokens
+            .generate(&UserId("Alice".to_string()));
+        assert!(AppConfig::default()
+            .domain_config()
+            .session_tokens
+            .validate(&token)
+            .is_none());
+        assert_eq!(
+            config.domain_config().session_tokens.validate(&token),
+            Some(UserId("Alice".to_string()))
+        );
+
+        let result =
+            AppConfig::default().apply_env(vars(&[("SIMTEST_SESSION_TOKEN_KEY", "c2hvcnQ=")]));
+        assert!(matches!(result, Err(ConfigError::InvalidVar { .. })));
+    }
+}
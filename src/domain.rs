@@ -1,13 +1,370 @@
-use std::string::FromUtf8Error;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    future::Future,
+    iter,
+    str::FromStr,
+    string::FromUtf8Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use async_std::task;
 use uuid::Uuid;
 
-use self::db::{Db, DbError, DbResult};
+pub use self::audit::{
+    AuditAction, AuditEvent, AuditOutcome, AuditSink, DiscardAuditSink, InMemoryAuditSink,
+};
+use self::db::{CredentialChange, CredentialCheck, Db, DbError, DbResult, SessionTimes};
+#[cfg(feature = "bcrypt")]
+pub use self::hasher::BcryptHasher;
+pub use self::hasher::{
+    Argon2Hasher, Argon2Params, FixedSalt, HashError, PasswordHasher, RandomSalt, SaltSource,
+};
+pub use self::logging::{DomainLog, InMemoryLog, LogEvent, TideLog};
+pub use self::password_policy::{
+    CommonPasswordChecker, PasswordPolicy, WeakPasswordReason, DEFAULT_MAX_PASSWORD_LEN,
+};
+#[cfg(feature = "signed-tokens")]
+pub use self::session_tokens::SignedTokens;
+pub use self::session_tokens::{OpaqueTokens, TokenGenerator};
+pub use self::slow_ops::{LogSlowOps, SlowOpSink};
+use crate::clock::{Clock, SystemClock};
 
+mod audit;
 pub mod db;
+mod hasher;
+mod logging;
+mod password_policy;
+mod session_tokens;
+mod slow_ops;
+#[cfg(feature = "totp")]
+pub mod totp;
 
-pub fn can_access_secret(db: &impl Db, user_id: &UserId) -> DbResult<bool> {
-    db.has_session(&user_id)
+/// Knobs of the domain logic. The plain functions like `register` use the defaults.
+#[derive(Clone, Debug)]
+pub struct DomainConfig {
+    pub password_policy: PasswordPolicy,
+    /// The cost of the hashes of new passwords.
+    pub argon2: Argon2Params,
+    /// Imported argon2 hashes that cost more than this are rejected, as every login of their user
+    /// would pay that cost. `Argon2Params::MAX_IMPORTED` by default.
+    pub max_imported_argon2: Argon2Params,
+    /// Names that can't be registered, like "admin". Compared case-insensitively.
+    pub reserved_names: HashSet<UserId>,
+    pub session_policy: SessionPolicy,
+    pub clock: Arc<dyn Clock>,
+    pub audit: Arc<dyn AuditSink>,
+    /// How the credentials in Basic auth headers are decoded.
+    pub credentials_charset: CredentialsCharset,
+    /// Makes the tokens of `login_with_token` and resolves them in `session_user`.
+    pub session_tokens: Arc<dyn TokenGenerator>,
+    /// Logins, registrations, logouts and secret accesses that take longer than this are
+    /// reported to `slow_ops`, e.g. to notice a too expensive hash configuration. Off by default.
+    pub slow_op_threshold: Option<Duration>,
+    pub slow_ops: Arc<dyn SlowOpSink>,
+    /// How many of their former passwords `change_password` keeps users from changing back to,
+    /// besides their current one. 5 by default.
+    pub password_history_len: usize,
+    /// Longer `Authorization` headers are rejected before their credentials are decoded.
+    pub max_auth_header_len: usize,
+    /// Where the domain logic logs to, tide's logger by default.
+    pub log: Arc<dyn DomainLog>,
+}
+
+impl Default for DomainConfig {
+    fn default() -> Self {
+        Self {
+            password_policy: PasswordPolicy::default(),
+            argon2: Argon2Params::default(),
+            max_imported_argon2: Argon2Params::MAX_IMPORTED,
+            reserved_names: HashSet::new(),
+            session_policy: SessionPolicy::default(),
+            clock: Arc::new(SystemClock),
+            audit: Arc::new(DiscardAuditSink),
+            credentials_charset: CredentialsCharset::default(),
+            session_tokens: Arc::new(OpaqueTokens),
+            slow_op_threshold: None,
+            slow_ops: Arc::new(LogSlowOps),
+            password_history_len: 5,
+            max_auth_header_len: DEFAULT_MAX_AUTH_HEADER_LEN,
+            log: Arc::new(TideLog),
+        }
+    }
+}
+
+/// The character set of the credentials in Basic auth headers, see RFC 7617.
+///
+/// Named `utf8` and `latin1_fallback` in configuration, see `FromStr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialsCharset {
+    /// Credentials that aren't valid UTF-8 are rejected. The default.
+    Utf8,
+    /// Credentials that aren't valid UTF-8 are read as ISO-8859-1, as some legacy clients send
+    /// them. Only enable this for such clients: every byte sequence is valid ISO-8859-1, so
+    /// bytes that `Utf8` rejects become credentials, and two different byte sequences can then
+    /// name the same user.
+    Latin1Fallback,
+}
+
+impl Default for CredentialsCharset {
+    fn default() -> Self {
+        Self::Utf8
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Invalid credentials charset, expected utf8 or latin1_fallback")]
+pub struct InvalidCharset;
+
+impl FromStr for CredentialsCharset {
+    type Err = InvalidCharset;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(Self::Utf8),
+            "latin1_fallback" => Ok(Self::Latin1Fallback),
+            _ => Err(InvalidCharset),
+        }
+    }
+}
+
+/// When sessions expire. By default they never do.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionPolicy {
+    /// Sessions that haven't been used for longer than this expire.
+    pub idle_timeout: Option<Duration>,
+    /// Sessions expire this long after login, no matter how actively they are used. The TTL is
+    /// absolute: logging in again while the session is live joins it, keeping its start, so only
+    /// a login after it expired starts the clock anew.
+    pub ttl: Option<Duration>,
+}
+
+impl SessionPolicy {
+    pub fn is_expired(&self, times: &SessionTimes, now: Instant) -> bool {
+        let exceeds = |since: Instant, limit: Option<Duration>| match limit {
+            Some(limit) => now.saturating_duration_since(since) > limit,
+            None => false,
+        };
+        exceeds(times.last_seen, self.idle_timeout) || exceeds(times.started, self.ttl)
+    }
+
+    /// How long until the session expires, as long as it isn't used meanwhile. `None` if this
+    /// policy never expires it.
+    pub fn time_left(&self, times: &SessionTimes, now: Instant) -> Option<Duration> {
+        let left = |since: Instant, limit: Option<Duration>| {
+            limit.map(|limit| limit.saturating_sub(now.saturating_duration_since(since)))
+        };
+        match (
+            left(times.last_seen, self.idle_timeout),
+            left(times.started, self.ttl),
+        ) {
+            (Some(idle), Some(ttl)) => Some(idle.min(ttl)),
+            (idle, ttl) => idle.or(ttl),
+        }
+    }
+}
+
+impl DomainConfig {
+    /// Records that `user` attempted `action`, with the outcome of the attempt.
+    fn record_audit<T, E: std::fmt::Display>(
+        &self,
+        action: AuditAction,
+        user: &UserId,
+        result: &Result<T, E>,
+    ) {
+        self.record_audit_for(action, Some(user.clone()), result);
+    }
+
+    /// Like `record_audit`, for attempts that don't say whom they are for.
+    fn record_anonymous_audit<T, E: std::fmt::Display>(
+        &self,
+        action: AuditAction,
+        result: &Result<T, E>,
+    ) {
+        self.record_audit_for(action, None, result);
+    }
+
+    fn record_audit_for<T, E: std::fmt::Display>(
+        &self,
+        action: AuditAction,
+        user: Option<UserId>,
+        result: &Result<T, E>,
+    ) {
+        self.audit.record(AuditEvent {
+            action,
+            user,
+            at: self.clock.now(),
+            outcome: match result {
+                Ok(_) => AuditOutcome::Success,
+                Err(e) => AuditOutcome::Failure(e.to_string()),
+            },
+        });
+    }
+
+    /// Parses the auth header of an attempt at `action`. Failures are recorded here, as there is
+    /// no user to record them for later.
+    fn parse_audited(
+        &self,
+        action: AuditAction,
+        auth_header: &AuthHeader,
+    ) -> Result<Credentials, ParseAuthError> {
+        let credentials = auth_header.parse_with_config(self);
+        if credentials.is_err() {
+            self.record_anonymous_audit(action, &credentials);
+        }
+        credentials
+    }
+
+    /// Runs `f`, reporting it as `op` if it exceeds the `slow_op_threshold`.
+    fn timed<T>(&self, op: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.report_if_slow(op, start);
+        result
+    }
+
+    /// Like `timed`, for async operations. The time they spend waiting counts as well.
+    async fn timed_async<T>(&self, op: &'static str, f: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = f.await;
+        self.report_if_slow(op, start);
+        result
+    }
+
+    fn report_if_slow(&self, op: &'static str, start: Instant) {
+        let took = start.elapsed();
+        if matches!(self.slow_op_threshold, Some(threshold) if took > threshold) {
+            self.slow_ops.record(op, took);
+        }
+    }
+
+    fn is_reserved(&self, user_id: &UserId) -> bool {
+        let folded = user_id.folded();
+        self.reserved_names
+            .iter()
+            .any(|reserved| reserved.folded() == folded)
+    }
+}
+
+/// Whether the user has a session and at least the `required` role.
+pub fn can_access_secret(db: &impl Db, user_id: &UserId, required: Role) -> DbResult<bool> {
+    can_access_secret_with(db, &DomainConfig::default(), user_id, required)
+}
+
+/// Like `can_access_secret`, but ends the session instead if it expired according to the
+/// configured `SessionPolicy`. Every successful access keeps the session from going idle.
+pub fn can_access_secret_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: &UserId,
+    required: Role,
+) -> DbResult<bool> {
+    config.timed("can_access_secret", || {
+        if !touch_live_session(db, config, user_id)? {
+            return Ok(false);
+        }
+        db.touch_user(user_id, config.clock.now())?;
+        Ok(db.get_role(user_id)? >= required)
+    })
+}
+
+/// Keeps the user's session from going idle without accessing anything, for clients that are
+/// still open but not in use. Returns whether the user had a live session.
+pub fn heartbeat(db: &impl Db, user_id: &UserId) -> DbResult<bool> {
+    heartbeat_with(db, &DomainConfig::default(), user_id)
+}
+
+/// Like `heartbeat`, but ends the session instead if it expired according to the configured
+/// `SessionPolicy`.
+pub fn heartbeat_with(db: &impl Db, config: &DomainConfig, user_id: &UserId) -> DbResult<bool> {
+    touch_live_session(db, config, user_id)
+}
+
+/// How long the user's session has left, `None` if they have no live session. Sessions that
+/// never expire have `Duration::MAX` left.
+pub fn session_ttl_remaining(db: &impl Db, user_id: &UserId) -> DbResult<Option<Duration>> {
+    session_ttl_remaining_with(db, &DomainConfig::default(), user_id)
+}
+
+/// Like `session_ttl_remaining`, according to the configured `SessionPolicy`. Only reads, so
+/// unlike `heartbeat_with` it neither keeps the session from going idle nor ends it once it
+/// expired.
+pub fn session_ttl_remaining_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: &UserId,
+) -> DbResult<Option<Duration>> {
+    if !db.has_session(user_id)? {
+        return Ok(None);
+    }
+    let times = match db.get_session_times(user_id)? {
+        Some(times) => times,
+        None => return Ok(Some(Duration::MAX)),
+    };
+    let now = config.clock.now();
+    if config.session_policy.is_expired(&times, now) {
+        return Ok(None);
+    }
+    Ok(Some(
+        config
+            .session_policy
+            .time_left(&times, now)
+            .unwrap_or(Duration::MAX),
+    ))
+}
+
+/// Records activity in the user's session, unless it expired, in which case it is ended.
+/// Returns whether the session is still live.
+fn touch_live_session(db: &impl Db, config: &DomainConfig, user_id: &UserId) -> DbResult<bool> {
+    if !db.has_session(&user_id)? {
+        return Ok(false);
+    }
+    let now = config.clock.now();
+    if let Some(times) = db.get_session_times(user_id)? {
+        if config.session_policy.is_expired(&times, now) {
+            db.remove_session(user_id)?;
+            return Ok(false);
+        }
+    }
+    db.touch_session(user_id, now)?;
+    Ok(true)
+}
+
+pub fn set_role(db: &impl Db, user_id: &UserId, role: Role) -> DbResult {
+    db.set_role(user_id, role)
+}
+
+/// Gauges for operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    pub users: usize,
+    pub sessions: usize,
+}
+
+pub fn metrics(db: &impl Db) -> DbResult<Metrics> {
+    Ok(Metrics {
+        users: db.count_users()?,
+        sessions: db.count_sessions()?,
+    })
+}
+
+pub fn user_exists(db: &impl Db, user_id: &UserId) -> DbResult<bool> {
+    db.user_exists(user_id)
+}
+
+pub fn get_secret(db: &impl Db, user_id: &UserId) -> DbResult<Option<String>> {
+    db.get_secret(user_id)
+}
+
+/// How often the user's secret was read, for audits.
+pub fn secret_access_count(db: &impl Db, user_id: &UserId) -> DbResult<u64> {
+    db.secret_access_count(user_id)
+}
+
+pub fn set_secret(db: &impl Db, user_id: &UserId, secret: String) -> DbResult {
+    db.set_secret(user_id, secret)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -15,27 +372,304 @@ pub enum LoginError {
     #[error("Invalid Credentials")]
     InvalidCredentials,
     #[error("Failed to process password")]
-    HashError(#[from] argon2::Error),
+    HashError(#[from] HashError),
     #[error("{0}")]
     ParseAuthError(#[from] ParseAuthError),
     #[error("{0}")]
     DbError(#[from] DbError),
     #[error("Not registered")]
     NotRegistered,
+    #[error("Two-factor code required")]
+    TotpRequired,
+    #[error("Invalid two-factor code")]
+    InvalidTotp,
+    #[error("Password too long")]
+    PasswordTooLong,
+    #[error("Invalid refresh token")]
+    InvalidRefreshToken,
+    /// The stored password of the user can't be verified. Not the client's fault, unlike
+    /// `InvalidCredentials`.
+    #[error("Stored password hash is corrupt")]
+    CorruptStoredHash,
+}
+
+/// Starts a session for the user of the auth header and returns who that is.
+pub fn login(db: &impl Db, auth_header: &AuthHeader) -> Result<UserId, LoginError> {
+    login_with(db, &DomainConfig::default(), auth_header)
+}
+
+pub fn login_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+) -> Result<UserId, LoginError> {
+    config.timed("login", || {
+        let Credentials { user_id, password } =
+            config.parse_audited(AuditAction::Login, auth_header)?;
+        let result = check_credentials(db, config, user_id.clone(), password).and_then(|user_id| {
+            check_single_factor(db, &user_id)?;
+            start_session(db, config, &user_id)?;
+            Ok(user_id)
+        });
+        config.record_audit(AuditAction::Login, &user_id, &result);
+        result
+    })
+}
+
+/// The user that logged in and the token identifying the new session.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug))]
+pub struct LoginOutcome {
+    pub user: UserId,
+    pub token: SessionToken,
+    /// Only issued if the user asked to be remembered.
+    pub refresh_token: Option<RefreshToken>,
+}
+
+/// Like `login`, but also issues a token that identifies the new session, e.g. in a cookie.
+/// If `remember` is set, a refresh token is issued as well, see `refresh`.
+pub fn login_with_token(
+    db: &impl Db,
+    auth_header: &AuthHeader,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    login_with_credentials(db, auth_header.parse()?, remember)
+}
+
+/// Like `login_with_token`, for credentials that were already parsed.
+pub fn login_with_credentials(
+    db: &impl Db,
+    credentials: Credentials,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    login_with_credentials_with(db, &DomainConfig::default(), credentials, remember)
+}
+
+/// Like `login_with_credentials`, but issues tokens with the configured `session_tokens`.
+pub fn login_with_credentials_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    credentials: Credentials,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    config.timed("login", || {
+        let Credentials { user_id, password } = credentials;
+        let result = check_credentials(db, config, user_id.clone(), password).and_then(|user_id| {
+            check_single_factor(db, &user_id)?;
+            issue_tokens(db, config, user_id, remember)
+        });
+        config.record_audit(AuditAction::Login, &user_id, &result);
+        result
+    })
+}
+
+/// Like `login_with_credentials`, but verifies the credentials on async-std's pool for blocking
+/// work, for async callers. The `Db` is cloned to be moved there, so it should be a cheap handle.
+pub async fn login_with_credentials_async(
+    db: &(impl Db + Clone + 'static),
+    credentials: Credentials,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    login_with_credentials_async_with(db, &DomainConfig::default(), credentials, remember).await
+}
+
+pub async fn login_with_credentials_async_with(
+    db: &(impl Db + Clone + 'static),
+    config: &DomainConfig,
+    credentials: Credentials,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    config
+        .timed_async("login", async {
+            let user_id = credentials.user_id.clone();
+            let result = verify_and_issue_tokens_async(db, config, credentials, remember).await;
+            config.record_audit(AuditAction::Login, &user_id, &result);
+            result
+        })
+        .await
 }
 
-pub fn login(db: &impl Db, auth_header: &str) -> Result<(), LoginError> {
-    let (user_id, pw) = parse_auth(auth_header)?;
+async fn verify_and_issue_tokens_async(
+    db: &(impl Db + Clone + 'static),
+    config: &DomainConfig,
+    credentials: Credentials,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    let Credentials { user_id, password } = credentials;
+    if config.password_policy.is_too_long(&password) {
+        return Err(LoginError::PasswordTooLong);
+    }
+    let check = {
+        let (db, user_id) = (db.clone(), user_id.clone());
+        task::spawn_blocking(move || db.verify_credentials(&user_id, &password)).await?
+    };
+    let user_id = accept_credentials(db, config, user_id, check)?;
+    check_single_factor(db, &user_id)?;
+    issue_tokens(db, config, user_id, remember)
+}
+
+/// Starts a new session for the user the refresh token was issued to, without asking for the
+/// password again. Refresh tokens are single-use, the outcome contains the next one.
+pub fn refresh(db: &impl Db, refresh_token: &RefreshToken) -> Result<LoginOutcome, LoginError> {
+    refresh_with(db, &DomainConfig::default(), refresh_token)
+}
+
+pub fn refresh_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    refresh_token: &RefreshToken,
+) -> Result<LoginOutcome, LoginError> {
+    let owner = db
+        .take_refresh_token(refresh_token)
+        .map_err(LoginError::from)
+        .and_then(|owner| owner.ok_or(LoginError::InvalidRefreshToken));
+    if owner.is_err() {
+        config.record_anonymous_audit(AuditAction::Refresh, &owner);
+    }
+    let user_id = owner?;
+    let result = issue_tokens(db, config, user_id.clone(), true);
+    config.record_audit(AuditAction::Refresh, &user_id, &result);
+    result
+}
 
-    let encoded = match db.get_pw(&user_id)? {
-        Some(it) => it,
-        None => return Err(LoginError::NotRegistered),
+fn issue_tokens(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    remember: bool,
+) -> Result<LoginOutcome, LoginError> {
+    let token = config.session_tokens.generate(&user_id);
+    db.add_session_token(token.clone(), user_id.clone())?;
+    let refresh_token = if remember {
+        let refresh_token = RefreshToken::generate();
+        db.add_refresh_token(refresh_token.clone(), user_id.clone())?;
+        Some(refresh_token)
+    } else {
+        None
     };
-    if encoded.verify(&pw)? {
-        db.add_session(user_id)?;
+    start_session(db, config, &user_id)?;
+    Ok(LoginOutcome {
+        user: user_id,
+        token,
+        refresh_token,
+    })
+}
+
+/// The user whose session is identified by `token`, if that session is still active.
+pub fn session_user(db: &impl Db, token: &SessionToken) -> DbResult<Option<UserId>> {
+    session_user_with(db, &DomainConfig::default(), token)
+}
+
+/// Like `session_user`, for tokens issued with the configured `session_tokens`.
+pub fn session_user_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    token: &SessionToken,
+) -> DbResult<Option<UserId>> {
+    let claimed = config.session_tokens.validate(token);
+    if claimed.is_none() && config.session_tokens.self_validating() {
+        return Ok(None);
+    }
+    match db.get_session_token(token)? {
+        Some(user_id)
+            if claimed.map_or(true, |claimed| claimed == user_id)
+                && db.has_session(&user_id)? =>
+        {
+            Ok(Some(user_id))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Ends every session of the authenticated user, e.g. after a password change or a suspected
+/// compromise.
+pub fn logout_all(db: &impl Db, auth_header: &AuthHeader) -> Result<(), LoginError> {
+    logout_all_with(db, &DomainConfig::default(), auth_header)
+}
+
+pub fn logout_all_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+) -> Result<(), LoginError> {
+    let Credentials { user_id, password } =
+        config.parse_audited(AuditAction::LogoutAll, auth_header)?;
+    let result = check_credentials(db, config, user_id.clone(), password).and_then(|user_id| {
+        db.remove_refresh_tokens(&user_id)?;
+        db.remove_all_sessions(&user_id)?;
         Ok(())
-    } else {
-        Err(LoginError::InvalidCredentials)
+    });
+    config.record_audit(AuditAction::LogoutAll, &user_id, &result);
+    result
+}
+
+/// Starts a session, or joins the user's live one, which keeps its start time, see
+/// `SessionPolicy::ttl`. An expired session that nothing ended yet is ended first, so that the
+/// new one doesn't inherit its start time.
+fn start_session(db: &impl Db, config: &DomainConfig, user_id: &UserId) -> DbResult {
+    let now = config.clock.now();
+    if let Some(times) = db.get_session_times(user_id)? {
+        if config.session_policy.is_expired(&times, now) {
+            db.remove_session(user_id)?;
+        }
+    }
+    db.add_session(user_id.clone())?;
+    db.touch_session(user_id, now)
+}
+
+/// Users with a TOTP secret can't log in with their password alone, see `totp::verify_totp`.
+fn check_single_factor(db: &impl Db, user_id: &UserId) -> Result<(), LoginError> {
+    if db.get_totp_secret(user_id)?.is_some() {
+        return Err(LoginError::TotpRequired);
+    }
+    Ok(())
+}
+
+/// Verified instead of a stored hash when a user doesn't exist, so that logging in as an unknown
+/// user takes as long as logging in with a wrong password and response times don't reveal which
+/// user names are registered.
+/// The price is a full argon2 verification for every login attempt with an unknown user name.
+///
+/// Uses the same parameters as `EnteredPassword::encode`, the salt and hash are arbitrary.
+const DUMMY_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$ZHVtbXktc2FsdC0xNmJ5dA$AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8";
+
+fn authenticate(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+) -> Result<UserId, LoginError> {
+    let credentials = auth_header.parse_with_config(config)?;
+    check_credentials(db, config, credentials.user_id, credentials.password)
+}
+
+fn check_credentials(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    pw: EnteredPassword,
+) -> Result<UserId, LoginError> {
+    if config.password_policy.is_too_long(&pw) {
+        return Err(LoginError::PasswordTooLong);
+    }
+
+    let check = db.verify_credentials(&user_id, &pw)?;
+    accept_credentials(db, config, user_id, check)
+}
+
+fn accept_credentials(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    check: CredentialCheck,
+) -> Result<UserId, LoginError> {
+    match check {
+        CredentialCheck::Valid => {
+            db.touch_user(&user_id, config.clock.now())?;
+            Ok(user_id)
+        }
+        CredentialCheck::WrongPassword => Err(LoginError::InvalidCredentials),
+        CredentialCheck::NoSuchUser => Err(LoginError::NotRegistered),
+        CredentialCheck::CorruptHash => Err(LoginError::CorruptStoredHash),
     }
 }
 
@@ -47,47 +681,373 @@ pub enum LogoutError {
     DbError(#[from] DbError),
 }
 
-pub fn logout(db: &impl Db, auth_header: &str) -> Result<(), LogoutError> {
-    let (user_id, _) = parse_auth(auth_header)?;
+pub fn logout(db: &impl Db, auth_header: &AuthHeader) -> Result<(), LogoutError> {
+    logout_with(db, &DomainConfig::default(), auth_header)
+}
+
+pub fn logout_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+) -> Result<(), LogoutError> {
+    let credentials = config.parse_audited(AuditAction::Logout, auth_header)?;
 
-    db.remove_session(&user_id)?;
+    logout_user_with(db, config, &credentials.user_id)?;
 
     Ok(())
 }
 
+/// Like `logout`, for a user whose credentials were already parsed.
+pub fn logout_user(db: &impl Db, user_id: &UserId) -> DbResult {
+    logout_user_with(db, &DomainConfig::default(), user_id)
+}
+
+pub fn logout_user_with(db: &impl Db, config: &DomainConfig, user_id: &UserId) -> DbResult {
+    config.timed("logout", || {
+        let result = db
+            .remove_refresh_tokens(user_id)
+            .and_then(|()| db.remove_session(user_id));
+        config.record_audit(AuditAction::Logout, user_id, &result);
+        result
+    })
+}
+
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum ParseAuthError {
-    #[error("Malformed Header")]
-    MalformedHeader,
+    /// Not `Basic` followed by a space and the credentials.
+    #[error("Unsupported auth scheme")]
+    WrongScheme,
+    #[error("Credentials aren't valid base64")]
+    InvalidBase64,
+    #[error("No colon between user name and password")]
+    MissingColon,
     #[error("UTF8 conversion failed")]
     Utf8Error(#[from] FromUtf8Error),
+    /// Longer than `DomainConfig::max_auth_header_len`.
+    #[error("Authorization header too long")]
+    TooLong,
 }
 
+/// 4 KiB, room for a user name and a password of `DEFAULT_MAX_PASSWORD_LEN` even after base64
+/// makes them a third longer.
+pub const DEFAULT_MAX_AUTH_HEADER_LEN: usize = 4 * 1024;
+
 fn parse_auth(auth_header: &str) -> Result<(UserId, EnteredPassword), ParseAuthError> {
-    const BASIC: &str = "Basic ";
+    parse_auth_with(
+        auth_header,
+        CredentialsCharset::Utf8,
+        DEFAULT_MAX_AUTH_HEADER_LEN,
+    )
+}
 
-    if !auth_header.starts_with(BASIC) {
-        return Err(ParseAuthError::MalformedHeader);
+fn parse_auth_with(
+    auth_header: &str,
+    charset: CredentialsCharset,
+    max_len: usize,
+) -> Result<(UserId, EnteredPassword), ParseAuthError> {
+    // Before decoding, which allocates in proportion to the header
+    if auth_header.len() > max_len {
+        return Err(ParseAuthError::TooLong);
+    }
+    // The scheme is case-insensitive (RFC 7617), the credentials follow after a single space
+    let (scheme, auth) = auth_header
+        .split_once(' ')
+        .ok_or(ParseAuthError::WrongScheme)?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return Err(ParseAuthError::WrongScheme);
     }
-    let auth = &auth_header[BASIC.len()..];
-    let auth = base64::decode(auth).map_err(|_| ParseAuthError::MalformedHeader)?;
-    let auth = String::from_utf8(auth)?;
+    // Some clients send URL-safe base64. It only differs from the standard alphabet in the two
+    // characters for 62 and 63, so trying both can't turn what either rejects into credentials.
+    let auth = base64::decode(auth)
+        .or_else(|_| base64::decode_config(auth, base64::URL_SAFE))
+        .map_err(|_| ParseAuthError::InvalidBase64)?;
+    let auth = match (String::from_utf8(auth), charset) {
+        (Ok(auth), _) => auth,
+        (Err(e), CredentialsCharset::Utf8) => return Err(e.into()),
+        // ISO-8859-1 maps each byte to the code point with the same value
+        (Err(e), CredentialsCharset::Latin1Fallback) => {
+            e.into_bytes().into_iter().map(char::from).collect()
+        }
+    };
     let parts = auth.splitn(2, ':').collect::<Vec<_>>();
     match parts.as_slice() {
         &[user, pass] => (Ok((UserId(user.to_string()), EnteredPassword(pass.to_string())))),
-        _ => Err(ParseAuthError::MalformedHeader),
+        _ => Err(ParseAuthError::MissingColon),
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, Clone)]
-pub struct UserId(pub String);
+/// User name and password as sent in a Basic auth header.
 #[derive(Clone)]
 #[cfg_attr(test, derive(Debug))]
+pub struct Credentials {
+    pub user_id: UserId,
+    pub password: EnteredPassword,
+}
+
+/// The value of an `Authorization` header, before it is parsed. The functions that
+/// authenticate a user take this rather than a `&str`, so that any other string can't be passed
+/// by mistake, and parse it with `parse`.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct AuthHeader(String);
+
+impl AuthHeader {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    /// A Basic auth header with the credentials, as a client would send it.
+    pub fn basic(user_id: &UserId, password: &EnteredPassword) -> Self {
+        let encoded = base64::encode(format!("{}:{}", user_id.0, password.0));
+        Self(format!("Basic {}", encoded))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The credentials of a Basic auth header, the only scheme that is supported.
+    pub fn parse(&self) -> Result<Credentials, ParseAuthError> {
+        self.parse_with(CredentialsCharset::Utf8)
+    }
+
+    /// Like `parse`, but decodes the credentials as `charset` says.
+    pub fn parse_with(&self, charset: CredentialsCharset) -> Result<Credentials, ParseAuthError> {
+        self.parse_limited(charset, DEFAULT_MAX_AUTH_HEADER_LEN)
+    }
+
+    /// Like `parse`, with the `credentials_charset` and `max_auth_header_len` of `config`.
+    pub fn parse_with_config(&self, config: &DomainConfig) -> Result<Credentials, ParseAuthError> {
+        self.parse_limited(config.credentials_charset, config.max_auth_header_len)
+    }
+
+    /// Like `parse_with_config`, but records failures as `AuditAction::Authenticate` with the
+    /// `audit` sink of `config`, for callers that parse headers before they know what for.
+    pub fn parse_audited(&self, config: &DomainConfig) -> Result<Credentials, ParseAuthError> {
+        config.parse_audited(AuditAction::Authenticate, self)
+    }
+
+    fn parse_limited(
+        &self,
+        charset: CredentialsCharset,
+        max_len: usize,
+    ) -> Result<Credentials, ParseAuthError> {
+        let (user_id, password) = parse_auth_with(&self.0, charset, max_len)?;
+        Ok(Credentials { user_id, password })
+    }
+}
+
+/// Entry point for the `parse_auth` fuzz target in `fuzz/`, not part of the API.
+#[doc(hidden)]
+pub fn fuzz_parse_auth(auth_header: &str) -> Result<(UserId, EnteredPassword), ParseAuthError> {
+    parse_auth(auth_header)
+}
+
+/// What a user may access. Roles are ordered, a higher role can do everything a lower one can.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::User
+    }
+}
+
+#[derive(
+    PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, serde::Serialize, serde::Deserialize,
+)]
+pub struct UserId(pub String);
+
+impl UserId {
+    /// The case folded name, for comparisons that should ignore case.
+    pub fn folded(&self) -> UserId {
+        UserId(self.0.to_lowercase())
+    }
+}
+
+/// A syntactically plausible email address, e.g. for account recovery.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Email(String);
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Invalid email address")]
+pub struct InvalidEmail;
+
+impl Email {
+    /// Accepts `local@domain.tld` without whitespace. Deliverability isn't checked.
+    pub fn parse(s: String) -> Result<Self, InvalidEmail> {
+        let (local, domain) = match s.split('@').collect::<Vec<_>>().as_slice() {
+            &[local, domain] => (local, domain),
+            _ => return Err(InvalidEmail),
+        };
+        let domain_ok = domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.');
+        if local.is_empty() || !domain_ok || s.chars().any(char::is_whitespace) {
+            return Err(InvalidEmail);
+        }
+        Ok(Self(s))
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Optional information stored alongside the credentials of a user.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct UserProfile {
+    pub email: Option<Email>,
+}
+
+/// An argon2 hash in the PHC string format, e.g. `$argon2i$v=19$m=4096,t=3,p=1$<salt>$<hash>`,
+/// or, with the `bcrypt` feature, a bcrypt hash in its similar modular crypt format, e.g.
+/// `$2b$12$<salt and hash>`.
+///
+/// Serializes as that string, deserializing validates it like `from_phc_string`.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+#[cfg_attr(test, derive(Debug))]
 pub struct EncodedPassword(String);
 
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("Not an argon2 or bcrypt hash in PHC string format")]
+pub struct InvalidHashError;
+
+/// Opaque random identifier of a session that clients can present instead of their user id.
+#[derive(PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(test, derive(Debug))]
+pub struct SessionToken(String);
+
+impl SessionToken {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+    pub fn new(s: String) -> Self {
+        Self(s)
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Opaque random, long-lived token that `refresh` exchanges for a new session.
+#[derive(PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(test, derive(Debug))]
+pub struct RefreshToken(String);
+
+impl RefreshToken {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+    pub fn new(s: String) -> Self {
+        Self(s)
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl EncodedPassword {
-    fn verify(&self, entered_password: &EnteredPassword) -> Result<bool, argon2::Error> {
-        argon2::verify_encoded(self.0.as_str(), entered_password.0.as_bytes())
+    /// Restores a hash that was stored with `as_str` or `into_string`.
+    /// bcrypt hashes are only accepted with the `bcrypt` feature, as they can't be verified
+    /// without it.
+    pub fn from_phc_string(s: String) -> Result<Self, InvalidHashError> {
+        fn is_b64(s: &str) -> bool {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+        }
+        fn is_param(param: &str, name: &str) -> bool {
+            match param.strip_prefix(name).and_then(|p| p.strip_prefix('=')) {
+                Some(value) => !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()),
+                None => false,
+            }
+        }
+
+        let parts = s.split('$').collect::<Vec<_>>();
+        if let &["", variant, cost, salt_and_hash] = parts.as_slice() {
+            let valid = cfg!(feature = "bcrypt")
+                && matches!(variant, "2a" | "2b" | "2y")
+                && cost.len() == 2
+                && cost.chars().all(|c| c.is_ascii_digit())
+                && salt_and_hash.len() == 53
+                && salt_and_hash
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '/');
+            return if valid {
+                Ok(Self(s))
+            } else {
+                Err(InvalidHashError)
+            };
+        }
+        // The version field is missing in hashes of argon2 version 0x10
+        let (variant, params, salt, hash) = match parts.as_slice() {
+            &["", variant, version, params, salt, hash] if is_param(version, "v") => {
+                (variant, params, salt, hash)
+            }
+            &["", variant, params, salt, hash] => (variant, params, salt, hash),
+            _ => return Err(InvalidHashError),
+        };
+        let valid_params = match params.split(',').collect::<Vec<_>>().as_slice() {
+            &[m, t, p] => is_param(m, "m") && is_param(t, "t") && is_param(p, "p"),
+            _ => false,
+        };
+        let valid = matches!(variant, "argon2i" | "argon2d" | "argon2id")
+            && valid_params
+            && is_b64(salt)
+            && is_b64(hash);
+        if valid {
+            Ok(Self(s))
+        } else {
+            Err(InvalidHashError)
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    pub fn into_string(self) -> String {
+        self.0
+    }
+    /// The cost of an argon2 hash, `None` for bcrypt hashes. Values too large for a `u32` are read
+    /// as `u32::MAX`.
+    pub fn argon2_params(&self) -> Option<Argon2Params> {
+        let params = self.0.split('$').find(|part| part.starts_with("m="))?;
+        let mut values = params
+            .split(',')
+            .map(|param| param[2..].parse().unwrap_or(u32::MAX));
+        Some(Argon2Params {
+            mem_cost: values.next()?,
+            time_cost: values.next()?,
+            lanes: values.next()?,
+        })
+    }
+    /// Verifies with the algorithm that produced the hash.
+    pub fn verify(&self, entered_password: &EnteredPassword) -> Result<bool, HashError> {
+        hasher::hasher_for(self)?.verify(self, entered_password)
+    }
+    /// Like `verify`, but on async-std's pool for blocking work, so that the hashing doesn't hold
+    /// up the other tasks of the executor thread.
+    pub async fn verify_async(
+        &self,
+        entered_password: &EnteredPassword,
+    ) -> Result<bool, HashError> {
+        let (encoded, entered_password) = (self.clone(), entered_password.clone());
+        task::spawn_blocking(move || encoded.verify(&entered_password)).await
+    }
+}
+
+impl TryFrom<String> for EncodedPassword {
+    type Error = InvalidHashError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_phc_string(s)
+    }
+}
+
+impl From<EncodedPassword> for String {
+    fn from(password: EncodedPassword) -> Self {
+        password.into_string()
     }
 }
 
@@ -99,43 +1059,427 @@ impl EnteredPassword {
     pub fn new(s: String) -> Self {
         Self(s)
     }
-    pub fn encode(self) -> Result<EncodedPassword, argon2::Error> {
-        let salt = Uuid::new_v4();
-        let encoded = argon2::hash_encoded(
-            self.0.as_bytes(),
-            salt.as_bytes(),
-            &argon2::Config::default(),
-        )?;
-        Ok(EncodedPassword(encoded))
+    /// Hashes the password with argon2, see `Argon2Hasher`.
+    pub fn encode(self) -> Result<EncodedPassword, HashError> {
+        self.encode_with(&RandomSalt)
+    }
+
+    /// Like `encode`, but on async-std's pool for blocking work, see
+    /// `EncodedPassword::verify_async`.
+    pub async fn encode_async(self) -> Result<EncodedPassword, HashError> {
+        task::spawn_blocking(move || self.encode()).await
+    }
+
+    /// Like `encode`, but with a salt from `salts`.
+    pub fn encode_with(self, salts: &dyn SaltSource) -> Result<EncodedPassword, HashError> {
+        Argon2Hasher::default().hash_with(&self, salts)
+    }
+
+    /// Like `encode`, but with the cost in `params`, e.g. `DomainConfig::argon2`.
+    pub fn encode_with_params(self, params: Argon2Params) -> Result<EncodedPassword, HashError> {
+        Argon2Hasher { params }.hash(&self)
     }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum RegisterError {
     #[error("Failed to process password")]
-    HashError(#[from] argon2::Error),
+    HashError(#[from] HashError),
+    #[error("{0}")]
+    ParseAuthError(#[from] ParseAuthError),
     #[error("{0}")]
     DbError(#[from] DbError),
+    #[error("Password too weak: {0}")]
+    WeakPassword(WeakPasswordReason),
+    #[error("Password too long")]
+    PasswordTooLong,
+    #[error("User name is reserved")]
+    ReservedName,
+    #[error("{0}")]
+    InvalidEmail(#[from] InvalidEmail),
+    #[error("User already exists")]
+    AlreadyExists,
+    #[error("{0}")]
+    InvalidHash(#[from] InvalidHashError),
+    #[error("Hash parameters exceed the import limits")]
+    HashTooExpensive,
 }
 
 pub fn register(db: &impl Db, user_id: UserId, pass: EnteredPassword) -> Result<(), RegisterError> {
-    Ok(db.register(user_id, pass.encode()?)?)
+    register_with(db, &DomainConfig::default(), user_id, pass)
 }
 
-#[cfg(test)]
-mod property_tests {
-    use crate::in_memory_db;
+pub fn register_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    pass: EnteredPassword,
+) -> Result<(), RegisterError> {
+    config.timed("register", || {
+        let result = check_registration(config, &user_id, &pass).and_then(|()| {
+            let encoded = pass.encode_with_params(config.argon2)?;
+            inserted(db.register_if_absent(user_id.clone(), encoded)?)
+        });
+        config.record_audit(AuditAction::Register, &user_id, &result);
+        result
+    })
+}
 
-    use super::*;
-    use quickcheck::Arbitrary;
-    use quickcheck_macros::quickcheck;
+/// Turns the outcome of `Db::register_if_absent` into an error for existing users.
+fn inserted(inserted: bool) -> Result<(), RegisterError> {
+    if inserted {
+        Ok(())
+    } else {
+        Err(RegisterError::AlreadyExists)
+    }
+}
 
-    impl Arbitrary for UserId {
-        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-            let mut s = String::arbitrary(g);
-            while s.is_ascii() || s.contains(':') || s.chars().any(|c| c.is_control()) {
-                s = String::arbitrary(g);
-            }
+/// Registers a user with a password that was hashed elsewhere, e.g. when migrating users from
+/// another system that only has their hashes. The hash is stored as is, so the password policy
+/// isn't checked, but argon2 hashes over `DomainConfig::max_imported_argon2` are rejected.
+pub fn import_user(
+    db: &impl Db,
+    user_id: UserId,
+    encoded: EncodedPassword,
+) -> Result<(), RegisterError> {
+    import_user_with(db, &DomainConfig::default(), user_id, encoded)
+}
+
+pub fn import_user_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    encoded: EncodedPassword,
+) -> Result<(), RegisterError> {
+    let too_expensive = encoded
+        .argon2_params()
+        .map_or(false, |params| !params.within(&config.max_imported_argon2));
+    let result = if config.is_reserved(&user_id) {
+        Err(RegisterError::ReservedName)
+    } else if too_expensive {
+        Err(RegisterError::HashTooExpensive)
+    } else {
+        db.register_if_absent(user_id.clone(), encoded)
+            .map_err(RegisterError::from)
+            .and_then(inserted)
+    };
+    config.record_audit(AuditAction::Register, &user_id, &result);
+    result
+}
+
+/// Like `import_user`, for a hash in the PHC string format, see
+/// `EncodedPassword::from_phc_string`.
+pub fn import_user_from_phc_string(
+    db: &impl Db,
+    user_id: UserId,
+    phc: String,
+) -> Result<(), RegisterError> {
+    import_user_from_phc_string_with(db, &DomainConfig::default(), user_id, phc)
+}
+
+pub fn import_user_from_phc_string_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    phc: String,
+) -> Result<(), RegisterError> {
+    import_user_with(db, config, user_id, EncodedPassword::from_phc_string(phc)?)
+}
+
+/// Like `register`, but hashes with `EnteredPassword::encode_async`, for async callers like the
+/// API.
+pub async fn register_async(
+    db: &impl Db,
+    user_id: UserId,
+    pass: EnteredPassword,
+) -> Result<(), RegisterError> {
+    register_async_with(db, &DomainConfig::default(), user_id, pass).await
+}
+
+pub async fn register_async_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    pass: EnteredPassword,
+) -> Result<(), RegisterError> {
+    config
+        .timed_async("register", async {
+            let result: Result<(), RegisterError> = async {
+                check_registration(config, &user_id, &pass)?;
+                let params = config.argon2;
+                let encoded = task::spawn_blocking(move || pass.encode_with_params(params)).await?;
+                inserted(db.register_if_absent(user_id.clone(), encoded)?)
+            }
+            .await;
+            config.record_audit(AuditAction::Register, &user_id, &result);
+            result
+        })
+        .await
+}
+
+/// Like `register_with`, but stores the profile along with the credentials.
+pub fn register_with_profile(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: UserId,
+    pass: EnteredPassword,
+    profile: UserProfile,
+) -> Result<(), RegisterError> {
+    let result = check_registration(config, &user_id, &pass).and_then(|()| {
+        let encoded = pass.encode_with_params(config.argon2)?;
+        Ok(db.register_with_profile(user_id.clone(), encoded, profile)?)
+    });
+    config.record_audit(AuditAction::Register, &user_id, &result);
+    result
+}
+
+/// Registers each user independently, e.g. to seed test data. A failed registration doesn't
+/// affect the others, the results are in the same order as `users`.
+pub fn register_many(
+    db: &impl Db,
+    users: Vec<(UserId, EnteredPassword)>,
+) -> Vec<Result<(), RegisterError>> {
+    register_many_with(db, &DomainConfig::default(), users)
+}
+
+/// Like `register_many`, checking and auditing each user like `register_with` does.
+pub fn register_many_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    users: Vec<(UserId, EnteredPassword)>,
+) -> Vec<Result<(), RegisterError>> {
+    let mut user_ids = Vec::with_capacity(users.len());
+    let mut rejected = Vec::with_capacity(users.len());
+    let mut batch = Vec::with_capacity(users.len());
+    for (user_id, pass) in users {
+        let encoded = check_registration(config, &user_id, &pass)
+            .and_then(|()| Ok(pass.encode_with_params(config.argon2)?));
+        user_ids.push(user_id.clone());
+        match encoded {
+            Ok(encoded) => {
+                batch.push((user_id, encoded));
+                rejected.push(None);
+            }
+            Err(e) => rejected.push(Some(e)),
+        }
+    }
+
+    let mut stored = db.register_batch(batch).into_iter();
+    rejected
+        .into_iter()
+        .zip(user_ids)
+        .map(|(rejected, user_id)| {
+            let result = match rejected {
+                Some(e) => Err(e),
+                None => Ok(stored
+                    .next()
+                    .expect("register_batch returns one result per registration")?),
+            };
+            config.record_audit(AuditAction::Register, &user_id, &result);
+            result
+        })
+        .collect()
+}
+
+pub fn get_profile(db: &impl Db, user_id: &UserId) -> DbResult<Option<UserProfile>> {
+    db.get_profile(user_id)
+}
+
+fn check_registration(
+    config: &DomainConfig,
+    user_id: &UserId,
+    pass: &EnteredPassword,
+) -> Result<(), RegisterError> {
+    if config.is_reserved(user_id) {
+        return Err(RegisterError::ReservedName);
+    }
+    check_password(config, user_id, pass)
+}
+
+/// Logs which rule a rejected password breaks, for telemetry, but never the password itself.
+fn check_password(
+    config: &DomainConfig,
+    user_id: &UserId,
+    pass: &EnteredPassword,
+) -> Result<(), RegisterError> {
+    if config.password_policy.is_too_long(pass) {
+        return Err(RegisterError::PasswordTooLong);
+    }
+    if let Err(reason) = config.password_policy.check(pass) {
+        config.log.log(LogEvent::WeakPasswordRejected {
+            user: user_id.clone(),
+            reason,
+        });
+        return Err(RegisterError::WeakPassword(reason));
+    }
+    Ok(())
+}
+
+/// Removes the user and everything stored about them, ending their sessions.
+pub fn unregister(db: &impl Db, user_id: &UserId) -> DbResult {
+    db.purge_sessions_for_password_change(user_id, CredentialChange::Unregister)
+        .map(drop)
+}
+
+/// All registered users, sorted by name.
+pub fn list_users(db: &impl Db) -> DbResult<Vec<UserId>> {
+    let mut users = db.list_users()?;
+    users.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(users)
+}
+
+/// Sets a new password for a registered user, subject to the same policy as registration.
+/// Existing sessions are ended, as they were started with the old password. All of this happens
+/// in one transaction, so a failure never leaves the new password with the old sessions.
+pub fn reset_password(
+    db: &impl Db,
+    config: &DomainConfig,
+    user_id: &UserId,
+    pass: EnteredPassword,
+) -> Result<(), RegisterError> {
+    let result = check_password(config, user_id, &pass).and_then(|()| {
+        let encoded = pass.encode_with_params(config.argon2)?;
+        Ok(db.transaction(&mut |tx| {
+            let change = CredentialChange::SetPassword(encoded.clone());
+            tx.purge_sessions_for_password_change(user_id, change)
+                .map(drop)
+        })?)
+    });
+    config.record_audit(AuditAction::PasswordChange, user_id, &result);
+    result
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChangePasswordError {
+    #[error("{0}")]
+    LoginError(#[from] LoginError),
+    #[error("{0}")]
+    RegisterError(#[from] RegisterError),
+    #[error("{0}")]
+    DbError(#[from] DbError),
+    /// The password was changed since it was verified, e.g. by a concurrent change.
+    #[error("Password was changed concurrently")]
+    Conflict,
+    /// The new password is the current one or one of the former ones kept in the password
+    /// history.
+    #[error("Password was used recently")]
+    Reused,
+}
+
+/// Changes the password of the user of the auth header, which has to carry their current
+/// password, to `new`, and ends their sessions like `reset_password`.
+///
+/// The new password only replaces the hash the old one was verified against, with
+/// `CredentialChange::SwapPassword`. If another change got there first the old password might
+/// not be valid anymore, so this fails with `ChangePasswordError::Conflict` instead of retrying.
+///
+/// The replaced hash is added to the user's password history, of which the last
+/// `DomainConfig::password_history_len` hashes are kept. A new password that matches the current
+/// one or any of those fails with `ChangePasswordError::Reused`.
+pub fn change_password(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+    new: EnteredPassword,
+) -> Result<(), ChangePasswordError> {
+    let credentials = config
+        .parse_audited(AuditAction::PasswordChange, auth_header)
+        .map_err(LoginError::from)?;
+    let result = swap_password(db, config, &credentials, new);
+    config.record_audit(AuditAction::PasswordChange, &credentials.user_id, &result);
+    result
+}
+
+fn swap_password(
+    db: &impl Db,
+    config: &DomainConfig,
+    credentials: &Credentials,
+    new: EnteredPassword,
+) -> Result<(), ChangePasswordError> {
+    let user_id = &credentials.user_id;
+    check_password(config, user_id, &new)?;
+    let current = db.get_pw(user_id)?.ok_or(LoginError::NotRegistered)?;
+    match current.verify(&credentials.password) {
+        Ok(true) => {}
+        Ok(false) => return Err(LoginError::InvalidCredentials.into()),
+        Err(_) => return Err(LoginError::CorruptStoredHash.into()),
+    }
+    for used in iter::once(current.clone()).chain(db.get_password_history(user_id)?) {
+        if used
+            .verify(&new)
+            .map_err(|_| LoginError::CorruptStoredHash)?
+        {
+            return Err(ChangePasswordError::Reused);
+        }
+    }
+    let encoded = new
+        .encode_with_params(config.argon2)
+        .map_err(RegisterError::from)?;
+    let mut swapped = false;
+    db.transaction(&mut |tx| {
+        let change = CredentialChange::SwapPassword {
+            expected: current.clone(),
+            new: encoded.clone(),
+        };
+        swapped = tx.purge_sessions_for_password_change(user_id, change)?;
+        if !swapped {
+            return Ok(());
+        }
+        tx.push_password_history(user_id, current.clone(), config.password_history_len)
+    })?;
+    if swapped {
+        Ok(())
+    } else {
+        Err(ChangePasswordError::Conflict)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RenameError {
+    #[error("{0}")]
+    LoginError(#[from] LoginError),
+    #[error("{0}")]
+    DbError(#[from] DbError),
+    #[error("User name is reserved")]
+    ReservedName,
+}
+
+/// Renames the user of the auth header to `new`, keeping their password, secret and sessions.
+pub fn rename(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+    new: UserId,
+) -> Result<(), RenameError> {
+    let user_id = authenticate(db, config, auth_header)?;
+    if config.is_reserved(&new) {
+        return Err(RenameError::ReservedName);
+    }
+    db.transaction(&mut |tx| tx.rename_user(&user_id, new.clone()))?;
+    Ok(())
+}
+
+/// Registers the user with the credentials of a Basic auth header.
+pub fn register_from_header(db: &impl Db, auth_header: &AuthHeader) -> Result<(), RegisterError> {
+    let credentials = auth_header.parse()?;
+    register(db, credentials.user_id, credentials.password)
+}
+
+#[cfg(test)]
+mod property_tests {
+    use crate::{clock::ManualClock, db::DbErrorKind, in_memory_db};
+
+    use super::*;
+    use quickcheck::Arbitrary;
+    use quickcheck_macros::quickcheck;
+    use std::time::Duration;
+
+    impl Arbitrary for UserId {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let mut s = String::arbitrary(g);
+            while s.is_ascii() || s.contains(':') || s.chars().any(|c| c.is_control()) {
+                s = String::arbitrary(g);
+            }
             UserId(s)
         }
     }
@@ -150,11 +1494,6 @@ mod property_tests {
         }
     }
 
-    fn auth_header(user: &UserId, pass: &EnteredPassword) -> String {
-        let encoded = base64::encode(format!("{}:{}", user.0, pass.0));
-        format!("Basic {encoded}")
-    }
-
     #[quickcheck]
     fn parse_basic_auth_roundtrip(user: UserId, pass: EnteredPassword) -> bool {
         let encoded = base64::encode(format!("{}:{}", user.0, pass.0));
@@ -162,42 +1501,1480 @@ mod property_tests {
         parse_auth(&header) == Ok((user, pass))
     }
 
+    #[derive(Clone, Debug)]
+    enum ParseOutcome {
+        Credentials(UserId, EnteredPassword),
+        WrongScheme,
+        InvalidBase64,
+        MissingColon,
+        NotUtf8,
+    }
+
+    /// A Basic auth header that is either well-formed or broken in one of the ways
+    /// `parse_auth` has to reject, together with the outcome expected from parsing it.
+    #[derive(Clone, Debug)]
+    struct RawAuthHeader {
+        header: String,
+        expected: ParseOutcome,
+    }
+
+    impl Arbitrary for RawAuthHeader {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let user = UserId::arbitrary(g);
+            let pass = EnteredPassword::arbitrary(g);
+            let encoded = base64::encode(format!("{}:{}", user.0, pass.0));
+            let (header, expected) = match u8::arbitrary(g) % 7 {
+                0 => (
+                    AuthHeader::basic(&user, &pass).0,
+                    ParseOutcome::Credentials(user, pass),
+                ),
+                1 => {
+                    // only the first colon separates, empty fields are fine
+                    let user = UserId(String::new());
+                    let pass = EnteredPassword(format!(":{}:", pass.0));
+                    (
+                        AuthHeader::basic(&user, &pass).0,
+                        ParseOutcome::Credentials(user, pass),
+                    )
+                }
+                2 => {
+                    let (scheme, expected) = g
+                        .choose(&[
+                            ("Bearer ", ParseOutcome::WrongScheme),
+                            ("Basic", ParseOutcome::WrongScheme),
+                            (" Basic ", ParseOutcome::WrongScheme),
+                            // the second space is part of the credentials
+                            ("Basic  ", ParseOutcome::InvalidBase64),
+                            ("Basi c ", ParseOutcome::WrongScheme),
+                            ("", ParseOutcome::WrongScheme),
+                        ])
+                        .unwrap()
+                        .clone();
+                    (format!("{}{}", scheme, encoded), expected)
+                }
+                3 => {
+                    // a length of 4n + 1 can never be valid base64
+                    let cut = usize::arbitrary(g) % (encoded.len() / 4) * 4 + 1;
+                    let truncated = &encoded[..cut];
+                    (format!("Basic {truncated}"), ParseOutcome::InvalidBase64)
+                }
+                4 => {
+                    let mut garbled = encoded;
+                    let at = usize::arbitrary(g) % (garbled.len() + 1);
+                    garbled.insert(at, *g.choose(&['!', '*', ' ', '-']).unwrap());
+                    (format!("Basic {garbled}"), ParseOutcome::InvalidBase64)
+                }
+                5 => {
+                    let mut raw = format!("{}:", user.0).into_bytes();
+                    raw.push(0xff);
+                    raw.extend(pass.0.bytes());
+                    let encoded = base64::encode(raw);
+                    (format!("Basic {encoded}"), ParseOutcome::NotUtf8)
+                }
+                _ => {
+                    let encoded = base64::encode(format!("{}{}", user.0, pass.0.replace(':', "")));
+                    (format!("Basic {encoded}"), ParseOutcome::MissingColon)
+                }
+            };
+            RawAuthHeader { header, expected }
+        }
+    }
+
+    #[quickcheck]
+    fn parse_auth_rejects_malformed_headers(raw: RawAuthHeader) -> bool {
+        match (parse_auth(&raw.header), raw.expected) {
+            (Ok(parsed), ParseOutcome::Credentials(user, pass)) => parsed == (user, pass),
+            (Err(ParseAuthError::WrongScheme), ParseOutcome::WrongScheme) => true,
+            (Err(ParseAuthError::InvalidBase64), ParseOutcome::InvalidBase64) => true,
+            (Err(ParseAuthError::MissingColon), ParseOutcome::MissingColon) => true,
+            (Err(ParseAuthError::Utf8Error(_)), ParseOutcome::NotUtf8) => true,
+            _ => false,
+        }
+    }
+
+    #[quickcheck]
+    fn basic_scheme_is_case_insensitive(user: UserId, pass: EnteredPassword) -> bool {
+        let encoded = base64::encode(format!("{}:{}", user.0, pass.0));
+        let expected = parse_auth(&format!("Basic {encoded}")).unwrap();
+        ["basic", "BASIC", "bAsIc"]
+            .iter()
+            .all(|scheme| parse_auth(&format!("{scheme} {encoded}")).unwrap() == expected)
+    }
+
+    #[test]
+    fn latin1_credentials_are_only_accepted_if_configured() {
+        let header = AuthHeader::new(format!("Basic {}", base64::encode(b"Alice:caf\xe9")));
+        assert!(matches!(header.parse(), Err(ParseAuthError::Utf8Error(_))));
+
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        let cafe = EnteredPassword::new("café".to_string());
+        register(&db, alice.clone(), cafe.clone()).unwrap();
+        assert!(matches!(
+            login(&db, &header),
+            Err(LoginError::ParseAuthError(ParseAuthError::Utf8Error(_)))
+        ));
+
+        let config = DomainConfig {
+            credentials_charset: CredentialsCharset::Latin1Fallback,
+            ..DomainConfig::default()
+        };
+        let credentials = header.parse_with(config.credentials_charset).unwrap();
+        assert_eq!(credentials.password, cafe);
+        assert_eq!(login_with(&db, &config, &header).unwrap(), alice);
+        // UTF-8 credentials still decode as UTF-8
+        let utf8 = AuthHeader::basic(&alice, &cafe);
+        assert_eq!(login_with(&db, &config, &utf8).unwrap(), alice);
+    }
+
+    #[test]
+    fn overlong_auth_headers_are_rejected_before_decoding() {
+        // Valid base64, so only the length check can reject it
+        let huge = AuthHeader::new(format!("Basic {}", "A".repeat(1024 * 1024)));
+        assert_eq!(huge.parse().unwrap_err(), ParseAuthError::TooLong);
+
+        let alice = UserId("Alice".to_string());
+        let longest = EnteredPassword::new("a".repeat(DEFAULT_MAX_PASSWORD_LEN));
+        let header = AuthHeader::basic(&alice, &longest);
+        assert_eq!(header.parse().unwrap().password, longest);
+
+        let config = DomainConfig {
+            max_auth_header_len: 16,
+            ..DomainConfig::default()
+        };
+        let db = in_memory_db::init_db();
+        assert!(matches!(
+            login_with(&db, &config, &header),
+            Err(LoginError::ParseAuthError(ParseAuthError::TooLong))
+        ));
+    }
+
+    #[test]
+    fn url_safe_base64_is_accepted_too() {
+        for pass in &["~~~", "???"] {
+            let raw = format!("Alice:{}", pass);
+            let url_safe = base64::encode_config(&raw, base64::URL_SAFE);
+            assert_ne!(url_safe, base64::encode(&raw));
+            assert_eq!(
+                parse_auth(&format!("Basic {url_safe}")),
+                Ok((
+                    UserId("Alice".to_string()),
+                    EnteredPassword(pass.to_string())
+                ))
+            );
+        }
+
+        // Mixing both alphabets is still rejected
+        for garbage in &["QWxpY2U6fn5-Pz8/", "!!!!", "QWxpY"] {
+            assert_eq!(
+                parse_auth(&format!("Basic {garbage}")),
+                Err(ParseAuthError::InvalidBase64),
+                "{}",
+                garbage
+            );
+        }
+    }
+
+    #[test]
+    fn common_passwords_are_rejected_if_configured() {
+        let db = in_memory_db::init_db();
+        let config = DomainConfig {
+            password_policy: PasswordPolicy {
+                common_passwords: Some(CommonPasswordChecker::embedded()),
+                ..PasswordPolicy::default()
+            },
+            ..DomainConfig::default()
+        };
+        let user = UserId("Alice".to_string());
+
+        for common in &["password", "PassWord", "hunter2"] {
+            let pass = EnteredPassword::new(common.to_string());
+            assert!(matches!(
+                register_with(&db, &config, user.clone(), pass),
+                Err(RegisterError::WeakPassword(WeakPasswordReason::Common))
+            ));
+        }
+        let strong = EnteredPassword::new(Uuid::new_v4().to_string());
+        register_with(&db, &config, user, strong).unwrap();
+    }
+
+    #[test]
+    fn reserved_names_are_rejected_regardless_of_case() {
+        let db = in_memory_db::init_db();
+        let config = DomainConfig {
+            reserved_names: vec![UserId("admin".to_string()), UserId("".to_string())]
+                .into_iter()
+                .collect(),
+            ..DomainConfig::default()
+        };
+        let pass = EnteredPassword::new("pw".to_string());
+
+        for reserved in &["admin", "Admin", "ADMIN", ""] {
+            let user = UserId(reserved.to_string());
+            assert!(matches!(
+                register_with(&db, &config, user, pass.clone()),
+                Err(RegisterError::ReservedName)
+            ));
+        }
+        register_with(&db, &config, UserId("Alice".to_string()), pass).unwrap();
+        assert_eq!(db.count_users().unwrap(), 1);
+    }
+
+    #[test]
+    fn imported_hashes_are_stored_as_is() {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        // The hash of `fixed_salt_gives_reproducible_hashes`
+        let hash = "$argon2i$v=19$m=4096,t=3,p=1$AAECAwQFBgcICQoLDA0ODw$quqSmjvZmlVQE8+7+WwAPkB9AUno9tfiUimMU7q79to";
+        import_user_from_phc_string(&db, alice.clone(), hash.to_string()).unwrap();
+        assert_eq!(db.get_pw(&alice).unwrap().unwrap().as_str(), hash);
+
+        let auth_header =
+            AuthHeader::basic(&alice, &EnteredPassword::new("correct horse".to_string()));
+        assert_eq!(login(&db, &auth_header).unwrap(), alice);
+
+        assert!(matches!(
+            import_user(
+                &db,
+                alice,
+                EncodedPassword::from_phc_string(hash.to_string()).unwrap()
+            ),
+            Err(RegisterError::AlreadyExists)
+        ));
+        let bob = UserId("Bob".to_string());
+        assert!(matches!(
+            import_user_from_phc_string(&db, bob.clone(), "correct horse".to_string()),
+            Err(RegisterError::InvalidHash(_))
+        ));
+        assert!(!db.user_exists(&bob).unwrap());
+    }
+
+    #[test]
+    fn imports_follow_the_config() {
+        let db = in_memory_db::init_db();
+        let config = DomainConfig {
+            reserved_names: vec![UserId("admin".to_string())].into_iter().collect(),
+            max_imported_argon2: Argon2Params {
+                time_cost: 3,
+                ..Argon2Params::MAX_IMPORTED
+            },
+            ..DomainConfig::default()
+        };
+        let hash = "$argon2i$v=19$m=4096,t=3,p=1$AAECAwQFBgcICQoLDA0ODw$quqSmjvZmlVQE8+7+WwAPkB9AUno9tfiUimMU7q79to";
+        assert!(matches!(
+            import_user_from_phc_string_with(
+                &db,
+                &config,
+                UserId("Admin".to_string()),
+                hash.to_string()
+            ),
+            Err(RegisterError::ReservedName)
+        ));
+
+        let alice = UserId("Alice".to_string());
+        for expensive in &[
+            hash.replace("t=3", "t=4"),
+            hash.replace("m=4096", "m=99999999999"),
+        ] {
+            assert!(matches!(
+                import_user_from_phc_string_with(&db, &config, alice.clone(), expensive.clone()),
+                Err(RegisterError::HashTooExpensive)
+            ));
+        }
+        assert!(matches!(
+            import_user_from_phc_string(&db, alice.clone(), hash.replace("t=3", "t=17")),
+            Err(RegisterError::HashTooExpensive)
+        ));
+        assert!(!db.user_exists(&alice).unwrap());
+
+        import_user_from_phc_string_with(&db, &config, alice.clone(), hash.to_string()).unwrap();
+        assert!(db.user_exists(&alice).unwrap());
+    }
+
+    #[test]
+    fn fixed_salt_gives_reproducible_hashes() {
+        let salts = FixedSalt([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        let pass = EnteredPassword::new("correct horse".to_string());
+        let encoded = pass.clone().encode_with(&salts).unwrap();
+        assert_eq!(
+            encoded.as_str(),
+            "$argon2i$v=19$m=4096,t=3,p=1$AAECAwQFBgcICQoLDA0ODw$quqSmjvZmlVQE8+7+WwAPkB9AUno9tfiUimMU7q79to"
+        );
+        assert!(encoded.verify(&pass).unwrap());
+    }
+
+    #[quickcheck]
+    fn equal_passwords_get_different_hashes(pass: EnteredPassword) -> bool {
+        // Each hash has its own random salt, yet both verify
+        let first = pass.clone().encode().unwrap();
+        let second = pass.clone().encode().unwrap();
+        first.as_str() != second.as_str()
+            && first.verify(&pass).unwrap()
+            && second.verify(&pass).unwrap()
+    }
+
+    #[test]
+    fn encoded_password_roundtrips_through_its_string_form() {
+        let pass = EnteredPassword::new("correct horse".to_string());
+        let encoded = pass.clone().encode().unwrap();
+
+        let restored = EncodedPassword::from_phc_string(encoded.clone().into_string()).unwrap();
+        assert_eq!(restored.as_str(), encoded.as_str());
+        assert!(restored.verify(&pass).unwrap());
+
+        let json = serde_json::to_string(&encoded).unwrap();
+        assert_eq!(json, format!("\"{}\"", encoded.as_str()));
+        let deserialized: EncodedPassword = serde_json::from_str(&json).unwrap();
+        assert!(deserialized.verify(&pass).unwrap());
+
+        EncodedPassword::from_phc_string(DUMMY_HASH.to_string()).unwrap();
+    }
+
+    #[cfg(feature = "bcrypt")]
+    #[test]
+    fn bcrypt_hashes_are_accepted() {
+        let bcrypt = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+        let encoded = EncodedPassword::from_phc_string(bcrypt.to_string()).unwrap();
+        assert_eq!(encoded.as_str(), bcrypt);
+    }
+
+    #[cfg(not(feature = "bcrypt"))]
+    #[test]
+    fn bcrypt_hashes_need_the_bcrypt_feature() {
+        let bcrypt = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+        assert_eq!(
+            EncodedPassword::from_phc_string(bcrypt.to_string()).unwrap_err(),
+            InvalidHashError
+        );
+    }
+
+    #[cfg(feature = "bcrypt")]
+    #[test]
+    fn verifies_argon2_and_bcrypt_hashes_side_by_side() {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        let pass = EnteredPassword::new("correct horse".to_string());
+        let wrong = EnteredPassword::new("hunter2".to_string());
+        let argon2 = Argon2Hasher::default().hash(&pass).unwrap();
+        let bcrypt = BcryptHasher { cost: 4 }.hash(&pass).unwrap();
+        assert!(bcrypt.as_str().starts_with("$2b$"));
+        EncodedPassword::from_phc_string(bcrypt.as_str().to_string()).unwrap();
+
+        for (user, encoded) in vec![(&alice, argon2), (&bob, bcrypt)] {
+            assert!(encoded.verify(&pass).unwrap());
+            assert!(!encoded.verify(&wrong).unwrap());
+            db.register(user.clone(), encoded).unwrap();
+        }
+        for user in &[alice, bob] {
+            assert_eq!(login(&db, &AuthHeader::basic(user, &pass)).unwrap(), *user);
+        }
+    }
+
+    #[test]
+    fn malformed_hashes_are_rejected() {
+        for malformed in &[
+            "",
+            "hunter2",
+            "$argon2i$v=19$m=4096,t=3,p=1$c2FsdA",
+            "$argon2x$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA",
+            "$argon2i$v=19$m=4096,t=3$c2FsdA$aGFzaA",
+            "$argon2i$v=19$m=lots,t=3,p=1$c2FsdA$aGFzaA",
+            "$argon2i$v=19$m=4096,t=3,p=1$$aGFzaA",
+            "$argon2i$v=19$m=4096,t=3,p=1$c2FsdA$aGFz aA",
+            "$2b$12$tooshort",
+            "$2x$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW",
+            "$2b$1$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW",
+        ] {
+            assert_eq!(
+                EncodedPassword::from_phc_string(malformed.to_string()).err(),
+                Some(InvalidHashError),
+                "{}",
+                malformed
+            );
+        }
+        assert!(serde_json::from_str::<EncodedPassword>("\"hunter2\"").is_err());
+    }
+
+    #[test]
+    fn huge_passwords_are_rejected_before_hashing() {
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let huge = EnteredPassword::new("a".repeat(2 * 1024 * 1024));
+        let header = AuthHeader::basic(&user, &huge);
+
+        let start = Instant::now();
+        assert!(matches!(
+            register(&db, user.clone(), huge),
+            Err(RegisterError::PasswordTooLong)
+        ));
+        assert!(matches!(
+            login(&db, &header),
+            Err(LoginError::PasswordTooLong)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(db.count_users().unwrap(), 0);
+
+        let longest = EnteredPassword::new("a".repeat(DEFAULT_MAX_PASSWORD_LEN));
+        register(&db, user, longest).unwrap();
+    }
+
+    #[test]
+    fn registering_twice_keeps_the_first_password() {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        let first = EnteredPassword::new("first".to_string());
+        let second = EnteredPassword::new("second".to_string());
+        register(&db, alice.clone(), first.clone()).unwrap();
+
+        assert!(matches!(
+            register(&db, alice.clone(), second.clone()),
+            Err(RegisterError::AlreadyExists)
+        ));
+        login(&db, &AuthHeader::basic(&alice, &first)).unwrap();
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(&alice, &second)),
+            Err(LoginError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn register_many_continues_after_failures() {
+        let db = in_memory_db::init_db();
+        let pass = || EnteredPassword::new("pw".to_string());
+        register(&db, UserId("Carol".to_string()), pass()).unwrap();
+
+        let users = ["Alice", "Bob", "Carol", "David", "Erin"]
+            .iter()
+            .map(|name| (UserId(name.to_string()), pass()))
+            .collect();
+        let results = register_many(&db, users);
+
+        assert_eq!(results.len(), 5);
+        assert!(matches!(
+            &results[2],
+            Err(RegisterError::DbError(e)) if e.kind() == DbErrorKind::Conflict
+        ));
+        for (i, result) in results.iter().enumerate().filter(|(i, _)| *i != 2) {
+            assert!(result.is_ok(), "registration {} failed: {:?}", i, result);
+        }
+        assert_eq!(db.count_users().unwrap(), 5);
+    }
+
+    #[test]
+    fn register_many_follows_the_config() {
+        let db = in_memory_db::init_db();
+        let audit = Arc::new(InMemoryAuditSink::default());
+        let config = DomainConfig {
+            reserved_names: vec![UserId("admin".to_string())].into_iter().collect(),
+            password_policy: PasswordPolicy {
+                min_len: 8,
+                ..PasswordPolicy::default()
+            },
+            audit: audit.clone(),
+            ..DomainConfig::default()
+        };
+        let users = vec![
+            (UserId("Admin".to_string()), "correct horse"),
+            (UserId("Alice".to_string()), "short"),
+            (UserId("Bob".to_string()), "correct horse"),
+        ]
+        .into_iter()
+        .map(|(user_id, pass)| (user_id, EnteredPassword::new(pass.to_string())))
+        .collect();
+        let results = register_many_with(&db, &config, users);
+
+        assert!(matches!(results[0], Err(RegisterError::ReservedName)));
+        assert!(matches!(results[1], Err(RegisterError::WeakPassword(_))));
+        assert!(results[2].is_ok());
+        assert_eq!(db.list_users().unwrap(), vec![UserId("Bob".to_string())]);
+        assert_eq!(audit.events().len(), 3);
+    }
+
+    #[test]
+    fn profile_is_stored_with_registration() {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        let profile = UserProfile {
+            email: Some(Email::parse("alice@example.com".to_string()).unwrap()),
+        };
+
+        let config = DomainConfig::default();
+        register_with_profile(&db, &config, alice.clone(), pass.clone(), profile.clone()).unwrap();
+        register(&db, bob.clone(), pass).unwrap();
+
+        assert_eq!(get_profile(&db, &alice).unwrap(), Some(profile));
+        assert_eq!(
+            get_profile(&db, &bob).unwrap(),
+            Some(UserProfile::default())
+        );
+        assert_eq!(
+            get_profile(&db, &UserId("Carol".to_string())).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn malformed_emails_are_rejected() {
+        for malformed in &[
+            "",
+            "alice",
+            "@example.com",
+            "alice@",
+            "alice@example",
+            "alice@.com",
+            "alice@example.",
+            "alice@@example.com",
+            "al ice@example.com",
+        ] {
+            assert_eq!(
+                Email::parse(malformed.to_string()),
+                Err(InvalidEmail),
+                "{}",
+                malformed
+            );
+        }
+        let email = Email::parse("alice@mail.example.com".to_string()).unwrap();
+        assert_eq!(email.as_str(), "alice@mail.example.com");
+    }
+
     #[quickcheck]
     fn cant_access_secret_without_logging_in(user: UserId) -> bool {
         let db = in_memory_db::init_db();
-        !can_access_secret(&db, &user).unwrap()
+        !can_access_secret(&db, &user, Role::User).unwrap()
     }
 
     #[quickcheck]
     fn cant_login_without_registering(user: UserId, pass: EnteredPassword) -> bool {
-        let header = auth_header(&user, &pass);
+        let header = AuthHeader::basic(&user, &pass);
         let db = in_memory_db::init_db();
         matches!(login(&db, &header), Err(LoginError::NotRegistered))
     }
 
     #[quickcheck]
     fn can_login_after_registering(user: UserId, pass: EnteredPassword) -> bool {
-        let header = auth_header(&user, &pass);
+        let header = AuthHeader::basic(&user, &pass);
         let db = in_memory_db::init_db();
         register(&db, user.clone(), pass.clone()).unwrap();
-        login(&db, &header).unwrap() == ()
+        login(&db, &header).unwrap() == user
     }
 
     #[quickcheck]
     fn can_access_secrets_after_logging_in(user: UserId, pass: EnteredPassword) -> bool {
-        let header = auth_header(&user, &pass);
+        let header = AuthHeader::basic(&user, &pass);
         let db = in_memory_db::init_db();
         register(&db, user.clone(), pass.clone()).unwrap();
         login(&db, &header).unwrap();
-        can_access_secret(&db, &user).unwrap()
+        can_access_secret(&db, &user, Role::User).unwrap()
     }
 
     #[quickcheck]
     fn cant_access_secrets_after_logging_in_and_out(user: UserId, pass: EnteredPassword) -> bool {
-        let header = auth_header(&user, &pass);
+        let header = AuthHeader::basic(&user, &pass);
+        let db = in_memory_db::init_db();
+        login(&db, &header).unwrap();
+        logout(&db, &header).unwrap();
+        !can_access_secret(&db, &user, Role::User).unwrap()
+    }
+
+    /// A focused version of what the simulator checks: repeating the whole session lifecycle of
+    /// one user never leaves anything behind.
+    #[quickcheck]
+    fn login_logout_cycles_are_stable(user: UserId, pass: EnteredPassword, rounds: u8) -> bool {
+        // Every login hashes, so the rounds are capped to keep the test fast
+        let rounds = rounds % 8 + 1;
+        let header = AuthHeader::basic(&user, &pass);
+        let db = in_memory_db::init_db();
+        register(&db, user.clone(), pass).unwrap();
+
+        (0..rounds).all(|_| {
+            login(&db, &header).unwrap();
+            let logged_in = observe(&db, &user) == (true, true, true, 1, 1);
+            logout(&db, &header).unwrap();
+            logged_in && observe(&db, &user) == (true, false, false, 1, 0)
+        })
+    }
+
+    /// What the rest of the domain can see of the user and the db.
+    fn observe(db: &in_memory_db::Db, user: &UserId) -> (bool, bool, bool, usize, usize) {
+        (
+            db.user_exists(user).unwrap(),
+            db.has_session(user).unwrap(),
+            can_access_secret(db, user, Role::User).unwrap(),
+            db.count_users().unwrap(),
+            db.count_sessions().unwrap(),
+        )
+    }
+
+    /// The simulator logs out users that may never have registered, so logging out has to work
+    /// for every user, and a second logout must not change anything.
+    #[quickcheck]
+    fn logout_is_idempotent(
+        user: UserId,
+        pass: EnteredPassword,
+        registered: bool,
+        logged_in: bool,
+    ) -> bool {
+        let header = AuthHeader::basic(&user, &pass);
+        let db = in_memory_db::init_db();
+        if registered {
+            register(&db, user.clone(), pass).unwrap();
+            if logged_in {
+                login(&db, &header).unwrap();
+            }
+        }
+
+        let once = logout(&db, &header).map(|()| observe(&db, &user));
+        let twice = logout(&db, &header).map(|()| observe(&db, &user));
+        match (once, twice) {
+            (Ok(once), Ok(twice)) => {
+                once == twice && once == (registered, false, false, registered as usize, 0)
+            }
+            _ => false,
+        }
+    }
+
+    #[quickcheck]
+    fn logging_in_counts_as_activity(user: UserId, pass: EnteredPassword) -> bool {
+        let header = AuthHeader::basic(&user, &pass);
         let db = in_memory_db::init_db();
+        register(&db, user.clone(), pass.clone()).unwrap();
+        let before_login = Instant::now();
         login(&db, &header).unwrap();
+        db.last_activity_before(before_login).unwrap().is_empty()
+            && db
+                .last_activity_before(Instant::now() + Duration::from_secs(1))
+                .unwrap()
+                == vec![user]
+    }
+
+    /// The argon2 parameters and the salt and hash lengths decide how long a verification
+    /// takes, so comparing them shows the same work without timing anything.
+    #[test]
+    fn unknown_users_take_as_long_as_wrong_passwords() {
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pw = EnteredPassword::new("wrong".to_string());
+        let encoded = EnteredPassword::new("correct".to_string())
+            .encode()
+            .unwrap();
+        db.register(user.clone(), encoded.clone()).unwrap();
+
+        let shape = |hash: &str| {
+            let parts = hash.split('$').collect::<Vec<_>>();
+            (parts[..4].join("$"), parts[4].len(), parts[5].len())
+        };
+        assert_eq!(shape(DUMMY_HASH), shape(encoded.as_str()));
+        // Really verified, rather than rejected as malformed
+        assert!(!EncodedPassword(DUMMY_HASH.to_string()).verify(&pw).unwrap());
+
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(&user, &pw)),
+            Err(LoginError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(&UserId("Mallory".to_string()), &pw)),
+            Err(LoginError::NotRegistered)
+        ));
+    }
+
+    #[test]
+    fn idle_sessions_expire_but_active_ones_survive() {
+        let idle_timeout = Duration::from_secs(60);
+        let (config, clock) = config_with_clock(SessionPolicy {
+            idle_timeout: Some(idle_timeout),
+            ttl: None,
+        });
+        let db = in_memory_db::init_db();
+        let active = UserId("Alice".to_string());
+        let idle = UserId("Bob".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        for user in &[&active, &idle] {
+            register(&db, (*user).clone(), pass.clone()).unwrap();
+            login_with(&db, &config, &AuthHeader::basic(user, &pass)).unwrap();
+        }
+
+        // Well past the idle window in total, but never idle for that long
+        for _ in 0..5 {
+            clock.advance(idle_timeout / 2);
+            assert!(can_access_secret_with(&db, &config, &active, Role::User).unwrap());
+        }
+        assert!(!can_access_secret_with(&db, &config, &idle, Role::User).unwrap());
+        assert!(!db.has_session(&idle).unwrap());
+    }
+
+    #[test]
+    fn heartbeats_push_the_idle_expiry_forward() {
+        let idle_timeout = Duration::from_secs(60);
+        let (config, clock) = config_with_clock(SessionPolicy {
+            idle_timeout: Some(idle_timeout),
+            ttl: None,
+        });
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(&db, user.clone(), pass.clone()).unwrap();
+        login_with(&db, &config, &AuthHeader::basic(&user, &pass)).unwrap();
+
+        clock.advance(idle_timeout - Duration::from_secs(1));
+        assert!(heartbeat_with(&db, &config, &user).unwrap());
+        let last_seen = db.get_session_times(&user).unwrap().unwrap().last_seen;
+        assert_eq!(last_seen, clock.now());
+
+        // Past the idle timeout counted from login, but not from the heartbeat
+        clock.advance(Duration::from_secs(2));
+        assert!(can_access_secret_with(&db, &config, &user, Role::User).unwrap());
+
+        clock.advance(idle_timeout + Duration::from_secs(1));
+        assert!(!heartbeat_with(&db, &config, &user).unwrap());
+        assert!(!db.has_session(&user).unwrap());
+    }
+
+    #[test]
+    fn session_ttl_remaining_counts_down_to_the_earlier_expiry() {
+        let ttl = Duration::from_secs(60);
+        let (config, clock) = config_with_clock(SessionPolicy {
+            idle_timeout: Some(ttl / 4),
+            ttl: Some(ttl),
+        });
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(&db, user.clone(), pass.clone()).unwrap();
+        assert_eq!(
+            session_ttl_remaining_with(&db, &config, &user).unwrap(),
+            None
+        );
+        login_with(&db, &config, &AuthHeader::basic(&user, &pass)).unwrap();
+
+        let remaining = || session_ttl_remaining_with(&db, &config, &user).unwrap();
+        assert_eq!(remaining(), Some(Duration::from_secs(15)));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(remaining(), Some(Duration::from_secs(5)));
+        assert!(heartbeat_with(&db, &config, &user).unwrap());
+        assert_eq!(remaining(), Some(Duration::from_secs(15)));
+
+        // Heartbeats keep the idle timeout away, until the TTL is closer
+        for _ in 0..4 {
+            clock.advance(Duration::from_secs(10));
+            assert!(heartbeat_with(&db, &config, &user).unwrap());
+        }
+        assert_eq!(remaining(), Some(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(remaining(), Some(Duration::from_secs(5)));
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(remaining(), None);
+    }
+
+    #[test]
+    fn sessions_expire_after_ttl_even_if_active() {
+        let ttl = Duration::from_secs(60);
+        let (config, clock) = config_with_clock(SessionPolicy {
+            idle_timeout: Some(ttl / 4),
+            ttl: Some(ttl),
+        });
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(&db, user.clone(), pass.clone()).unwrap();
+        login_with(&db, &config, &AuthHeader::basic(&user, &pass)).unwrap();
+
+        for _ in 0..6 {
+            clock.advance(ttl / 6);
+            assert!(can_access_secret_with(&db, &config, &user, Role::User).unwrap());
+        }
+        clock.advance(Duration::from_secs(1));
+        assert!(!can_access_secret_with(&db, &config, &user, Role::User).unwrap());
+    }
+
+    #[test]
+    fn logging_in_again_doesnt_extend_the_ttl() {
+        let ttl = Duration::from_secs(60);
+        let (config, clock) = config_with_clock(SessionPolicy {
+            idle_timeout: None,
+            ttl: Some(ttl),
+        });
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(&db, user.clone(), pass.clone()).unwrap();
+        let header = AuthHeader::basic(&user, &pass);
+        login_with(&db, &config, &header).unwrap();
+
+        clock.advance(ttl / 2);
+        login_with(&db, &config, &header).unwrap();
+        clock.advance(ttl / 2 + Duration::from_secs(1));
+        assert!(!can_access_secret_with(&db, &config, &user, Role::User).unwrap());
+
+        login_with(&db, &config, &header).unwrap();
+        clock.advance(ttl / 2);
+        assert!(can_access_secret_with(&db, &config, &user, Role::User).unwrap());
+    }
+
+    #[test]
+    fn reset_password_replaces_the_old_one_and_ends_sessions() {
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let old = EnteredPassword::new("old".to_string());
+        let new = EnteredPassword::new("new".to_string());
+        register(&db, user.clone(), old.clone()).unwrap();
+        login(&db, &AuthHeader::basic(&user, &old)).unwrap();
+
+        reset_password(&db, &DomainConfig::default(), &user, new.clone()).unwrap();
+        assert!(!db.has_session(&user).unwrap());
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(&user, &old)),
+            Err(LoginError::InvalidCredentials)
+        ));
+        login(&db, &AuthHeader::basic(&user, &new)).unwrap();
+
+        let unknown = UserId("Bob".to_string());
+        match reset_password(&db, &DomainConfig::default(), &unknown, new) {
+            Err(RegisterError::DbError(e)) => assert_eq!(e.kind(), DbErrorKind::NotFound),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_one_of_two_concurrent_password_changes_succeeds() {
+        let db = Arc::new(in_memory_db::init_db());
+        let user = UserId("Alice".to_string());
+        let old = EnteredPassword::new("old".to_string());
+        register(&*db, user.clone(), old.clone()).unwrap();
+        login(&*db, &AuthHeader::basic(&user, &old)).unwrap();
+
+        let changes = ["new-1", "new-2"]
+            .iter()
+            .map(|new| {
+                let db = db.clone();
+                let header = AuthHeader::basic(&user, &old);
+                let new = EnteredPassword::new(new.to_string());
+                std::thread::spawn(move || {
+                    let result =
+                        change_password(&*db, &DomainConfig::default(), &header, new.clone());
+                    (new, result)
+                })
+            })
+            .collect::<Vec<_>>();
+        let (won, lost): (Vec<_>, Vec<_>) = changes
+            .into_iter()
+            .map(|change| change.join().unwrap())
+            .partition(|(_, result)| result.is_ok());
+
+        assert_eq!(won.len(), 1, "{:?}", lost);
+        assert!(
+            matches!(
+                lost[0].1,
+                Err(ChangePasswordError::Conflict)
+                    | Err(ChangePasswordError::LoginError(
+                        LoginError::InvalidCredentials
+                    ))
+            ),
+            "{:?}",
+            lost[0].1
+        );
+        let stored = db.get_pw(&user).unwrap().unwrap();
+        assert!(stored.verify(&won[0].0).unwrap());
+        assert!(!db.has_session(&user).unwrap());
+    }
+
+    #[test]
+    fn password_changes_reject_recently_used_passwords() {
+        let db = in_memory_db::init_db();
+        let config = DomainConfig {
+            password_history_len: 2,
+            ..DomainConfig::default()
+        };
+        let user = UserId("Alice".to_string());
+        let pass = |pass: &str| EnteredPassword::new(pass.to_string());
+        let change = |from: &str, to: &str| {
+            change_password(
+                &db,
+                &config,
+                &AuthHeader::basic(&user, &pass(from)),
+                pass(to),
+            )
+        };
+        register(&db, user.clone(), pass("pw-0")).unwrap();
+
+        change("pw-0", "pw-1").unwrap();
+        assert!(matches!(
+            change("pw-1", "pw-0"),
+            Err(ChangePasswordError::Reused)
+        ));
+        assert!(matches!(
+            change("pw-1", "pw-1"),
+            Err(ChangePasswordError::Reused)
+        ));
+        change("pw-1", "pw-2").unwrap();
+        change("pw-2", "pw-3").unwrap();
+
+        // pw-0 was evicted by pw-2
+        let history = db.get_password_history(&user).unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].verify(&pass("pw-2")).unwrap());
+        assert!(history[1].verify(&pass("pw-1")).unwrap());
+        change("pw-3", "pw-0").unwrap();
+        login(&db, &AuthHeader::basic(&user, &pass("pw-0"))).unwrap();
+    }
+
+    #[test]
+    fn unregistered_users_are_gone() {
+        let db = in_memory_db::init_db();
+        let pass = EnteredPassword::new("pw".to_string());
+        let users = ["Bob", "Alice"]
+            .iter()
+            .map(|name| UserId(name.to_string()))
+            .collect::<Vec<_>>();
+        for user in &users {
+            register(&db, user.clone(), pass.clone()).unwrap();
+        }
+        let bob = &users[0];
+        login(&db, &AuthHeader::basic(bob, &pass)).unwrap();
+        assert_eq!(
+            list_users(&db).unwrap(),
+            vec![users[1].clone(), bob.clone()]
+        );
+
+        unregister(&db, bob).unwrap();
+        assert_eq!(list_users(&db).unwrap(), vec![users[1].clone()]);
+        assert!(!db.has_session(bob).unwrap());
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(bob, &pass)),
+            Err(LoginError::NotRegistered)
+        ));
+        assert_eq!(
+            unregister(&db, bob).unwrap_err().kind(),
+            DbErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn renamed_users_keep_their_password_secret_and_session() {
+        let db = in_memory_db::init_db();
+        let config = DomainConfig::default();
+        let pass = EnteredPassword::new("pw".to_string());
+        let typo = UserId("Alcie".to_string());
+        let alice = UserId("Alice".to_string());
+        register(&db, typo.clone(), pass.clone()).unwrap();
+        login(&db, &AuthHeader::basic(&typo, &pass)).unwrap();
+        set_secret(&db, &typo, "swordfish".to_string()).unwrap();
+
+        rename(
+            &db,
+            &config,
+            &AuthHeader::basic(&typo, &pass),
+            alice.clone(),
+        )
+        .unwrap();
+        assert!(can_access_secret(&db, &alice, Role::User).unwrap());
+        assert_eq!(
+            get_secret(&db, &alice).unwrap().as_deref(),
+            Some("swordfish")
+        );
+        logout(&db, &AuthHeader::basic(&alice, &pass)).unwrap();
+        login(&db, &AuthHeader::basic(&alice, &pass)).unwrap();
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(&typo, &pass)),
+            Err(LoginError::NotRegistered)
+        ));
+        assert!(!can_access_secret(&db, &typo, Role::User).unwrap());
+
+        let bob = UserId("Bob".to_string());
+        register(&db, bob.clone(), pass.clone()).unwrap();
+        match rename(&db, &config, &AuthHeader::basic(&bob, &pass), alice.clone()) {
+            Err(RenameError::DbError(e)) => assert_eq!(e.kind(), DbErrorKind::Conflict),
+            other => panic!("renamed to a taken name: {:?}", other),
+        }
+        assert!(matches!(
+            rename(&db, &config, &AuthHeader::basic(&typo, &pass), bob),
+            Err(RenameError::LoginError(LoginError::NotRegistered))
+        ));
+    }
+
+    #[test]
+    fn audit_trail_records_failed_and_successful_logins_in_order() {
+        let audit = Arc::new(InMemoryAuditSink::default());
+        let config = DomainConfig {
+            audit: audit.clone(),
+            ..DomainConfig::default()
+        };
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("correct horse".to_string());
+        let wrong = EnteredPassword::new("hunter2".to_string());
+        register_with(&db, &config, user.clone(), pass.clone()).unwrap();
+
+        assert!(login_with(&db, &config, &AuthHeader::basic(&user, &wrong)).is_err());
+        login_with(&db, &config, &AuthHeader::basic(&user, &pass)).unwrap();
+        logout_with(&db, &config, &AuthHeader::basic(&user, &pass)).unwrap();
+
+        let events = audit.events();
+        let summary = events
+            .iter()
+            .map(|event| (event.action, event.outcome.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (AuditAction::Register, AuditOutcome::Success),
+                (
+                    AuditAction::Login,
+                    AuditOutcome::Failure(LoginError::InvalidCredentials.to_string())
+                ),
+                (AuditAction::Login, AuditOutcome::Success),
+                (AuditAction::Logout, AuditOutcome::Success),
+            ]
+        );
+        assert!(events
+            .iter()
+            .all(|event| event.user.as_ref() == Some(&user)));
+        assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+        let trail = format!("{:?}", events);
+        assert!(!trail.contains(&pass.0) && !trail.contains(&wrong.0));
+    }
+
+    #[test]
+    fn audit_trail_records_refreshes_logouts_and_unparseable_headers() {
+        let audit = Arc::new(InMemoryAuditSink::default());
+        let config = DomainConfig {
+            audit: audit.clone(),
+            ..DomainConfig::default()
+        };
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(&db, user.clone(), pass.clone()).unwrap();
+        let credentials = |password: &str| Credentials {
+            user_id: user.clone(),
+            password: EnteredPassword::new(password.to_string()),
+        };
+
+        let outcome = login_with_credentials_with(&db, &config, credentials("pw"), true).unwrap();
+        assert!(
+            futures_lite::future::block_on(login_with_credentials_async_with(
+                &db,
+                &config,
+                credentials("hunter2"),
+                false
+            ))
+            .is_err()
+        );
+        refresh_with(&db, &config, &outcome.refresh_token.unwrap()).unwrap();
+        assert!(refresh_with(&db, &config, &RefreshToken::generate()).is_err());
+        logout_all_with(&db, &config, &AuthHeader::basic(&user, &pass)).unwrap();
+        let garbage = AuthHeader::new("Bearer garbage".to_string());
+        assert!(logout_all_with(&db, &config, &garbage).is_err());
+
+        let summary = audit
+            .events()
+            .into_iter()
+            .map(|event| {
+                (
+                    event.action,
+                    event.user,
+                    event.outcome == AuditOutcome::Success,
+                )
+            })
+            .collect::<Vec<_>>();
+        let alice = Some(user.clone());
+        assert_eq!(
+            summary,
+            vec![
+                (AuditAction::Login, alice.clone(), true),
+                (AuditAction::Login, alice.clone(), false),
+                (AuditAction::Refresh, alice.clone(), true),
+                (AuditAction::Refresh, None, false),
+                (AuditAction::LogoutAll, alice, true),
+                (AuditAction::LogoutAll, None, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn weak_passwords_are_reported_with_the_rule_but_without_the_password() {
+        let audit = Arc::new(InMemoryAuditSink::default());
+        let log = Arc::new(InMemoryLog::default());
+        let config = DomainConfig {
+            password_policy: PasswordPolicy {
+                min_len: 8,
+                ..PasswordPolicy::default()
+            },
+            audit: audit.clone(),
+            log: log.clone(),
+            ..DomainConfig::default()
+        };
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let short = EnteredPassword::new("x7q!z".to_string());
+
+        let e = register_with(&db, &config, user.clone(), short.clone()).unwrap_err();
+        let reason = WeakPasswordReason::TooShort { min: 8 };
+        assert!(
+            matches!(e, RegisterError::WeakPassword(r) if r == reason),
+            "{:?}",
+            e
+        );
+
+        let events = audit.events();
+        assert_eq!(
+            events[0].outcome,
+            AuditOutcome::Failure(RegisterError::WeakPassword(reason).to_string())
+        );
+        assert_eq!(
+            log.events(),
+            vec![LogEvent::WeakPasswordRejected { user, reason }]
+        );
+        let trail = format!("{:?} {:?} {}", log.events(), events, e);
+        assert!(trail.contains("shorter than 8 characters"), "{}", trail);
+        assert!(!trail.contains(&short.0), "{}", trail);
+    }
+
+    fn remembered_login(db: &impl Db) -> (UserId, AuthHeader, RefreshToken) {
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(db, user.clone(), pass.clone()).unwrap();
+        let header = AuthHeader::basic(&user, &pass);
+        let outcome = login_with_token(db, &header, true).unwrap();
+        (user, header, outcome.refresh_token.unwrap())
+    }
+
+    #[test]
+    fn refresh_tokens_are_only_issued_when_remembering() {
+        let db = in_memory_db::init_db();
+        let (_, header, _) = remembered_login(&db);
+        let outcome = login_with_token(&db, &header, false).unwrap();
+        assert!(outcome.refresh_token.is_none());
+    }
+
+    #[test]
+    fn refresh_starts_a_new_session_and_rotates_the_token() {
+        let db = in_memory_db::init_db();
+        let (user, _, first) = remembered_login(&db);
+        db.remove_session(&user).unwrap();
+
+        let outcome = refresh(&db, &first).unwrap();
+        assert_eq!(outcome.user, user);
+        assert_eq!(session_user(&db, &outcome.token).unwrap(), Some(user));
+        let second = outcome.refresh_token.unwrap();
+        assert!(second != first);
+
+        assert!(matches!(
+            refresh(&db, &first),
+            Err(LoginError::InvalidRefreshToken)
+        ));
+        refresh(&db, &second).unwrap();
+    }
+
+    #[cfg(feature = "signed-tokens")]
+    #[test]
+    fn signed_session_tokens_end_with_their_session() {
+        let config = DomainConfig {
+            session_tokens: Arc::new(SignedTokens::new(vec![7; 32])),
+            ..DomainConfig::default()
+        };
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let password = EnteredPassword::new("pw".to_string());
+        register(&db, user.clone(), password.clone()).unwrap();
+        let header = AuthHeader::basic(&user, &password);
+
+        let credentials = Credentials {
+            user_id: user.clone(),
+            password,
+        };
+        let token = login_with_credentials_with(&db, &config, credentials, false)
+            .unwrap()
+            .token;
+        assert_eq!(
+            session_user_with(&db, &config, &token).unwrap(),
+            Some(user.clone())
+        );
+
+        logout(&db, &header).unwrap();
+        let credentials = Credentials {
+            user_id: user.clone(),
+            password: EnteredPassword::new("pw".to_string()),
+        };
+        let next = login_with_credentials_with(&db, &config, credentials, false)
+            .unwrap()
+            .token;
+        assert_eq!(session_user_with(&db, &config, &token).unwrap(), None);
+        assert_eq!(session_user_with(&db, &config, &next).unwrap(), Some(user));
+
+        // Forged tokens are rejected before the lookup, even if they are stored
+        let forged = SessionToken::new("forged".to_string());
+        db.add_session_token(forged.clone(), UserId("Alice".to_string()))
+            .unwrap();
+        assert_eq!(session_user_with(&db, &config, &forged).unwrap(), None);
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordedSlowOps(std::sync::Mutex<Vec<(&'static str, Duration)>>);
+
+    impl SlowOpSink for RecordedSlowOps {
+        fn record(&self, op: &'static str, took: Duration) {
+            self.0.lock().unwrap().push((op, took));
+        }
+    }
+
+    #[test]
+    fn operations_over_the_threshold_are_reported_as_slow() {
+        let slow_ops = Arc::new(RecordedSlowOps::default());
+        let config = DomainConfig {
+            slow_op_threshold: Some(Duration::from_millis(50)),
+            slow_ops: slow_ops.clone(),
+            ..DomainConfig::default()
+        };
+        let delay = Duration::from_millis(60);
+        let fast = Arc::new(in_memory_db::init_db());
+        let db = Arc::new(crate::latency_db::LatencyDb::new(
+            Arc::clone(&fast),
+            delay,
+            delay,
+            0,
+        ));
+        let user = UserId("Alice".to_string());
+        let password = EnteredPassword::new("pw".to_string());
+        register(&fast, user.clone(), password.clone()).unwrap();
+
+        login_with(&db, &config, &AuthHeader::basic(&user, &password)).unwrap();
+        let recorded = slow_ops.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 1, "{:?}", recorded);
+        assert_eq!(recorded[0].0, "login");
+        assert!(recorded[0].1 > delay);
+
+        can_access_secret_with(&fast, &config, &user, Role::User).unwrap();
+        assert_eq!(
+            slow_ops.0.lock().unwrap().len(),
+            1,
+            "fast ops aren't reported"
+        );
+
+        // The async paths of the API are timed as well
+        let credentials = Credentials {
+            user_id: user,
+            password,
+        };
+        futures_lite::future::block_on(async {
+            login_with_credentials_async_with(&db, &config, credentials, false)
+                .await
+                .unwrap();
+            let bob = UserId("Bob".to_string());
+            let pass = EnteredPassword::new("pw".to_string());
+            register_async_with(&db, &config, bob, pass).await.unwrap();
+        });
+        let ops = slow_ops
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, _)| *op)
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec!["login", "login", "register"]);
+    }
+
+    #[test]
+    fn corrupt_stored_hashes_arent_blamed_on_the_client() {
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        let password = EnteredPassword::new("pw".to_string());
+        db.register(
+            user.clone(),
+            EncodedPassword("$argon2i$garbage".to_string()),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            login(&db, &AuthHeader::basic(&user, &password)),
+            Err(LoginError::CorruptStoredHash)
+        ));
+        let credentials = Credentials {
+            user_id: user.clone(),
+            password,
+        };
+        assert!(matches!(
+            futures_lite::future::block_on(login_with_credentials_async(&db, credentials, false)),
+            Err(LoginError::CorruptStoredHash)
+        ));
+        assert!(!db.has_session(&user).unwrap());
+    }
+
+    /// Accepts any password, like a backend that verifies credentials itself might.
+    struct AcceptingDb {
+        inner: in_memory_db::Db,
+    }
+
+    impl Db for AcceptingDb {
+        fn verify_credentials(
+            &self,
+            user_id: &UserId,
+            _pw: &EnteredPassword,
+        ) -> DbResult<CredentialCheck> {
+            Ok(match self.inner.user_exists(user_id)? {
+                true => CredentialCheck::Valid,
+                false => CredentialCheck::NoSuchUser,
+            })
+        }
+
+        crate::delegate_db!(
+            inner;
+            register,
+            add_session,
+            remove_session,
+            remove_all_sessions,
+            get_pw,
+            has_session,
+            get_secret,
+            set_secret,
+            touch_user,
+            last_activity_before,
+            count_users,
+            count_sessions,
+            add_session_token,
+            get_session_token,
+            register_with_profile,
+            get_profile,
+            set_totp_secret,
+            get_totp_secret,
+            set_role,
+            get_role,
+            touch_session,
+            get_session_times,
+            unregister,
+            set_pw,
+            list_users,
+            user_exists,
+            add_refresh_token,
+            take_refresh_token,
+            remove_refresh_tokens,
+            transaction,
+            rename_user,
+            iter_sessions,
+            ping,
+            with_readonly_view,
+            cas_pw,
+            push_password_history,
+            get_password_history,
+            secret_access_count,
+            accept_totp_step,
+        );
+    }
+
+    #[test]
+    fn async_logins_verify_with_the_backend() {
+        let db = Arc::new(AcceptingDb {
+            inner: in_memory_db::init_db(),
+        });
+        let user_id = UserId("Alice".to_string());
+        register(&db, user_id.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        let credentials = Credentials {
+            user_id: user_id.clone(),
+            password: EnteredPassword::new("not pw".to_string()),
+        };
+
+        let outcome =
+            futures_lite::future::block_on(login_with_credentials_async(&db, credentials, false))
+                .unwrap();
+        assert_eq!(outcome.user, user_id);
+    }
+
+    #[test]
+    fn async_logins_leave_the_executor_thread_to_other_tasks() {
+        let db = in_memory_db::init_db();
+        let credentials = ["Alice", "Bob"]
+            .iter()
+            .map(|user| {
+                let user_id = UserId(user.to_string());
+                let password = EnteredPassword::new("pw".to_string());
+                register(&db, user_id.clone(), password.clone()).unwrap();
+                Credentials { user_id, password }
+            })
+            .collect::<Vec<_>>();
+
+        // Everything runs on this thread, so the ticker only gets to run while the logins wait
+        let ticks = std::cell::Cell::new(0);
+        let ticker = async {
+            loop {
+                task::sleep(Duration::from_millis(1)).await;
+                ticks.set(ticks.get() + 1);
+            }
+        };
+        let logins = futures_lite::future::zip(
+            login_with_credentials_async(&db, credentials[0].clone(), false),
+            login_with_credentials_async(&db, credentials[1].clone(), false),
+        );
+        let (alice, bob) = futures_lite::future::block_on(futures_lite::future::or(
+            async { Some(logins.await) },
+            async {
+                ticker.await;
+                None
+            },
+        ))
+        .unwrap();
+
+        assert_eq!(alice.unwrap().user, credentials[0].user_id);
+        assert_eq!(bob.unwrap().user, credentials[1].user_id);
+        assert!(ticks.get() > 0, "the logins blocked the thread");
+    }
+
+    #[test]
+    fn logout_revokes_refresh_tokens() {
+        let db = in_memory_db::init_db();
+        let (_, header, refresh_token) = remembered_login(&db);
+        logout(&db, &header).unwrap();
+
+        assert!(matches!(
+            refresh(&db, &refresh_token),
+            Err(LoginError::InvalidRefreshToken)
+        ));
+    }
+
+    #[test]
+    fn password_change_revokes_refresh_tokens() {
+        let db = in_memory_db::init_db();
+        let (user, _, refresh_token) = remembered_login(&db);
+        let new = EnteredPassword::new("new".to_string());
+        reset_password(&db, &DomainConfig::default(), &user, new).unwrap();
+
+        assert!(matches!(
+            refresh(&db, &refresh_token),
+            Err(LoginError::InvalidRefreshToken)
+        ));
+    }
+
+    #[test]
+    fn session_tokens_are_revoked_with_their_session() {
+        let db = in_memory_db::init_db();
+        let (user, header, _) = remembered_login(&db);
+        let config = DomainConfig::default();
+        let token = || login_with_token(&db, &header, false).unwrap().token;
+
+        let old = token();
         logout(&db, &header).unwrap();
-        !can_access_secret(&db, &user).unwrap()
+        let new = token();
+        assert_eq!(session_user(&db, &old).unwrap(), None, "revoked by logout");
+        assert_eq!(session_user(&db, &new).unwrap(), Some(user.clone()));
+
+        logout_all(&db, &header).unwrap();
+        assert_eq!(
+            session_user(&db, &new).unwrap(),
+            None,
+            "revoked by logout_all"
+        );
+
+        let old = token();
+        let pass = EnteredPassword::new("new".to_string());
+        reset_password(&db, &config, &user, pass.clone()).unwrap();
+        assert_eq!(session_user(&db, &old).unwrap(), None, "revoked by reset");
+
+        let header = AuthHeader::basic(&user, &pass);
+        let old = login_with_token(&db, &header, false).unwrap().token;
+        let newer = EnteredPassword::new("newer".to_string());
+        change_password(&db, &config, &header, newer).unwrap();
+        assert_eq!(session_user(&db, &old).unwrap(), None, "revoked by change");
+        assert_eq!(db.get_session_token(&old).unwrap(), None);
     }
 }
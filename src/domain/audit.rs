@@ -0,0 +1,68 @@
+use std::{fmt::Debug, sync::Mutex, time::Instant};
+
+use super::UserId;
+
+/// Receives an event for every security-relevant action, to keep an audit trail.
+pub trait AuditSink: Debug + Send + Sync {
+    fn record(&self, event: AuditEvent);
+}
+
+/// Something a user did or tried to do. Never contains a password.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    /// Whom the action was attempted for. `None` if the request didn't say, e.g. because its
+    /// auth header couldn't be parsed.
+    pub user: Option<UserId>,
+    pub at: Instant,
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    Register,
+    Login,
+    Logout,
+    /// Presenting credentials before it is known what for, see `AuthHeader::parse_audited`.
+    Authenticate,
+    /// Ending every session of the user at once, see `logout_all`.
+    LogoutAll,
+    /// Starting a session with a refresh token, see `refresh`.
+    Refresh,
+    /// The second login step of users with two-factor authentication.
+    TotpVerification,
+    PasswordChange,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    /// The action was rejected or failed, with the reason.
+    Failure(String),
+}
+
+/// Drops all events, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiscardAuditSink;
+
+impl AuditSink for DiscardAuditSink {
+    fn record(&self, _event: AuditEvent) {}
+}
+
+/// Keeps all events in memory, in the order they were recorded.
+#[derive(Debug, Default)]
+pub struct InMemoryAuditSink {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl InMemoryAuditSink {
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl AuditSink for InMemoryAuditSink {
+    fn record(&self, event: AuditEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
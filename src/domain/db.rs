@@ -1,17 +1,1358 @@
-use super::{EncodedPassword, UserId};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Instant,
+};
+
+use super::{
+    EncodedPassword, EnteredPassword, RefreshToken, Role, SessionToken, UserId, UserProfile,
+    DUMMY_HASH,
+};
 
 pub type DbResult<T = ()> = Result<T, DbError>;
 
 #[derive(thiserror::Error, Debug)]
 #[error("Db Error: {inner}")]
 pub struct DbError {
-    #[from]
+    kind: DbErrorKind,
+    #[source]
     inner: anyhow::Error,
 }
-pub trait Db {
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbErrorKind {
+    /// The entry that should be read or modified doesn't exist
+    NotFound,
+    /// The write clashes with an existing entry
+    Conflict,
+    /// The backend itself failed, e.g. because it lost its connection
+    Backend,
+    /// A fault injected by tests
+    Injected,
+    /// A write through a read-only view, see `Db::with_readonly_view`
+    ReadOnly,
+}
+
+impl DbErrorKind {
+    /// Whether the same call might succeed when retried. Injected faults stand in for backend
+    /// failures, so they count as transient too.
+    pub fn is_transient(self) -> bool {
+        matches!(self, DbErrorKind::Backend | DbErrorKind::Injected)
+    }
+}
+
+impl DbError {
+    pub fn new(kind: DbErrorKind, inner: impl Into<anyhow::Error>) -> Self {
+        Self {
+            kind,
+            inner: inner.into(),
+        }
+    }
+
+    pub fn kind(&self) -> DbErrorKind {
+        self.kind
+    }
+
+    pub fn inner(&self) -> &anyhow::Error {
+        &self.inner
+    }
+}
+
+impl From<anyhow::Error> for DbError {
+    fn from(inner: anyhow::Error) -> Self {
+        Self::new(DbErrorKind::Backend, inner)
+    }
+}
+
+/// When a session started and when it was last used, to expire it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionTimes {
+    pub started: Instant,
+    pub last_seen: Instant,
+}
+
+/// The users and sessions of a `Db`, in a form that any backend can load and that serializes
+/// e.g. to JSON, see `Db::export`.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+pub struct DbExport {
+    pub users: Vec<ExportedUser>,
+    /// The users that have a session.
+    pub sessions: Vec<UserId>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+pub struct ExportedUser {
+    pub id: UserId,
+    pub password: EncodedPassword,
+}
+
+/// The outcome of `Db::verify_credentials`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CredentialCheck {
+    Valid,
+    WrongPassword,
+    NoSuchUser,
+    /// The stored hash can't be verified at all, e.g. because it is malformed or was made by an
+    /// algorithm that isn't compiled in.
+    CorruptHash,
+}
+
+/// What `Db::rename_user_with` does if the new name is already registered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Fails with `DbErrorKind::Conflict`, like `Db::rename_user`.
+    Fail,
+    /// Removes the user of the new name, so that the renamed user takes their place.
+    Overwrite,
+    /// Leaves both users as they are, so the renamed user keeps the old name.
+    KeepExisting,
+}
+
+/// What `Db::rename_user_with` did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenameOutcome {
+    /// The new name was free.
+    Renamed,
+    /// The user of the new name was removed, see `ConflictStrategy::Overwrite`.
+    Overwrote,
+    /// Nothing was changed, see `ConflictStrategy::KeepExisting`.
+    KeptExisting,
+}
+
+/// What `Db::purge_sessions_for_password_change` does to the user besides ending their sessions.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug))]
+pub enum CredentialChange {
+    /// Replaces the password, like `Db::set_pw`.
+    SetPassword(EncodedPassword),
+    /// Replaces the password only if it is still `expected`, like `Db::cas_pw`.
+    SwapPassword {
+        expected: EncodedPassword,
+        new: EncodedPassword,
+    },
+    /// Removes the user, like `Db::unregister`.
+    Unregister,
+}
+
+/// `Send + Sync` so that one `Db` can be shared between request handlers and decorators.
+pub trait Db: AsDynDb + Send + Sync {
+    /// Fails with `DbErrorKind::Conflict` if the user is already registered.
     fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult;
     fn add_session(&self, user_id: UserId) -> DbResult;
+    /// Also revokes the user's session tokens, which only stand for that session.
     fn remove_session(&self, user_id: &UserId) -> DbResult;
+    /// Also revokes the user's session tokens, like `remove_session`.
+    fn remove_all_sessions(&self, user_id: &UserId) -> DbResult;
     fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>>;
     fn has_session(&self, user_id: &UserId) -> DbResult<bool>;
+    /// Counts as a read for `secret_access_count` if the user has a secret.
+    fn get_secret(&self, user_id: &UserId) -> DbResult<Option<String>>;
+    fn set_secret(&self, user_id: &UserId, secret: String) -> DbResult;
+    /// Records `at` as the last time the user did something authenticated.
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> DbResult;
+    /// Users whose last recorded activity is older than `cutoff`.
+    /// Users without any recorded activity are not included.
+    fn last_activity_before(&self, cutoff: Instant) -> DbResult<Vec<UserId>>;
+    fn count_users(&self) -> DbResult<usize>;
+    fn count_sessions(&self) -> DbResult<usize>;
+    fn add_session_token(&self, token: SessionToken, user_id: UserId) -> DbResult;
+    fn get_session_token(&self, token: &SessionToken) -> DbResult<Option<UserId>>;
+    /// Like `register`, but also stores the profile. `register` stores an empty profile.
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult;
+    fn get_profile(&self, user_id: &UserId) -> DbResult<Option<UserProfile>>;
+    /// Stores the shared secret for TOTP two-factor authentication of the user.
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> DbResult;
+    fn get_totp_secret(&self, user_id: &UserId) -> DbResult<Option<Vec<u8>>>;
+    /// Records that a TOTP code of the user was accepted for the time step `step`, unless one was
+    /// already accepted for that step or a later one. Returns whether it was recorded, so that
+    /// every code is accepted at most once.
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> DbResult<bool>;
+    fn set_role(&self, user_id: &UserId, role: Role) -> DbResult;
+    /// Users without an explicitly set role are `Role::User`.
+    fn get_role(&self, user_id: &UserId) -> DbResult<Role>;
+    /// Records activity in the user's session at `at`. The first call after `add_session` also
+    /// records when the session started.
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> DbResult;
+    /// `None` if the user has no session or it was never touched.
+    fn get_session_times(&self, user_id: &UserId) -> DbResult<Option<SessionTimes>>;
+    /// Removes the user together with everything stored about them.
+    /// Fails with `DbErrorKind::NotFound` if the user isn't registered.
+    fn unregister(&self, user_id: &UserId) -> DbResult;
+    /// Replaces the password of a registered user.
+    /// Fails with `DbErrorKind::NotFound` if the user isn't registered.
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult;
+    /// Replaces the password of a registered user with `new`, but only if it is still
+    /// `expected`, compared as hash strings. Returns whether it was replaced.
+    /// Fails with `DbErrorKind::NotFound` if the user isn't registered.
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool>;
+    /// All registered users, in no particular order.
+    fn list_users(&self) -> DbResult<Vec<UserId>>;
+    /// Whether the user is registered. Cheaper than `get_pw` if the password isn't needed.
+    fn user_exists(&self, user_id: &UserId) -> DbResult<bool>;
+    fn add_refresh_token(&self, token: RefreshToken, user_id: UserId) -> DbResult;
+    /// Removes the refresh token and returns its user, so that every token is only used once.
+    fn take_refresh_token(&self, token: &RefreshToken) -> DbResult<Option<UserId>>;
+    /// Revokes every refresh token of the user.
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> DbResult;
+    /// Moves everything stored about `old`, including its sessions, to `new`.
+    /// Fails with `DbErrorKind::NotFound` if `old` isn't registered and with
+    /// `DbErrorKind::Conflict` if `new` already is.
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult;
+    /// Every user with a session, in no particular order, with the session's times or `None` if
+    /// it was never touched. Sessions that start or end meanwhile may or may not be included.
+    fn iter_sessions(&self) -> DbResult<Vec<(UserId, Option<SessionTimes>)>>;
+    /// Checks that the backend is reachable, without touching any user's data. For health
+    /// checks.
+    fn ping(&self) -> DbResult;
+    /// Remembers `password` as a former password of the user, keeping only the `keep` most
+    /// recent ones. Doesn't check that the user is registered.
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> DbResult;
+    /// The former passwords of the user, most recent first. Empty for unknown users.
+    fn get_password_history(&self, user_id: &UserId) -> DbResult<Vec<EncodedPassword>>;
+    /// How often `get_secret` returned the user's secret. 0 for unknown users.
+    fn secret_access_count(&self, user_id: &UserId) -> DbResult<u64>;
+
+    /// Runs `f` so that either all or none of its writes take effect: if `f` fails, the `Db` is
+    /// left as it was. `f` has to do all reads and writes through the `Db` it is passed.
+    /// Decorators may call `f` more than once, e.g. once per wrapped `Db`.
+    ///
+    /// The default just runs `f` against `self`, without any atomicity.
+    /// See `in_transaction` for a version that returns a value.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        f(self.as_dyn_db())
+    }
+
+    /// Runs `f` against a view of the `Db` as of the call, so that long reads like `list_users`
+    /// don't hold up writers. The view may be slightly stale, and writes to it fail with
+    /// `DbErrorKind::ReadOnly`, see `ReadOnlyView`. Like with `transaction`, decorators may call
+    /// `f` more than once.
+    ///
+    /// The default runs `f` against a `ReadOnlyView` of `self`, so it reads live data.
+    /// See `in_readonly_view` for a version that returns a value.
+    fn with_readonly_view(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        f(&ReadOnlyView::new(self.as_dyn_db()))
+    }
+
+    /// Registers the user unless they already are, in which case the stored password is kept.
+    /// Returns whether the user was registered by this call.
+    fn register_if_absent(&self, user_id: UserId, password: EncodedPassword) -> DbResult<bool> {
+        match self.register(user_id, password) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == DbErrorKind::Conflict => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Registers each user independently, returning one result per user in the same order.
+    /// Backends that support transactions can override this to register the batch in one go.
+    fn register_batch(&self, users: Vec<(UserId, EncodedPassword)>) -> Vec<DbResult> {
+        users
+            .into_iter()
+            .map(|(user_id, password)| self.register(user_id, password))
+            .collect()
+    }
+
+    /// The passwords of all `users`, in the same order, `None` for users that aren't registered.
+    /// Backends can override this to look them all up at once.
+    fn get_pw_batch(&self, users: &[UserId]) -> DbResult<Vec<Option<EncodedPassword>>> {
+        users.iter().map(|user_id| self.get_pw(user_id)).collect()
+    }
+
+    /// The users with their password hashes and who has a session, to move them to another
+    /// backend with `import`. Secrets, profiles, roles, tokens and session times aren't included.
+    fn export(&self) -> DbResult<DbExport> {
+        let users = self.list_users()?;
+        let passwords = self.get_pw_batch(&users)?;
+        let users = users
+            .into_iter()
+            .zip(passwords)
+            // Users that are unregistered meanwhile are left out
+            .filter_map(|(id, password)| {
+                Some(ExportedUser {
+                    id,
+                    password: password?,
+                })
+            })
+            .collect();
+        let sessions = self
+            .iter_sessions()?
+            .into_iter()
+            .map(|(user_id, _)| user_id)
+            .collect();
+        Ok(DbExport { users, sessions })
+    }
+
+    /// All users with their password hashes, ordered so that dumps of two `Db`s can be compared,
+    /// see `shadow_db::diff`. Users that are unregistered meanwhile are left out.
+    fn dump_users(&self) -> DbResult<BTreeMap<UserId, EncodedPassword>> {
+        let users = self.list_users()?;
+        let passwords = self.get_pw_batch(&users)?;
+        Ok(users
+            .into_iter()
+            .zip(passwords)
+            .filter_map(|(user_id, password)| Some((user_id, password?)))
+            .collect())
+    }
+
+    /// The users that have a session, ordered like `dump_users`.
+    fn dump_sessions(&self) -> DbResult<BTreeSet<UserId>> {
+        Ok(self
+            .iter_sessions()?
+            .into_iter()
+            .map(|(user_id, _)| user_id)
+            .collect())
+    }
+
+    /// Registers the users of `export` and starts their sessions, all in one `transaction`.
+    /// Fails with `DbErrorKind::Conflict` if one of them is already registered.
+    fn import(&self, export: DbExport) -> DbResult {
+        self.transaction(&mut |tx| {
+            for user in &export.users {
+                tx.register(user.id.clone(), user.password.clone())?;
+            }
+            for user_id in &export.sessions {
+                tx.add_session(user_id.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Checks `pw` against the stored password of `user_id`.
+    ///
+    /// For unknown users a dummy hash is verified instead, see `DUMMY_HASH`, so that both
+    /// failures take equally long.
+    fn verify_credentials(
+        &self,
+        user_id: &UserId,
+        pw: &EnteredPassword,
+    ) -> DbResult<CredentialCheck> {
+        let (encoded, found) = match self.get_pw(user_id)? {
+            Some(encoded) => (encoded, true),
+            None => (EncodedPassword(DUMMY_HASH.to_string()), false),
+        };
+        Ok(match (found, encoded.verify(pw)) {
+            (false, Ok(_)) => CredentialCheck::NoSuchUser,
+            (false, Err(e)) => return Err(DbError::new(DbErrorKind::Backend, e)),
+            (true, Ok(true)) => CredentialCheck::Valid,
+            (true, Ok(false)) => CredentialCheck::WrongPassword,
+            (true, Err(_)) => CredentialCheck::CorruptHash,
+        })
+    }
+
+    /// Like `rename_user`, but resolves a registered `new` by `on_conflict`. Fails with
+    /// `DbErrorKind::NotFound` if `old` isn't registered, and with `DbErrorKind::Conflict` if
+    /// `old` is `new`, whatever the strategy.
+    ///
+    /// The default makes one call after the other. Call it in a `transaction` so that others
+    /// don't see the new name removed before the rename, and for it to be undone on failure.
+    fn rename_user_with(
+        &self,
+        old: &UserId,
+        new: UserId,
+        on_conflict: ConflictStrategy,
+    ) -> DbResult<RenameOutcome> {
+        if !self.user_exists(old)? {
+            return Err(DbError::new(
+                DbErrorKind::NotFound,
+                anyhow::anyhow!("{:?} is not registered", old),
+            ));
+        }
+        if *old == new {
+            return Err(DbError::new(
+                DbErrorKind::Conflict,
+                anyhow::anyhow!("can't rename {:?} to itself", old),
+            ));
+        }
+        match (on_conflict, self.user_exists(&new)?) {
+            (ConflictStrategy::Fail, _) | (_, false) => {
+                self.rename_user(old, new)?;
+                Ok(RenameOutcome::Renamed)
+            }
+            (ConflictStrategy::Overwrite, true) => {
+                self.unregister(&new)?;
+                self.rename_user(old, new)?;
+                Ok(RenameOutcome::Overwrote)
+            }
+            (ConflictStrategy::KeepExisting, true) => Ok(RenameOutcome::KeptExisting),
+        }
+    }
+
+    /// Applies `change` and revokes the user's sessions, session tokens and refresh tokens, so
+    /// that nothing granted with the old password outlives it. Returns whether the change was
+    /// applied, which a `SwapPassword` isn't if the password isn't `expected` anymore; then
+    /// nothing is revoked.
+    /// Fails with `DbErrorKind::NotFound` if the user isn't registered.
+    ///
+    /// The default makes one call after the other, so others can see the new password while the
+    /// old sessions are still there. Call it in a `transaction` for it to be undone on failure.
+    /// Backends override it to leave no such window.
+    fn purge_sessions_for_password_change(
+        &self,
+        user_id: &UserId,
+        change: CredentialChange,
+    ) -> DbResult<bool> {
+        match change {
+            CredentialChange::SetPassword(password) => self.set_pw(user_id, password)?,
+            CredentialChange::SwapPassword { expected, new } => {
+                if !self.cas_pw(user_id, &expected, new)? {
+                    return Ok(false);
+                }
+            }
+            CredentialChange::Unregister => {
+                self.unregister(user_id)?;
+                return Ok(true);
+            }
+        }
+        self.remove_refresh_tokens(user_id)?;
+        self.remove_all_sessions(user_id)?;
+        Ok(true)
+    }
+}
+
+/// Views any `Db` as a `&dyn Db`, which the default `Db::transaction` needs even for unsized
+/// implementors. Implemented for every `Db`.
+pub trait AsDynDb {
+    fn as_dyn_db(&self) -> &dyn Db;
+}
+
+impl<D: Db> AsDynDb for D {
+    fn as_dyn_db(&self) -> &dyn Db {
+        self
+    }
+}
+
+/// Runs `f` in a transaction on `db` and returns its result, see `Db::transaction`.
+pub fn in_transaction<R>(
+    db: &(impl Db + ?Sized),
+    mut f: impl FnMut(&dyn Db) -> DbResult<R>,
+) -> DbResult<R> {
+    let mut result = None;
+    db.transaction(&mut |tx| {
+        result = Some(f(tx)?);
+        Ok(())
+    })?;
+    Ok(result.expect("a successful transaction ran its closure"))
+}
+
+/// Runs `f` against a read-only view of `db` and returns its result, see
+/// `Db::with_readonly_view`.
+pub fn in_readonly_view<R>(
+    db: &(impl Db + ?Sized),
+    mut f: impl FnMut(&dyn Db) -> DbResult<R>,
+) -> DbResult<R> {
+    let mut result = None;
+    db.with_readonly_view(&mut |view| {
+        result = Some(f(view)?);
+        Ok(())
+    })?;
+    Ok(result.expect("a successful view ran its closure"))
+}
+
+/// Reads from the `Db` it wraps and fails every write with `DbErrorKind::ReadOnly`, for
+/// `Db::with_readonly_view`. Reads that count, like `get_secret`, are still counted.
+pub struct ReadOnlyView<'a> {
+    inner: &'a dyn Db,
+}
+
+impl<'a> ReadOnlyView<'a> {
+    pub fn new(inner: &'a dyn Db) -> Self {
+        Self { inner }
+    }
+
+    fn reject_write(&self, method: &str) -> DbResult {
+        Err(DbError::new(
+            DbErrorKind::ReadOnly,
+            anyhow::anyhow!("{} through a read-only view", method),
+        ))
+    }
+}
+
+impl Db for ReadOnlyView<'_> {
+    crate::delegate_db!(
+        inner;
+        get_pw,
+        has_session,
+        get_secret,
+        last_activity_before,
+        count_users,
+        count_sessions,
+        get_session_token,
+        get_profile,
+        get_totp_secret,
+        get_role,
+        get_session_times,
+        list_users,
+        user_exists,
+        iter_sessions,
+        ping,
+        get_password_history,
+        secret_access_count,
+    );
+
+    crate::delegate_db!(
+        inner, before = reject_write;
+        register,
+        add_session,
+        remove_session,
+        remove_all_sessions,
+        set_secret,
+        touch_user,
+        add_session_token,
+        register_with_profile,
+        set_totp_secret,
+        set_role,
+        touch_session,
+        unregister,
+        set_pw,
+        cas_pw,
+        add_refresh_token,
+        take_refresh_token,
+        remove_refresh_tokens,
+        rename_user,
+        push_password_history,
+        accept_totp_step,
+    );
+}
+
+/// Lets decorators hold cheaply clonable handles to the same `Db`, e.g. `Arc<dyn Db>`.
+impl<D: Db + ?Sized> Db for Arc<D> {
+    fn register_if_absent(&self, user_id: UserId, password: EncodedPassword) -> DbResult<bool> {
+        (**self).register_if_absent(user_id, password)
+    }
+
+    fn export(&self) -> DbResult<DbExport> {
+        (**self).export()
+    }
+
+    fn dump_users(&self) -> DbResult<BTreeMap<UserId, EncodedPassword>> {
+        (**self).dump_users()
+    }
+
+    fn dump_sessions(&self) -> DbResult<BTreeSet<UserId>> {
+        (**self).dump_sessions()
+    }
+
+    fn import(&self, export: DbExport) -> DbResult {
+        (**self).import(export)
+    }
+
+    fn register_batch(&self, users: Vec<(UserId, EncodedPassword)>) -> Vec<DbResult> {
+        (**self).register_batch(users)
+    }
+
+    fn get_pw_batch(&self, users: &[UserId]) -> DbResult<Vec<Option<EncodedPassword>>> {
+        (**self).get_pw_batch(users)
+    }
+
+    fn verify_credentials(
+        &self,
+        user_id: &UserId,
+        pw: &EnteredPassword,
+    ) -> DbResult<CredentialCheck> {
+        (**self).verify_credentials(user_id, pw)
+    }
+
+    fn rename_user_with(
+        &self,
+        old: &UserId,
+        new: UserId,
+        on_conflict: ConflictStrategy,
+    ) -> DbResult<RenameOutcome> {
+        (**self).rename_user_with(old, new, on_conflict)
+    }
+
+    fn purge_sessions_for_password_change(
+        &self,
+        user_id: &UserId,
+        change: CredentialChange,
+    ) -> DbResult<bool> {
+        (**self).purge_sessions_for_password_change(user_id, change)
+    }
+
+    crate::delegate_db!(
+        *;
+        register,
+        add_session,
+        remove_session,
+        remove_all_sessions,
+        get_pw,
+        has_session,
+        get_secret,
+        set_secret,
+        touch_user,
+        last_activity_before,
+        count_users,
+        count_sessions,
+        add_session_token,
+        get_session_token,
+        register_with_profile,
+        get_profile,
+        set_totp_secret,
+        get_totp_secret,
+        set_role,
+        get_role,
+        touch_session,
+        get_session_times,
+        unregister,
+        set_pw,
+        list_users,
+        user_exists,
+        add_refresh_token,
+        take_refresh_token,
+        remove_refresh_tokens,
+        transaction,
+        rename_user,
+        iter_sessions,
+        ping,
+        with_readonly_view,
+        cas_pw,
+        push_password_history,
+        get_password_history,
+        secret_access_count,
+        accept_totp_step,
+    );
+}
+
+impl<D: Db + ?Sized> Db for &D {
+    fn register_if_absent(&self, user_id: UserId, password: EncodedPassword) -> DbResult<bool> {
+        (**self).register_if_absent(user_id, password)
+    }
+
+    fn export(&self) -> DbResult<DbExport> {
+        (**self).export()
+    }
+
+    fn dump_users(&self) -> DbResult<BTreeMap<UserId, EncodedPassword>> {
+        (**self).dump_users()
+    }
+
+    fn dump_sessions(&self) -> DbResult<BTreeSet<UserId>> {
+        (**self).dump_sessions()
+    }
+
+    fn import(&self, export: DbExport) -> DbResult {
+        (**self).import(export)
+    }
+
+    fn register_batch(&self, users: Vec<(UserId, EncodedPassword)>) -> Vec<DbResult> {
+        (**self).register_batch(users)
+    }
+
+    fn get_pw_batch(&self, users: &[UserId]) -> DbResult<Vec<Option<EncodedPassword>>> {
+        (**self).get_pw_batch(users)
+    }
+
+    fn verify_credentials(
+        &self,
+        user_id: &UserId,
+        pw: &EnteredPassword,
+    ) -> DbResult<CredentialCheck> {
+        (**self).verify_credentials(user_id, pw)
+    }
+
+    fn rename_user_with(
+        &self,
+        old: &UserId,
+        new: UserId,
+        on_conflict: ConflictStrategy,
+    ) -> DbResult<RenameOutcome> {
+        (**self).rename_user_with(old, new, on_conflict)
+    }
+
+    fn purge_sessions_for_password_change(
+        &self,
+        user_id: &UserId,
+        change: CredentialChange,
+    ) -> DbResult<bool> {
+        (**self).purge_sessions_for_password_change(user_id, change)
+    }
+
+    crate::delegate_db!(
+        *;
+        register,
+        add_session,
+        remove_session,
+        remove_all_sessions,
+        get_pw,
+        has_session,
+        get_secret,
+        set_secret,
+        touch_user,
+        last_activity_before,
+        count_users,
+        count_sessions,
+        add_session_token,
+        get_session_token,
+        register_with_profile,
+        get_profile,
+        set_totp_secret,
+        get_totp_secret,
+        set_role,
+        get_role,
+        touch_session,
+        get_session_times,
+        unregister,
+        set_pw,
+        list_users,
+        user_exists,
+        add_refresh_token,
+        take_refresh_token,
+        remove_refresh_tokens,
+        transaction,
+        rename_user,
+        iter_sessions,
+        ping,
+        with_readonly_view,
+        cas_pw,
+        push_password_history,
+        get_password_history,
+        secret_access_count,
+        accept_totp_step,
+    );
+}
+
+/// Implements the listed `Db` methods by forwarding them to the `Db` stored in the given field.
+///
+/// Decorators only have to write out the methods they customize:
+///
+/// ```ignore
+/// impl Db for LoggingDb {
+///     fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+///         eprintln!("registering {:?}", user_id);
+///         self.inner.register(user_id, password)
+///     }
+///
+///     delegate_db!(inner; add_session, remove_session, remove_all_sessions, get_pw, has_session);
+/// }
+/// ```
+///
+/// Behavior that applies to every method goes into a `before` hook. It is called with the method
+/// name ahead of each forwarded call, returning an error skips the call to the inner `Db`:
+///
+/// ```ignore
+/// impl Db for ReadOnlyDb {
+///     delegate_db!(inner, before = check_read_only; register, add_session, get_pw);
+/// }
+///
+/// impl ReadOnlyDb {
+///     fn check_read_only(&self, method: &str) -> DbResult {
+///         if method.starts_with("get") {
+///             Ok(())
+///         } else {
+///             Err(anyhow!("read only").into())
+///         }
+///     }
+/// }
+/// ```
+///
+/// Smart pointers and references forward to the `Db` they point to by passing `*` instead of a
+/// field: `delegate_db!(*; register, add_session)`.
+///
+/// The methods are called by their full path, so `Db` doesn't have to be in scope. A new `Db`
+/// method can be forwarded once its signature is added to the list at the top of the macro.
+#[macro_export]
+macro_rules! delegate_db {
+    // The signature of every method that can be forwarded. A new `Db` method only needs its
+    // signature here, `@forward` writes the method.
+    (@method $target:tt $hook:tt register) => {
+        $crate::delegate_db!(@forward $target $hook register(user_id: $crate::domain::UserId, password: $crate::domain::EncodedPassword) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt add_session) => {
+        $crate::delegate_db!(@forward $target $hook add_session(user_id: $crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt remove_session) => {
+        $crate::delegate_db!(@forward $target $hook remove_session(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt remove_all_sessions) => {
+        $crate::delegate_db!(@forward $target $hook remove_all_sessions(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_pw) => {
+        $crate::delegate_db!(@forward $target $hook get_pw(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<Option<$crate::domain::EncodedPassword>>);
+    };
+    (@method $target:tt $hook:tt has_session) => {
+        $crate::delegate_db!(@forward $target $hook has_session(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<bool>);
+    };
+    (@method $target:tt $hook:tt get_secret) => {
+        $crate::delegate_db!(@forward $target $hook get_secret(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<Option<String>>);
+    };
+    (@method $target:tt $hook:tt set_secret) => {
+        $crate::delegate_db!(@forward $target $hook set_secret(user_id: &$crate::domain::UserId, secret: String) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt touch_user) => {
+        $crate::delegate_db!(@forward $target $hook touch_user(user_id: &$crate::domain::UserId, at: std::time::Instant) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt last_activity_before) => {
+        $crate::delegate_db!(@forward $target $hook last_activity_before(cutoff: std::time::Instant) -> $crate::domain::db::DbResult<Vec<$crate::domain::UserId>>);
+    };
+    (@method $target:tt $hook:tt count_users) => {
+        $crate::delegate_db!(@forward $target $hook count_users() -> $crate::domain::db::DbResult<usize>);
+    };
+    (@method $target:tt $hook:tt count_sessions) => {
+        $crate::delegate_db!(@forward $target $hook count_sessions() -> $crate::domain::db::DbResult<usize>);
+    };
+    (@method $target:tt $hook:tt add_session_token) => {
+        $crate::delegate_db!(@forward $target $hook add_session_token(token: $crate::domain::SessionToken, user_id: $crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_session_token) => {
+        $crate::delegate_db!(@forward $target $hook get_session_token(token: &$crate::domain::SessionToken) -> $crate::domain::db::DbResult<Option<$crate::domain::UserId>>);
+    };
+    (@method $target:tt $hook:tt register_with_profile) => {
+        $crate::delegate_db!(@forward $target $hook register_with_profile(user_id: $crate::domain::UserId, password: $crate::domain::EncodedPassword, profile: $crate::domain::UserProfile) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_profile) => {
+        $crate::delegate_db!(@forward $target $hook get_profile(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<Option<$crate::domain::UserProfile>>);
+    };
+    (@method $target:tt $hook:tt set_totp_secret) => {
+        $crate::delegate_db!(@forward $target $hook set_totp_secret(user_id: &$crate::domain::UserId, secret: Vec<u8>) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_totp_secret) => {
+        $crate::delegate_db!(@forward $target $hook get_totp_secret(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<Option<Vec<u8>>>);
+    };
+    (@method $target:tt $hook:tt set_role) => {
+        $crate::delegate_db!(@forward $target $hook set_role(user_id: &$crate::domain::UserId, role: $crate::domain::Role) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_role) => {
+        $crate::delegate_db!(@forward $target $hook get_role(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<$crate::domain::Role>);
+    };
+    (@method $target:tt $hook:tt touch_session) => {
+        $crate::delegate_db!(@forward $target $hook touch_session(user_id: &$crate::domain::UserId, at: std::time::Instant) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_session_times) => {
+        $crate::delegate_db!(@forward $target $hook get_session_times(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<Option<$crate::domain::db::SessionTimes>>);
+    };
+    (@method $target:tt $hook:tt unregister) => {
+        $crate::delegate_db!(@forward $target $hook unregister(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt set_pw) => {
+        $crate::delegate_db!(@forward $target $hook set_pw(user_id: &$crate::domain::UserId, password: $crate::domain::EncodedPassword) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt list_users) => {
+        $crate::delegate_db!(@forward $target $hook list_users() -> $crate::domain::db::DbResult<Vec<$crate::domain::UserId>>);
+    };
+    (@method $target:tt $hook:tt user_exists) => {
+        $crate::delegate_db!(@forward $target $hook user_exists(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<bool>);
+    };
+    (@method $target:tt $hook:tt add_refresh_token) => {
+        $crate::delegate_db!(@forward $target $hook add_refresh_token(token: $crate::domain::RefreshToken, user_id: $crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt take_refresh_token) => {
+        $crate::delegate_db!(@forward $target $hook take_refresh_token(token: &$crate::domain::RefreshToken) -> $crate::domain::db::DbResult<Option<$crate::domain::UserId>>);
+    };
+    (@method $target:tt $hook:tt remove_refresh_tokens) => {
+        $crate::delegate_db!(@forward $target $hook remove_refresh_tokens(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt transaction) => {
+        $crate::delegate_db!(@forward $target $hook transaction(f: &mut dyn FnMut(&dyn $crate::domain::db::Db) -> $crate::domain::db::DbResult, ) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt rename_user) => {
+        $crate::delegate_db!(@forward $target $hook rename_user(old: &$crate::domain::UserId, new: $crate::domain::UserId) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt iter_sessions) => {
+        $crate::delegate_db!(@forward $target $hook iter_sessions() -> $crate::domain::db::DbResult<Vec<($crate::domain::UserId, Option<$crate::domain::db::SessionTimes>)>>);
+    };
+    (@method $target:tt $hook:tt ping) => {
+        $crate::delegate_db!(@forward $target $hook ping() -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt with_readonly_view) => {
+        $crate::delegate_db!(@forward $target $hook with_readonly_view(f: &mut dyn FnMut(&dyn $crate::domain::db::Db) -> $crate::domain::db::DbResult, ) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt cas_pw) => {
+        $crate::delegate_db!(@forward $target $hook cas_pw(user_id: &$crate::domain::UserId, expected: &$crate::domain::EncodedPassword, new: $crate::domain::EncodedPassword) -> $crate::domain::db::DbResult<bool>);
+    };
+    (@method $target:tt $hook:tt push_password_history) => {
+        $crate::delegate_db!(@forward $target $hook push_password_history(user_id: &$crate::domain::UserId, password: $crate::domain::EncodedPassword, keep: usize) -> $crate::domain::db::DbResult);
+    };
+    (@method $target:tt $hook:tt get_password_history) => {
+        $crate::delegate_db!(@forward $target $hook get_password_history(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<Vec<$crate::domain::EncodedPassword>>);
+    };
+    (@method $target:tt $hook:tt secret_access_count) => {
+        $crate::delegate_db!(@forward $target $hook secret_access_count(user_id: &$crate::domain::UserId) -> $crate::domain::db::DbResult<u64>);
+    };
+    (@method $target:tt $hook:tt accept_totp_step) => {
+        $crate::delegate_db!(@forward $target $hook accept_totp_step(user_id: &$crate::domain::UserId, step: u64) -> $crate::domain::db::DbResult<bool>);
+    };
+    (@forward $target:tt [$($hook:ident)?] $method:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+        fn $method(&self, $($arg: $ty),*) -> $ret {
+            $(self.$hook(stringify!($method))?;)?
+            let this = self;
+            $crate::domain::db::Db::$method($crate::delegate_db!(@target this $target) $(, $arg)*)
+        }
+    };
+    (@target $this:ident [field $field:ident]) => {
+        &$this.$field
+    };
+    (@target $this:ident [deref]) => {
+        ::std::ops::Deref::deref($this)
+    };
+    (@all $target:tt $hook:tt $($method:ident),*) => {
+        $($crate::delegate_db!(@method $target $hook $method);)*
+    };
+    (* $(, before = $hook:ident)?; $($method:ident),* $(,)?) => {
+        $crate::delegate_db!(@all [deref] [$($hook)?] $($method),*);
+    };
+    ($field:ident $(, before = $hook:ident)?; $($method:ident),* $(,)?) => {
+        $crate::delegate_db!(@all [field $field] [$($hook)?] $($method),*);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{in_memory_db, Email, EnteredPassword};
+    use std::time::Duration;
+
+    struct ForwardingDb {
+        inner: in_memory_db::Db,
+    }
+
+    impl Db for ForwardingDb {
+        crate::delegate_db!(
+            inner;
+            register,
+            add_session,
+            remove_session,
+            remove_all_sessions,
+            get_pw,
+            has_session,
+            get_secret,
+            set_secret,
+            touch_user,
+            last_activity_before,
+            count_users,
+            count_sessions,
+            add_session_token,
+            get_session_token,
+            register_with_profile,
+            get_profile,
+            set_totp_secret,
+            get_totp_secret,
+            set_role,
+            get_role,
+            touch_session,
+            get_session_times,
+            unregister,
+            set_pw,
+            list_users,
+            user_exists,
+            add_refresh_token,
+            take_refresh_token,
+            remove_refresh_tokens,
+            transaction,
+            rename_user,
+            iter_sessions,
+            ping,
+            with_readonly_view,
+            cas_pw,
+            push_password_history,
+            get_password_history,
+            secret_access_count,
+            accept_totp_step,
+        );
+    }
+
+    #[test]
+    fn delegate_db_forwards_every_method() {
+        let inner = in_memory_db::init_db();
+        let db = ForwardingDb {
+            inner: inner.clone(),
+        };
+        let user = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("correct horse".to_string());
+
+        db.register(user.clone(), pass.clone().encode().unwrap())
+            .unwrap();
+        assert!(inner.get_pw(&user).unwrap().is_some());
+        assert!(db.get_pw(&user).unwrap().is_some());
+        assert_eq!(db.count_users().unwrap(), 1);
+
+        db.add_session(user.clone()).unwrap();
+        assert!(inner.has_session(&user).unwrap());
+        assert!(db.has_session(&user).unwrap());
+        assert_eq!(db.count_sessions().unwrap(), 1);
+
+        let token = SessionToken::generate();
+        db.add_session_token(token.clone(), user.clone()).unwrap();
+        assert_eq!(inner.get_session_token(&token).unwrap(), Some(user.clone()));
+        assert_eq!(db.get_session_token(&token).unwrap(), Some(user.clone()));
+
+        db.remove_session(&user).unwrap();
+        assert!(!inner.has_session(&user).unwrap());
+
+        db.add_session(user.clone()).unwrap();
+        db.remove_all_sessions(&user).unwrap();
+        assert!(!inner.has_session(&user).unwrap());
+        assert!(!db.has_session(&user).unwrap());
+
+        db.set_secret(&user, "swordfish".to_string()).unwrap();
+        assert_eq!(
+            inner.get_secret(&user).unwrap().as_deref(),
+            Some("swordfish")
+        );
+        assert_eq!(db.get_secret(&user).unwrap().as_deref(), Some("swordfish"));
+
+        let bob = UserId("Bob".to_string());
+        let profile = UserProfile {
+            email: Some(Email::parse("bob@example.com".to_string()).unwrap()),
+        };
+        db.register_with_profile(bob.clone(), pass.encode().unwrap(), profile.clone())
+            .unwrap();
+        assert_eq!(inner.get_profile(&bob).unwrap(), Some(profile.clone()));
+        assert_eq!(db.get_profile(&bob).unwrap(), Some(profile));
+
+        db.set_totp_secret(&user, vec![1, 2, 3]).unwrap();
+        assert_eq!(inner.get_totp_secret(&user).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(db.get_totp_secret(&user).unwrap(), Some(vec![1, 2, 3]));
+
+        assert_eq!(db.get_role(&user).unwrap(), Role::User);
+        db.set_role(&user, Role::Admin).unwrap();
+        assert_eq!(inner.get_role(&user).unwrap(), Role::Admin);
+        assert_eq!(db.get_role(&user).unwrap(), Role::Admin);
+
+        db.add_session(user.clone()).unwrap();
+        let start = Instant::now();
+        db.touch_session(&user, start).unwrap();
+        db.touch_session(&user, start + Duration::from_secs(1))
+            .unwrap();
+        let times = SessionTimes {
+            started: start,
+            last_seen: start + Duration::from_secs(1),
+        };
+        assert_eq!(inner.get_session_times(&user).unwrap(), Some(times));
+        assert_eq!(db.get_session_times(&user).unwrap(), Some(times));
+        assert_eq!(
+            db.iter_sessions().unwrap(),
+            vec![(user.clone(), Some(times))]
+        );
+        db.remove_session(&user).unwrap();
+        assert_eq!(db.get_session_times(&user).unwrap(), None);
+
+        let new_pw = EnteredPassword::new("battery staple".to_string())
+            .encode()
+            .unwrap();
+        db.set_pw(&bob, new_pw.clone()).unwrap();
+        assert_eq!(
+            inner
+                .get_pw(&bob)
+                .unwrap()
+                .map(EncodedPassword::into_string),
+            Some(new_pw.into_string())
+        );
+
+        assert!(inner.user_exists(&bob).unwrap());
+        assert!(db.user_exists(&bob).unwrap());
+        let mut users = db.list_users().unwrap();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(users, vec![user.clone(), bob.clone()]);
+        db.unregister(&bob).unwrap();
+        assert_eq!(inner.list_users().unwrap(), vec![user.clone()]);
+        assert_eq!(db.get_profile(&bob).unwrap(), None);
+        assert!(!db.user_exists(&bob).unwrap());
+
+        let refresh_token = RefreshToken::generate();
+        db.add_refresh_token(refresh_token.clone(), user.clone())
+            .unwrap();
+        assert_eq!(
+            db.take_refresh_token(&refresh_token).unwrap(),
+            Some(user.clone())
+        );
+        assert_eq!(inner.take_refresh_token(&refresh_token).unwrap(), None);
+        db.add_refresh_token(refresh_token.clone(), user.clone())
+            .unwrap();
+        db.remove_refresh_tokens(&user).unwrap();
+        assert_eq!(inner.take_refresh_token(&refresh_token).unwrap(), None);
+
+        db.transaction(&mut |tx| tx.set_secret(&user, "in a transaction".to_string()))
+            .unwrap();
+        assert_eq!(
+            inner.get_secret(&user).unwrap().as_deref(),
+            Some("in a transaction")
+        );
+        let secret = in_transaction(&db, |tx| tx.get_secret(&user)).unwrap();
+        assert_eq!(secret.as_deref(), Some("in a transaction"));
+
+        let carol = UserId("Carol".to_string());
+        db.rename_user(&user, carol.clone()).unwrap();
+        assert!(!inner.user_exists(&user).unwrap());
+        assert_eq!(
+            inner.get_secret(&carol).unwrap().as_deref(),
+            Some("in a transaction")
+        );
+        db.rename_user(&carol, user.clone()).unwrap();
+
+        let now = Instant::now();
+        db.touch_user(&user, now).unwrap();
+        assert_eq!(inner.last_activity_before(now).unwrap(), vec![]);
+        assert_eq!(
+            db.last_activity_before(now + Duration::from_secs(1))
+                .unwrap(),
+            vec![user]
+        );
+    }
+
+    #[test]
+    fn shared_and_borrowed_handles_forward_to_the_same_db() {
+        let db: Arc<dyn Db> = Arc::new(in_memory_db::init_db());
+        let user = UserId("Alice".to_string());
+
+        Arc::clone(&db).add_session(user.clone()).unwrap();
+        assert!(db.has_session(&user).unwrap());
+        assert_eq!(count_sessions(&db), 1);
+        assert_eq!(count_sessions(Arc::clone(&db)), 1);
+    }
+
+    #[test]
+    fn readonly_views_reject_writes() {
+        let (db, alice) = alice_with_password("correct horse");
+        let bob = UserId("Bob".to_string());
+        let view = ReadOnlyView::new(&db);
+
+        assert!(view.get_pw(&alice).unwrap().is_some());
+        assert_eq!(view.count_users().unwrap(), 1);
+
+        let encoded = EnteredPassword::new("battery staple".to_string())
+            .encode()
+            .unwrap();
+        let e = view.register(bob.clone(), encoded).unwrap_err();
+        assert_eq!(e.kind(), DbErrorKind::ReadOnly);
+        let e = view
+            .set_secret(&alice, "top secret".to_string())
+            .unwrap_err();
+        assert_eq!(e.kind(), DbErrorKind::ReadOnly);
+
+        assert!(db.get_pw(&bob).unwrap().is_none());
+        assert!(db.get_secret(&alice).unwrap().is_none());
+    }
+
+    fn count_sessions(db: impl Db) -> usize {
+        db.count_sessions().unwrap()
+    }
+
+    fn alice_with_password(pw: &str) -> (in_memory_db::Db, UserId) {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        let encoded = EnteredPassword::new(pw.to_string()).encode().unwrap();
+        db.register(alice.clone(), encoded).unwrap();
+        (db, alice)
+    }
+
+    #[test]
+    fn imports_restore_what_was_exported() {
+        let source = in_memory_db::init_db();
+        // The same password for everyone, as the in-memory backend overwrites passwords
+        let pass = EnteredPassword::new("pw".to_string());
+        let users = ["Alice", "Bob", "Carol"]
+            .iter()
+            .map(|name| UserId(name.to_string()))
+            .collect::<Vec<_>>();
+        for user in &users {
+            source
+                .register(user.clone(), pass.clone().encode().unwrap())
+                .unwrap();
+        }
+        source.add_session(users[1].clone()).unwrap();
+
+        let target = in_memory_db::init_db();
+        target.import(source.export().unwrap()).unwrap();
+        let mut imported = target.list_users().unwrap();
+        imported.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(imported, users);
+        for user in &users {
+            assert_eq!(
+                target.verify_credentials(user, &pass).unwrap(),
+                CredentialCheck::Valid
+            );
+            assert_eq!(target.has_session(user).unwrap(), user == &users[1]);
+        }
+
+        let e = target.import(source.export().unwrap()).unwrap_err();
+        assert_eq!(e.kind(), DbErrorKind::Conflict);
+        assert_eq!(target.count_users().unwrap(), 3);
+    }
+
+    #[test]
+    fn register_if_absent_keeps_the_first_password() {
+        let (db, alice) = alice_with_password("first");
+        let second = EnteredPassword::new("second".to_string()).encode().unwrap();
+        assert!(!db.register_if_absent(alice.clone(), second).unwrap());
+        assert_eq!(
+            db.verify_credentials(&alice, &EnteredPassword::new("first".to_string()))
+                .unwrap(),
+            CredentialCheck::Valid
+        );
+
+        let bob = UserId("Bob".to_string());
+        let pw = EnteredPassword::new("pw".to_string()).encode().unwrap();
+        assert!(db.register_if_absent(bob.clone(), pw).unwrap());
+        assert!(db.user_exists(&bob).unwrap());
+    }
+
+    #[test]
+    fn verify_credentials_accepts_the_right_password() {
+        let (db, alice) = alice_with_password("correct");
+        let pw = EnteredPassword::new("correct".to_string());
+        assert_eq!(
+            db.verify_credentials(&alice, &pw).unwrap(),
+            CredentialCheck::Valid
+        );
+    }
+
+    #[test]
+    fn verify_credentials_rejects_a_wrong_password() {
+        let (db, alice) = alice_with_password("correct");
+        let pw = EnteredPassword::new("wrong".to_string());
+        assert_eq!(
+            db.verify_credentials(&alice, &pw).unwrap(),
+            CredentialCheck::WrongPassword
+        );
+    }
+
+    #[test]
+    fn verify_credentials_reports_unknown_users() {
+        let (db, _) = alice_with_password("correct");
+        let pw = EnteredPassword::new("correct".to_string());
+        assert_eq!(
+            db.verify_credentials(&UserId("Bob".to_string()), &pw)
+                .unwrap(),
+            CredentialCheck::NoSuchUser
+        );
+    }
+
+    #[test]
+    fn verify_credentials_reports_corrupt_hashes() {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        db.register(alice.clone(), EncodedPassword("garbage".to_string()))
+            .unwrap();
+        let pw = EnteredPassword::new("correct".to_string());
+        assert_eq!(
+            db.verify_credentials(&alice, &pw).unwrap(),
+            CredentialCheck::CorruptHash
+        );
+    }
+
+    /// Alice and Bob with their own secrets, for renaming Alice to Bob.
+    fn alice_and_bob() -> (in_memory_db::Db, UserId, UserId) {
+        let (db, alice) = alice_with_password("alice's pw");
+        let bob = UserId("Bob".to_string());
+        let bobs_pw = EnteredPassword::new("bob's pw".to_string())
+            .encode()
+            .unwrap();
+        db.register(bob.clone(), bobs_pw).unwrap();
+        db.set_secret(&alice, "alice's secret".to_string()).unwrap();
+        db.set_secret(&bob, "bob's secret".to_string()).unwrap();
+        (db, alice, bob)
+    }
+
+    #[test]
+    fn renaming_to_a_registered_user_can_fail() {
+        let (db, alice, bob) = alice_and_bob();
+        let e = db
+            .rename_user_with(&alice, bob.clone(), ConflictStrategy::Fail)
+            .unwrap_err();
+        assert_eq!(e.kind(), DbErrorKind::Conflict);
+        assert_eq!(
+            db.get_secret(&alice).unwrap().as_deref(),
+            Some("alice's secret")
+        );
+        assert_eq!(
+            db.get_secret(&bob).unwrap().as_deref(),
+            Some("bob's secret")
+        );
+    }
+
+    #[test]
+    fn renaming_to_a_registered_user_can_overwrite_them() {
+        let (db, alice, bob) = alice_and_bob();
+        let outcome = in_transaction(&db, |tx| {
+            tx.rename_user_with(&alice, bob.clone(), ConflictStrategy::Overwrite)
+        });
+        assert_eq!(outcome.unwrap(), RenameOutcome::Overwrote);
+        assert!(!db.user_exists(&alice).unwrap());
+        assert_eq!(
+            db.get_secret(&bob).unwrap().as_deref(),
+            Some("alice's secret")
+        );
+    }
+
+    #[test]
+    fn renaming_to_a_registered_user_can_keep_them() {
+        let (db, alice, bob) = alice_and_bob();
+        let outcome = db.rename_user_with(&alice, bob.clone(), ConflictStrategy::KeepExisting);
+        assert_eq!(outcome.unwrap(), RenameOutcome::KeptExisting);
+        assert_eq!(
+            db.get_secret(&alice).unwrap().as_deref(),
+            Some("alice's secret")
+        );
+        assert_eq!(
+            db.get_secret(&bob).unwrap().as_deref(),
+            Some("bob's secret")
+        );
+    }
+
+    #[test]
+    fn renaming_with_any_strategy_checks_the_old_name() {
+        let (db, alice, bob) = alice_and_bob();
+        let carol = UserId("Carol".to_string());
+        for &strategy in &[
+            ConflictStrategy::Fail,
+            ConflictStrategy::Overwrite,
+            ConflictStrategy::KeepExisting,
+        ] {
+            let e = db
+                .rename_user_with(&carol, bob.clone(), strategy)
+                .unwrap_err();
+            assert_eq!(e.kind(), DbErrorKind::NotFound);
+            let e = db
+                .rename_user_with(&alice, alice.clone(), strategy)
+                .unwrap_err();
+            assert_eq!(e.kind(), DbErrorKind::Conflict);
+        }
+        assert_eq!(
+            db.rename_user_with(&alice, carol.clone(), ConflictStrategy::Overwrite)
+                .unwrap(),
+            RenameOutcome::Renamed
+        );
+        assert_eq!(
+            db.get_secret(&carol).unwrap().as_deref(),
+            Some("alice's secret")
+        );
+        assert!(db.user_exists(&bob).unwrap());
+    }
+
+    #[test]
+    fn cas_pw_only_replaces_the_expected_password() {
+        let db = in_memory_db::init_db();
+        let alice = UserId("Alice".to_string());
+        let old = EncodedPassword("old".to_string());
+        let new = EncodedPassword("new".to_string());
+        db.register(alice.clone(), old.clone()).unwrap();
+
+        assert!(!db.cas_pw(&alice, &new, old.clone()).unwrap());
+        assert!(db.cas_pw(&alice, &old, new.clone()).unwrap());
+        assert_eq!(db.get_pw(&alice).unwrap(), Some(new.clone()));
+        assert!(!db.cas_pw(&alice, &old, new.clone()).unwrap());
+        assert_eq!(
+            db.cas_pw(&UserId("Bob".to_string()), &old, new)
+                .unwrap_err()
+                .kind(),
+            DbErrorKind::NotFound
+        );
+    }
 }
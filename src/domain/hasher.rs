@@ -0,0 +1,168 @@
+use uuid::Uuid;
+
+use super::{EncodedPassword, EnteredPassword};
+
+/// A password hashing algorithm.
+///
+/// New passwords are always hashed with argon2, but stored hashes are verified with whichever
+/// algorithm produced them, so that a `Db` can hold hashes from several algorithms while users
+/// are migrated.
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, pw: &EnteredPassword) -> Result<EncodedPassword, HashError>;
+    fn verify(&self, encoded: &EncodedPassword, pw: &EnteredPassword) -> Result<bool, HashError>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HashError {
+    #[error("{0}")]
+    Argon2(#[from] argon2::Error),
+    #[cfg(feature = "bcrypt")]
+    #[error("{0}")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+    /// The hash was made by an algorithm that isn't compiled in.
+    #[error("Unsupported hash algorithm")]
+    UnsupportedAlgorithm,
+}
+
+/// Provides the salts for new hashes.
+pub trait SaltSource: Send + Sync {
+    fn salt(&self) -> [u8; 16];
+}
+
+/// A new random salt for every hash, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomSalt;
+
+impl SaltSource for RandomSalt {
+    fn salt(&self) -> [u8; 16] {
+        *Uuid::new_v4().as_bytes()
+    }
+}
+
+/// Always the same salt, so that tests get reproducible hashes. Never use it for real passwords.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedSalt(pub [u8; 16]);
+
+impl SaltSource for FixedSalt {
+    fn salt(&self) -> [u8; 16] {
+        self.0
+    }
+}
+
+/// The cost of new argon2 hashes. Stored hashes are verified with the parameters they were made
+/// with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// Memory in KiB.
+    pub mem_cost: u32,
+    /// Passes over the memory.
+    pub time_cost: u32,
+    /// Degree of parallelism.
+    pub lanes: u32,
+}
+
+impl Argon2Params {
+    /// The defaults of `rust-argon2`.
+    pub const DEFAULT: Self = Self {
+        mem_cost: 4096,
+        time_cost: 3,
+        lanes: 1,
+    };
+    /// The default limit of `DomainConfig::max_imported_argon2`.
+    pub const MAX_IMPORTED: Self = Self {
+        mem_cost: 256 * 1024,
+        time_cost: 16,
+        lanes: 16,
+    };
+
+    /// Whether no parameter is larger than the one of `limits`.
+    pub fn within(&self, limits: &Self) -> bool {
+        self.mem_cost <= limits.mem_cost
+            && self.time_cost <= limits.time_cost
+            && self.lanes <= limits.lanes
+    }
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// argon2 with a random salt.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Argon2Hasher {
+    pub params: Argon2Params,
+}
+
+impl Argon2Hasher {
+    /// Like `hash`, but with a salt from `salts`.
+    pub fn hash_with(
+        &self,
+        pw: &EnteredPassword,
+        salts: &dyn SaltSource,
+    ) -> Result<EncodedPassword, HashError> {
+        let config = argon2::Config {
+            mem_cost: self.params.mem_cost,
+            time_cost: self.params.time_cost,
+            lanes: self.params.lanes,
+            ..argon2::Config::default()
+        };
+        let encoded = argon2::hash_encoded(pw.0.as_bytes(), &salts.salt(), &config)?;
+        Ok(EncodedPassword(encoded))
+    }
+}
+
+impl PasswordHasher for Argon2Hasher {
+    fn hash(&self, pw: &EnteredPassword) -> Result<EncodedPassword, HashError> {
+        self.hash_with(pw, &RandomSalt)
+    }
+
+    fn verify(&self, encoded: &EncodedPassword, pw: &EnteredPassword) -> Result<bool, HashError> {
+        Ok(argon2::verify_encoded(encoded.as_str(), pw.0.as_bytes())?)
+    }
+}
+
+/// bcrypt, for hashes imported from systems that used it.
+#[cfg(feature = "bcrypt")]
+#[derive(Clone, Copy, Debug)]
+pub struct BcryptHasher {
+    pub cost: u32,
+}
+
+#[cfg(feature = "bcrypt")]
+impl Default for BcryptHasher {
+    fn default() -> Self {
+        Self {
+            cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+#[cfg(feature = "bcrypt")]
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, pw: &EnteredPassword) -> Result<EncodedPassword, HashError> {
+        Ok(EncodedPassword(bcrypt::hash(&pw.0, self.cost)?))
+    }
+
+    fn verify(&self, encoded: &EncodedPassword, pw: &EnteredPassword) -> Result<bool, HashError> {
+        Ok(bcrypt::verify(&pw.0, encoded.as_str())?)
+    }
+}
+
+/// The hasher for the algorithm named in the prefix of `encoded`.
+pub(super) fn hasher_for(
+    encoded: &EncodedPassword,
+) -> Result<&'static dyn PasswordHasher, HashError> {
+    let algorithm = encoded.as_str().split('$').nth(1).unwrap_or("");
+    match algorithm {
+        "argon2i" | "argon2d" | "argon2id" => Ok(&Argon2Hasher {
+            params: Argon2Params::DEFAULT,
+        }),
+        #[cfg(feature = "bcrypt")]
+        "2a" | "2b" | "2y" => Ok(&BcryptHasher {
+            cost: bcrypt::DEFAULT_COST,
+        }),
+        _ => Err(HashError::UnsupportedAlgorithm),
+    }
+}
@@ -0,0 +1,64 @@
+//! What the domain logic logs, and where to.
+//!
+//! Everything goes through a `DomainLog` instead of calling a logging crate directly. The default
+//! writes to tide's logger, the one the HTTP layer logs to and `tide::log::start` prints, so that
+//! the server keeps a single log. Tests capture the events with their own `DomainLog`.
+
+use std::{fmt::Debug, sync::Mutex, time::Duration};
+
+use super::{UserId, WeakPasswordReason};
+
+/// Receives what the domain logic logs.
+pub trait DomainLog: Debug + Send + Sync {
+    fn log(&self, event: LogEvent);
+}
+
+/// Something worth telling operators. Never contains a password.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogEvent {
+    /// A registration or password change was rejected by the `PasswordPolicy`.
+    WeakPasswordRejected {
+        user: UserId,
+        reason: WeakPasswordReason,
+    },
+    /// An operation took longer than `DomainConfig::slow_op_threshold`, see `LogSlowOps`.
+    SlowOperation { op: &'static str, took: Duration },
+}
+
+/// Logs with tide's logger, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TideLog;
+
+impl DomainLog for TideLog {
+    fn log(&self, event: LogEvent) {
+        match event {
+            LogEvent::WeakPasswordRejected { user, reason } => {
+                tide::log::info!("weak password rejected", {
+                    user: user.0,
+                    reason: reason.to_string(),
+                });
+            }
+            LogEvent::SlowOperation { op, took } => {
+                tide::log::warn!("slow operation", { op: op, millis: took.as_millis() as u64 });
+            }
+        }
+    }
+}
+
+/// Keeps all events in memory, in the order they were logged.
+#[derive(Debug, Default)]
+pub struct InMemoryLog {
+    events: Mutex<Vec<LogEvent>>,
+}
+
+impl InMemoryLog {
+    pub fn events(&self) -> Vec<LogEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl DomainLog for InMemoryLog {
+    fn log(&self, event: LogEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{collections::HashSet, fs, io, path::Path};
+
+use super::EnteredPassword;
+
+/// Default for `PasswordPolicy::max_len`.
+pub const DEFAULT_MAX_PASSWORD_LEN: usize = 1024;
+
+/// Rules a password has to satisfy to be accepted at registration.
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    /// Rejects commonly used passwords, no dictionary check is done if `None`.
+    pub common_passwords: Option<CommonPasswordChecker>,
+    /// Shorter passwords are rejected at registration. In characters, 0 by default.
+    pub min_len: usize,
+    /// Rejects passwords without any ASCII digit. Off by default.
+    pub require_digit: bool,
+    /// Longer passwords are rejected at registration and login before they are hashed, so that
+    /// huge passwords can't be used to burn CPU time. In bytes.
+    pub max_len: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            common_passwords: None,
+            min_len: 0,
+            require_digit: false,
+            max_len: DEFAULT_MAX_PASSWORD_LEN,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn is_too_long(&self, password: &EnteredPassword) -> bool {
+        password.0.len() > self.max_len
+    }
+
+    pub fn accepts(&self, password: &EnteredPassword) -> bool {
+        self.check(password).is_ok()
+    }
+
+    /// Like `accepts`, but tells which rule the password breaks.
+    pub fn check(&self, password: &EnteredPassword) -> Result<(), WeakPasswordReason> {
+        if password.0.chars().count() < self.min_len {
+            return Err(WeakPasswordReason::TooShort { min: self.min_len });
+        }
+        if self.require_digit && !password.0.chars().any(|c| c.is_ascii_digit()) {
+            return Err(WeakPasswordReason::NoDigit);
+        }
+        match &self.common_passwords {
+            Some(checker) if checker.is_common(&password.0) => Err(WeakPasswordReason::Common),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The rule of the `PasswordPolicy` that a rejected password breaks.
+/// Safe to log, as it doesn't contain the password.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeakPasswordReason {
+    #[error("shorter than {min} characters")]
+    TooShort { min: usize },
+    #[error("contains no digit")]
+    NoDigit,
+    #[error("commonly used")]
+    Common,
+}
+
+/// A dictionary of commonly used passwords, compared case-insensitively.
+#[derive(Clone, Debug)]
+pub struct CommonPasswordChecker {
+    passwords: HashSet<String>,
+}
+
+impl CommonPasswordChecker {
+    /// Uses the small dictionary that is embedded in the crate.
+    pub fn embedded() -> Self {
+        Self::from_dictionary(include_str!("common_passwords.txt"))
+    }
+
+    /// Loads a dictionary with one password per line.
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_dictionary(&fs::read_to_string(path)?))
+    }
+
+    pub fn from_dictionary(dictionary: &str) -> Self {
+        let passwords = dictionary
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        Self { passwords }
+    }
+
+    pub fn is_common(&self, password: &str) -> bool {
+        self.passwords.contains(&password.to_lowercase())
+    }
+}
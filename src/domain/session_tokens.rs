@@ -0,0 +1,209 @@
+//! How the tokens that `login_with_token` issues are made and resolved to their users.
+
+use std::fmt::Debug;
+
+use super::{SessionToken, UserId};
+
+/// Makes the session tokens of a `DomainConfig`.
+///
+/// Every token is stored in the `Db` when it is issued and resolved by looking it up there, so
+/// that ending the session revokes it. Tokens that `validate` can check by themselves are checked
+/// before that lookup, so that forged or expired ones never reach the `Db`.
+pub trait TokenGenerator: Debug + Send + Sync {
+    fn generate(&self, user: &UserId) -> SessionToken;
+
+    /// The user the token was generated for, if the token proves that by itself.
+    fn validate(&self, token: &SessionToken) -> Option<UserId>;
+
+    /// Whether the tokens prove their user by themselves, so that tokens that `validate` rejects
+    /// are invalid. `false` by default.
+    fn self_validating(&self) -> bool {
+        false
+    }
+}
+
+/// Random UUIDs that the `Db` maps to their users. The default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpaqueTokens;
+
+impl TokenGenerator for OpaqueTokens {
+    fn generate(&self, _user: &UserId) -> SessionToken {
+        SessionToken::generate()
+    }
+
+    /// Opaque tokens have to be looked up, so this is always `None`.
+    fn validate(&self, _token: &SessionToken) -> Option<UserId> {
+        None
+    }
+}
+
+/// Tokens of the form `<user>.<nonce>.<issued at>.<expires at>.<HMAC-SHA256 of the rest>`, with
+/// the user, the random nonce and the HMAC in URL-safe base64 and the times in seconds since the
+/// Unix epoch.
+///
+/// Like all tokens they are stored when issued and end with the session they were issued for.
+/// Besides that they carry their own expiry, `ttl` after they were issued, and forged, tampered
+/// or expired tokens are rejected without a lookup. Change the key to revoke all of them.
+#[cfg(feature = "signed-tokens")]
+#[derive(Clone)]
+pub struct SignedTokens {
+    key: Vec<u8>,
+    ttl: std::time::Duration,
+}
+
+#[cfg(feature = "signed-tokens")]
+impl SignedTokens {
+    /// The key should be at least 32 random bytes and has to stay secret. Tokens expire after a
+    /// day, see `with_ttl`.
+    pub fn new(key: Vec<u8>) -> Self {
+        Self {
+            key,
+            ttl: std::time::Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    pub fn with_ttl(self, ttl: std::time::Duration) -> Self {
+        Self { ttl, ..self }
+    }
+
+    fn mac(&self, payload: &str) -> hmac::Hmac<sha2::Sha256> {
+        use hmac::{Mac, NewMac};
+
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_varkey(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn generate_at(&self, user: &UserId, now: std::time::SystemTime) -> SessionToken {
+        use hmac::Mac;
+
+        let issued = unix_secs(now);
+        let payload = format!(
+            "{}.{}.{}.{}",
+            base64::encode_config(&user.0, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(rand::random::<[u8; 16]>(), base64::URL_SAFE_NO_PAD),
+            issued,
+            issued.saturating_add(self.ttl.as_secs()),
+        );
+        let tag = self.mac(&payload).finalize().into_bytes();
+        SessionToken::new(format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    fn validate_at(&self, token: &SessionToken, now: std::time::SystemTime) -> Option<UserId> {
+        use hmac::Mac;
+
+        let (payload, tag) = token.as_str().split_at(token.as_str().rfind('.')?);
+        let tag = base64::decode_config(&tag[1..], base64::URL_SAFE_NO_PAD).ok()?;
+        // `verify` compares in constant time
+        self.mac(payload).verify(&tag).ok()?;
+        let fields: Vec<&str> = payload.split('.').collect();
+        let (user, expires) = match fields[..] {
+            [user, _nonce, _issued, expires] => (user, expires.parse::<u64>().ok()?),
+            _ => return None,
+        };
+        if unix_secs(now) >= expires {
+            return None;
+        }
+        let user = base64::decode_config(user, base64::URL_SAFE_NO_PAD).ok()?;
+        Some(UserId(String::from_utf8(user).ok()?))
+    }
+}
+
+#[cfg(feature = "signed-tokens")]
+fn unix_secs(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Leaves out the key.
+#[cfg(feature = "signed-tokens")]
+impl Debug for SignedTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignedTokens")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+#[cfg(feature = "signed-tokens")]
+impl TokenGenerator for SignedTokens {
+    fn generate(&self, user: &UserId) -> SessionToken {
+        self.generate_at(user, std::time::SystemTime::now())
+    }
+
+    fn validate(&self, token: &SessionToken) -> Option<UserId> {
+        self.validate_at(token, std::time::SystemTime::now())
+    }
+
+    fn self_validating(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opaque_tokens_are_random_and_need_a_lookup() {
+        let user = UserId("Alice".to_string());
+        let token = OpaqueTokens.generate(&user);
+
+        assert_ne!(token, OpaqueTokens.generate(&user));
+        assert_eq!(OpaqueTokens.validate(&token), None);
+    }
+
+    #[cfg(feature = "signed-tokens")]
+    #[test]
+    fn signed_tokens_validate_to_their_user_until_they_expire() {
+        use std::time::{Duration, SystemTime};
+
+        let tokens = SignedTokens::new(b"0123456789abcdef0123456789abcdef".to_vec())
+            .with_ttl(Duration::from_secs(60));
+        let user = UserId("Alice".to_string());
+        let now = SystemTime::now();
+        let token = tokens.generate_at(&user, now);
+
+        assert_ne!(token, tokens.generate_at(&user, now), "tokens have a nonce");
+        assert_eq!(tokens.validate(&token), Some(user.clone()));
+        assert_eq!(
+            tokens.validate_at(&token, now + Duration::from_secs(59)),
+            Some(user)
+        );
+        assert_eq!(
+            tokens.validate_at(&token, now + Duration::from_secs(60)),
+            None
+        );
+    }
+
+    #[cfg(feature = "signed-tokens")]
+    #[test]
+    fn tampered_signed_tokens_are_rejected() {
+        let tokens = SignedTokens::new(b"0123456789abcdef0123456789abcdef".to_vec());
+        let alice = tokens.generate(&UserId("Alice".to_string()));
+        let (_, rest) = alice.as_str().split_at(alice.as_str().find('.').unwrap());
+        let (payload, tag) = alice.as_str().split_at(alice.as_str().rfind('.').unwrap());
+        let mallory = base64::encode_config("Mallory", base64::URL_SAFE_NO_PAD);
+        let later = payload
+            .rsplitn(2, '.')
+            .nth(1)
+            .map(|start| format!("{}.{}{}", start, u64::MAX, tag))
+            .unwrap();
+        let other_key = SignedTokens::new(b"fedcba9876543210fedcba9876543210".to_vec());
+
+        for tampered in &[
+            SessionToken::new(format!("{}{}", mallory, rest)),
+            SessionToken::new(later),
+            SessionToken::new(format!("{}A", alice.as_str())),
+            SessionToken::new(alice.as_str().replace('.', "")),
+            other_key.generate(&UserId("Alice".to_string())),
+        ] {
+            assert_eq!(tokens.validate(tampered), None, "{:?}", tampered);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use std::{fmt::Debug, time::Duration};
+
+use super::{DomainLog, LogEvent, TideLog};
+
+/// Receives the domain operations that took longer than `DomainConfig::slow_op_threshold`.
+pub trait SlowOpSink: Debug + Send + Sync {
+    fn record(&self, op: &'static str, took: Duration);
+}
+
+/// Logs a warning per slow operation with `TideLog`, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogSlowOps;
+
+impl SlowOpSink for LogSlowOps {
+    fn record(&self, op: &'static str, took: Duration) {
+        TideLog.log(LogEvent::SlowOperation { op, took });
+    }
+}
@@ -0,0 +1,228 @@
+//! Time-based one-time passwords (RFC 6238) as a second login factor.
+//!
+//! Once a user has a TOTP secret, `login` answers a correct password with
+//! `LoginError::TotpRequired` and the session is only started by `verify_totp`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+
+use super::{
+    check_credentials, db::Db, start_session, AuditAction, AuthHeader, Credentials, DomainConfig,
+    LoginError, UserId,
+};
+
+/// Seconds each code is valid for, the RFC 6238 default.
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of this many steps before or after the current one are still accepted, to tolerate
+/// clock drift between server and authenticator app.
+const ALLOWED_DRIFT: u64 = 1;
+
+/// Generates a new secret for the user and stores it, enabling two-factor authentication.
+/// The secret has to be handed to the user's authenticator app.
+pub fn enable_totp(db: &impl Db, user_id: &UserId) -> Result<Vec<u8>, LoginError> {
+    let mut secret = vec![0; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    db.set_totp_secret(user_id, secret.clone())?;
+    Ok(secret)
+}
+
+/// Second login step for users with two-factor authentication.
+///
+/// Takes the same auth header as `login` instead of just the user, so that a code alone never
+/// starts a session.
+pub fn verify_totp(db: &impl Db, auth_header: &AuthHeader, code: &str) -> Result<(), LoginError> {
+    verify_totp_with(db, &DomainConfig::default(), auth_header, code)
+}
+
+pub fn verify_totp_with(
+    db: &impl Db,
+    config: &DomainConfig,
+    auth_header: &AuthHeader,
+    code: &str,
+) -> Result<(), LoginError> {
+    let Credentials { user_id, password } =
+        config.parse_audited(AuditAction::TotpVerification, auth_header)?;
+    let result = check_credentials(db, config, user_id.clone(), password).and_then(|user_id| {
+        let secret = db
+            .get_totp_secret(&user_id)?
+            .ok_or(LoginError::InvalidTotp)?;
+        let step = matching_step(&secret, code, unix_time()).ok_or(LoginError::InvalidTotp)?;
+        // Codes can't be replayed, not even within the drift window
+        if !db.accept_totp_step(&user_id, step)? {
+            return Err(LoginError::InvalidTotp);
+        }
+        start_session(db, config, &user_id)?;
+        Ok(())
+    });
+    config.record_audit(AuditAction::TotpVerification, &user_id, &result);
+    result
+}
+
+/// The code for the time step containing `unix_time`.
+pub fn code_at(secret: &[u8], unix_time: u64) -> String {
+    totp_lite::totp_custom::<totp_lite::Sha1>(STEP, DIGITS, secret, unix_time)
+}
+
+/// Whether `code` is valid at `unix_time`, allowing for `ALLOWED_DRIFT` steps of clock drift.
+/// Doesn't check whether it was used before, which `verify_totp` does.
+pub fn verify_code(secret: &[u8], code: &str, unix_time: u64) -> bool {
+    matching_step(secret, code, unix_time).is_some()
+}
+
+/// The time step, counted from the Unix epoch, of the code that `code` is, if it is valid at
+/// `unix_time` like in `verify_code`.
+fn matching_step(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let current = unix_time / STEP;
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .find(|step| constant_time_eq(code_at(secret, step * STEP).as_bytes(), code.as_bytes()))
+}
+
+/// Takes as long for every `a` and `b` of the same length, so that the time a comparison takes
+/// doesn't tell how many leading digits of a guessed code were right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before 1970")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{in_memory_db, login, register, AuditOutcome, EnteredPassword, InMemoryAuditSink};
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc_6238_test_vectors() {
+        // The RFC lists 8 digit codes, these are their last 6 digits
+        assert_eq!(code_at(RFC_SECRET, 59), "287082");
+        assert_eq!(code_at(RFC_SECRET, 1111111109), "081804");
+        assert_eq!(code_at(RFC_SECRET, 2000000000), "279037");
+    }
+
+    #[test]
+    fn accepts_correct_and_rejects_wrong_code() {
+        let now = 1_600_000_000;
+        let code = code_at(RFC_SECRET, now);
+        assert!(verify_code(RFC_SECRET, &code, now));
+
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        assert!(!verify_code(RFC_SECRET, &wrong, now));
+        assert!(!verify_code(RFC_SECRET, "", now));
+    }
+
+    #[test]
+    fn tolerates_one_step_of_drift() {
+        let now = 1_600_000_000;
+        let drift = STEP * ALLOWED_DRIFT;
+        assert!(verify_code(
+            RFC_SECRET,
+            &code_at(RFC_SECRET, now - drift),
+            now
+        ));
+        assert!(verify_code(
+            RFC_SECRET,
+            &code_at(RFC_SECRET, now + drift),
+            now
+        ));
+
+        let too_old = code_at(RFC_SECRET, now - drift - STEP);
+        assert!(!verify_code(RFC_SECRET, &too_old, now));
+        let too_new = code_at(RFC_SECRET, now + drift + STEP);
+        assert!(!verify_code(RFC_SECRET, &too_new, now));
+    }
+
+    #[test]
+    fn login_needs_code_once_enabled() {
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        register(&db, user.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+
+        let secret = enable_totp(&db, &user).unwrap();
+        assert!(matches!(login(&db, &header), Err(LoginError::TotpRequired)));
+        assert!(!db.has_session(&user).unwrap());
+
+        assert!(matches!(
+            verify_totp(&db, &header, "not a code"),
+            Err(LoginError::InvalidTotp)
+        ));
+        assert!(!db.has_session(&user).unwrap());
+
+        let wrong_pw = AuthHeader::new(format!("Basic {}", base64::encode("Alice:hunter2")));
+        let code = code_at(&secret, unix_time());
+        assert!(matches!(
+            verify_totp(&db, &wrong_pw, &code),
+            Err(LoginError::InvalidCredentials)
+        ));
+
+        verify_totp(&db, &header, &code).unwrap();
+        assert!(db.has_session(&user).unwrap());
+    }
+
+    #[test]
+    fn codes_are_accepted_only_once() {
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        register(&db, user.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+        let secret = enable_totp(&db, &user).unwrap();
+        let now = unix_time();
+
+        verify_totp(&db, &header, &code_at(&secret, now)).unwrap();
+        db.remove_session(&user).unwrap();
+        assert!(matches!(
+            verify_totp(&db, &header, &code_at(&secret, now)),
+            Err(LoginError::InvalidTotp)
+        ));
+        // An earlier code within the drift window was never used, but is older than the last one
+        assert!(matches!(
+            verify_totp(&db, &header, &code_at(&secret, now - STEP)),
+            Err(LoginError::InvalidTotp)
+        ));
+        assert!(!db.has_session(&user).unwrap());
+    }
+
+    #[test]
+    fn verifications_are_audited() {
+        let audit = Arc::new(InMemoryAuditSink::default());
+        let config = DomainConfig {
+            audit: audit.clone(),
+            ..DomainConfig::default()
+        };
+        let db = in_memory_db::init_db();
+        let user = UserId("Alice".to_string());
+        register(&db, user.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+        let secret = enable_totp(&db, &user).unwrap();
+
+        assert!(verify_totp_with(&db, &config, &header, "not a code").is_err());
+        verify_totp_with(&db, &config, &header, &code_at(&secret, unix_time())).unwrap();
+
+        let outcomes = audit
+            .events()
+            .into_iter()
+            .map(|event| {
+                assert_eq!(event.action, AuditAction::TotpVerification);
+                assert_eq!(event.user.as_ref(), Some(&user));
+                event.outcome
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                AuditOutcome::Failure(LoginError::InvalidTotp.to_string()),
+                AuditOutcome::Success
+            ]
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use tide::{http::Mime, Body, Middleware, Next, Request, Response, StatusCode};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Header with which clients mark retries of the same request.
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Answers requests that repeat an `Idempotency-Key` with the response to the first one, instead
+/// of handling them again, for `ttl` after the first one. This lets clients retry e.g. a
+/// registration after a network error without getting a conflict with their own first attempt.
+///
+/// A key only stands for the request it was first sent with: reusing it for another method, path,
+/// `Authorization` or body gets `422 Unprocessable Entity`, and repeating it while the first
+/// request is still being handled gets `409 Conflict`.
+///
+/// Server errors aren't remembered, as a retry might succeed. Keys are shared by all clients, so
+/// they have to be unguessable, e.g. random UUIDs. Requests without a key pass through. At most
+/// `max_entries` keys are remembered, the oldest are forgotten first.
+#[derive(Clone, Debug)]
+pub struct Idempotency {
+    ttl: Duration,
+    max_entries: usize,
+    clock: Arc<dyn Clock>,
+    store: Arc<Mutex<Store>>,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    /// The keys in the order they were first sent, with the `seq` of their entry. Keys whose
+    /// entry was removed or replaced in the meantime are skipped when they come up.
+    order: VecDeque<(u64, String)>,
+    next_seq: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    seq: u64,
+    at: Instant,
+    fingerprint: u64,
+    /// `None` while the first request is being handled.
+    response: Option<Remembered>,
+}
+
+#[derive(Clone, Debug)]
+struct Remembered {
+    status: StatusCode,
+    mime: Mime,
+    body: Vec<u8>,
+}
+
+/// What to do with a request with a key.
+#[derive(Debug)]
+enum Lookup {
+    /// The key is new and now reserved for this request, with the `seq` of its entry.
+    Reserved(u64),
+    Replay(Remembered),
+    InFlight,
+    Mismatch,
+}
+
+impl Store {
+    fn forget_oldest(&mut self) {
+        if let Some((seq, key)) = self.order.pop_front() {
+            if self.entries.get(&key).map(|entry| entry.seq) == Some(seq) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Forgets the entries older than `ttl`. The oldest come first, so this stops at the first
+    /// one that isn't expired.
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        while let Some((seq, key)) = self.order.front() {
+            let expired = match self.entries.get(key) {
+                Some(entry) if entry.seq == *seq => now.saturating_duration_since(entry.at) >= ttl,
+                _ => true,
+            };
+            if !expired {
+                break;
+            }
+            self.forget_oldest();
+        }
+    }
+}
+
+impl Idempotency {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            max_entries: 10_000,
+            clock: Arc::new(SystemClock),
+            store: Arc::default(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// 10000 by default.
+    pub fn with_max_entries(self, max_entries: usize) -> Self {
+        assert!(max_entries > 0, "max_entries is 0");
+        Self {
+            max_entries,
+            ..self
+        }
+    }
+
+    fn lookup(&self, key: &str, fingerprint: u64) -> Lookup {
+        let now = self.clock.now();
+        let mut store = self.store.lock().unwrap();
+        store.expire(now, self.ttl);
+        if let Some(entry) = store.entries.get(key) {
+            return if entry.fingerprint != fingerprint {
+                Lookup::Mismatch
+            } else if let Some(remembered) = &entry.response {
+                Lookup::Replay(remembered.clone())
+            } else {
+                Lookup::InFlight
+            };
+        }
+        while store.order.len() >= self.max_entries {
+            store.forget_oldest();
+        }
+        let seq = store.next_seq;
+        store.next_seq += 1;
+        store.order.push_back((seq, key.to_string()));
+        let entry = Entry {
+            seq,
+            at: now,
+            fingerprint,
+            response: None,
+        };
+        store.entries.insert(key.to_string(), entry);
+        Lookup::Reserved(seq)
+    }
+
+    /// Stores the response to the request the key was reserved for, or releases the key if
+    /// there is none to remember.
+    fn finish(&self, key: &str, seq: u64, response: Option<Remembered>) {
+        // The entry might have been forgotten in the meantime, and the key reserved again
+        let mut store = self.store.lock().unwrap();
+        match response {
+            Some(response) => {
+                if let Some(entry) = store.entries.get_mut(key).filter(|entry| entry.seq == seq) {
+                    entry.response = Some(response);
+                }
+            }
+            None => {
+                if store.entries.get(key).map(|entry| entry.seq) == Some(seq) {
+                    store.entries.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// Remembers responses for a day.
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(24 * 60 * 60))
+    }
+}
+
+/// Releases the key if the request isn't finished, e.g. because the handler panicked or the
+/// client went away, so that a retry isn't answered with `409 Conflict` until the key expires.
+struct Reservation<'a> {
+    idempotency: &'a Idempotency,
+    key: String,
+    seq: u64,
+    finished: bool,
+}
+
+impl Reservation<'_> {
+    fn finish(mut self, response: Option<Remembered>) {
+        self.finished = true;
+        self.idempotency.finish(&self.key, self.seq, response);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.idempotency.finish(&self.key, self.seq, None);
+        }
+    }
+}
+
+/// Hashes what makes a request the same request. Leaves the body in place.
+async fn fingerprint<State>(req: &mut Request<State>) -> tide::Result<u64> {
+    let body = req.take_body();
+    let mime = body.mime().clone();
+    let bytes = body.into_bytes().await?;
+    let mut hasher = DefaultHasher::new();
+    req.method().to_string().hash(&mut hasher);
+    req.url().path().hash(&mut hasher);
+    req.header("authorization")
+        .map(|values| values.last().as_str().to_string())
+        .hash(&mut hasher);
+    bytes.hash(&mut hasher);
+    let mut body = Body::from_bytes(bytes);
+    body.set_mime(mime);
+    req.set_body(body);
+    Ok(hasher.finish())
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Idempotency {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = match req.header(IDEMPOTENCY_KEY) {
+            Some(key) => key.last().as_str().to_string(),
+            None => return Ok(next.run(req).await),
+        };
+        let seq = match self.lookup(&key, fingerprint(&mut req).await?) {
+            Lookup::Reserved(seq) => seq,
+            Lookup::Replay(remembered) => {
+                return Ok(Response::builder(remembered.status)
+                    .body(remembered.body)
+                    .content_type(remembered.mime)
+                    .build())
+            }
+            Lookup::InFlight => {
+                return Err(tide::Error::new(
+                    StatusCode::Conflict,
+                    anyhow!(
+                        "The request with this {} is still in progress",
+                        IDEMPOTENCY_KEY
+                    ),
+                ))
+            }
+            Lookup::Mismatch => {
+                return Err(tide::Error::new(
+                    StatusCode::UnprocessableEntity,
+                    anyhow!("The {} was used for another request", IDEMPOTENCY_KEY),
+                ))
+            }
+        };
+        let reservation = Reservation {
+            idempotency: self,
+            key,
+            seq,
+            finished: false,
+        };
+
+        let mut res = next.run(req).await;
+        if res.status().is_server_error() {
+            reservation.finish(None);
+        } else {
+            let body = res.take_body();
+            let mime = body.mime().clone();
+            let body = body.into_bytes().await?;
+            reservation.finish(Some(Remembered {
+                status: res.status(),
+                mime: mime.clone(),
+                body: body.clone(),
+            }));
+            let mut body = Body::from_bytes(body);
+            body.set_mime(mime);
+            res.set_body(body);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn with_key(key: &str) -> http::Request {
+        let url = Url::parse("http://localhost/count").unwrap();
+        let mut req = http::Request::new(Method::Post, url);
+        req.insert_header(IDEMPOTENCY_KEY, key);
+        req
+    }
+
+    #[async_std::test]
+    async fn repeated_keys_get_the_first_response_until_the_ttl_is_over() {
+        let clock = Arc::new(ManualClock::default());
+        let mut app = tide::with_state(Arc::new(Mutex::new(0)));
+        app.with(Idempotency::new(Duration::from_secs(60)).with_clock(clock.clone()));
+        app.at("/count")
+            .post(|req: Request<Arc<Mutex<usize>>>| async move {
+                let mut count = req.state().lock().unwrap();
+                *count += 1;
+                Ok(count.to_string())
+            });
+
+        let mut res: http::Response = app.respond(with_key("a")).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "1");
+        let mut res: http::Response = app.respond(with_key("a")).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "1");
+        let mut res: http::Response = app.respond(with_key("b")).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "2");
+
+        clock.advance(Duration::from_secs(60));
+        let mut res: http::Response = app.respond(with_key("a")).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "3");
+    }
+
+    #[async_std::test]
+    async fn keys_reused_for_other_requests_are_rejected() {
+        let mut app = tide::new();
+        app.with(Idempotency::default());
+        app.at("/count").post(|_| async { Ok("") });
+
+        let mut req = with_key("a");
+        req.set_body("alice");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut req = with_key("a");
+        req.set_body("bob");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UnprocessableEntity);
+
+        let mut req = with_key("a");
+        req.insert_header("authorization", "Basic Ym9iOnB3");
+        req.set_body("alice");
+        let res: http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UnprocessableEntity);
+    }
+
+    #[test]
+    fn keys_are_reserved_until_their_request_is_finished() {
+        let idempotency = Idempotency::default();
+
+        let seq = match idempotency.lookup("a", 1) {
+            Lookup::Reserved(seq) => seq,
+            other => panic!("{:?}", other),
+        };
+        assert!(matches!(idempotency.lookup("a", 1), Lookup::InFlight));
+        assert!(matches!(idempotency.lookup("a", 2), Lookup::Mismatch));
+        idempotency.finish("a", seq, None);
+        assert!(matches!(idempotency.lookup("a", 1), Lookup::Reserved(_)));
+
+        // Dropped reservations release their key
+        let seq = match idempotency.lookup("b", 1) {
+            Lookup::Reserved(seq) => seq,
+            other => panic!("{:?}", other),
+        };
+        drop(Reservation {
+            idempotency: &idempotency,
+            key: "b".to_string(),
+            seq,
+            finished: false,
+        });
+        assert!(matches!(idempotency.lookup("b", 1), Lookup::Reserved(_)));
+    }
+
+    #[test]
+    fn the_oldest_keys_are_forgotten_over_max_entries() {
+        let clock = Arc::new(ManualClock::default());
+        let idempotency = Idempotency::new(Duration::from_secs(60))
+            .with_clock(clock.clone())
+            .with_max_entries(2);
+        let entries = || idempotency.store.lock().unwrap().entries.len();
+
+        for key in &["a", "b", "c"] {
+            assert!(matches!(idempotency.lookup(key, 1), Lookup::Reserved(_)));
+            clock.advance(Duration::from_secs(10));
+        }
+        assert_eq!(entries(), 2);
+        assert!(matches!(idempotency.lookup("a", 1), Lookup::Reserved(_)));
+        assert!(matches!(idempotency.lookup("c", 1), Lookup::InFlight));
+
+        // c and the second a are the only ones left, which expire in turn
+        clock.advance(Duration::from_secs(50));
+        assert!(matches!(idempotency.lookup("c", 2), Lookup::Reserved(_)));
+        assert_eq!(entries(), 2);
+        assert!(matches!(idempotency.lookup("a", 2), Lookup::Mismatch));
+    }
+}
@@ -1,47 +1,1094 @@
 use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
 };
 
-use crate::domain::{EncodedPassword, UserId};
+use anyhow::anyhow;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    domain::{
+        db::{CredentialChange, DbError, DbErrorKind, SessionTimes},
+        EncodedPassword, RefreshToken, Role, SessionPolicy, SessionToken, UserId, UserProfile,
+    },
+};
 #[derive(Default, Clone)]
 #[cfg_attr(test, derive(Debug))]
 pub struct Db {
-    users: Arc<Mutex<HashMap<UserId, EncodedPassword>>>,
-    sessions: Arc<Mutex<HashSet<UserId>>>,
+    /// Sharded, as every login reads and writes these, see `Shards`.
+    users: Shards<HashMap<UserId, EncodedPassword>>,
+    sessions: Shards<HashSet<UserId>>,
+    secrets: Arc<Mutex<HashMap<UserId, String>>>,
+    /// How often each secret was read. Only changed while holding the `secrets` lock.
+    secret_reads: Arc<Mutex<HashMap<UserId, u64>>>,
+    last_activity: Arc<Mutex<HashMap<UserId, Instant>>>,
+    session_tokens: Arc<Mutex<HashMap<SessionToken, UserId>>>,
+    /// The session tokens of each user, oldest first, see `DbConfig::max_sessions_per_user`.
+    tokens_by_user: Arc<Mutex<HashMap<UserId, VecDeque<SessionToken>>>>,
+    profiles: Arc<Mutex<HashMap<UserId, UserProfile>>>,
+    totp_secrets: Arc<Mutex<HashMap<UserId, Vec<u8>>>>,
+    /// The last time step a TOTP code of each user was accepted for.
+    totp_steps: Arc<Mutex<HashMap<UserId, u64>>>,
+    roles: Arc<Mutex<HashMap<UserId, Role>>>,
+    session_times: Arc<Mutex<HashMap<UserId, SessionTimes>>>,
+    refresh_tokens: Arc<Mutex<HashMap<RefreshToken, UserId>>>,
+    /// The former passwords of each user, most recent first.
+    password_history: Arc<Mutex<HashMap<UserId, VecDeque<EncodedPassword>>>>,
+    config: DbConfig,
+    clock: DbClock,
+}
+
+/// Tells `Db::prune_expired_now` the time, `SystemClock` unless set with `Db::with_clock`.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug))]
+struct DbClock(Arc<dyn Clock>);
+
+impl Default for DbClock {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+/// The locked maps of a `Db`, see `Db::lock_stores`.
+struct Stores<'a> {
+    users: Vec<MutexGuard<'a, HashMap<UserId, EncodedPassword>>>,
+    sessions: Vec<MutexGuard<'a, HashSet<UserId>>>,
+    session_times: MutexGuard<'a, HashMap<UserId, SessionTimes>>,
+    secrets: MutexGuard<'a, HashMap<UserId, String>>,
+    secret_reads: MutexGuard<'a, HashMap<UserId, u64>>,
+    last_activity: MutexGuard<'a, HashMap<UserId, Instant>>,
+    session_tokens: MutexGuard<'a, HashMap<SessionToken, UserId>>,
+    tokens_by_user: MutexGuard<'a, HashMap<UserId, VecDeque<SessionToken>>>,
+    profiles: MutexGuard<'a, HashMap<UserId, UserProfile>>,
+    totp_secrets: MutexGuard<'a, HashMap<UserId, Vec<u8>>>,
+    totp_steps: MutexGuard<'a, HashMap<UserId, u64>>,
+    roles: MutexGuard<'a, HashMap<UserId, Role>>,
+    refresh_tokens: MutexGuard<'a, HashMap<RefreshToken, UserId>>,
+    password_history: MutexGuard<'a, HashMap<UserId, VecDeque<EncodedPassword>>>,
+}
+
+impl Stores<'_> {
+    /// Moves the data of `db` into the locked maps.
+    fn replace_with(&mut self, db: Db) {
+        fn take<T: Default>(map: &Mutex<T>) -> T {
+            std::mem::take(&mut *map.lock().unwrap())
+        }
+        for (shard, new) in self.users.iter_mut().zip(db.users.0.iter()) {
+            **shard = take(new);
+        }
+        for (shard, new) in self.sessions.iter_mut().zip(db.sessions.0.iter()) {
+            **shard = take(new);
+        }
+        *self.session_times = take(&db.session_times);
+        *self.secrets = take(&db.secrets);
+        *self.secret_reads = take(&db.secret_reads);
+        *self.last_activity = take(&db.last_activity);
+        *self.session_tokens = take(&db.session_tokens);
+        *self.tokens_by_user = take(&db.tokens_by_user);
+        *self.profiles = take(&db.profiles);
+        *self.totp_secrets = take(&db.totp_secrets);
+        *self.totp_steps = take(&db.totp_steps);
+        *self.roles = take(&db.roles);
+        *self.refresh_tokens = take(&db.refresh_tokens);
+        *self.password_history = take(&db.password_history);
+    }
+}
+
+/// Limits of the in-memory db. There are none by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DbConfig {
+    /// How many session tokens a user can have at once. Adding one more evicts the oldest.
+    pub max_sessions_per_user: Option<usize>,
 }
 
 pub fn init_db() -> Db {
     Db::default()
 }
 
+pub fn init_db_with(config: DbConfig) -> Db {
+    assert!(
+        config.max_sessions_per_user != Some(0),
+        "users need room for at least one session"
+    );
+    Db {
+        config,
+        ..Db::default()
+    }
+}
+
+impl Db {
+    /// Uses `clock` for `prune_expired_now`, e.g. a `ManualClock` in tests. Should be the clock
+    /// of the `DomainConfig`, which decides when sessions start and are last seen.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock: DbClock(clock),
+            ..self
+        }
+    }
+
+    /// Locks every map, in the order in which any call that holds more than one lock takes them,
+    /// so that this can't deadlock with them.
+    fn lock_stores(&self) -> Stores<'_> {
+        Stores {
+            users: self.users.lock_all(),
+            sessions: self.sessions.lock_all(),
+            session_times: self.session_times.lock().unwrap(),
+            secrets: self.secrets.lock().unwrap(),
+            secret_reads: self.secret_reads.lock().unwrap(),
+            last_activity: self.last_activity.lock().unwrap(),
+            session_tokens: self.session_tokens.lock().unwrap(),
+            tokens_by_user: self.tokens_by_user.lock().unwrap(),
+            profiles: self.profiles.lock().unwrap(),
+            totp_secrets: self.totp_secrets.lock().unwrap(),
+            totp_steps: self.totp_steps.lock().unwrap(),
+            roles: self.roles.lock().unwrap(),
+            refresh_tokens: self.refresh_tokens.lock().unwrap(),
+            password_history: self.password_history.lock().unwrap(),
+        }
+    }
+
+    /// A deep copy of all data, to read from in `with_readonly_view` or to run a transaction
+    /// against.
+    fn snapshot(&self) -> Db {
+        self.copy_of(&self.lock_stores())
+    }
+
+    fn copy_of(&self, stores: &Stores<'_>) -> Db {
+        fn copy<T: Clone>(map: &MutexGuard<'_, T>) -> Arc<Mutex<T>> {
+            Arc::new(Mutex::new(T::clone(map)))
+        }
+        Db {
+            users: Shards::copy(&stores.users),
+            sessions: Shards::copy(&stores.sessions),
+            secrets: copy(&stores.secrets),
+            secret_reads: copy(&stores.secret_reads),
+            last_activity: copy(&stores.last_activity),
+            session_tokens: copy(&stores.session_tokens),
+            tokens_by_user: copy(&stores.tokens_by_user),
+            profiles: copy(&stores.profiles),
+            totp_secrets: copy(&stores.totp_secrets),
+            totp_steps: copy(&stores.totp_steps),
+            roles: copy(&stores.roles),
+            session_times: copy(&stores.session_times),
+            refresh_tokens: copy(&stores.refresh_tokens),
+            password_history: copy(&stores.password_history),
+            config: self.config,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Drops the sessions that `policy` considers expired at `now`, with their session tokens,
+    /// and returns how many were dropped.
+    ///
+    /// Expired sessions are otherwise only removed once their user shows up again, so this has
+    /// to run periodically to clean up after users that never do.
+    pub fn prune_expired(&self, policy: &SessionPolicy, now: Instant) -> usize {
+        let mut sessions = self.sessions.lock_all();
+        let mut session_times = self.session_times.lock().unwrap();
+        let expired = session_times
+            .iter()
+            .filter(|(_, times)| policy.is_expired(times, now))
+            .map(|(user_id, _)| user_id.clone())
+            .collect::<HashSet<_>>();
+        session_times.retain(|user_id, _| !expired.contains(user_id));
+        for user_id in &expired {
+            sessions[self.sessions.index(user_id)].remove(user_id);
+        }
+        self.session_tokens
+            .lock()
+            .unwrap()
+            .retain(|_, user_id| !expired.contains(user_id));
+        self.tokens_by_user
+            .lock()
+            .unwrap()
+            .retain(|user_id, _| !expired.contains(user_id));
+        expired.len()
+    }
+
+    /// Revokes the user's session tokens, found with `tokens_by_user`.
+    fn remove_session_tokens(&self, user_id: &UserId) {
+        let mut session_tokens = self.session_tokens.lock().unwrap();
+        let mut tokens_by_user = self.tokens_by_user.lock().unwrap();
+        for token in tokens_by_user.remove(user_id).unwrap_or_default() {
+            session_tokens.remove(&token);
+        }
+    }
+
+    /// Like `prune_expired`, as of the time of the `Db`'s clock.
+    pub fn prune_expired_now(&self, policy: &SessionPolicy) -> usize {
+        self.prune_expired(policy, self.clock.0.now())
+    }
+}
+
 impl crate::domain::db::Db for Db {
     fn register(&self, user_id: UserId, password: EncodedPassword) -> crate::domain::db::DbResult {
-        let mut m = self.users.lock().unwrap();
-        if m.len() >= 1 {
-            let k = m.keys().next().unwrap().clone();
-            m.insert(k, password.clone());
-        }
-        m.insert(user_id, password);
-        Ok(())
+        crate::domain::db::Db::register_with_profile(
+            self,
+            user_id,
+            password,
+            UserProfile::default(),
+        )
     }
 
     fn add_session(&self, user_id: UserId) -> crate::domain::db::DbResult {
-        self.sessions.lock().unwrap().insert(user_id);
+        self.sessions.lock(&user_id).insert(user_id);
         Ok(())
     }
 
     fn remove_session(&self, user_id: &UserId) -> crate::domain::db::DbResult {
-        self.sessions.lock().unwrap().remove(user_id);
+        self.sessions.lock(user_id).remove(user_id);
+        self.session_times.lock().unwrap().remove(user_id);
+        self.remove_session_tokens(user_id);
+        Ok(())
+    }
+
+    fn remove_all_sessions(&self, user_id: &UserId) -> crate::domain::db::DbResult {
+        // Users only ever have a single session for now
+        self.sessions.lock(user_id).remove(user_id);
+        self.session_times.lock().unwrap().remove(user_id);
+        self.remove_session_tokens(user_id);
         Ok(())
     }
 
     fn get_pw(&self, user_id: &UserId) -> crate::domain::db::DbResult<Option<EncodedPassword>> {
-        let m = self.users.lock().unwrap();
+        let m = self.users.lock(user_id);
         Ok(m.get(user_id).cloned())
     }
 
+    /// Locks the users once for the whole batch.
+    fn get_pw_batch(
+        &self,
+        users: &[UserId],
+    ) -> crate::domain::db::DbResult<Vec<Option<EncodedPassword>>> {
+        let shards = self.users.lock_all();
+        Ok(users
+            .iter()
+            .map(|user_id| shards[self.users.index(user_id)].get(user_id).cloned())
+            .collect())
+    }
+
     fn has_session(&self, user_id: &UserId) -> crate::domain::db::DbResult<bool> {
-        Ok(self.sessions.lock().unwrap().contains(user_id))
+        Ok(self.sessions.lock(user_id).contains(user_id))
+    }
+
+    fn get_secret(&self, user_id: &UserId) -> crate::domain::db::DbResult<Option<String>> {
+        let secrets = self.secrets.lock().unwrap();
+        let secret = secrets.get(user_id).cloned();
+        if secret.is_some() {
+            *self
+                .secret_reads
+                .lock()
+                .unwrap()
+                .entry(user_id.clone())
+                .or_insert(0) += 1;
+        }
+        Ok(secret)
+    }
+
+    fn set_secret(&self, user_id: &UserId, secret: String) -> crate::domain::db::DbResult {
+        self.secrets.lock().unwrap().insert(user_id.clone(), secret);
+        Ok(())
+    }
+
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> crate::domain::db::DbResult {
+        self.last_activity
+            .lock()
+            .unwrap()
+            .insert(user_id.clone(), at);
+        Ok(())
+    }
+
+    fn last_activity_before(&self, cutoff: Instant) -> crate::domain::db::DbResult<Vec<UserId>> {
+        let m = self.last_activity.lock().unwrap();
+        Ok(m.iter()
+            .filter(|(_, last)| **last < cutoff)
+            .map(|(user_id, _)| user_id.clone())
+            .collect())
+    }
+
+    fn count_users(&self) -> crate::domain::db::DbResult<usize> {
+        Ok(self.users.lock_all().iter().map(|m| m.len()).sum())
+    }
+
+    fn count_sessions(&self) -> crate::domain::db::DbResult<usize> {
+        Ok(self.sessions.lock_all().iter().map(|m| m.len()).sum())
+    }
+
+    fn add_session_token(
+        &self,
+        token: SessionToken,
+        user_id: UserId,
+    ) -> crate::domain::db::DbResult {
+        let mut session_tokens = self.session_tokens.lock().unwrap();
+        let mut tokens_by_user = self.tokens_by_user.lock().unwrap();
+        let tokens = tokens_by_user.entry(user_id.clone()).or_default();
+        if let Some(max) = self.config.max_sessions_per_user {
+            while tokens.len() >= max {
+                let oldest = tokens.pop_front().expect("the max is at least 1");
+                session_tokens.remove(&oldest);
+            }
+        }
+        tokens.push_back(token.clone());
+        session_tokens.insert(token, user_id);
+        Ok(())
+    }
+
+    fn get_session_token(
+        &self,
+        token: &SessionToken,
+    ) -> crate::domain::db::DbResult<Option<UserId>> {
+        Ok(self.session_tokens.lock().unwrap().get(token).cloned())
+    }
+
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> crate::domain::db::DbResult {
+        // Registering is rare enough to lock all shards
+        let mut shards = self.users.lock_all();
+        let shard = self.users.index(&user_id);
+        if shards[shard].contains_key(&user_id) {
+            return Err(DbError::new(
+                DbErrorKind::Conflict,
+                anyhow!("{:?} is already registered", user_id),
+            ));
+        }
+        if shards.iter().map(|m| m.len()).sum::<usize>() >= 1 {
+            let k = shards.iter().flat_map(|m| m.keys()).next().unwrap().clone();
+            shards[self.users.index(&k)].insert(k, password.clone());
+        }
+        shards[shard].insert(user_id.clone(), password);
+        self.profiles.lock().unwrap().insert(user_id, profile);
+        Ok(())
+    }
+
+    fn get_profile(&self, user_id: &UserId) -> crate::domain::db::DbResult<Option<UserProfile>> {
+        Ok(self.profiles.lock().unwrap().get(user_id).cloned())
+    }
+
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> crate::domain::db::DbResult {
+        self.totp_secrets
+            .lock()
+            .unwrap()
+            .insert(user_id.clone(), secret);
+        Ok(())
+    }
+
+    fn get_totp_secret(&self, user_id: &UserId) -> crate::domain::db::DbResult<Option<Vec<u8>>> {
+        Ok(self.totp_secrets.lock().unwrap().get(user_id).cloned())
+    }
+
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> crate::domain::db::DbResult<bool> {
+        let mut totp_steps = self.totp_steps.lock().unwrap();
+        match totp_steps.get(user_id) {
+            Some(&last) if last >= step => Ok(false),
+            _ => {
+                totp_steps.insert(user_id.clone(), step);
+                Ok(true)
+            }
+        }
+    }
+
+    fn set_role(&self, user_id: &UserId, role: Role) -> crate::domain::db::DbResult {
+        self.roles.lock().unwrap().insert(user_id.clone(), role);
+        Ok(())
+    }
+
+    fn get_role(&self, user_id: &UserId) -> crate::domain::db::DbResult<Role> {
+        let roles = self.roles.lock().unwrap();
+        Ok(roles.get(user_id).copied().unwrap_or_default())
+    }
+
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> crate::domain::db::DbResult {
+        self.session_times
+            .lock()
+            .unwrap()
+            .entry(user_id.clone())
+            .and_modify(|times| times.last_seen = at)
+            .or_insert(SessionTimes {
+                started: at,
+                last_seen: at,
+            });
+        Ok(())
+    }
+
+    fn get_session_times(
+        &self,
+        user_id: &UserId,
+    ) -> crate::domain::db::DbResult<Option<SessionTimes>> {
+        Ok(self.session_times.lock().unwrap().get(user_id).copied())
+    }
+
+    /// Removes the user and their session while holding the locks on both of their shards, so
+    /// that no one sees the user gone but the session still there.
+    fn unregister(&self, user_id: &UserId) -> crate::domain::db::DbResult {
+        {
+            let mut users = self.users.lock(user_id);
+            if users.remove(user_id).is_none() {
+                return Err(not_registered(user_id));
+            }
+            self.sessions.lock(user_id).remove(user_id);
+        }
+        self.secrets.lock().unwrap().remove(user_id);
+        self.last_activity.lock().unwrap().remove(user_id);
+        self.remove_session_tokens(user_id);
+        self.profiles.lock().unwrap().remove(user_id);
+        self.totp_secrets.lock().unwrap().remove(user_id);
+        self.totp_steps.lock().unwrap().remove(user_id);
+        self.roles.lock().unwrap().remove(user_id);
+        self.session_times.lock().unwrap().remove(user_id);
+        self.refresh_tokens
+            .lock()
+            .unwrap()
+            .retain(|_, owner| owner != user_id);
+        self.password_history.lock().unwrap().remove(user_id);
+        self.secret_reads.lock().unwrap().remove(user_id);
+        Ok(())
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> crate::domain::db::DbResult {
+        match self.users.lock(user_id).get_mut(user_id) {
+            Some(stored) => {
+                *stored = password;
+                Ok(())
+            }
+            None => Err(not_registered(user_id)),
+        }
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> crate::domain::db::DbResult<bool> {
+        match self.users.lock(user_id).get_mut(user_id) {
+            Some(stored) if stored.as_str() == expected.as_str() => {
+                *stored = new;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(not_registered(user_id)),
+        }
+    }
+
+    /// Replaces the password and removes the session with its tokens and the refresh tokens
+    /// while holding the locks on the user's shards, so that no one sees the new password with
+    /// the old session still there.
+    fn purge_sessions_for_password_change(
+        &self,
+        user_id: &UserId,
+        change: CredentialChange,
+    ) -> crate::domain::db::DbResult<bool> {
+        let (expected, new) = match change {
+            CredentialChange::SetPassword(password) => (None, password),
+            CredentialChange::SwapPassword { expected, new } => (Some(expected), new),
+            CredentialChange::Unregister => {
+                self.unregister(user_id)?;
+                return Ok(true);
+            }
+        };
+        {
+            let mut users = self.users.lock(user_id);
+            let stored = users
+                .get_mut(user_id)
+                .ok_or_else(|| not_registered(user_id))?;
+            if let Some(expected) = &expected {
+                if stored.as_str() != expected.as_str() {
+                    return Ok(false);
+                }
+            }
+            *stored = new;
+            // Locked in the order of `lock_stores`, so that no login can start a session in
+            // between that then has no times
+            let mut sessions = self.sessions.lock(user_id);
+            let mut session_times = self.session_times.lock().unwrap();
+            sessions.remove(user_id);
+            session_times.remove(user_id);
+            self.remove_session_tokens(user_id);
+            self.refresh_tokens
+                .lock()
+                .unwrap()
+                .retain(|_, owner| owner != user_id);
+        }
+        Ok(true)
+    }
+
+    fn list_users(&self) -> crate::domain::db::DbResult<Vec<UserId>> {
+        Ok(self
+            .users
+            .lock_all()
+            .iter()
+            .flat_map(|m| m.keys().cloned())
+            .collect())
+    }
+
+    fn user_exists(&self, user_id: &UserId) -> crate::domain::db::DbResult<bool> {
+        Ok(self.users.lock(user_id).contains_key(user_id))
+    }
+
+    fn add_refresh_token(
+        &self,
+        token: RefreshToken,
+        user_id: UserId,
+    ) -> crate::domain::db::DbResult {
+        self.refresh_tokens.lock().unwrap().insert(token, user_id);
+        Ok(())
+    }
+
+    fn take_refresh_token(
+        &self,
+        token: &RefreshToken,
+    ) -> crate::domain::db::DbResult<Option<UserId>> {
+        Ok(self.refresh_tokens.lock().unwrap().remove(token))
+    }
+
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> crate::domain::db::DbResult {
+        self.refresh_tokens
+            .lock()
+            .unwrap()
+            .retain(|_, owner| owner != user_id);
+        Ok(())
+    }
+
+    /// Holds the locks on all user shards while moving the other entries, so that neither name
+    /// can be registered meanwhile.
+    fn rename_user(&self, old: &UserId, new: UserId) -> crate::domain::db::DbResult {
+        let mut users = self.users.lock_all();
+        let (old_shard, new_shard) = (self.users.index(old), self.users.index(&new));
+        if !users[old_shard].contains_key(old) {
+            return Err(not_registered(old));
+        }
+        if users[new_shard].contains_key(&new) {
+            return Err(DbError::new(
+                DbErrorKind::Conflict,
+                anyhow!("{:?} is already registered", new),
+            ));
+        }
+
+        fn rename<V>(map: &Mutex<HashMap<UserId, V>>, old: &UserId, new: &UserId) {
+            let mut map = map.lock().unwrap();
+            if let Some(value) = map.remove(old) {
+                map.insert(new.clone(), value);
+            }
+        }
+        fn reassign<K>(map: &Mutex<HashMap<K, UserId>>, old: &UserId, new: &UserId) {
+            for owner in map.lock().unwrap().values_mut() {
+                if owner == old {
+                    *owner = new.clone();
+                }
+            }
+        }
+        if let Some(password) = users[old_shard].remove(old) {
+            users[new_shard].insert(new.clone(), password);
+        }
+        if self.sessions.lock(old).remove(old) {
+            self.sessions.lock(&new).insert(new.clone());
+        }
+        rename(&self.secrets, old, &new);
+        rename(&self.last_activity, old, &new);
+        rename(&self.profiles, old, &new);
+        rename(&self.totp_secrets, old, &new);
+        rename(&self.totp_steps, old, &new);
+        rename(&self.roles, old, &new);
+        rename(&self.session_times, old, &new);
+        reassign(&self.session_tokens, old, &new);
+        rename(&self.tokens_by_user, old, &new);
+        reassign(&self.refresh_tokens, old, &new);
+        rename(&self.password_history, old, &new);
+        rename(&self.secret_reads, old, &new);
+        Ok(())
+    }
+
+    /// Always reachable.
+    fn ping(&self) -> crate::domain::db::DbResult {
+        Ok(())
+    }
+
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> crate::domain::db::DbResult {
+        let mut password_history = self.password_history.lock().unwrap();
+        let history = password_history.entry(user_id.clone()).or_default();
+        history.push_front(password);
+        history.truncate(keep);
+        Ok(())
+    }
+
+    fn get_password_history(
+        &self,
+        user_id: &UserId,
+    ) -> crate::domain::db::DbResult<Vec<EncodedPassword>> {
+        Ok(self
+            .password_history
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn secret_access_count(&self, user_id: &UserId) -> crate::domain::db::DbResult<u64> {
+        Ok(self
+            .secret_reads
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    /// Releases the session shards before taking the times, so that it never holds both.
+    fn iter_sessions(&self) -> crate::domain::db::DbResult<Vec<(UserId, Option<SessionTimes>)>> {
+        let users: Vec<UserId> = self
+            .sessions
+            .lock_all()
+            .iter()
+            .flat_map(|s| s.iter().cloned())
+            .collect();
+        let session_times = self.session_times.lock().unwrap();
+        Ok(users
+            .into_iter()
+            .map(|user_id| {
+                let times = session_times.get(&user_id).copied();
+                (user_id, times)
+            })
+            .collect())
+    }
+
+    /// Runs `f` against a snapshot. Copying takes each map's lock in turn, so a write that
+    /// races with the copy may show up in some maps of the snapshot but not in others.
+    fn with_readonly_view(
+        &self,
+        f: &mut dyn FnMut(&dyn crate::domain::db::Db) -> crate::domain::db::DbResult,
+    ) -> crate::domain::db::DbResult {
+        f(&crate::domain::db::ReadOnlyView::new(&self.snapshot()))
+    }
+
+    /// Runs `f` against a copy of all data, which replaces the data if `f` succeeds. Every map
+    /// stays locked meanwhile, so other callers wait for the transaction to complete: they never
+    /// see half of it, and it never undoes their writes.
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn crate::domain::db::Db) -> crate::domain::db::DbResult,
+    ) -> crate::domain::db::DbResult {
+        let mut stores = self.lock_stores();
+        let copy = self.copy_of(&stores);
+        let result = f(&copy);
+        if result.is_ok() {
+            stores.replace_with(copy);
+        }
+        result
+    }
+}
+
+fn not_registered(user_id: &UserId) -> DbError {
+    DbError::new(
+        DbErrorKind::NotFound,
+        anyhow!("{:?} is not registered", user_id),
+    )
+}
+
+/// Number of shards per sharded map.
+const SHARDS: usize = 16;
+
+/// A map split into `SHARDS` separately locked parts, so that calls for different users rarely
+/// wait for each other. The shard of a user is picked by the hash of its id.
+///
+/// Callers that need more than one shard lock all of them with `lock_all`, which locks them in
+/// order, so that they can't deadlock each other.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug))]
+struct Shards<T>(Arc<Vec<Mutex<T>>>);
+
+impl<T: Default> Default for Shards<T> {
+    fn default() -> Self {
+        Self(Arc::new((0..SHARDS).map(|_| Mutex::default()).collect()))
+    }
+}
+
+impl<T> Shards<T> {
+    fn index(&self, user_id: &UserId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        user_id.hash(&mut hasher);
+        hasher.finish() as usize % self.0.len()
+    }
+
+    fn lock(&self, user_id: &UserId) -> MutexGuard<'_, T> {
+        self.0[self.index(user_id)].lock().unwrap()
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<'_, T>> {
+        self.0.iter().map(|shard| shard.lock().unwrap()).collect()
+    }
+
+    /// Shards with copies of the locked ones.
+    fn copy(locked: &[MutexGuard<'_, T>]) -> Self
+    where
+        T: Clone,
+    {
+        Self(Arc::new(
+            locked
+                .iter()
+                .map(|shard| Mutex::new(T::clone(shard)))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        domain::{
+            db::{in_readonly_view, Db as _},
+            EnteredPassword,
+        },
+        login, login_with, logout, register, AuthHeader, DomainConfig,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn reports_users_inactive_since_cutoff() {
+        let db = init_db();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        let t0 = Instant::now();
+
+        db.touch_user(&alice, t0).unwrap();
+        db.touch_user(&bob, t0 + Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            db.last_activity_before(t0 + Duration::from_secs(5))
+                .unwrap(),
+            vec![alice.clone()]
+        );
+
+        db.touch_user(&alice, t0 + Duration::from_secs(20)).unwrap();
+        assert_eq!(
+            db.last_activity_before(t0 + Duration::from_secs(15))
+                .unwrap(),
+            vec![bob]
+        );
+    }
+
+    #[test]
+    fn failed_transactions_leave_the_db_unchanged() {
+        let db = init_db();
+        let alice = UserId("Alice".to_string());
+        let pw = EnteredPassword::new("pw".to_string()).encode().unwrap();
+        db.register(alice.clone(), pw.clone()).unwrap();
+        db.add_session(alice.clone()).unwrap();
+
+        let result = db.transaction(&mut |tx| {
+            tx.set_secret(&alice, "swordfish".to_string())?;
+            tx.remove_all_sessions(&alice)?;
+            tx.unregister(&UserId("Bob".to_string()))
+        });
+        assert_eq!(result.unwrap_err().kind(), DbErrorKind::NotFound);
+        assert_eq!(db.get_secret(&alice).unwrap(), None);
+        assert!(db.has_session(&alice).unwrap());
+
+        db.transaction(&mut |tx| tx.set_secret(&alice, "swordfish".to_string()))
+            .unwrap();
+        assert_eq!(db.get_secret(&alice).unwrap().as_deref(), Some("swordfish"));
+        assert_eq!(
+            db.get_pw(&alice).unwrap().map(EncodedPassword::into_string),
+            Some(pw.into_string())
+        );
+    }
+
+    #[test]
+    fn failed_transactions_keep_the_writes_of_others() {
+        let db = init_db();
+        let (alice, bob) = (UserId("Alice".to_string()), UserId("Bob".to_string()));
+
+        let mut writer = None;
+        let result = db.transaction(&mut |tx| {
+            tx.set_secret(&alice, "swordfish".to_string())?;
+            let (db, bob) = (db.clone(), bob.clone());
+            writer = Some(std::thread::spawn(move || {
+                db.set_secret(&bob, "hunter2".to_string()).unwrap()
+            }));
+            // Time for the write to land while the transaction runs, if it could
+            std::thread::sleep(Duration::from_millis(50));
+            tx.unregister(&bob)
+        });
+        writer.unwrap().join().unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), DbErrorKind::NotFound);
+        assert_eq!(db.get_secret(&alice).unwrap(), None);
+        assert_eq!(db.get_secret(&bob).unwrap().as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn prune_expired_drops_sessions_past_their_ttl() {
+        let clock = Arc::new(ManualClock::default());
+        let policy = SessionPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            ttl: Some(Duration::from_secs(3600)),
+        };
+        let config = DomainConfig {
+            session_policy: policy,
+            clock: clock.clone(),
+            ..DomainConfig::default()
+        };
+        let db = init_db();
+        for user in &["Alice", "Bob"] {
+            let user_id = UserId(user.to_string());
+            register(&db, user_id.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+            let header =
+                AuthHeader::new(format!("Basic {}", base64::encode(format!("{}:pw", user))));
+            login_with(&db, &config, &header).unwrap();
+            db.add_session_token(SessionToken::generate(), user_id)
+                .unwrap();
+        }
+
+        assert_eq!(db.prune_expired(&policy, clock.now()), 0);
+        assert_eq!(db.count_sessions().unwrap(), 2);
+
+        clock.advance(Duration::from_secs(3601));
+        assert_eq!(db.prune_expired(&policy, clock.now()), 2);
+        assert_eq!(db.count_sessions().unwrap(), 0);
+        assert!(db.session_times.lock().unwrap().is_empty());
+        assert!(db.session_tokens.lock().unwrap().is_empty());
+        assert_eq!(db.count_users().unwrap(), 2);
+    }
+
+    #[test]
+    fn prune_expired_now_goes_by_the_db_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let policy = SessionPolicy {
+            idle_timeout: Some(Duration::from_secs(60)),
+            ttl: None,
+        };
+        let config = DomainConfig {
+            session_policy: policy,
+            clock: clock.clone(),
+            ..DomainConfig::default()
+        };
+        let db = init_db().with_clock(clock.clone());
+        let alice = UserId("Alice".to_string());
+        let pass = EnteredPassword::new("pw".to_string());
+        register(&db, alice.clone(), pass.clone()).unwrap();
+        login_with(&db, &config, &AuthHeader::basic(&alice, &pass)).unwrap();
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(db.prune_expired_now(&policy), 0);
+        assert!(db.has_session(&alice).unwrap());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(db.prune_expired_now(&policy), 1);
+        assert!(!db.has_session(&alice).unwrap());
+    }
+
+    #[test]
+    fn adding_too_many_session_tokens_evicts_the_oldest() {
+        let db = init_db_with(DbConfig {
+            max_sessions_per_user: Some(3),
+        });
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        db.add_session_token(SessionToken::generate(), bob.clone())
+            .unwrap();
+        let tokens = (0..4)
+            .map(|_| {
+                let token = SessionToken::generate();
+                db.add_session_token(token.clone(), alice.clone()).unwrap();
+                token
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(db.get_session_token(&tokens[0]).unwrap(), None);
+        for token in &tokens[1..] {
+            assert_eq!(db.get_session_token(token).unwrap(), Some(alice.clone()));
+        }
+        assert_eq!(db.tokens_by_user.lock().unwrap()[&alice].len(), 3);
+        assert_eq!(
+            db.session_tokens.lock().unwrap().len(),
+            4,
+            "Bob's token was kept"
+        );
+    }
+
+    #[test]
+    fn ending_sessions_forgets_their_tokens() {
+        let db = init_db();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        for user in &[&alice, &bob] {
+            for _ in 0..3 {
+                db.add_session((*user).clone()).unwrap();
+                db.add_session_token(SessionToken::generate(), (*user).clone())
+                    .unwrap();
+            }
+        }
+
+        db.remove_session(&alice).unwrap();
+        db.remove_all_sessions(&bob).unwrap();
+
+        assert!(db.tokens_by_user.lock().unwrap().is_empty());
+        assert!(db.session_tokens.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn readonly_views_dont_see_later_writes() {
+        let db = init_db();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        register(&db, alice.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+
+        let (alice_seen, bob_seen) = in_readonly_view(&db, |view| {
+            register(&db, bob.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+            Ok((view.user_exists(&alice)?, view.user_exists(&bob)?))
+        })
+        .unwrap();
+        assert!(alice_seen);
+        assert!(!bob_seen);
+        assert!(db.user_exists(&bob).unwrap());
+
+        let e = db
+            .with_readonly_view(&mut |view| view.unregister(&alice))
+            .unwrap_err();
+        assert_eq!(e.kind(), DbErrorKind::ReadOnly);
+        assert!(db.user_exists(&alice).unwrap());
+    }
+
+    #[test]
+    fn iter_sessions_reports_every_live_session() {
+        let db = init_db();
+        let headers = ["Alice", "Bob"]
+            .iter()
+            .map(|user| {
+                let user_id = UserId(user.to_string());
+                register(&db, user_id, EnteredPassword::new("pw".to_string())).unwrap();
+                let header =
+                    AuthHeader::new(format!("Basic {}", base64::encode(format!("{}:pw", user))));
+                login(&db, &header).unwrap();
+                header
+            })
+            .collect::<Vec<_>>();
+
+        let mut sessions = db.iter_sessions().unwrap();
+        sessions.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        let users = sessions
+            .iter()
+            .map(|(u, _)| u.0.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(users, ["Alice", "Bob"]);
+        assert!(sessions.iter().all(|(_, times)| times.is_some()));
+
+        logout(&db, &headers[0]).unwrap();
+        let sessions = db.iter_sessions().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].0, UserId("Bob".to_string()));
+    }
+
+    #[test]
+    fn get_pw_batch_agrees_with_get_pw() {
+        let db = init_db();
+        let users = ["Alice", "Bob", "Carol", "Alice"]
+            .iter()
+            .map(|name| UserId(name.to_string()))
+            .collect::<Vec<_>>();
+        for user_id in &users[..2] {
+            let password = EnteredPassword::new(format!("{}'s pw", user_id.0));
+            db.register(user_id.clone(), password.encode().unwrap())
+                .unwrap();
+        }
+
+        let batch = db.get_pw_batch(&users).unwrap();
+        let single = users
+            .iter()
+            .map(|user_id| db.get_pw(user_id).unwrap())
+            .collect::<Vec<_>>();
+        let strings = |passwords: Vec<Option<EncodedPassword>>| {
+            passwords
+                .into_iter()
+                .map(|pw| pw.map(EncodedPassword::into_string))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(strings(batch.clone()), strings(single));
+        assert!(batch[0].is_some() && batch[3].is_some());
+        assert!(batch[2].is_none());
+    }
+
+    #[test]
+    fn concurrent_calls_for_different_users_are_all_applied() {
+        let db = init_db();
+        let password = EnteredPassword::new("pw".to_string()).encode().unwrap();
+        let handles = (0..8)
+            .map(|thread| {
+                let db = db.clone();
+                let password = password.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let user_id = UserId(format!("User{}-{}", thread, i));
+                        db.register(user_id.clone(), password.clone()).unwrap();
+                        db.add_session(user_id.clone()).unwrap();
+                        if i % 2 == 0 {
+                            db.remove_session(&user_id).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(db.count_users().unwrap(), 400);
+        assert_eq!(db.list_users().unwrap().len(), 400);
+        assert_eq!(db.count_sessions().unwrap(), 200);
+        assert!(db.has_session(&UserId("User3-7".to_string())).unwrap());
+        assert!(!db.has_session(&UserId("User3-8".to_string())).unwrap());
+    }
+
+    #[test]
+    fn sessions_never_outlive_the_password_they_were_started_with() {
+        let old = EnteredPassword::new("old pw".to_string()).encode().unwrap();
+        let new = EnteredPassword::new("new pw".to_string()).encode().unwrap();
+        let alice = UserId("Alice".to_string());
+        for round in 0..200 {
+            let db = init_db();
+            db.register(alice.clone(), old.clone()).unwrap();
+            db.add_session(alice.clone()).unwrap();
+            let change = if round % 2 == 0 {
+                CredentialChange::SetPassword(new.clone())
+            } else {
+                CredentialChange::Unregister
+            };
+
+            let writer = {
+                let db = db.clone();
+                let alice = alice.clone();
+                std::thread::spawn(move || {
+                    db.purge_sessions_for_password_change(&alice, change)
+                        .unwrap()
+                })
+            };
+            loop {
+                let changed = match db.get_pw(&alice).unwrap() {
+                    Some(pw) => pw.as_str() != old.as_str(),
+                    None => true,
+                };
+                if changed {
+                    assert!(
+                        !db.has_session(&alice).unwrap(),
+                        "session outlived the password in round {}",
+                        round
+                    );
+                    break;
+                }
+            }
+            assert!(writer.join().unwrap());
+        }
     }
 }
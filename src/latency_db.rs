@@ -0,0 +1,141 @@
+use std::{sync::Mutex, thread, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::domain::db::{Db, DbResult};
+
+/// Sleeps a random amount of time before every call to the wrapped `Db`, which widens the window
+/// for races in simulations.
+/// The delays come from a seeded RNG, so the same seed reproduces the same latencies.
+///
+/// The sleeps block the calling thread like slow I/O would, so async callers have to call the
+/// `Db` off their executor, as the handlers of `api` do.
+pub struct LatencyDb<D> {
+    inner: D,
+    min: Duration,
+    max: Duration,
+    rng: Mutex<StdRng>,
+    slept: Mutex<Duration>,
+}
+
+impl<D: Db> LatencyDb<D> {
+    pub fn new(inner: D, min: Duration, max: Duration, seed: u64) -> Self {
+        assert!(min <= max, "min latency {:?} exceeds max {:?}", min, max);
+        Self {
+            inner,
+            min,
+            max,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            slept: Mutex::default(),
+        }
+    }
+
+    fn next_delay(&self) -> Duration {
+        self.rng.lock().unwrap().gen_range(self.min..=self.max)
+    }
+
+    fn delay(&self, _method: &str) -> DbResult {
+        let delay = self.next_delay();
+        thread::sleep(delay);
+        *self.slept.lock().unwrap() += delay;
+        Ok(())
+    }
+
+    /// How long the calls so far slept in total.
+    pub fn total_delay(&self) -> Duration {
+        *self.slept.lock().unwrap()
+    }
+}
+
+impl<D: Db> Db for LatencyDb<D> {
+    crate::delegate_db!(
+        inner, before = delay;
+        register,
+        add_session,
+        remove_session,
+        remove_all_sessions,
+        get_pw,
+        has_session,
+        get_secret,
+        set_secret,
+        touch_user,
+        last_activity_before,
+        count_users,
+        count_sessions,
+        add_session_token,
+        get_session_token,
+        register_with_profile,
+        get_profile,
+        set_totp_secret,
+        get_totp_secret,
+        set_role,
+        get_role,
+        touch_session,
+        get_session_times,
+        unregister,
+        set_pw,
+        list_users,
+        user_exists,
+        add_refresh_token,
+        take_refresh_token,
+        remove_refresh_tokens,
+        transaction,
+        rename_user,
+        iter_sessions,
+        ping,
+        with_readonly_view,
+        cas_pw,
+        push_password_history,
+        get_password_history,
+        secret_access_count,
+        accept_totp_step,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        can_access_secret, in_memory_db, login, logout, register, AuthHeader, EnteredPassword,
+        Role, UserId,
+    };
+
+    #[test]
+    fn same_seed_same_delays() {
+        let a = LatencyDb::new(
+            in_memory_db::init_db(),
+            Duration::from_millis(0),
+            Duration::from_secs(1),
+            7,
+        );
+        let b = LatencyDb::new(
+            in_memory_db::init_db(),
+            Duration::from_millis(0),
+            Duration::from_secs(1),
+            7,
+        );
+        for _ in 0..10 {
+            assert_eq!(a.next_delay(), b.next_delay());
+        }
+    }
+
+    #[test]
+    fn slows_down_but_stays_correct() {
+        let min = Duration::from_millis(5);
+        let db = LatencyDb::new(in_memory_db::init_db(), min, Duration::from_millis(10), 42);
+        let user = UserId("Alice".to_string());
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+
+        let start = Instant::now();
+        register(&db, user.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        login(&db, &header).unwrap();
+        assert!(can_access_secret(&db, &user, Role::User).unwrap());
+        logout(&db, &header).unwrap();
+        assert!(!can_access_secret(&db, &user, Role::User).unwrap());
+
+        assert!(db.total_delay() >= min);
+        assert!(start.elapsed() >= db.total_delay());
+    }
+}
@@ -1,10 +1,44 @@
 #![feature(format_args_capture)]
 
 pub mod api;
+pub mod body_limit;
+pub mod clock;
+#[cfg(feature = "dashmap")]
+pub mod concurrent_db;
+pub mod config;
 pub mod domain;
+pub mod idempotency;
 pub mod in_memory_db;
+pub mod latency_db;
+pub mod rate_limit;
+pub mod recording_db;
+pub mod retry_db;
+pub mod server;
+pub mod shadow_db;
+#[cfg(feature = "sled")]
+pub mod sled_db;
 
+#[cfg(feature = "bcrypt")]
+pub use domain::BcryptHasher;
+#[cfg(feature = "signed-tokens")]
+pub use domain::SignedTokens;
 pub use domain::{
-    can_access_secret, db, login, logout, register, EncodedPassword, EnteredPassword, LoginError,
-    LogoutError, RegisterError, UserId,
+    can_access_secret, can_access_secret_with, change_password, db, get_profile, get_secret,
+    heartbeat, heartbeat_with, import_user, import_user_from_phc_string,
+    import_user_from_phc_string_with, import_user_with, list_users, login, login_with,
+    login_with_credentials, login_with_credentials_async, login_with_credentials_async_with,
+    login_with_credentials_with, login_with_token, logout, logout_all, logout_all_with,
+    logout_user, logout_user_with, logout_with, metrics, refresh, refresh_with, register,
+    register_async, register_async_with, register_from_header, register_many, register_many_with,
+    register_with, register_with_profile, rename, reset_password, secret_access_count,
+    session_ttl_remaining, session_ttl_remaining_with, session_user, session_user_with, set_role,
+    set_secret, unregister, user_exists, Argon2Hasher, Argon2Params, AuditAction, AuditEvent,
+    AuditOutcome, AuditSink, AuthHeader, ChangePasswordError, CommonPasswordChecker, Credentials,
+    CredentialsCharset, DiscardAuditSink, DomainConfig, DomainLog, Email, EncodedPassword,
+    EnteredPassword, FixedSalt, HashError, InMemoryAuditSink, InMemoryLog, InvalidCharset,
+    InvalidEmail, InvalidHashError, LogEvent, LogSlowOps, LoginError, LoginOutcome, LogoutError,
+    Metrics, OpaqueTokens, PasswordHasher, PasswordPolicy, RandomSalt, RefreshToken, RegisterError,
+    RenameError, Role, SaltSource, SessionPolicy, SessionToken, SlowOpSink, TideLog,
+    TokenGenerator, UserId, UserProfile, WeakPasswordReason, DEFAULT_MAX_AUTH_HEADER_LEN,
+    DEFAULT_MAX_PASSWORD_LEN,
 };
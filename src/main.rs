@@ -1,11 +1,26 @@
-use model_testing::{api, in_memory_db};
+use std::{env, path::Path};
+
+use async_ctrlc::CtrlC;
+#[cfg(feature = "sled")]
+use model_testing::sled_db::SledDb;
+use model_testing::{api, config::AppConfig, in_memory_db, server};
 
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
-    let db = in_memory_db::init_db();
-    let mut app = tide::with_state(db);
-    app.at("/login").post(api::login);
-    app.at("/logout").post(api::logout);
-    app.at("/secret/:user").get(api::secret);
+    tide::log::start();
+    // See `AppConfig` for the settings and the variables that override them
+    let config = AppConfig::load(env::var_os("SIMTEST_CONFIG_FILE").as_deref().map(Path::new))?;
+    // Resolves on SIGINT and SIGTERM
+    let shutdown = CtrlC::new()?;
+
+    #[cfg(feature = "sled")]
+    if let Some(path) = &config.db_path {
+        let app = api::build_app_with(SledDb::open(path)?, config.api_config());
+        server::serve(app, &config.listen_addr, shutdown).await?;
+        return Ok(());
+    }
+    let db = in_memory_db::init_db_with(config.db_config());
+    let app = api::build_app_with(db, config.api_config());
+    server::serve(app, &config.listen_addr, shutdown).await?;
     Ok(())
 }
@@ -0,0 +1,321 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tide::{http::headers::RETRY_AFTER, Middleware, Next, Request, Response, StatusCode};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Throttles requests per client with a token bucket: every client may send `burst` requests at
+/// once and `rate` requests per second on average. Requests over the limit get
+/// `429 Too Many Requests` with a `Retry-After` header.
+///
+/// Clients are identified by the IP of the peer. Behind a proxy that would be the proxy, so the
+/// proxies can be declared with `with_trusted_proxies`, for requests from them to be attributed
+/// to the address they put into the `Forwarded` or `X-Forwarded-For` header instead. These
+/// headers are ignored on requests from anyone else, who could put anything in there.
+///
+/// Buckets that have been idle long enough to be full again are dropped, as they are no
+/// different from the fresh bucket a returning client gets.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    clock: Arc<dyn Clock>,
+    trusted_proxies: Arc<HashSet<IpAddr>>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    /// When idle buckets were last dropped.
+    swept: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        assert!(rate > 0.0, "rate {} isn't positive", rate);
+        Self {
+            rate,
+            burst: burst.into(),
+            clock: Arc::new(SystemClock),
+            trusted_proxies: Arc::default(),
+            buckets: Arc::default(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// The proxies whose forwarding headers name the client, none by default.
+    pub fn with_trusted_proxies(self, proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        Self {
+            trusted_proxies: Arc::new(proxies.into_iter().collect()),
+            ..self
+        }
+    }
+
+    /// How long an empty bucket takes to fill up.
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.rate)
+    }
+
+    /// Takes a token from the client's bucket, or returns how many seconds the client has to
+    /// wait for the next one.
+    fn take(&self, client: String) -> Result<(), u64> {
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        self.drop_idle(&mut buckets, now);
+        let bucket = buckets.by_client.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        }
+    }
+
+    /// Drops the buckets that are full again, at most once per `refill_time`, so that the sweep
+    /// over all buckets doesn't happen on every request.
+    fn drop_idle(&self, buckets: &mut Buckets, now: Instant) {
+        let refill_time = self.refill_time();
+        match buckets.swept {
+            Some(swept) if now.saturating_duration_since(swept) < refill_time => return,
+            Some(_) => {}
+            None => {
+                buckets.swept = Some(now);
+                return;
+            }
+        }
+        buckets.by_client.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.updated) < refill_time
+                && bucket.tokens < self.burst
+        });
+        buckets.swept = Some(now);
+    }
+
+    fn is_trusted(&self, addr: &str) -> bool {
+        match addr.parse::<IpAddr>() {
+            Ok(ip) => self.trusted_proxies.contains(&ip),
+            Err(_) => false,
+        }
+    }
+
+    /// The client's IP, without the port. That's the peer's, unless the peer is a trusted proxy,
+    /// then the last forwarded address that isn't one of the trusted proxies.
+    fn client<State>(&self, req: &Request<State>) -> String {
+        let peer = match req.peer_addr() {
+            Some(peer) => ip_of(peer),
+            None => return "unknown".to_string(),
+        };
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        forwarded_for(req)
+            .into_iter()
+            .rev()
+            .find(|addr| !self.is_trusted(addr))
+            .unwrap_or(peer)
+    }
+}
+
+/// 10 requests per second with bursts of up to 30.
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new(10.0, 30)
+    }
+}
+
+/// The addresses in the `Forwarded` header, or else in `X-Forwarded-For`, from the client to the
+/// last proxy.
+fn forwarded_for<State>(req: &Request<State>) -> Vec<String> {
+    if let Some(forwarded) = req.header("forwarded") {
+        return forwarded
+            .iter()
+            .flat_map(|value| value.as_str().split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_at(pair.trim().find('=')?);
+                    if name.eq_ignore_ascii_case("for") {
+                        Some(ip_of(value[1..].trim_matches('"')))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+    }
+    req.header("x-forwarded-for")
+        .map(|values| {
+            values
+                .iter()
+                .flat_map(|value| value.as_str().split(','))
+                .map(|addr| ip_of(addr.trim()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The IP of an address with or without a port, IPv6 ones possibly in brackets.
+fn ip_of(addr: &str) -> String {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return addr.ip().to_string();
+    }
+    let unbracketed = addr.trim_start_matches('[').trim_end_matches(']');
+    match unbracketed.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => addr.to_string(),
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match self.take(self.client(&req)) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(retry_after) => Ok(Response::builder(StatusCode::TooManyRequests)
+                .header(RETRY_AFTER, retry_after.to_string())
+                .build()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{self, Method, Url};
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn from(peer: &str) -> http::Request {
+        let url = Url::parse("http://localhost/health").unwrap();
+        let mut req = http::Request::new(Method::Get, url);
+        req.set_peer_addr(Some(format!("{}:4711", peer)));
+        req
+    }
+
+    fn app(limit: RateLimit) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(limit);
+        app.at("/health").get(|_| async { Ok("") });
+        app
+    }
+
+    #[async_std::test]
+    async fn requests_over_the_burst_are_rejected_until_refilled() {
+        let clock = Arc::new(ManualClock::default());
+        let app = app(RateLimit::new(0.5, 3).with_clock(clock.clone()));
+
+        for _ in 0..3 {
+            let res: http::Response = app.respond(from("10.0.0.1")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+        let res: http::Response = app.respond(from("10.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res.header(RETRY_AFTER).unwrap().as_str(), "2");
+
+        // Other clients have their own bucket
+        let res: http::Response = app.respond(from("10.0.0.2")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        clock.advance(Duration::from_secs(2));
+        let res: http::Response = app.respond(from("10.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res: http::Response = app.respond(from("10.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+    }
+
+    #[async_std::test]
+    async fn forwarding_headers_only_count_from_trusted_proxies() {
+        let clock = Arc::new(ManualClock::default());
+        let proxy: IpAddr = "10.0.0.100".parse().unwrap();
+        let app = app(RateLimit::new(0.5, 1)
+            .with_clock(clock)
+            .with_trusted_proxies(vec![proxy]));
+        let forwarded = |peer: &str, header: &str, value: &str| {
+            let mut req = from(peer);
+            req.insert_header(header, value);
+            req
+        };
+
+        // Spoofed headers of untrusted peers don't get them fresh buckets
+        let res: http::Response = app
+            .respond(forwarded("10.0.0.1", "x-forwarded-for", "192.0.2.1"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res: http::Response = app
+            .respond(forwarded("10.0.0.1", "x-forwarded-for", "192.0.2.2"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+
+        // Clients behind the trusted proxy have their own buckets, whatever the client claims
+        let res: http::Response = app
+            .respond(forwarded(
+                "10.0.0.100",
+                "x-forwarded-for",
+                "203.0.113.9, 192.0.2.1",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res: http::Response = app
+            .respond(forwarded(
+                "10.0.0.100",
+                "forwarded",
+                "for=203.0.113.7, for=\"192.0.2.1:4711\";proto=https",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        let res: http::Response = app
+            .respond(forwarded("10.0.0.100", "x-forwarded-for", "192.0.2.3"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn idle_buckets_are_dropped() {
+        let clock = Arc::new(ManualClock::default());
+        let limit = RateLimit::new(1.0, 2).with_clock(clock.clone());
+        let buckets = || limit.buckets.lock().unwrap().by_client.len();
+
+        limit.take("10.0.0.1".to_string()).unwrap();
+        limit.take("10.0.0.2".to_string()).unwrap();
+        assert_eq!(buckets(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        limit.take("10.0.0.2".to_string()).unwrap();
+        assert_eq!(buckets(), 2);
+
+        // 10.0.0.1 is full again, 10.0.0.2 still refilling
+        clock.advance(Duration::from_secs(1));
+        limit.take("10.0.0.3".to_string()).unwrap();
+        assert_eq!(buckets(), 2);
+        assert!(!limit
+            .buckets
+            .lock()
+            .unwrap()
+            .by_client
+            .contains_key("10.0.0.1"));
+    }
+}
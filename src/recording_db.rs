@@ -0,0 +1,367 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::domain::{
+    db::{Db, DbResult, SessionTimes},
+    EncodedPassword, RefreshToken, Role, SessionToken, UserId, UserProfile,
+};
+
+/// Records every call to the wrapped `Db` before forwarding it, so that tests can assert which
+/// methods the domain logic calls in what order.
+pub struct RecordingDb<D> {
+    inner: D,
+    calls: Arc<Mutex<Vec<DbCall>>>,
+}
+
+/// A call to a `Db` method with its arguments.
+/// Passwords, tokens and secrets are left out, so that the calls can be printed safely.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DbCall {
+    Register(UserId),
+    AddSession(UserId),
+    RemoveSession(UserId),
+    RemoveAllSessions(UserId),
+    GetPw(UserId),
+    HasSession(UserId),
+    GetSecret(UserId),
+    SetSecret(UserId),
+    TouchUser(UserId, Instant),
+    LastActivityBefore(Instant),
+    CountUsers,
+    CountSessions,
+    AddSessionToken(UserId),
+    GetSessionToken,
+    RegisterWithProfile(UserId, UserProfile),
+    GetProfile(UserId),
+    SetTotpSecret(UserId),
+    GetTotpSecret(UserId),
+    SetRole(UserId, Role),
+    GetRole(UserId),
+    TouchSession(UserId, Instant),
+    GetSessionTimes(UserId),
+    Unregister(UserId),
+    SetPw(UserId),
+    CasPw(UserId),
+    ListUsers,
+    UserExists(UserId),
+    AddRefreshToken(UserId),
+    TakeRefreshToken,
+    RemoveRefreshTokens(UserId),
+    RenameUser(UserId, UserId),
+    IterSessions,
+    Ping,
+    PushPasswordHistory(UserId),
+    GetPasswordHistory(UserId),
+    SecretAccessCount(UserId),
+    AcceptTotpStep(UserId, u64),
+    /// Followed by the calls made in the transaction.
+    Transaction,
+    /// Followed by the calls made on the view.
+    ReadonlyView,
+}
+
+impl<D: Db> RecordingDb<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            calls: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// All calls recorded so far, oldest first.
+    pub fn calls(&self) -> Vec<DbCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: DbCall) -> &D {
+        self.calls.lock().unwrap().push(call);
+        &self.inner
+    }
+}
+
+impl<D: Db> Db for RecordingDb<D> {
+    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+        self.record(DbCall::Register(user_id.clone()))
+            .register(user_id, password)
+    }
+
+    fn add_session(&self, user_id: UserId) -> DbResult {
+        self.record(DbCall::AddSession(user_id.clone()))
+            .add_session(user_id)
+    }
+
+    fn remove_session(&self, user_id: &UserId) -> DbResult {
+        self.record(DbCall::RemoveSession(user_id.clone()))
+            .remove_session(user_id)
+    }
+
+    fn remove_all_sessions(&self, user_id: &UserId) -> DbResult {
+        self.record(DbCall::RemoveAllSessions(user_id.clone()))
+            .remove_all_sessions(user_id)
+    }
+
+    fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>> {
+        self.record(DbCall::GetPw(user_id.clone())).get_pw(user_id)
+    }
+
+    fn has_session(&self, user_id: &UserId) -> DbResult<bool> {
+        self.record(DbCall::HasSession(user_id.clone()))
+            .has_session(user_id)
+    }
+
+    fn get_secret(&self, user_id: &UserId) -> DbResult<Option<String>> {
+        self.record(DbCall::GetSecret(user_id.clone()))
+            .get_secret(user_id)
+    }
+
+    fn set_secret(&self, user_id: &UserId, secret: String) -> DbResult {
+        self.record(DbCall::SetSecret(user_id.clone()))
+            .set_secret(user_id, secret)
+    }
+
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.record(DbCall::TouchUser(user_id.clone(), at))
+            .touch_user(user_id, at)
+    }
+
+    fn last_activity_before(&self, cutoff: Instant) -> DbResult<Vec<UserId>> {
+        self.record(DbCall::LastActivityBefore(cutoff))
+            .last_activity_before(cutoff)
+    }
+
+    fn count_users(&self) -> DbResult<usize> {
+        self.record(DbCall::CountUsers).count_users()
+    }
+
+    fn count_sessions(&self) -> DbResult<usize> {
+        self.record(DbCall::CountSessions).count_sessions()
+    }
+
+    fn add_session_token(&self, token: SessionToken, user_id: UserId) -> DbResult {
+        self.record(DbCall::AddSessionToken(user_id.clone()))
+            .add_session_token(token, user_id)
+    }
+
+    fn get_session_token(&self, token: &SessionToken) -> DbResult<Option<UserId>> {
+        self.record(DbCall::GetSessionToken)
+            .get_session_token(token)
+    }
+
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult {
+        self.record(DbCall::RegisterWithProfile(
+            user_id.clone(),
+            profile.clone(),
+        ))
+        .register_with_profile(user_id, password, profile)
+    }
+
+    fn get_profile(&self, user_id: &UserId) -> DbResult<Option<UserProfile>> {
+        self.record(DbCall::GetProfile(user_id.clone()))
+            .get_profile(user_id)
+    }
+
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> DbResult {
+        self.record(DbCall::SetTotpSecret(user_id.clone()))
+            .set_totp_secret(user_id, secret)
+    }
+
+    fn get_totp_secret(&self, user_id: &UserId) -> DbResult<Option<Vec<u8>>> {
+        self.record(DbCall::GetTotpSecret(user_id.clone()))
+            .get_totp_secret(user_id)
+    }
+
+    fn set_role(&self, user_id: &UserId, role: Role) -> DbResult {
+        self.record(DbCall::SetRole(user_id.clone(), role))
+            .set_role(user_id, role)
+    }
+
+    fn get_role(&self, user_id: &UserId) -> DbResult<Role> {
+        self.record(DbCall::GetRole(user_id.clone()))
+            .get_role(user_id)
+    }
+
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.record(DbCall::TouchSession(user_id.clone(), at))
+            .touch_session(user_id, at)
+    }
+
+    fn get_session_times(&self, user_id: &UserId) -> DbResult<Option<SessionTimes>> {
+        self.record(DbCall::GetSessionTimes(user_id.clone()))
+            .get_session_times(user_id)
+    }
+
+    fn unregister(&self, user_id: &UserId) -> DbResult {
+        self.record(DbCall::Unregister(user_id.clone()))
+            .unregister(user_id)
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult {
+        self.record(DbCall::SetPw(user_id.clone()))
+            .set_pw(user_id, password)
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool> {
+        self.record(DbCall::CasPw(user_id.clone()))
+            .cas_pw(user_id, expected, new)
+    }
+
+    fn list_users(&self) -> DbResult<Vec<UserId>> {
+        self.record(DbCall::ListUsers).list_users()
+    }
+
+    fn user_exists(&self, user_id: &UserId) -> DbResult<bool> {
+        self.record(DbCall::UserExists(user_id.clone()))
+            .user_exists(user_id)
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken, user_id: UserId) -> DbResult {
+        self.record(DbCall::AddRefreshToken(user_id.clone()))
+            .add_refresh_token(token, user_id)
+    }
+
+    fn take_refresh_token(&self, token: &RefreshToken) -> DbResult<Option<UserId>> {
+        self.record(DbCall::TakeRefreshToken)
+            .take_refresh_token(token)
+    }
+
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> DbResult {
+        self.record(DbCall::RemoveRefreshTokens(user_id.clone()))
+            .remove_refresh_tokens(user_id)
+    }
+
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult {
+        self.record(DbCall::RenameUser(old.clone(), new.clone()))
+            .rename_user(old, new)
+    }
+
+    fn iter_sessions(&self) -> DbResult<Vec<(UserId, Option<SessionTimes>)>> {
+        self.record(DbCall::IterSessions).iter_sessions()
+    }
+
+    fn ping(&self) -> DbResult {
+        self.record(DbCall::Ping).ping()
+    }
+
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> DbResult {
+        self.record(DbCall::PushPasswordHistory(user_id.clone()))
+            .push_password_history(user_id, password, keep)
+    }
+
+    fn get_password_history(&self, user_id: &UserId) -> DbResult<Vec<EncodedPassword>> {
+        self.record(DbCall::GetPasswordHistory(user_id.clone()))
+            .get_password_history(user_id)
+    }
+
+    fn secret_access_count(&self, user_id: &UserId) -> DbResult<u64> {
+        self.record(DbCall::SecretAccessCount(user_id.clone()))
+            .secret_access_count(user_id)
+    }
+
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> DbResult<bool> {
+        self.record(DbCall::AcceptTotpStep(user_id.clone(), step))
+            .accept_totp_step(user_id, step)
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        // Calls in the transaction go to the inner `Db`'s handle, so that is recorded too
+        self.record(DbCall::Transaction).transaction(&mut |tx| {
+            f(&RecordingDb {
+                inner: tx,
+                calls: Arc::clone(&self.calls),
+            })
+        })
+    }
+
+    fn with_readonly_view(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        self.record(DbCall::ReadonlyView)
+            .with_readonly_view(&mut |view| {
+                f(&RecordingDb {
+                    inner: view,
+                    calls: Arc::clone(&self.calls),
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::ManualClock, in_memory_db, login_with, register, reset_password, AuthHeader,
+        DomainConfig, EnteredPassword,
+    };
+
+    fn alice() -> UserId {
+        UserId("Alice".to_string())
+    }
+
+    /// Registering isn't recorded.
+    fn registered_alice() -> RecordingDb<in_memory_db::Db> {
+        let db = in_memory_db::init_db();
+        register(&db, alice(), EnteredPassword::new("pw".to_string())).unwrap();
+        RecordingDb::new(db)
+    }
+
+    #[test]
+    fn records_calls_of_a_login() {
+        let now = Instant::now();
+        let config = DomainConfig {
+            clock: Arc::new(ManualClock::new(now)),
+            ..DomainConfig::default()
+        };
+        let db = registered_alice();
+
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+        login_with(&db, &config, &header).unwrap();
+        assert_eq!(
+            db.calls(),
+            vec![
+                DbCall::GetPw(alice()),
+                DbCall::TouchUser(alice(), now),
+                DbCall::GetTotpSecret(alice()),
+                DbCall::AddSession(alice()),
+                DbCall::TouchSession(alice(), now),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_calls_inside_transactions() {
+        let config = DomainConfig::default();
+        let db = registered_alice();
+
+        reset_password(
+            &db,
+            &config,
+            &alice(),
+            EnteredPassword::new("new".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            db.calls(),
+            vec![
+                DbCall::Transaction,
+                DbCall::SetPw(alice()),
+                DbCall::RemoveRefreshTokens(alice()),
+                DbCall::RemoveAllSessions(alice()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::domain::{
+    db::{Db, DbResult, SessionTimes},
+    EncodedPassword, RefreshToken, Role, SessionToken, UserId, UserProfile,
+};
+
+/// Retries calls to the wrapped `Db` that fail with a transient error, see
+/// `DbErrorKind::is_transient`. Errors like `NotFound` or `Conflict` are returned right away, as
+/// retrying them can't change the outcome.
+///
+/// Waits `backoff` before the first retry and doubles the wait before every further one. The
+/// waits block the calling thread, so async callers have to call the `Db` off their executor, as
+/// the handlers of `api` do.
+pub struct RetryDb<D> {
+    inner: D,
+    max_attempts: usize,
+    backoff: Duration,
+}
+
+impl<D: Db> RetryDb<D> {
+    /// Makes up to `max_attempts` calls per operation, the first one included.
+    pub fn new(inner: D, max_attempts: usize, backoff: Duration) -> Self {
+        assert!(max_attempts > 0, "need at least one attempt");
+        Self {
+            inner,
+            max_attempts,
+            backoff,
+        }
+    }
+
+    fn retry<T>(&self, mut call: impl FnMut(&D) -> DbResult<T>) -> DbResult<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match call(&self.inner) {
+                Err(e) if e.kind().is_transient() && attempt < self.max_attempts => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<D: Db> Db for RetryDb<D> {
+    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+        self.retry(|db| db.register(user_id.clone(), password.clone()))
+    }
+
+    fn add_session(&self, user_id: UserId) -> DbResult {
+        self.retry(|db| db.add_session(user_id.clone()))
+    }
+
+    fn remove_session(&self, user_id: &UserId) -> DbResult {
+        self.retry(|db| db.remove_session(user_id))
+    }
+
+    fn remove_all_sessions(&self, user_id: &UserId) -> DbResult {
+        self.retry(|db| db.remove_all_sessions(user_id))
+    }
+
+    fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>> {
+        self.retry(|db| db.get_pw(user_id))
+    }
+
+    fn has_session(&self, user_id: &UserId) -> DbResult<bool> {
+        self.retry(|db| db.has_session(user_id))
+    }
+
+    fn get_secret(&self, user_id: &UserId) -> DbResult<Option<String>> {
+        self.retry(|db| db.get_secret(user_id))
+    }
+
+    fn set_secret(&self, user_id: &UserId, secret: String) -> DbResult {
+        self.retry(|db| db.set_secret(user_id, secret.clone()))
+    }
+
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.retry(|db| db.touch_user(user_id, at))
+    }
+
+    fn last_activity_before(&self, cutoff: Instant) -> DbResult<Vec<UserId>> {
+        self.retry(|db| db.last_activity_before(cutoff))
+    }
+
+    fn count_users(&self) -> DbResult<usize> {
+        self.retry(|db| db.count_users())
+    }
+
+    fn count_sessions(&self) -> DbResult<usize> {
+        self.retry(|db| db.count_sessions())
+    }
+
+    fn add_session_token(&self, token: SessionToken, user_id: UserId) -> DbResult {
+        self.retry(|db| db.add_session_token(token.clone(), user_id.clone()))
+    }
+
+    fn get_session_token(&self, token: &SessionToken) -> DbResult<Option<UserId>> {
+        self.retry(|db| db.get_session_token(token))
+    }
+
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult {
+        self.retry(|db| {
+            db.register_with_profile(user_id.clone(), password.clone(), profile.clone())
+        })
+    }
+
+    fn get_profile(&self, user_id: &UserId) -> DbResult<Option<UserProfile>> {
+        self.retry(|db| db.get_profile(user_id))
+    }
+
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> DbResult {
+        self.retry(|db| db.set_totp_secret(user_id, secret.clone()))
+    }
+
+    fn get_totp_secret(&self, user_id: &UserId) -> DbResult<Option<Vec<u8>>> {
+        self.retry(|db| db.get_totp_secret(user_id))
+    }
+
+    fn set_role(&self, user_id: &UserId, role: Role) -> DbResult {
+        self.retry(|db| db.set_role(user_id, role))
+    }
+
+    fn get_role(&self, user_id: &UserId) -> DbResult<Role> {
+        self.retry(|db| db.get_role(user_id))
+    }
+
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.retry(|db| db.touch_session(user_id, at))
+    }
+
+    fn get_session_times(&self, user_id: &UserId) -> DbResult<Option<SessionTimes>> {
+        self.retry(|db| db.get_session_times(user_id))
+    }
+
+    fn unregister(&self, user_id: &UserId) -> DbResult {
+        self.retry(|db| db.unregister(user_id))
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult {
+        self.retry(|db| db.set_pw(user_id, password.clone()))
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool> {
+        self.retry(|db| db.cas_pw(user_id, expected, new.clone()))
+    }
+
+    fn list_users(&self) -> DbResult<Vec<UserId>> {
+        self.retry(|db| db.list_users())
+    }
+
+    fn user_exists(&self, user_id: &UserId) -> DbResult<bool> {
+        self.retry(|db| db.user_exists(user_id))
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken, user_id: UserId) -> DbResult {
+        self.retry(|db| db.add_refresh_token(token.clone(), user_id.clone()))
+    }
+
+    fn take_refresh_token(&self, token: &RefreshToken) -> DbResult<Option<UserId>> {
+        self.retry(|db| db.take_refresh_token(token))
+    }
+
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> DbResult {
+        self.retry(|db| db.remove_refresh_tokens(user_id))
+    }
+
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult {
+        self.retry(|db| db.rename_user(old, new.clone()))
+    }
+
+    fn iter_sessions(&self) -> DbResult<Vec<(UserId, Option<SessionTimes>)>> {
+        self.retry(|db| db.iter_sessions())
+    }
+
+    fn ping(&self) -> DbResult {
+        self.retry(|db| db.ping())
+    }
+
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> DbResult {
+        self.retry(|db| db.push_password_history(user_id, password.clone(), keep))
+    }
+
+    fn get_password_history(&self, user_id: &UserId) -> DbResult<Vec<EncodedPassword>> {
+        self.retry(|db| db.get_password_history(user_id))
+    }
+
+    fn secret_access_count(&self, user_id: &UserId) -> DbResult<u64> {
+        self.retry(|db| db.secret_access_count(user_id))
+    }
+
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> DbResult<bool> {
+        self.retry(|db| db.accept_totp_step(user_id, step))
+    }
+
+    /// Not retried, as backends without rollback may have applied part of the transaction.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        self.inner.transaction(f)
+    }
+
+    /// Not retried, as `f` may have collected part of its results already.
+    fn with_readonly_view(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        self.inner.with_readonly_view(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::{
+        db::{DbError, DbErrorKind},
+        in_memory_db, register, EnteredPassword,
+    };
+
+    /// Fails the next `failures` calls of `method` with `kind`, and counts all calls of it.
+    struct FlakyDb {
+        inner: in_memory_db::Db,
+        method: &'static str,
+        kind: DbErrorKind,
+        failures: Mutex<usize>,
+        calls: Mutex<usize>,
+    }
+
+    impl FlakyDb {
+        fn new(method: &'static str, kind: DbErrorKind, failures: usize) -> Self {
+            Self {
+                inner: in_memory_db::init_db(),
+                method,
+                kind,
+                failures: Mutex::new(failures),
+                calls: Mutex::new(0),
+            }
+        }
+
+        fn calls(&self) -> usize {
+            *self.calls.lock().unwrap()
+        }
+
+        fn flake(&self, method: &str) -> DbResult {
+            if method != self.method {
+                return Ok(());
+            }
+            *self.calls.lock().unwrap() += 1;
+            let mut failures = self.failures.lock().unwrap();
+            if *failures == 0 {
+                return Ok(());
+            }
+            *failures -= 1;
+            Err(DbError::new(self.kind, anyhow!("flaky {}", method)))
+        }
+    }
+
+    impl Db for FlakyDb {
+        crate::delegate_db!(
+            inner, before = flake;
+            register,
+            add_session,
+            remove_session,
+            remove_all_sessions,
+            get_pw,
+            has_session,
+            get_secret,
+            set_secret,
+            touch_user,
+            last_activity_before,
+            count_users,
+            count_sessions,
+            add_session_token,
+            get_session_token,
+            register_with_profile,
+            get_profile,
+            set_totp_secret,
+            get_totp_secret,
+            set_role,
+            get_role,
+            touch_session,
+            get_session_times,
+            unregister,
+            set_pw,
+            list_users,
+            user_exists,
+            add_refresh_token,
+            take_refresh_token,
+            remove_refresh_tokens,
+            transaction,
+            rename_user,
+            iter_sessions,
+            ping,
+            with_readonly_view,
+            cas_pw,
+            push_password_history,
+            get_password_history,
+            secret_access_count,
+            accept_totp_step,
+        );
+    }
+
+    fn retrying(flaky: FlakyDb) -> RetryDb<FlakyDb> {
+        RetryDb::new(flaky, 3, Duration::from_millis(1))
+    }
+
+    #[test]
+    fn recovers_from_transient_failures() {
+        let db = retrying(FlakyDb::new("register", DbErrorKind::Injected, 1));
+        let user = UserId("Alice".to_string());
+        register(&db, user.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+
+        assert_eq!(db.inner.calls(), 2);
+        assert!(db.user_exists(&user).unwrap());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let db = retrying(FlakyDb::new("count_users", DbErrorKind::Backend, 3));
+        let e = db.count_users().unwrap_err();
+
+        assert_eq!(e.kind(), DbErrorKind::Backend);
+        assert_eq!(db.inner.calls(), 3);
+    }
+
+    #[test]
+    fn doesnt_retry_permanent_failures() {
+        for kind in &[DbErrorKind::NotFound, DbErrorKind::Conflict] {
+            let db = retrying(FlakyDb::new("count_users", *kind, 1));
+            let e = db.count_users().unwrap_err();
+
+            assert_eq!(e.kind(), *kind);
+            assert_eq!(db.inner.calls(), 1, "{:?}", kind);
+        }
+    }
+}
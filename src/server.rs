@@ -0,0 +1,136 @@
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_std::task;
+use tide::{listener::Listener, Middleware, Next, Request, Server};
+
+/// How long `serve` waits for in-flight requests after shutdown was requested.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves `app` on `addr` until `shutdown` completes.
+///
+/// On shutdown the listening socket is closed so that no new connections are accepted, then
+/// requests that are still being handled get up to `DRAIN_TIMEOUT` to finish.
+pub async fn serve<State>(
+    mut app: Server<State>,
+    addr: &str,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let in_flight = InFlight::default();
+    app.with(in_flight.clone());
+    let mut listener = app.bind(addr.to_string()).await?;
+    for info in listener.info() {
+        tide::log::info!("Server listening on {}", info);
+    }
+
+    futures_lite::future::or(listener.accept(), async {
+        shutdown.await;
+        Ok(())
+    })
+    .await?;
+    drop(listener);
+
+    tide::log::info!("Shutting down, draining in-flight requests");
+    if async_std::future::timeout(DRAIN_TIMEOUT, in_flight.drained())
+        .await
+        .is_err()
+    {
+        tide::log::warn!("Shutting down with requests still in flight");
+    }
+    Ok(())
+}
+
+/// Counts the requests that are currently being handled.
+#[derive(Clone, Default)]
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    async fn drained(&self) {
+        while self.0.load(Ordering::SeqCst) > 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// Decrements the count even if the request handler gets cancelled, e.g. by a closed connection.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for InFlight {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        let _guard = InFlightGuard(Arc::clone(&self.0));
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api, in_memory_db};
+    use async_std::{io::prelude::*, net::TcpStream};
+    use std::sync::atomic::AtomicBool;
+
+    fn free_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn connect(addr: &str) -> TcpStream {
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(addr).await {
+                return stream;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server at {} didn't come up", addr)
+    }
+
+    #[async_std::test]
+    async fn serves_health_until_shutdown() {
+        let addr = free_addr();
+        let stop = Arc::new(AtomicBool::new(false));
+        let shutdown = {
+            let stop = Arc::clone(&stop);
+            async move {
+                while !stop.load(Ordering::SeqCst) {
+                    task::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        let app = api::build_app(in_memory_db::init_db());
+        let server = task::spawn({
+            let addr = addr.clone();
+            async move { serve(app, &addr, shutdown).await }
+        });
+
+        let mut stream = connect(&addr).await;
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        stop.store(true, Ordering::SeqCst);
+        server.await.unwrap();
+        assert!(TcpStream::connect(&addr).await.is_err());
+    }
+}
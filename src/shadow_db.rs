@@ -0,0 +1,358 @@
+use std::{fmt::Debug, sync::Mutex, time::Instant};
+
+use crate::domain::{
+    db::{Db, DbError, DbResult, SessionTimes},
+    EncodedPassword, RefreshToken, Role, SessionToken, UserId, UserProfile,
+};
+
+/// Runs every call against two `Db`s and records where their results differ, to validate a new
+/// backend (`primary`) against a trusted one (`reference`).
+/// Callers only ever see the results of `primary`.
+pub struct ShadowDb<P, R> {
+    primary: P,
+    reference: R,
+    divergences: Mutex<Vec<Divergence>>,
+}
+
+/// A call for which primary and reference returned different results.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub method: &'static str,
+    pub primary: String,
+    pub reference: String,
+}
+
+impl<P: Db, R: Db> ShadowDb<P, R> {
+    pub fn new(primary: P, reference: R) -> Self {
+        Self {
+            primary,
+            reference,
+            divergences: Mutex::new(vec![]),
+        }
+    }
+
+    /// All divergences recorded so far, oldest first.
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.divergences.lock().unwrap().clone()
+    }
+
+    /// Compares the whole state of primary (`a`) and reference (`b`) with `diff`, to catch drift
+    /// that no single call returned, e.g. after writes whose results agreed.
+    pub fn diff_state(&self) -> DbResult<Vec<StateMismatch>> {
+        diff(&self.primary, &self.reference)
+    }
+
+    /// Results agree if both succeeded with equal values or both failed with the same kind of
+    /// error.
+    fn check<T: PartialEq + Debug>(
+        &self,
+        method: &'static str,
+        primary: Result<T, &DbError>,
+        reference: Result<T, &DbError>,
+    ) {
+        let agree = match (&primary, &reference) {
+            (Ok(primary), Ok(reference)) => primary == reference,
+            (Err(primary), Err(reference)) => primary.kind() == reference.kind(),
+            _ => false,
+        };
+        if !agree {
+            self.divergences.lock().unwrap().push(Divergence {
+                method,
+                primary: format!("{:?}", primary),
+                reference: format!("{:?}", reference),
+            });
+        }
+    }
+
+    fn both<T: PartialEq + Debug>(
+        &self,
+        method: &'static str,
+        call: impl Fn(&dyn Db) -> DbResult<T>,
+    ) -> DbResult<T> {
+        let primary = call(&self.primary);
+        let reference = call(&self.reference);
+        self.check(method, primary.as_ref(), reference.as_ref());
+        primary
+    }
+}
+
+/// An entry in which two `Db`s differ, see `diff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateMismatch {
+    UserOnlyInA(UserId),
+    UserOnlyInB(UserId),
+    /// Registered in both, with different password hashes.
+    PasswordDiffers(UserId),
+    SessionOnlyInA(UserId),
+    SessionOnlyInB(UserId),
+}
+
+/// Every user and session that `a` and `b` disagree on, ordered by user.
+///
+/// Password hashes are compared as strings, so the same password hashed twice with different
+/// salts differs. Dbs that should agree have to be given the same hashes, as `ShadowDb` does.
+pub fn diff(a: &impl Db, b: &impl Db) -> DbResult<Vec<StateMismatch>> {
+    let (users_a, users_b) = (a.dump_users()?, b.dump_users()?);
+    let mut mismatches = vec![];
+    for (user_id, password) in &users_a {
+        match users_b.get(user_id) {
+            None => mismatches.push(StateMismatch::UserOnlyInA(user_id.clone())),
+            Some(other) if other.as_str() != password.as_str() => {
+                mismatches.push(StateMismatch::PasswordDiffers(user_id.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    mismatches.extend(
+        users_b
+            .keys()
+            .filter(|user_id| !users_a.contains_key(user_id))
+            .map(|user_id| StateMismatch::UserOnlyInB(user_id.clone())),
+    );
+
+    let (sessions_a, sessions_b) = (a.dump_sessions()?, b.dump_sessions()?);
+    mismatches.extend(
+        sessions_a
+            .difference(&sessions_b)
+            .map(|user_id| StateMismatch::SessionOnlyInA(user_id.clone())),
+    );
+    mismatches.extend(
+        sessions_b
+            .difference(&sessions_a)
+            .map(|user_id| StateMismatch::SessionOnlyInB(user_id.clone())),
+    );
+    Ok(mismatches)
+}
+
+impl<P: Db, R: Db> Db for ShadowDb<P, R> {
+    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+        self.both("register", |db| {
+            db.register(user_id.clone(), password.clone())
+        })
+    }
+
+    fn add_session(&self, user_id: UserId) -> DbResult {
+        self.both("add_session", |db| db.add_session(user_id.clone()))
+    }
+
+    fn remove_session(&self, user_id: &UserId) -> DbResult {
+        self.both("remove_session", |db| db.remove_session(user_id))
+    }
+
+    fn remove_all_sessions(&self, user_id: &UserId) -> DbResult {
+        self.both("remove_all_sessions", |db| db.remove_all_sessions(user_id))
+    }
+
+    fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>> {
+        // Both got the same hash from `register`, so the strings have to match
+        let primary = self.primary.get_pw(user_id);
+        let reference = self.reference.get_pw(user_id);
+        fn as_str(pw: &DbResult<Option<EncodedPassword>>) -> Result<Option<&str>, &DbError> {
+            pw.as_ref()
+                .map(|pw| pw.as_ref().map(EncodedPassword::as_str))
+        }
+        self.check("get_pw", as_str(&primary), as_str(&reference));
+        primary
+    }
+
+    fn has_session(&self, user_id: &UserId) -> DbResult<bool> {
+        self.both("has_session", |db| db.has_session(user_id))
+    }
+
+    fn get_secret(&self, user_id: &UserId) -> DbResult<Option<String>> {
+        self.both("get_secret", |db| db.get_secret(user_id))
+    }
+
+    fn set_secret(&self, user_id: &UserId, secret: String) -> DbResult {
+        self.both("set_secret", |db| db.set_secret(user_id, secret.clone()))
+    }
+
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.both("touch_user", |db| db.touch_user(user_id, at))
+    }
+
+    fn last_activity_before(&self, cutoff: Instant) -> DbResult<Vec<UserId>> {
+        // The order of the users isn't specified
+        self.both("last_activity_before", |db| {
+            let mut users = db.last_activity_before(cutoff)?;
+            users.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(users)
+        })
+    }
+
+    fn count_users(&self) -> DbResult<usize> {
+        self.both("count_users", |db| db.count_users())
+    }
+
+    fn count_sessions(&self) -> DbResult<usize> {
+        self.both("count_sessions", |db| db.count_sessions())
+    }
+
+    fn add_session_token(&self, token: SessionToken, user_id: UserId) -> DbResult {
+        self.both("add_session_token", |db| {
+            db.add_session_token(token.clone(), user_id.clone())
+        })
+    }
+
+    fn get_session_token(&self, token: &SessionToken) -> DbResult<Option<UserId>> {
+        self.both("get_session_token", |db| db.get_session_token(token))
+    }
+
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult {
+        self.both("register_with_profile", |db| {
+            db.register_with_profile(user_id.clone(), password.clone(), profile.clone())
+        })
+    }
+
+    fn get_profile(&self, user_id: &UserId) -> DbResult<Option<UserProfile>> {
+        self.both("get_profile", |db| db.get_profile(user_id))
+    }
+
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> DbResult {
+        self.both("set_totp_secret", |db| {
+            db.set_totp_secret(user_id, secret.clone())
+        })
+    }
+
+    fn get_totp_secret(&self, user_id: &UserId) -> DbResult<Option<Vec<u8>>> {
+        self.both("get_totp_secret", |db| db.get_totp_secret(user_id))
+    }
+
+    fn set_role(&self, user_id: &UserId, role: Role) -> DbResult {
+        self.both("set_role", |db| db.set_role(user_id, role))
+    }
+
+    fn get_role(&self, user_id: &UserId) -> DbResult<Role> {
+        self.both("get_role", |db| db.get_role(user_id))
+    }
+
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.both("touch_session", |db| db.touch_session(user_id, at))
+    }
+
+    fn get_session_times(&self, user_id: &UserId) -> DbResult<Option<SessionTimes>> {
+        self.both("get_session_times", |db| db.get_session_times(user_id))
+    }
+
+    fn unregister(&self, user_id: &UserId) -> DbResult {
+        self.both("unregister", |db| db.unregister(user_id))
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult {
+        self.both("set_pw", |db| db.set_pw(user_id, password.clone()))
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool> {
+        self.both("cas_pw", |db| db.cas_pw(user_id, expected, new.clone()))
+    }
+
+    fn list_users(&self) -> DbResult<Vec<UserId>> {
+        // The order of the users isn't specified
+        self.both("list_users", |db| {
+            let mut users = db.list_users()?;
+            users.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(users)
+        })
+    }
+
+    fn user_exists(&self, user_id: &UserId) -> DbResult<bool> {
+        self.both("user_exists", |db| db.user_exists(user_id))
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        // The reference goes first, so that values `f` captures end up being the primary's
+        let reference = self.reference.transaction(f);
+        let primary = self.primary.transaction(f);
+        self.check("transaction", primary.as_ref(), reference.as_ref());
+        primary
+    }
+
+    fn with_readonly_view(&self, f: &mut dyn FnMut(&dyn Db) -> DbResult) -> DbResult {
+        let reference = self.reference.with_readonly_view(f);
+        let primary = self.primary.with_readonly_view(f);
+        self.check("with_readonly_view", primary.as_ref(), reference.as_ref());
+        primary
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken, user_id: UserId) -> DbResult {
+        self.both("add_refresh_token", |db| {
+            db.add_refresh_token(token.clone(), user_id.clone())
+        })
+    }
+
+    fn take_refresh_token(&self, token: &RefreshToken) -> DbResult<Option<UserId>> {
+        self.both("take_refresh_token", |db| db.take_refresh_token(token))
+    }
+
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> DbResult {
+        self.both("remove_refresh_tokens", |db| {
+            db.remove_refresh_tokens(user_id)
+        })
+    }
+
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult {
+        self.both("rename_user", |db| db.rename_user(old, new.clone()))
+    }
+
+    fn iter_sessions(&self) -> DbResult<Vec<(UserId, Option<SessionTimes>)>> {
+        // The order of the sessions isn't specified
+        self.both("iter_sessions", |db| {
+            let mut sessions = db.iter_sessions()?;
+            sessions.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+            Ok(sessions)
+        })
+    }
+
+    fn ping(&self) -> DbResult {
+        self.both("ping", |db| db.ping())
+    }
+
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> DbResult {
+        self.both("push_password_history", |db| {
+            db.push_password_history(user_id, password.clone(), keep)
+        })
+    }
+
+    fn get_password_history(&self, user_id: &UserId) -> DbResult<Vec<EncodedPassword>> {
+        // Like for `get_pw`, both got the same hashes
+        let primary = self.primary.get_password_history(user_id);
+        let reference = self.reference.get_password_history(user_id);
+        fn as_strs(history: &DbResult<Vec<EncodedPassword>>) -> Result<Vec<&str>, &DbError> {
+            history.as_ref().map(|history| {
+                history
+                    .iter()
+                    .map(EncodedPassword::as_str)
+                    .collect::<Vec<_>>()
+            })
+        }
+        self.check(
+            "get_password_history",
+            as_strs(&primary),
+            as_strs(&reference),
+        );
+        primary
+    }
+
+    fn secret_access_count(&self, user_id: &UserId) -> DbResult<u64> {
+        self.both("secret_access_count", |db| db.secret_access_count(user_id))
+    }
+
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> DbResult<bool> {
+        self.both("accept_totp_step", |db| db.accept_totp_step(user_id, step))
+    }
+}
@@ -0,0 +1,904 @@
+use std::{
+    convert::TryFrom,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use sled::{
+    transaction::{
+        abort, ConflictableTransactionResult, TransactionError, Transactional, TransactionalTree,
+    },
+    IVec, Tree,
+};
+
+use crate::domain::{
+    db::{CredentialChange, Db, DbError, DbErrorKind, DbResult, SessionTimes},
+    Email, EncodedPassword, RefreshToken, Role, SessionToken, UserId, UserProfile,
+};
+
+/// A `Db` in a sled database on disk, so that users and sessions survive restarts and crashes.
+///
+/// Every kind of entry has its own tree, keyed by the UTF-8 user id or token. The tokens of each
+/// user are also listed in a tree keyed by user, so that they can be revoked together without a
+/// scan, in the same transaction as the rest. Passwords are
+/// stored in their PHC string format, former passwords in one newline-separated entry per user,
+/// most recent first. Activity and session times are stored as nanoseconds since the Unix epoch,
+/// see `Timeline`, so that sessions still expire after a restart.
+///
+/// `register`, `unregister` and `rename_user` are atomic. `transaction` is the default one,
+/// without atomicity, and so is `with_readonly_view`, which reads live data.
+#[derive(Clone)]
+pub struct SledDb {
+    db: sled::Db,
+    users: Tree,
+    sessions: Tree,
+    secrets: Tree,
+    session_tokens: Tree,
+    /// The session tokens of each user, newline-separated.
+    session_tokens_by_user: Tree,
+    profiles: Tree,
+    totp_secrets: Tree,
+    /// The last time step a TOTP code of each user was accepted for, as big-endian `u64`s.
+    totp_steps: Tree,
+    roles: Tree,
+    refresh_tokens: Tree,
+    /// The refresh tokens of each user, newline-separated.
+    refresh_tokens_by_user: Tree,
+    password_history: Tree,
+    /// How often each secret was read, as big-endian `u64`s.
+    secret_reads: Tree,
+    /// When each user was last active, see `Timeline::encode`.
+    last_activity: Tree,
+    /// When each session started and was last used, both encoded like `last_activity`.
+    session_times: Tree,
+    timeline: Timeline,
+}
+
+impl SledDb {
+    /// Opens the database at `path`, creating it if it doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> DbResult<Self> {
+        Self::with_config(sled::Config::new().path(path))
+    }
+
+    /// A database in a temporary directory that is removed once the `SledDb` is dropped.
+    pub fn temporary() -> DbResult<Self> {
+        Self::with_config(sled::Config::new().temporary(true))
+    }
+
+    fn with_config(config: sled::Config) -> DbResult<Self> {
+        let db = config.open().map_err(backend)?;
+        let tree = |name: &str| db.open_tree(name).map_err(backend);
+        Ok(Self {
+            users: tree("users")?,
+            sessions: tree("sessions")?,
+            secrets: tree("secrets")?,
+            session_tokens: tree("session_tokens")?,
+            session_tokens_by_user: tree("session_tokens_by_user")?,
+            profiles: tree("profiles")?,
+            totp_secrets: tree("totp_secrets")?,
+            totp_steps: tree("totp_steps")?,
+            roles: tree("roles")?,
+            refresh_tokens: tree("refresh_tokens")?,
+            refresh_tokens_by_user: tree("refresh_tokens_by_user")?,
+            password_history: tree("password_history")?,
+            secret_reads: tree("secret_reads")?,
+            last_activity: tree("last_activity")?,
+            session_times: tree("session_times")?,
+            timeline: Timeline::starting_now(),
+            db,
+        })
+    }
+
+    /// Writes all changes to disk. sled does so by itself every few hundred milliseconds.
+    pub fn flush(&self) -> DbResult {
+        self.db.flush().map_err(backend)?;
+        Ok(())
+    }
+
+    /// The trees of both kinds of tokens, each followed by its index by user.
+    fn token_trees(&self) -> [&Tree; 4] {
+        [
+            &self.session_tokens,
+            &self.session_tokens_by_user,
+            &self.refresh_tokens,
+            &self.refresh_tokens_by_user,
+        ]
+    }
+
+    /// All trees keyed by user id, `users` first.
+    fn trees_by_user(&self) -> [&Tree; 11] {
+        [
+            &self.users,
+            &self.sessions,
+            &self.session_times,
+            &self.secrets,
+            &self.profiles,
+            &self.totp_secrets,
+            &self.totp_steps,
+            &self.roles,
+            &self.password_history,
+            &self.secret_reads,
+            &self.last_activity,
+        ]
+    }
+
+    /// Everything stored about users, `token_trees` first, then `trees_by_user`.
+    fn user_trees(&self) -> Vec<&Tree> {
+        let mut trees = self.token_trees().to_vec();
+        trees.extend_from_slice(&self.trees_by_user());
+        trees
+    }
+}
+
+impl Db for SledDb {
+    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+        self.register_with_profile(user_id, password, UserProfile::default())
+    }
+
+    fn add_session(&self, user_id: UserId) -> DbResult {
+        self.sessions
+            .insert(key(&user_id), IVec::default())
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    /// Removes the session, its times and its tokens in one sled transaction.
+    fn remove_session(&self, user_id: &UserId) -> DbResult {
+        let trees = (
+            &self.sessions,
+            &self.session_times,
+            &self.session_tokens,
+            &self.session_tokens_by_user,
+        );
+        let result = trees.transaction(|(sessions, times, tokens, tokens_by_user)| {
+            sessions.remove(key(user_id))?;
+            times.remove(key(user_id))?;
+            remove_all_owned(tokens, tokens_by_user, key(user_id))?;
+            Ok(())
+        });
+        transaction_result(result, || anyhow!("can't end the session of {:?}", user_id))
+    }
+
+    fn remove_all_sessions(&self, user_id: &UserId) -> DbResult {
+        // Users only ever have a single session for now
+        self.remove_session(user_id)
+    }
+
+    fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>> {
+        match self.users.get(key(user_id)).map_err(backend)? {
+            Some(bytes) => {
+                let password = EncodedPassword::from_phc_string(string(bytes)?)
+                    .map_err(|e| DbError::new(DbErrorKind::Backend, e))?;
+                Ok(Some(password))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn has_session(&self, user_id: &UserId) -> DbResult<bool> {
+        self.sessions.contains_key(key(user_id)).map_err(backend)
+    }
+
+    /// Reads the secret and counts the read in one sled transaction.
+    fn get_secret(&self, user_id: &UserId) -> DbResult<Option<String>> {
+        let result: Result<_, TransactionError<DbErrorKind>> = (&self.secrets, &self.secret_reads)
+            .transaction(|(secrets, reads)| {
+                let secret = match secrets.get(key(user_id))? {
+                    Some(secret) => secret,
+                    None => return Ok(None),
+                };
+                let count = read_count(reads.get(key(user_id))?);
+                reads.insert(key(user_id), &(count + 1).to_be_bytes()[..])?;
+                Ok(Some(secret))
+            });
+        match transaction_result(result, || anyhow!("can't read the secret of {:?}", user_id))? {
+            Some(bytes) => Ok(Some(string(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_secret(&self, user_id: &UserId, secret: String) -> DbResult {
+        self.secrets
+            .insert(key(user_id), secret.as_bytes())
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn touch_user(&self, user_id: &UserId, at: Instant) -> DbResult {
+        self.last_activity
+            .insert(key(user_id), &self.timeline.encode(at)[..])
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn last_activity_before(&self, cutoff: Instant) -> DbResult<Vec<UserId>> {
+        let mut users = Vec::new();
+        for entry in self.last_activity.iter() {
+            let (user_id, last) = entry.map_err(backend)?;
+            if self.timeline.decode(&last)? < cutoff {
+                users.push(UserId(string(user_id)?));
+            }
+        }
+        Ok(users)
+    }
+
+    fn count_users(&self) -> DbResult<usize> {
+        Ok(self.users.len())
+    }
+
+    fn count_sessions(&self) -> DbResult<usize> {
+        Ok(self.sessions.len())
+    }
+
+    fn add_session_token(&self, token: SessionToken, user_id: UserId) -> DbResult {
+        add_token(
+            &self.session_tokens,
+            &self.session_tokens_by_user,
+            token.as_str(),
+            &user_id,
+        )
+    }
+
+    fn get_session_token(&self, token: &SessionToken) -> DbResult<Option<UserId>> {
+        match self.session_tokens.get(token.as_str()).map_err(backend)? {
+            Some(bytes) => Ok(Some(UserId(string(bytes)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult {
+        let password = password.into_string();
+        let email = profile.email.as_ref().map_or("", Email::as_str);
+        let result = (&self.users, &self.profiles).transaction(|(users, profiles)| {
+            if users.get(key(&user_id))?.is_some() {
+                return abort(DbErrorKind::Conflict);
+            }
+            users.insert(key(&user_id), password.as_bytes())?;
+            profiles.insert(key(&user_id), email.as_bytes())?;
+            Ok(())
+        });
+        transaction_result(result, || anyhow!("{:?} is already registered", user_id))
+    }
+
+    fn get_profile(&self, user_id: &UserId) -> DbResult<Option<UserProfile>> {
+        let email = match self.profiles.get(key(user_id)).map_err(backend)? {
+            Some(bytes) => string(bytes)?,
+            None => return Ok(None),
+        };
+        let email = if email.is_empty() {
+            None
+        } else {
+            Some(Email::parse(email).map_err(|e| DbError::new(DbErrorKind::Backend, e))?)
+        };
+        Ok(Some(UserProfile { email }))
+    }
+
+    fn set_totp_secret(&self, user_id: &UserId, secret: Vec<u8>) -> DbResult {
+        self.totp_secrets
+            .insert(key(user_id), secret)
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn get_totp_secret(&self, user_id: &UserId) -> DbResult<Option<Vec<u8>>> {
+        let secret = self.totp_secrets.get(key(user_id)).map_err(backend)?;
+        Ok(secret.map(|bytes| bytes.to_vec()))
+    }
+
+    fn accept_totp_step(&self, user_id: &UserId, step: u64) -> DbResult<bool> {
+        let previous = self
+            .totp_steps
+            .fetch_and_update(key(user_id), |last| {
+                let last = last.and_then(be_u64).map_or(step, |last| last.max(step));
+                Some(last.to_be_bytes().to_vec())
+            })
+            .map_err(backend)?;
+        Ok(previous
+            .and_then(|last| be_u64(&last))
+            .map_or(true, |last| last < step))
+    }
+
+    fn set_role(&self, user_id: &UserId, role: Role) -> DbResult {
+        let byte: u8 = match role {
+            Role::User => 0,
+            Role::Admin => 1,
+        };
+        self.roles
+            .insert(key(user_id), vec![byte])
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn get_role(&self, user_id: &UserId) -> DbResult<Role> {
+        match self.roles.get(key(user_id)).map_err(backend)?.as_deref() {
+            None | Some([0]) => Ok(Role::User),
+            Some([1]) => Ok(Role::Admin),
+            Some(other) => Err(DbError::new(
+                DbErrorKind::Backend,
+                anyhow!("invalid role {:?} stored for {:?}", other, user_id),
+            )),
+        }
+    }
+
+    /// Stores the start of the session followed by `at`, keeping a start that is already stored.
+    fn touch_session(&self, user_id: &UserId, at: Instant) -> DbResult {
+        let at = self.timeline.encode(at);
+        self.session_times
+            .fetch_and_update(key(user_id), |old| {
+                let started = old.and_then(|old| old.get(..8)).unwrap_or(&at[..]);
+                Some([started, &at[..]].concat())
+            })
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn get_session_times(&self, user_id: &UserId) -> DbResult<Option<SessionTimes>> {
+        match self.session_times.get(key(user_id)).map_err(backend)? {
+            Some(bytes) => Ok(Some(self.timeline.decode_session_times(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the user together with their tokens in one sled transaction.
+    fn unregister(&self, user_id: &UserId) -> DbResult {
+        let trees = self.user_trees();
+        let result = trees.as_slice().transaction(|trees| {
+            let (tokens, by_user) = trees.split_at(4);
+            if by_user[0].get(key(user_id))?.is_none() {
+                return abort(DbErrorKind::NotFound);
+            }
+            for tree in by_user {
+                tree.remove(key(user_id))?;
+            }
+            for kind in tokens.chunks(2) {
+                remove_all_owned(&kind[0], &kind[1], key(user_id))?;
+            }
+            Ok(())
+        });
+        transaction_result(result, || anyhow!("{:?} is not registered", user_id))
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult {
+        let password = password.into_string();
+        let previous = self
+            .users
+            .fetch_and_update(key(user_id), |old| old.map(|_| password.as_bytes()))
+            .map_err(backend)?;
+        match previous {
+            Some(_) => Ok(()),
+            None => Err(not_registered(user_id)),
+        }
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool> {
+        let swapped = self
+            .users
+            .compare_and_swap(
+                key(user_id),
+                Some(expected.as_str().as_bytes()),
+                Some(new.into_string().as_bytes()),
+            )
+            .map_err(backend)?;
+        match swapped {
+            Ok(()) => Ok(true),
+            Err(sled::CompareAndSwapError {
+                current: Some(_), ..
+            }) => Ok(false),
+            Err(sled::CompareAndSwapError { current: None, .. }) => Err(not_registered(user_id)),
+        }
+    }
+
+    /// Replaces the password and removes the session, its times and all tokens in one sled
+    /// transaction.
+    fn purge_sessions_for_password_change(
+        &self,
+        user_id: &UserId,
+        change: CredentialChange,
+    ) -> DbResult<bool> {
+        let (expected, new) = match change {
+            CredentialChange::SetPassword(password) => (None, password.into_string()),
+            CredentialChange::SwapPassword { expected, new } => (Some(expected), new.into_string()),
+            CredentialChange::Unregister => {
+                self.unregister(user_id)?;
+                return Ok(true);
+            }
+        };
+        let trees = (
+            &self.users,
+            &self.sessions,
+            &self.session_times,
+            &self.session_tokens,
+            &self.session_tokens_by_user,
+            &self.refresh_tokens,
+            &self.refresh_tokens_by_user,
+        );
+        let result = trees.transaction(
+            |(
+                users,
+                sessions,
+                session_times,
+                session_tokens,
+                session_tokens_by_user,
+                refresh_tokens,
+                refresh_tokens_by_user,
+            )| {
+                let stored = match users.get(key(user_id))? {
+                    Some(stored) => stored,
+                    None => return abort(DbErrorKind::NotFound),
+                };
+                if let Some(expected) = &expected {
+                    if &stored[..] != expected.as_str().as_bytes() {
+                        return Ok(false);
+                    }
+                }
+                users.insert(key(user_id), new.as_bytes())?;
+                sessions.remove(key(user_id))?;
+                session_times.remove(key(user_id))?;
+                remove_all_owned(session_tokens, session_tokens_by_user, key(user_id))?;
+                remove_all_owned(refresh_tokens, refresh_tokens_by_user, key(user_id))?;
+                Ok(true)
+            },
+        );
+        transaction_result(result, || anyhow!("{:?} is not registered", user_id))
+    }
+
+    fn list_users(&self) -> DbResult<Vec<UserId>> {
+        self.users
+            .iter()
+            .keys()
+            .map(|user_id| Ok(UserId(string(user_id.map_err(backend)?)?)))
+            .collect()
+    }
+
+    fn user_exists(&self, user_id: &UserId) -> DbResult<bool> {
+        self.users.contains_key(key(user_id)).map_err(backend)
+    }
+
+    fn add_refresh_token(&self, token: RefreshToken, user_id: UserId) -> DbResult {
+        add_token(
+            &self.refresh_tokens,
+            &self.refresh_tokens_by_user,
+            token.as_str(),
+            &user_id,
+        )
+    }
+
+    fn take_refresh_token(&self, token: &RefreshToken) -> DbResult<Option<UserId>> {
+        let trees = (&self.refresh_tokens, &self.refresh_tokens_by_user);
+        let result = trees.transaction(|(tokens, tokens_by_user)| {
+            let owner = match tokens.remove(token.as_str())? {
+                Some(owner) => owner,
+                None => return Ok(None),
+            };
+            remove_owned(tokens_by_user, &owner, token.as_str().as_bytes())?;
+            Ok(Some(owner))
+        });
+        match transaction_result(result, || anyhow!("can't take a refresh token"))? {
+            Some(bytes) => Ok(Some(UserId(string(bytes)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_refresh_tokens(&self, user_id: &UserId) -> DbResult {
+        let trees = (&self.refresh_tokens, &self.refresh_tokens_by_user);
+        let result = trees.transaction(|(tokens, tokens_by_user)| {
+            remove_all_owned(tokens, tokens_by_user, key(user_id))?;
+            Ok(())
+        });
+        transaction_result(result, || {
+            anyhow!("can't remove the refresh tokens of {:?}", user_id)
+        })
+    }
+
+    /// Moves the entries keyed by user and hands over the tokens in one sled transaction.
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult {
+        let trees = self.user_trees();
+        let result = trees.as_slice().transaction(|trees| {
+            let (tokens, by_user) = trees.split_at(4);
+            if by_user[0].get(key(old))?.is_none() {
+                return abort(DbErrorKind::NotFound);
+            }
+            if by_user[0].get(key(&new))?.is_some() {
+                return abort(DbErrorKind::Conflict);
+            }
+            for tree in by_user {
+                if let Some(value) = tree.remove(key(old))? {
+                    tree.insert(key(&new), value)?;
+                }
+            }
+            for kind in tokens.chunks(2) {
+                reassign(&kind[0], &kind[1], old, &new)?;
+            }
+            Ok(())
+        });
+        transaction_result(result, || anyhow!("can't rename {:?} to {:?}", old, new))
+    }
+
+    fn iter_sessions(&self) -> DbResult<Vec<(UserId, Option<SessionTimes>)>> {
+        self.sessions
+            .iter()
+            .keys()
+            .map(|user_id| {
+                let user_id = UserId(string(user_id.map_err(backend)?)?);
+                let times = self.get_session_times(&user_id)?;
+                Ok((user_id, times))
+            })
+            .collect()
+    }
+
+    /// Reads from the users tree, which fails if sled can't read from disk.
+    fn ping(&self) -> DbResult {
+        self.users.first().map_err(backend)?;
+        Ok(())
+    }
+
+    fn push_password_history(
+        &self,
+        user_id: &UserId,
+        password: EncodedPassword,
+        keep: usize,
+    ) -> DbResult {
+        let password = password.into_string();
+        self.password_history
+            .fetch_and_update(key(user_id), |old| {
+                let old = old.map(|old| String::from_utf8_lossy(old).into_owned());
+                let history: Vec<&str> = std::iter::once(password.as_str())
+                    .chain(old.as_deref().into_iter().flat_map(|old| old.split('\n')))
+                    .take(keep)
+                    .collect();
+                if history.is_empty() {
+                    None
+                } else {
+                    Some(history.join("\n").into_bytes())
+                }
+            })
+            .map_err(backend)?;
+        Ok(())
+    }
+
+    fn get_password_history(&self, user_id: &UserId) -> DbResult<Vec<EncodedPassword>> {
+        let history = match self.password_history.get(key(user_id)).map_err(backend)? {
+            Some(bytes) => string(bytes)?,
+            None => return Ok(Vec::new()),
+        };
+        history
+            .split('\n')
+            .map(|password| {
+                EncodedPassword::from_phc_string(password.to_string())
+                    .map_err(|e| DbError::new(DbErrorKind::Backend, e))
+            })
+            .collect()
+    }
+
+    fn secret_access_count(&self, user_id: &UserId) -> DbResult<u64> {
+        Ok(read_count(
+            self.secret_reads.get(key(user_id)).map_err(backend)?,
+        ))
+    }
+}
+
+fn key(user_id: &UserId) -> &[u8] {
+    user_id.0.as_bytes()
+}
+
+/// 0 if there is no count yet.
+fn read_count(bytes: Option<IVec>) -> u64 {
+    bytes.and_then(|bytes| be_u64(&bytes)).unwrap_or(0)
+}
+
+fn be_u64(bytes: &[u8]) -> Option<u64> {
+    <[u8; 8]>::try_from(bytes).ok().map(u64::from_be_bytes)
+}
+
+fn string(bytes: IVec) -> DbResult<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| DbError::new(DbErrorKind::Backend, e))
+}
+
+fn backend(e: sled::Error) -> DbError {
+    DbError::new(DbErrorKind::Backend, e)
+}
+
+fn not_registered(user_id: &UserId) -> DbError {
+    DbError::new(
+        DbErrorKind::NotFound,
+        anyhow!("{:?} is not registered", user_id),
+    )
+}
+
+/// Turns a transaction aborted with a `DbErrorKind` into a `DbError` with `message`.
+fn transaction_result<T>(
+    result: Result<T, TransactionError<DbErrorKind>>,
+    message: impl FnOnce() -> anyhow::Error,
+) -> DbResult<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(TransactionError::Abort(kind)) => Err(DbError::new(kind, message())),
+        Err(TransactionError::Storage(e)) => Err(backend(e)),
+    }
+}
+
+/// Stores `token` as one of `user_id`'s in the trees of its kind.
+fn add_token(tokens: &Tree, tokens_by_user: &Tree, token: &str, user_id: &UserId) -> DbResult {
+    let result = (tokens, tokens_by_user).transaction(|(tokens, tokens_by_user)| {
+        tokens.insert(token.as_bytes(), key(user_id))?;
+        add_owned(tokens_by_user, key(user_id), token.as_bytes())
+    });
+    transaction_result(result, || anyhow!("can't add a token for {:?}", user_id))
+}
+
+/// Adds `token` to the tokens of `owner` in `tokens_by_user`.
+fn add_owned(
+    tokens_by_user: &TransactionalTree,
+    owner: &[u8],
+    token: &[u8],
+) -> ConflictableTransactionResult<(), DbErrorKind> {
+    let mut owned = tokens_by_user
+        .get(owner)?
+        .map_or_else(Vec::new, |owned| owned.to_vec());
+    if !owned.is_empty() {
+        owned.push(b'\n');
+    }
+    owned.extend_from_slice(token);
+    tokens_by_user.insert(owner, owned)?;
+    Ok(())
+}
+
+/// Removes `token` from the tokens of `owner` in `tokens_by_user`.
+fn remove_owned(
+    tokens_by_user: &TransactionalTree,
+    owner: &[u8],
+    token: &[u8],
+) -> ConflictableTransactionResult<(), DbErrorKind> {
+    let owned = match tokens_by_user.get(owner)? {
+        Some(owned) => owned,
+        None => return Ok(()),
+    };
+    let rest: Vec<&[u8]> = owned
+        .split(|&byte| byte == b'\n')
+        .filter(|owned| *owned != token)
+        .collect();
+    if rest.is_empty() {
+        tokens_by_user.remove(owner)?;
+    } else {
+        tokens_by_user.insert(owner, rest.join(&b'\n'))?;
+    }
+    Ok(())
+}
+
+/// Removes all tokens of `owner` from the trees of their kind and returns them.
+fn remove_all_owned(
+    tokens: &TransactionalTree,
+    tokens_by_user: &TransactionalTree,
+    owner: &[u8],
+) -> ConflictableTransactionResult<Vec<Vec<u8>>, DbErrorKind> {
+    let owned = match tokens_by_user.remove(owner)? {
+        Some(owned) => owned,
+        None => return Ok(Vec::new()),
+    };
+    let owned: Vec<Vec<u8>> = owned
+        .split(|&byte| byte == b'\n')
+        .map(<[u8]>::to_vec)
+        .collect();
+    for token in &owned {
+        tokens.remove(&token[..])?;
+    }
+    Ok(owned)
+}
+
+/// Hands the tokens of one kind that belong to `old` over to `new`.
+fn reassign(
+    tokens: &TransactionalTree,
+    tokens_by_user: &TransactionalTree,
+    old: &UserId,
+    new: &UserId,
+) -> ConflictableTransactionResult<(), DbErrorKind> {
+    for token in remove_all_owned(tokens, tokens_by_user, key(old))? {
+        tokens.insert(&token[..], key(new))?;
+        add_owned(tokens_by_user, key(new), &token)?;
+    }
+    Ok(())
+}
+
+/// Maps `Instant`s, which have no meaning outside of the process that took them, to nanoseconds
+/// since the Unix epoch and back, by way of the wall-clock time the `SledDb` was opened at.
+#[derive(Clone, Copy)]
+struct Timeline {
+    opened: Instant,
+    /// `opened` in nanoseconds since the Unix epoch.
+    opened_nanos: u64,
+}
+
+impl Timeline {
+    fn starting_now() -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            opened: Instant::now(),
+            opened_nanos: nanos(since_epoch),
+        }
+    }
+
+    fn encode(&self, at: Instant) -> [u8; 8] {
+        let at = match at.checked_duration_since(self.opened) {
+            Some(after) => self.opened_nanos.saturating_add(nanos(after)),
+            None => self.opened_nanos.saturating_sub(nanos(self.opened - at)),
+        };
+        at.to_be_bytes()
+    }
+
+    /// Times before the earliest `Instant` of this process, e.g. from before a reboot, are
+    /// clamped to when the `SledDb` was opened.
+    fn decode(&self, bytes: &[u8]) -> DbResult<Instant> {
+        let at = <[u8; 8]>::try_from(bytes)
+            .map(u64::from_be_bytes)
+            .map_err(|_| DbError::new(DbErrorKind::Backend, anyhow!("invalid time {:?}", bytes)))?;
+        Ok(if at >= self.opened_nanos {
+            self.opened + Duration::from_nanos(at - self.opened_nanos)
+        } else {
+            let before = Duration::from_nanos(self.opened_nanos - at);
+            self.opened.checked_sub(before).unwrap_or(self.opened)
+        })
+    }
+
+    /// The start followed by the last use, see `SledDb::touch_session`.
+    fn decode_session_times(&self, bytes: &[u8]) -> DbResult<SessionTimes> {
+        if bytes.len() != 16 {
+            return Err(DbError::new(
+                DbErrorKind::Backend,
+                anyhow!("invalid session times {:?}", bytes),
+            ));
+        }
+        let (started, last_seen) = bytes.split_at(8);
+        Ok(SessionTimes {
+            started: self.decode(started)?,
+            last_seen: self.decode(last_seen)?,
+        })
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        change_password, login, login_with_token, register, AuthHeader, DomainConfig,
+        EnteredPassword,
+    };
+
+    #[test]
+    fn data_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("sled-db-{}", uuid::Uuid::new_v4()));
+        let alice = UserId("Alice".to_string());
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+        {
+            let db = SledDb::open(&path).unwrap();
+            register(&db, alice.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+            login(&db, &header).unwrap();
+            db.set_secret(&alice, "s3cret".to_string()).unwrap();
+            db.set_role(&alice, Role::Admin).unwrap();
+            db.flush().unwrap();
+        }
+
+        let db = SledDb::open(&path).unwrap();
+        assert_eq!(db.list_users().unwrap(), vec![alice.clone()]);
+        assert!(db.has_session(&alice).unwrap());
+        assert_eq!(db.get_secret(&alice).unwrap().as_deref(), Some("s3cret"));
+        assert_eq!(db.get_role(&alice).unwrap(), Role::Admin);
+        login(&db, &header).unwrap();
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn times_survive_reopening_and_go_with_the_user() {
+        let path = std::env::temp_dir().join(format!("sled-db-{}", uuid::Uuid::new_v4()));
+        let alice = UserId("Alice".to_string());
+        let header = AuthHeader::new(format!("Basic {}", base64::encode("Alice:pw")));
+        let before = {
+            let db = SledDb::open(&path).unwrap();
+            register(&db, alice.clone(), EnteredPassword::new("pw".to_string())).unwrap();
+            login(&db, &header).unwrap();
+            db.touch_user(&alice, Instant::now()).unwrap();
+            db.flush().unwrap();
+            db.get_session_times(&alice).unwrap().unwrap()
+        };
+
+        let db = SledDb::open(&path).unwrap();
+        let after = db.get_session_times(&alice).unwrap().unwrap();
+        // Both mappings go through the wall clock, which drifts a little from `Instant`s
+        let drift = |a: Instant, b: Instant| if a > b { a - b } else { b - a };
+        assert!(drift(before.started, after.started) < Duration::from_secs(1));
+        assert!(drift(before.last_seen, after.last_seen) < Duration::from_secs(1));
+        let later = Instant::now() + Duration::from_secs(60);
+        assert_eq!(db.last_activity_before(later).unwrap(), vec![alice.clone()]);
+
+        db.unregister(&alice).unwrap();
+        assert_eq!(db.get_session_times(&alice).unwrap(), None);
+        assert!(db.last_activity_before(later).unwrap().is_empty());
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn users_exported_from_memory_can_log_in_after_importing() {
+        let source = crate::in_memory_db::init_db();
+        for user in &["Alice", "Bob"] {
+            let user_id = UserId(user.to_string());
+            register(&source, user_id, EnteredPassword::new("pw".to_string())).unwrap();
+        }
+        let json = serde_json::to_string(&source.export().unwrap()).unwrap();
+
+        let db = SledDb::temporary().unwrap();
+        db.import(serde_json::from_str(&json).unwrap()).unwrap();
+        for user in &["Alice", "Bob"] {
+            let user_id = UserId(user.to_string());
+            let pass = EnteredPassword::new("pw".to_string());
+            assert_eq!(
+                login(&db, &AuthHeader::basic(&user_id, &pass)).unwrap(),
+                user_id
+            );
+        }
+    }
+
+    #[test]
+    fn rename_moves_everything_or_nothing() {
+        let db = SledDb::temporary().unwrap();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        for user in &[&alice, &bob] {
+            register(&db, (*user).clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        }
+        db.add_session(alice.clone()).unwrap();
+
+        let e = db.rename_user(&alice, bob.clone()).unwrap_err();
+        assert_eq!(e.kind(), DbErrorKind::Conflict);
+        assert!(db.has_session(&alice).unwrap());
+
+        let carol = UserId("Carol".to_string());
+        db.rename_user(&alice, carol.clone()).unwrap();
+        assert!(!db.user_exists(&alice).unwrap());
+        assert!(db.has_session(&carol).unwrap());
+        assert!(db.get_pw(&carol).unwrap().is_some());
+    }
+
+    #[test]
+    fn password_changes_revoke_every_token_of_the_user() {
+        let db = SledDb::temporary().unwrap();
+        let alice = UserId("Alice".to_string());
+        let bob = UserId("Bob".to_string());
+        for user in &[&alice, &bob] {
+            register(&db, (*user).clone(), EnteredPassword::new("pw".to_string())).unwrap();
+        }
+        let header = AuthHeader::basic(&alice, &EnteredPassword::new("pw".to_string()));
+        let first = login_with_token(&db, &header, true).unwrap();
+        let second = login_with_token(&db, &header, true).unwrap();
+        let bobs = SessionToken::generate();
+        db.add_session_token(bobs.clone(), bob.clone()).unwrap();
+        let taken = first.refresh_token.unwrap();
+        assert_eq!(db.take_refresh_token(&taken).unwrap(), Some(alice.clone()));
+
+        let new = EnteredPassword::new("new".to_string());
+        change_password(&db, &DomainConfig::default(), &header, new).unwrap();
+        assert_eq!(db.get_session_token(&first.token).unwrap(), None);
+        assert_eq!(db.get_session_token(&second.token).unwrap(), None);
+        let refresh_token = second.refresh_token.unwrap();
+        assert_eq!(db.take_refresh_token(&refresh_token).unwrap(), None);
+        assert!(!db.has_session(&alice).unwrap());
+        assert_eq!(db.get_session_token(&bobs).unwrap(), Some(bob));
+    }
+}
@@ -0,0 +1,82 @@
+//! Runs the `admin` binary against a sled database in a temporary directory.
+
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use model_testing::{login, sled_db::SledDb, AuthHeader, EnteredPassword, UserId};
+use uuid::Uuid;
+
+/// Removes the database directory when dropped, also if the test fails.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn admin(db_path: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_admin"))
+        .args(args)
+        .env("SIMTEST_DB_PATH", db_path)
+        .env_remove("SIMTEST_CONFIG_FILE")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn succeeds(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn admin_manages_the_users_of_the_database() {
+    let dir = TempDir(env::temp_dir().join(format!("admin-test-{}", Uuid::new_v4())));
+    let db_path = dir.0.join("db");
+
+    succeeds(admin(&db_path, &["add-user", "alice"], "correct horse\n"));
+    succeeds(admin(&db_path, &["add-user", "bob"], "battery staple\n"));
+    assert!(!admin(&db_path, &["add-user", "bob"], "battery staple\n")
+        .status
+        .success());
+    assert_eq!(
+        succeeds(admin(&db_path, &["list-users"], "")),
+        "alice\nbob\n"
+    );
+
+    succeeds(admin(
+        &db_path,
+        &["reset-password", "alice"],
+        "staple horse\n",
+    ));
+    succeeds(admin(&db_path, &["delete-user", "bob"], ""));
+    assert_eq!(succeeds(admin(&db_path, &["list-users"], "")), "alice\n");
+
+    let usage = admin(&db_path, &["remove-user", "alice"], "");
+    assert_eq!(usage.status.code(), Some(2));
+
+    let db = SledDb::open(&db_path).unwrap();
+    let alice = UserId("alice".to_string());
+    let header =
+        |password: &str| AuthHeader::basic(&alice, &EnteredPassword::new(password.to_string()));
+    assert!(login(&db, &header("correct horse")).is_err());
+    assert_eq!(login(&db, &header("staple horse")).unwrap(), alice);
+}
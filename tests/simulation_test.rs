@@ -1,29 +1,50 @@
 #![feature(format_args_capture)]
 
 use std::{
-    collections::{HashMap, HashSet},
-    error,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, bail};
 use error::Error;
 use fail::fail_point;
+#[cfg(feature = "dashmap")]
+use model_testing::concurrent_db;
+#[cfg(feature = "sled")]
+use model_testing::sled_db::SledDb;
 use model_testing::{
-    can_access_secret,
-    db::{Db, DbResult},
-    in_memory_db, login, logout, register, EncodedPassword, EnteredPassword, LoginError, UserId,
+    api, can_access_secret_with,
+    clock::{Clock, ManualClock},
+    db::{Db, DbError, DbErrorKind, DbResult},
+    in_memory_db,
+    latency_db::LatencyDb,
+    login, login_with, logout_with, register,
+    shadow_db::{ShadowDb, StateMismatch},
+    AuthHeader, DomainConfig, EncodedPassword, EnteredPassword, LoginError, RegisterError, Role,
+    SessionPolicy, UserId, UserProfile, DEFAULT_MAX_PASSWORD_LEN,
 };
-use quickcheck::Arbitrary;
+use quickcheck::{Arbitrary, QuickCheck, TestResult};
 use quickcheck_macros::quickcheck;
 
 #[derive(Clone, Debug)]
 enum Op {
     Register(UserId, Pass),
+    /// Registers the user with each password at once, from a thread per password.
+    ConcurrentRegister(UserId, Vec<Pass>),
     LoginWithCorrectPw(UserId),
     LoginWithWrongPw(UserId),
     Logout(UserId),
     AccessSecret(UserId),
+    /// Advances the clock the ops run on, see `SIM_SESSION_TTL`.
+    Wait(Duration),
+    /// Turns the failpoint on for good.
     Fail(String),
+    /// Makes the next calls through the failpoint fail, as many as given, and turns it off after
+    /// them. Not generated, as concurrent simulations would take each other's failures.
+    FailSchedule(String, usize),
 }
 
 use Op::*;
@@ -31,16 +52,88 @@ use Op::*;
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 struct UserName(String);
 
+/// How long sessions last in simulations, so that `Op::Wait` can expire them.
+const SIM_SESSION_TTL: Duration = Duration::from_secs(60);
+
 const TEST_USERS: &[&str] = &[
-    "Alice", "Bob", "Carol", "David", "Erin", "Frank"
-    // , "Greta", "Holger", "Isabelle", "Jacob",
-    // "Kate", "Larry", "Margaret", "Noah", "Olivia", "Paul", "Quinn", "Robert", "Susan", "Thomas",
-    // "Ursula", "Vincent", "Wanda", "Xavier", "Yvonne", "Zachary",
+    "Alice", "Bob", "Carol", "David", "Erin", "Frank", "Greta", "Holger", "Isabelle", "Jacob",
+    "Kate", "Larry", "Margaret", "Noah", "Olivia", "Paul", "Quinn", "Robert", "Susan", "Thomas",
+    "Ursula", "Vincent", "Wanda", "Xavier", "Yvonne", "Zachary",
 ];
 
+/// Shape of the workload the simulator generates.
+#[derive(Clone, Debug)]
+struct SimConfig {
+    /// How many distinct users the ops pick from. Small pools make ops on the same user more
+    /// likely, large ones fill up the `Db`.
+    users: usize,
+    /// Whether ops may inject faults. Failpoints stay active until the end of the simulation.
+    faults: bool,
+    weights: OpWeights,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            users: 6,
+            faults: true,
+            weights: OpWeights::default(),
+        }
+    }
+}
+
+/// Relative probabilities of the ops, to bias workloads, e.g. towards registrations to fill up
+/// the `Db` before anyone logs in.
+#[derive(Clone, Debug)]
+struct OpWeights {
+    register: u32,
+    login_with_correct_pw: u32,
+    login_with_wrong_pw: u32,
+    logout: u32,
+    access_secret: u32,
+    wait: u32,
+    /// 0 by default, so that workloads stay single-threaded unless asked otherwise.
+    concurrent_register: u32,
+    /// Out of 256, how many ops inject a fault instead, if faults are enabled at all.
+    fault_rate: u8,
+}
+
+/// Every single-threaded op is equally likely, with a fault in about one op out of 13.
+impl Default for OpWeights {
+    fn default() -> Self {
+        Self {
+            register: 1,
+            login_with_correct_pw: 1,
+            login_with_wrong_pw: 1,
+            logout: 1,
+            access_secret: 1,
+            wait: 1,
+            concurrent_register: 0,
+            fault_rate: 20,
+        }
+    }
+}
+
+impl SimConfig {
+    fn user_name(&self, g: &mut quickcheck::Gen) -> UserName {
+        nth_user(usize::arbitrary(g) % self.users)
+    }
+
+    fn ops(&self, g: &mut quickcheck::Gen, len: usize) -> Vec<Op> {
+        (0..len).map(|_| Op::arbitrary_with(g, self)).collect()
+    }
+}
+
+fn nth_user(i: usize) -> UserName {
+    match TEST_USERS.get(i) {
+        Some(name) => UserName(name.to_string()),
+        None => UserName(format!("User{}", i)),
+    }
+}
+
 impl Arbitrary for UserName {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-        UserName(g.choose(TEST_USERS).unwrap().to_string())
+        SimConfig::default().user_name(g)
     }
 }
 
@@ -56,7 +149,10 @@ struct Pass(String);
 impl Arbitrary for Pass {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
         let mut s = String::arbitrary(g);
-        while s.is_empty() || s.chars().any(|c| c.is_control()) {
+        while s.is_empty()
+            || s.len() > DEFAULT_MAX_PASSWORD_LEN
+            || s.chars().any(|c| c.is_control())
+        {
             s = String::arbitrary(g);
         }
         Self(s)
@@ -71,251 +167,919 @@ impl Pass {
 
 impl Arbitrary for Op {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-        if u8::arbitrary(g) < 20 {
-            let fail_points = vec![
-                "db.register",
-                "db.add_session",
-                "db.remove_session",
-                "db.get_pw",
-                "db.has_session",
-            ];
-            if !fail_points.is_empty() {
-                return Op::Fail(g.choose(&fail_points).unwrap().to_string());
-            }
+        Op::arbitrary_with(g, &SimConfig::default())
+    }
+}
+
+impl Op {
+    fn arbitrary_with(g: &mut quickcheck::Gen, config: &SimConfig) -> Self {
+        if config.faults && u8::arbitrary(g) < config.weights.fault_rate {
+            return Op::Fail(g.choose(FAIL_POINTS).unwrap().to_string());
         }
 
-        let user_id = UserName::arbitrary(g);
+        let user_id = config.user_name(g);
         let pass = Pass::arbitrary(g);
-        g.choose(&[
-            Op::Register(user_id.id(), pass),
-            Op::LoginWithCorrectPw(user_id.id()),
-            Op::LoginWithWrongPw(user_id.id()),
-            Op::Logout(user_id.id()),
-            Op::AccessSecret(user_id.id()),
-        ])
-        .unwrap()
-        .clone()
+        let racing = (0..2 + usize::arbitrary(g) % 4)
+            .map(|_| Pass::arbitrary(g))
+            .collect();
+        // Up to twice the TTL, so that about half of the waits expire sessions
+        let wait = Duration::from_secs(u64::arbitrary(g) % (2 * SIM_SESSION_TTL.as_secs() + 1));
+        let weights = &config.weights;
+        let weighted = vec![
+            (weights.register, Op::Register(user_id.id(), pass)),
+            (
+                weights.concurrent_register,
+                Op::ConcurrentRegister(user_id.id(), racing),
+            ),
+            (
+                weights.login_with_correct_pw,
+                Op::LoginWithCorrectPw(user_id.id()),
+            ),
+            (
+                weights.login_with_wrong_pw,
+                Op::LoginWithWrongPw(user_id.id()),
+            ),
+            (weights.logout, Op::Logout(user_id.id())),
+            (weights.access_secret, Op::AccessSecret(user_id.id())),
+            (weights.wait, Op::Wait(wait)),
+        ];
+        let total: u32 = weighted.iter().map(|(weight, _)| weight).sum();
+        assert!(total > 0, "all op weights are 0");
+        let mut pick = u32::arbitrary(g) % total;
+        for (weight, op) in weighted {
+            if pick < weight {
+                return op;
+            }
+            pick -= weight;
+        }
+        unreachable!("picked below the total weight")
     }
 }
 
-struct FailDb {
-    inner: Box<dyn Db>,
+struct FailDb<D> {
+    inner: D,
 }
 
-impl FailDb {
-    fn new(inner: impl Db + 'static) -> Self {
-        Self {
-            inner: Box::new(inner),
-        }
+impl<D: Db> FailDb<D> {
+    fn new(inner: D) -> Self {
+        Self { inner }
+    }
+
+    /// Checks the failpoint `db.<method>` before forwarding to the inner `Db`.
+    fn fail_point(&self, method: &str) -> DbResult {
+        let name = format!("db.{}", method);
+        fail_point!(&name, |_| {
+            INJECTED.with(|injected| injected.borrow_mut().push(name.clone()));
+            COVERAGE.with(|coverage| *coverage.borrow_mut().entry(name.clone()).or_insert(0) += 1);
+            Err(injected(&name))
+        });
+        Ok(())
     }
 }
 
-impl Db for FailDb {
-    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
-        fail_point!("db.register", |_| Err(
-            anyhow!("db.register failpoint").into()
-        ));
-        self.inner.register(user_id, password)
+thread_local! {
+    /// The failpoints that failed a call on this thread, in order, so that simulations can tell
+    /// which of their failures were scheduled.
+    static INJECTED: RefCell<Vec<String>> = RefCell::new(Vec::new());
+}
+
+fn take_injected() -> Vec<String> {
+    INJECTED.with(|injected| injected.take())
+}
+
+thread_local! {
+    /// How many failures each failpoint injected on this thread, see `take_coverage`.
+    static COVERAGE: RefCell<BTreeMap<String, usize>> = RefCell::new(BTreeMap::new());
+}
+
+/// How many failures each failpoint injected on this thread since the last call, to tell
+/// whether simulations explored failure paths at all.
+fn take_coverage() -> BTreeMap<String, usize> {
+    COVERAGE.with(|coverage| coverage.take())
+}
+
+/// Prints how often each failpoint fired and which of `FAIL_POINTS` never did.
+fn print_coverage(coverage: &BTreeMap<String, usize>) {
+    println!("failpoints that fired:");
+    for (fail_point, hits) in coverage {
+        println!("  {fail_point}: {hits}");
     }
+    let missed = FAIL_POINTS
+        .iter()
+        .filter(|fail_point| !coverage.contains_key(**fail_point))
+        .collect::<Vec<_>>();
+    println!("failpoints that never fired: {:?}", missed);
+}
 
-    fn add_session(&self, user_id: UserId) -> DbResult {
-        fail_point!("db.add_session", |_| Err(anyhow!(
-            "db.add_session failpoint"
-        )
-        .into()));
-        self.inner.add_session(user_id)
+/// The `fail` action of `Op::FailSchedule`.
+fn fail_schedule_action(failures: usize) -> String {
+    format!("{}*return->off", failures)
+}
+
+/// Implements `Db` for `FailDb` with a failpoint `db.<method>` in front of every method, and
+/// lists these failpoints in `FAIL_POINTS`, so that the list can't miss a method.
+macro_rules! fail_db {
+    ($($method:ident),* $(,)?) => {
+        /// The failpoints that generated `Op::Fail`s pick from, one per method of `FailDb`.
+        const FAIL_POINTS: &[&str] = &[$(concat!("db.", stringify!($method))),*];
+
+        impl<D: Db> Db for FailDb<D> {
+            model_testing::delegate_db!(inner, before = fail_point; $($method),*);
+        }
+    };
+}
+
+fail_db!(
+    register,
+    add_session,
+    remove_session,
+    remove_all_sessions,
+    get_pw,
+    has_session,
+    get_secret,
+    set_secret,
+    touch_user,
+    last_activity_before,
+    count_users,
+    count_sessions,
+    add_session_token,
+    get_session_token,
+    register_with_profile,
+    get_profile,
+    set_totp_secret,
+    get_totp_secret,
+    set_role,
+    get_role,
+    touch_session,
+    get_session_times,
+    unregister,
+    set_pw,
+    list_users,
+    user_exists,
+    add_refresh_token,
+    take_refresh_token,
+    remove_refresh_tokens,
+    transaction,
+    rename_user,
+    iter_sessions,
+    ping,
+    with_readonly_view,
+    cas_pw,
+    push_password_history,
+    get_password_history,
+    secret_access_count,
+    accept_totp_step,
+);
+
+/// The in-memory backend without its deliberate password overwrite bug.
+#[derive(Default)]
+struct FixedDb {
+    inner: in_memory_db::Db,
+    passwords: Mutex<HashMap<UserId, EncodedPassword>>,
+}
+
+impl Db for FixedDb {
+    fn register(&self, user_id: UserId, password: EncodedPassword) -> DbResult {
+        self.register_with_profile(user_id, password, UserProfile::default())
     }
 
-    fn remove_session(&self, user_id: &UserId) -> DbResult {
-        fail_point!("db.remove_session", |_| Err(anyhow!(
-            "db.remove_session failpoint"
-        )
-        .into()));
-        self.inner.remove_session(user_id)
+    fn register_with_profile(
+        &self,
+        user_id: UserId,
+        password: EncodedPassword,
+        profile: UserProfile,
+    ) -> DbResult {
+        self.inner
+            .register_with_profile(user_id.clone(), password.clone(), profile)?;
+        self.passwords.lock().unwrap().insert(user_id, password);
+        Ok(())
     }
 
     fn get_pw(&self, user_id: &UserId) -> DbResult<Option<EncodedPassword>> {
-        fail_point!("db.get_pw", |_| Err(anyhow!("db.get_pw failpoint").into()));
-        self.inner.get_pw(user_id)
+        Ok(self.passwords.lock().unwrap().get(user_id).cloned())
     }
 
-    fn has_session(&self, user_id: &UserId) -> DbResult<bool> {
-        fail_point!("db.has_session", |_| Err(anyhow!(
-            "db.has_session failpoint"
-        )
-        .into()));
-        self.inner.has_session(user_id)
+    fn unregister(&self, user_id: &UserId) -> DbResult {
+        self.inner.unregister(user_id)?;
+        self.passwords.lock().unwrap().remove(user_id);
+        Ok(())
+    }
+
+    fn set_pw(&self, user_id: &UserId, password: EncodedPassword) -> DbResult {
+        self.inner.set_pw(user_id, password.clone())?;
+        self.passwords
+            .lock()
+            .unwrap()
+            .insert(user_id.clone(), password);
+        Ok(())
+    }
+
+    fn cas_pw(
+        &self,
+        user_id: &UserId,
+        expected: &EncodedPassword,
+        new: EncodedPassword,
+    ) -> DbResult<bool> {
+        let mut passwords = self.passwords.lock().unwrap();
+        match passwords.get(user_id) {
+            Some(stored) if stored.as_str() == expected.as_str() => {}
+            Some(_) => return Ok(false),
+            // not registered, which the inner db reports
+            None => return self.inner.cas_pw(user_id, expected, new),
+        }
+        self.inner.set_pw(user_id, new.clone())?;
+        passwords.insert(user_id.clone(), new);
+        Ok(true)
     }
+
+    fn rename_user(&self, old: &UserId, new: UserId) -> DbResult {
+        self.inner.rename_user(old, new.clone())?;
+        let mut passwords = self.passwords.lock().unwrap();
+        if let Some(password) = passwords.remove(old) {
+            passwords.insert(new, password);
+        }
+        Ok(())
+    }
+
+    model_testing::delegate_db!(
+        inner;
+        add_session,
+        remove_session,
+        remove_all_sessions,
+        has_session,
+        get_secret,
+        set_secret,
+        touch_user,
+        last_activity_before,
+        count_users,
+        count_sessions,
+        add_session_token,
+        get_session_token,
+        get_profile,
+        set_totp_secret,
+        get_totp_secret,
+        set_role,
+        get_role,
+        touch_session,
+        get_session_times,
+        list_users,
+        user_exists,
+        add_refresh_token,
+        take_refresh_token,
+        remove_refresh_tokens,
+        iter_sessions,
+        ping,
+        push_password_history,
+        get_password_history,
+        secret_access_count,
+        accept_totp_step,
+    );
 }
-fn auth_header(user: &UserId, pass: &Pass) -> String {
+
+fn auth_header(user: &UserId, pass: &Pass) -> AuthHeader {
     let encoded = base64::encode(format!("{}:{}", user.0, pass.0));
-    format!("Basic {encoded}")
+    AuthHeader::new(format!("Basic {encoded}"))
+}
+
+fn injected(fail_point: &str) -> DbError {
+    DbError::new(DbErrorKind::Injected, anyhow!("{} failpoint", fail_point))
 }
 
 fn assert_failpoint_err(e: impl Error + Send + Sync + 'static) -> anyhow::Result<()> {
-    if e.to_string().contains("failpoint") {
+    let e = anyhow::Error::new(e);
+    let injected = e
+        .chain()
+        .any(|cause| match cause.downcast_ref::<DbError>() {
+            Some(db_error) => db_error.kind() == DbErrorKind::Injected,
+            None => false,
+        });
+    if injected {
         Ok(())
     } else {
-        Err(e.into())
+        Err(e)
     }
 }
 
-fn run_simulator(ops: Vec<Op>) -> anyhow::Result<bool> {
-    // eprintln!("simulating ops {:?}", ops);
-    let db = FailDb::new(in_memory_db::init_db());
-    let mut not_registered = HashSet::new();
-    let mut registered = HashMap::new();
-    let mut sessions = HashSet::new();
-    let mut no_session = HashSet::new();
-    for op in ops {
-        // eprintln!("Handling Op {:?}", op);
-        match op {
-            Op::Register(user_id, pass) => {
-                if !registered.contains_key(&user_id) {
-                    match register(&db, user_id.clone(), pass.entered_password()) {
-                        Ok(()) => {
-                            not_registered.remove(&user_id);
-                            registered.insert(user_id, pass);
+fn run_simulator(ops: Vec<Op>) -> SimOutcome {
+    run_simulator_on(FailDb::new(in_memory_db::init_db()), ops)
+}
+
+/// What the simulator expects the `Db` to contain.
+#[derive(Debug)]
+struct ModelInvariants {
+    registered: HashMap<UserId, Pass>,
+    not_registered: HashSet<UserId>,
+    /// When each session started, by `clock`.
+    sessions: HashMap<UserId, Instant>,
+    no_session: HashSet<UserId>,
+    /// Only advanced by `Op::Wait`.
+    clock: Arc<ManualClock>,
+    /// What the ops and checks run with: `clock`, and sessions that expire after
+    /// `SIM_SESSION_TTL`.
+    config: DomainConfig,
+    /// The failpoints of `Op::FailSchedule`s, with how many failures they were scheduled to
+    /// inject and did inject.
+    fail_schedules: HashMap<String, (usize, usize)>,
+}
+
+impl Default for ModelInvariants {
+    fn default() -> Self {
+        let clock = Arc::new(ManualClock::default());
+        Self {
+            registered: HashMap::new(),
+            not_registered: HashSet::new(),
+            sessions: HashMap::new(),
+            no_session: HashSet::new(),
+            config: DomainConfig {
+                clock: clock.clone(),
+                session_policy: SessionPolicy {
+                    ttl: Some(SIM_SESSION_TTL),
+                    ..SessionPolicy::default()
+                },
+                ..DomainConfig::default()
+            },
+            clock,
+            fail_schedules: HashMap::new(),
+        }
+    }
+}
+
+impl ModelInvariants {
+    /// Advances the clock and forgets the sessions that expired meanwhile. The `Db` ends those
+    /// the next time they are used or logged into.
+    fn wait(&mut self, by: Duration) {
+        self.clock.advance(by);
+        let now = self.clock.now();
+        self.sessions
+            .retain(|_, started| now.saturating_duration_since(*started) <= SIM_SESSION_TTL);
+    }
+
+    /// Counts the failures injected on this thread since the last call against the schedules of
+    /// their failpoints, none of which may inject more than scheduled. Fewer are fine, the ops
+    /// may not have called the failpoint often enough; tests that rely on the failures check
+    /// `injected_by_schedule`.
+    fn check_injected(&mut self) -> anyhow::Result<()> {
+        for fail_point in take_injected() {
+            self.check_scheduled(&fail_point)?;
+        }
+        Ok(())
+    }
+
+    fn check_scheduled(&mut self, fail_point: &str) -> anyhow::Result<()> {
+        let (scheduled, injected) = match self.fail_schedules.get_mut(fail_point) {
+            Some((scheduled, injected)) => (*scheduled, injected),
+            None => return Ok(()),
+        };
+        *injected += 1;
+        if *injected > scheduled {
+            bail!("{} failed more often than scheduled", fail_point)
+        }
+        Ok(())
+    }
+
+    /// How many failures the `Op::FailSchedule` of the failpoint injected so far.
+    fn injected_by_schedule(&self, fail_point: &str) -> Option<usize> {
+        self.fail_schedules
+            .get(fail_point)
+            .map(|(_, injected)| *injected)
+    }
+
+    /// Checks that the model doesn't contradict itself and that `db` agrees with it.
+    ///
+    /// The checks probe `db` by logging users in and out. When an injected fault prevents
+    /// restoring a user's session state, the model is updated to match.
+    fn check_all(&mut self, db: &impl Db) -> anyhow::Result<()> {
+        self.check_stored(db)?;
+        self.check_registered(db)?;
+        self.check_sessions(db)
+    }
+
+    /// Registered users have the password they registered with stored. Looks them up in one
+    /// batch.
+    ///
+    /// Unlike the logins of `check_registered`, this points right at the user whose password
+    /// changed, even if it changed as a side effect of an op for another user.
+    fn check_stored(&self, db: &impl Db) -> anyhow::Result<()> {
+        let (users, passes): (Vec<_>, Vec<_>) = self
+            .registered
+            .iter()
+            .map(|(user_id, pass)| (user_id.clone(), pass))
+            .unzip();
+        let passwords = match db.get_pw_batch(&users) {
+            Ok(passwords) => passwords,
+            Err(e) => return assert_failpoint_err(e),
+        };
+        for ((user_id, pass), password) in users.iter().zip(passes).zip(passwords) {
+            let password = match password {
+                Some(password) => password,
+                None => bail!("{:?} registered but has no password stored", user_id),
+            };
+            if !password.verify(&pass.entered_password())? {
+                bail!(
+                    "{:?} has a different password stored than registered",
+                    user_id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Registered users can log in and out, and only access secrets while logged in.
+    fn check_registered(&mut self, db: &impl Db) -> anyhow::Result<()> {
+        for (user_id, pass) in &self.registered {
+            if self.not_registered.contains(&user_id) {
+                bail!("{:?} in registered and unregistered at once", user_id);
+            }
+            let auth_header = auth_header(user_id, pass);
+            if self.sessions.contains_key(user_id) {
+                match logout_with(db, &self.config, &auth_header) {
+                    Ok(()) => match login_with(db, &self.config, &auth_header) {
+                        Ok(_) => {
+                            self.sessions.insert(user_id.clone(), self.clock.now());
                         }
                         Err(e) => {
                             assert_failpoint_err(e)?;
-                            not_registered.insert(user_id);
+                            self.sessions.remove(user_id);
                         }
+                    },
+                    Err(e) => {
+                        assert_failpoint_err(e)?;
                     }
                 }
-            }
-            Op::LoginWithCorrectPw(user_id) => {
-                if let Some(pass) = registered.get(&user_id) {
-                    let auth_header = auth_header(&user_id, &pass);
-                    match login(&db, &auth_header) {
-                        Ok(()) => {
-                            sessions.insert(user_id);
-                        }
-                        Err(e) => {
-                            assert_failpoint_err(e)?;
-                            no_session.insert(user_id);
-                        }
+            } else {
+                match can_access_secret_with(db, &self.config, user_id, Role::User) {
+                    Ok(true) => {
+                        bail!("{:?} has no session but can access secret", user_id);
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        assert_failpoint_err(e)?;
                     }
                 }
-            }
-            Op::LoginWithWrongPw(user_id) => {
-                let wrong_pw = Pass("hunter2".to_string());
-                let auth_header = auth_header(&user_id, &wrong_pw);
-                match registered.get(&user_id) {
-                    Some(_existing_pw) => match login(&db, &auth_header) {
-                        Ok(_) => return Ok(false),
-                        Err(LoginError::InvalidCredentials) => {}
-                        Err(e) => {
+                match login_with(db, &self.config, &auth_header) {
+                    Ok(_) => {
+                        if let Err(e) = logout_with(db, &self.config, &auth_header) {
                             assert_failpoint_err(e)?;
+                            self.sessions.insert(user_id.clone(), self.clock.now());
+                            self.no_session.remove(user_id);
                         }
-                    },
-                    None => match login(&db, &auth_header) {
-                        Ok(()) => return Ok(false),
-                        Err(LoginError::NotRegistered) => {}
-                        Err(e) => {
-                            assert_failpoint_err(e)?;
-                        }
-                    },
-                };
-            }
-            Op::Logout(user_id) => {
-                let pass = registered
-                    .get(&user_id)
-                    .cloned()
-                    .unwrap_or(Pass("hunter2".to_string()));
-                let auth_header = auth_header(&user_id, &pass);
-                match logout(&db, &auth_header) {
-                    Ok(()) => {
-                        sessions.remove(&user_id);
                     }
                     Err(e) => {
                         assert_failpoint_err(e)?;
                     }
                 }
             }
-            Op::AccessSecret(user_id) => match can_access_secret(&db, &user_id) {
-                Ok(b) => {
-                    if sessions.contains(&user_id) != b {
-                        return Ok(false);
-                    }
+        }
+        Ok(())
+    }
+
+    /// Users with a session are registered and can access secrets.
+    fn check_sessions(&self, db: &impl Db) -> anyhow::Result<()> {
+        for session in self.sessions.keys() {
+            if self.no_session.contains(&session) {
+                bail!("{:?} in session and no_session at once", session);
+            }
+            if !self.registered.contains_key(session) {
+                bail!("{:?} in session but not registered", session);
+            }
+            match can_access_secret_with(db, &self.config, session, Role::User) {
+                Ok(true) => {}
+                Ok(false) => {
+                    bail!("{:?} in session but can't access secret", session);
                 }
                 Err(e) => {
                     assert_failpoint_err(e)?;
                 }
-            },
-            Op::Fail(fail_point_name) => {
-                fail::cfg(fail_point_name, "return").unwrap();
             }
         }
+        Ok(())
+    }
+}
 
-        for (user_id, pass) in &registered {
-            if not_registered.contains(&user_id) {
-                bail!("{:?} in registered and unregistered at once", user_id);
+/// How a simulation ended.
+#[derive(Debug)]
+enum SimOutcome {
+    Passed,
+    /// The op at `op_index` or the invariant checks after it found the `Db` disagreeing with the
+    /// model.
+    ModelViolation {
+        detail: String,
+        op_index: usize,
+    },
+    /// The simulation itself couldn't be run as specified.
+    HarnessError(anyhow::Error),
+}
+
+impl SimOutcome {
+    fn passed(&self) -> bool {
+        matches!(self, SimOutcome::Passed)
+    }
+}
+
+/// Failpoints are global, so simulations run one at a time, each starting and ending with all
+/// failpoints off.
+fn run_simulator_on(db: impl Db, ops: Vec<Op>) -> SimOutcome {
+    let _scenario = fail::FailScenario::setup();
+    let mut model = ModelInvariants::default();
+    take_injected();
+    for (op_index, op) in ops.into_iter().enumerate() {
+        let configured = match &op {
+            Op::Fail(fail_point_name) => {
+                model.fail_schedules.remove(fail_point_name);
+                Some((fail_point_name, "return".to_string()))
             }
-            let auth_header = auth_header(user_id, pass);
-            if sessions.contains(user_id) {
-                match logout(&db, &auth_header) {
+            Op::FailSchedule(fail_point_name, failures) => {
+                model
+                    .fail_schedules
+                    .insert(fail_point_name.clone(), (*failures, 0));
+                Some((fail_point_name, fail_schedule_action(*failures)))
+            }
+            _ => None,
+        };
+        if let Some((fail_point_name, action)) = configured {
+            if let Err(e) = fail::cfg(fail_point_name, &action) {
+                return SimOutcome::HarnessError(anyhow!("configuring {}: {}", fail_point_name, e));
+            }
+        }
+        let detail = match apply_op(&db, &mut model, op.clone()) {
+            Ok(()) => match model.check_all(&db) {
+                Ok(()) => match model.check_injected() {
+                    Ok(()) => continue,
+                    Err(e) => format!("{:?} or the checks after it: {:#}", op, e),
+                },
+                Err(e) => format!("invariant broken after {:?}: {:#}", op, e),
+            },
+            Err(e) => format!("{:?}: {:#}", op, e),
+        };
+        return SimOutcome::ModelViolation { detail, op_index };
+    }
+    SimOutcome::Passed
+}
+
+/// Runs the op against `db` and updates the model with its expected effect.
+fn apply_op(db: &impl Db, model: &mut ModelInvariants, op: Op) -> anyhow::Result<()> {
+    match op {
+        Op::Register(user_id, pass) => {
+            if !model.registered.contains_key(&user_id) {
+                match register(db, user_id.clone(), pass.entered_password()) {
                     Ok(()) => {
-                        if let Err(e) = login(&db, &auth_header) {
-                            assert_failpoint_err(e)?;
-                            sessions.remove(user_id);
-                        }
+                        model.not_registered.remove(&user_id);
+                        model.registered.insert(user_id, pass);
                     }
                     Err(e) => {
                         assert_failpoint_err(e)?;
+                        model.not_registered.insert(user_id);
                     }
                 }
-            } else {
-                match can_access_secret(&db, user_id) {
-                    Ok(true) => {
-                        bail!("{:?} has no session but can access secret", user_id);
+            }
+        }
+        Op::ConcurrentRegister(user_id, passes) => {
+            let results = crossbeam_utils::thread::scope(|scope| {
+                let registrations = passes
+                    .iter()
+                    .map(|pass| {
+                        let user_id = user_id.clone();
+                        scope.spawn(move |_| register(db, user_id, pass.entered_password()))
+                    })
+                    .collect::<Vec<_>>();
+                registrations
+                    .into_iter()
+                    .map(|registration| registration.join().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap();
+
+            let mut winners = Vec::new();
+            for (pass, result) in passes.iter().zip(results) {
+                match result {
+                    Ok(()) => winners.push(pass),
+                    Err(RegisterError::AlreadyExists) => {}
+                    Err(e) => assert_failpoint_err(e)?,
+                }
+            }
+            match winners[..] {
+                [] => {
+                    if !model.registered.contains_key(&user_id) {
+                        model.not_registered.insert(user_id);
                     }
-                    Ok(false) => {}
-                    Err(e) => {
-                        assert_failpoint_err(e)?;
+                }
+                [winner] => {
+                    if model.registered.contains_key(&user_id) {
+                        bail!("registered again while already registered");
                     }
+                    check_single_winner(db, &user_id, &passes, winner)?;
+                    model.not_registered.remove(&user_id);
+                    model.registered.insert(user_id, winner.clone());
                 }
-                match login(&db, &auth_header) {
-                    Ok(()) => {
-                        if let Err(e) = logout(&db, &auth_header) {
-                            assert_failpoint_err(e)?;
-                            sessions.insert(user_id.clone());
-                            no_session.remove(user_id);
+                _ => bail!("{} of the racing registrations succeeded", winners.len()),
+            }
+        }
+        Op::LoginWithCorrectPw(user_id) => {
+            if let Some(pass) = model.registered.get(&user_id) {
+                let auth_header = auth_header(&user_id, &pass);
+                match login_with(db, &model.config, &auth_header) {
+                    Ok(logged_in) => {
+                        if logged_in != user_id {
+                            bail!("logged in as {:?}", logged_in);
                         }
+                        // Logging in again keeps the session going, with its start time
+                        let now = model.clock.now();
+                        model.sessions.entry(user_id).or_insert(now);
                     }
                     Err(e) => {
                         assert_failpoint_err(e)?;
+                        model.no_session.insert(user_id);
                     }
                 }
             }
         }
-        for session in &sessions {
-            if no_session.contains(&session) {
-                bail!("{:?} in session and no_session at once", session);
-            }
-            if !registered.contains_key(session) {
-                bail!("{:?} in session but not registered", session);
+        Op::LoginWithWrongPw(user_id) => {
+            let wrong_pw = Pass("hunter2".to_string());
+            let auth_header = auth_header(&user_id, &wrong_pw);
+            match model.registered.get(&user_id) {
+                Some(_existing_pw) => match login_with(db, &model.config, &auth_header) {
+                    Ok(_) => bail!("logged in with a wrong password"),
+                    Err(LoginError::InvalidCredentials) => {}
+                    Err(e) => {
+                        assert_failpoint_err(e)?;
+                    }
+                },
+                None => match login_with(db, &model.config, &auth_header) {
+                    Ok(_) => bail!("logged in without registering"),
+                    Err(LoginError::NotRegistered) => {}
+                    Err(e) => {
+                        assert_failpoint_err(e)?;
+                    }
+                },
+            };
+        }
+        Op::Logout(user_id) => {
+            let pass = model
+                .registered
+                .get(&user_id)
+                .cloned()
+                .unwrap_or(Pass("hunter2".to_string()));
+            let auth_header = auth_header(&user_id, &pass);
+            match logout_with(db, &model.config, &auth_header) {
+                Ok(()) => {
+                    model.sessions.remove(&user_id);
+                }
+                Err(e) => {
+                    assert_failpoint_err(e)?;
+                }
             }
-            match can_access_secret(&db, session) {
-                Ok(true) => {}
-                Ok(false) => {
-                    bail!("{:?} in session but can't access secret", session);
+        }
+        Op::AccessSecret(user_id) => {
+            match can_access_secret_with(db, &model.config, &user_id, Role::User) {
+                Ok(b) => {
+                    if model.sessions.contains_key(&user_id) != b {
+                        bail!("access to secret was {} instead of {}", b, !b);
+                    }
                 }
                 Err(e) => {
                     assert_failpoint_err(e)?;
                 }
             }
         }
+        Op::Wait(by) => model.wait(by),
+        // Configured by `run_simulator_on`, as a failure to do so isn't the `Db`'s fault
+        Op::Fail(_) | Op::FailSchedule(..) => {}
     }
-    Ok(true)
+    Ok(())
 }
 
-#[quickcheck]
-fn simulate_login(ops: Vec<Op>) -> anyhow::Result<bool> {
-    eprintln!("Called with {} Ops {:?}", ops.len(), ops);
-    dbg!(run_simulator(ops))
+/// Only the password of the registration that succeeded verifies against the stored hash.
+fn check_single_winner(
+    db: &impl Db,
+    user_id: &UserId,
+    passes: &[Pass],
+    winner: &Pass,
+) -> anyhow::Result<()> {
+    let stored = match db.get_pw(user_id) {
+        Ok(Some(stored)) => stored,
+        Ok(None) => bail!("registration succeeded but no password is stored"),
+        Err(e) => return assert_failpoint_err(e),
+    };
+    if !stored.verify(&winner.entered_password())? {
+        bail!("the password of the successful registration isn't stored");
+    }
+    for pass in passes {
+        // Racing passwords may be the same as the winner's
+        if pass.0 != winner.0 && stored.verify(&pass.entered_password())? {
+            bail!("a password of a failed registration verifies");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the failpoint coverage of all runs, which `cargo test -- --nocapture` shows.
+#[test]
+fn simulate_login() {
+    take_coverage();
+    QuickCheck::new().quickcheck(simulate_login_once as fn(Vec<Op>) -> TestResult);
+    print_coverage(&take_coverage());
+}
+
+fn simulate_login_once(ops: Vec<Op>) -> TestResult {
+    match run_simulator(ops.clone()) {
+        SimOutcome::Passed => TestResult::passed(),
+        SimOutcome::ModelViolation { detail, op_index } => TestResult::error(format!(
+            "model violation at op {} of {}: {}\nops: {:?}",
+            op_index,
+            ops.len(),
+            detail,
+            ops
+        )),
+        SimOutcome::HarnessError(e) => TestResult::error(format!("harness error: {:#}", e)),
+    }
+}
+
+/// Bounds of the exhaustive search of `model_check`.
+#[derive(Clone, Debug)]
+struct ModelCheckBounds {
+    /// Longest op sequence to try.
+    max_len: usize,
+    /// How many distinct users the ops pick from.
+    users: usize,
+    /// Stops the search after this many sequences, to keep it tractable.
+    max_sequences: usize,
+}
+
+/// Every op on one of `users` users, without faults as failpoints would leak between runs.
+/// Each user has their own password, so that mixing up users is noticed.
+fn op_alphabet(users: usize) -> Vec<Op> {
+    (0..users)
+        .map(nth_user)
+        .flat_map(|user| {
+            let pass = Pass(format!("{}'s password", user.0));
+            vec![
+                Register(user.id(), pass),
+                LoginWithCorrectPw(user.id()),
+                LoginWithWrongPw(user.id()),
+                Logout(user.id()),
+                AccessSecret(user.id()),
+            ]
+        })
+        .collect()
+}
+
+/// Runs every sequence of ops from `op_alphabet`, shortest first, each against a fresh `Db`.
+/// Returns how many sequences passed, or the first one that didn't along with its outcome.
+fn model_check<D: Db>(
+    bounds: &ModelCheckBounds,
+    new_db: impl Fn() -> D,
+) -> Result<usize, (Vec<Op>, SimOutcome)> {
+    let alphabet = op_alphabet(bounds.users);
+    let mut checked = 0;
+    for len in 1..=bounds.max_len {
+        // Counts through all sequences of `len` ops, with the last op changing fastest
+        let mut indices = vec![0; len];
+        loop {
+            if checked == bounds.max_sequences {
+                return Ok(checked);
+            }
+            let ops = indices
+                .iter()
+                .map(|&i| alphabet[i].clone())
+                .collect::<Vec<_>>();
+            let outcome = run_simulator_on(new_db(), ops.clone());
+            if !outcome.passed() {
+                return Err((ops, outcome));
+            }
+            checked += 1;
+
+            match indices.iter().rposition(|&i| i + 1 < alphabet.len()) {
+                Some(pos) => {
+                    indices[pos] += 1;
+                    indices[pos + 1..].iter_mut().for_each(|i| *i = 0);
+                }
+                None => break,
+            }
+        }
+    }
+    Ok(checked)
+}
+
+#[test]
+fn model_check_two_users_up_to_three_ops() {
+    let bounds = ModelCheckBounds {
+        max_len: 3,
+        users: 2,
+        max_sequences: 10_000,
+    };
+    match model_check(&bounds, FixedDb::default) {
+        // 10 ops, so 10 + 10^2 + 10^3 sequences
+        Ok(checked) => assert_eq!(checked, 1110),
+        Err((ops, outcome)) => panic!("{:?} with ops {:?}", outcome, ops),
+    }
+}
+
+#[test]
+fn model_check_finds_password_overwrite_bug() {
+    let bounds = ModelCheckBounds {
+        max_len: 2,
+        users: 2,
+        max_sequences: 1000,
+    };
+    let (ops, outcome) = model_check(&bounds, in_memory_db::init_db)
+        .expect_err("the in-memory backend overwrites passwords");
+    assert!(
+        matches!(outcome, SimOutcome::ModelViolation { op_index: 1, .. }),
+        "{:?}",
+        outcome
+    );
+    assert!(
+        matches!(&ops[..], [Register(first, _), Register(second, _)] if first != second),
+        "{:?}",
+        ops
+    );
+}
+
+fn assert_passed(outcome: SimOutcome) {
+    assert!(outcome.passed(), "{:?}", outcome);
+}
+
+/// Pins an op sequence as a test that `run_simulator` passes on it. Takes the ops as the
+/// simulator prints them for a failed run, so they can be pasted right from the test output:
+///
+/// ```ignore
+/// sim_regression!(greta_registers_twice: [
+///     Register(UserId("Greta"), Pass("pw")),
+///     Fail("db.register"),
+///     LoginWithCorrectPw(UserId("Greta")),
+/// ]);
+/// ```
+macro_rules! sim_regression {
+    ($name:ident: [$($op:ident($($args:tt)*)),* $(,)?]) => {
+        #[test]
+        fn $name() {
+            assert_passed(run_simulator(vec![$(sim_op!($op($($args)*))),*]));
+        }
+    };
+}
+
+/// One op of `sim_regression!`, in its `Debug` format.
+macro_rules! sim_op {
+    (Register(UserId($user:literal), Pass($pass:literal))) => {
+        Register(UserId($user.to_string()), Pass($pass.to_string()))
+    };
+    (Fail($fail_point:literal)) => {
+        Fail($fail_point.to_string())
+    };
+    (FailSchedule($fail_point:literal, $failures:literal)) => {
+        FailSchedule($fail_point.to_string(), $failures)
+    };
+    (ConcurrentRegister(UserId($user:literal), [$(Pass($pass:literal)),* $(,)?])) => {
+        ConcurrentRegister(UserId($user.to_string()), vec![$(Pass($pass.to_string())),*])
+    };
+    (Wait($duration:tt)) => {
+        Wait(parse_debug_duration(stringify!($duration)))
+    };
+    ($op:ident(UserId($user:literal))) => {
+        $op(UserId($user.to_string()))
+    };
 }
 
+/// Reads a `Duration` back from its `Debug` format, like `61s` or `1.5ms`.
+fn parse_debug_duration(debug: &str) -> Duration {
+    let unit_start = debug
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or_else(|| panic!("{:?} has no unit", debug));
+    let (value, unit) = debug.split_at(unit_start);
+    let nanos_per_unit: u128 = match unit {
+        "s" => 1_000_000_000,
+        "ms" => 1_000_000,
+        "µs" => 1_000,
+        "ns" => 1,
+        _ => panic!("{:?} has an unknown unit", debug),
+    };
+    let (whole, fraction) = match value.find('.') {
+        Some(point) => (&value[..point], &value[point + 1..]),
+        None => (value, ""),
+    };
+    let digits = |digits: &str| -> u128 {
+        if digits.is_empty() {
+            0
+        } else {
+            digits
+                .parse()
+                .unwrap_or_else(|_| panic!("{:?} isn't a duration", debug))
+        }
+    };
+    let nanos = digits(whole) * nanos_per_unit
+        + digits(fraction) * nanos_per_unit / 10u128.pow(fraction.len() as u32);
+    Duration::from_nanos(nanos as u64)
+}
+
+sim_regression!(replayed_ops_run_as_a_test: [
+    Register(UserId("Greta"), Pass("D1—\u{10fffd}5")),
+    LoginWithWrongPw(UserId("Greta")),
+    LoginWithCorrectPw(UserId("Greta")),
+    AccessSecret(UserId("Greta")),
+    Logout(UserId("Greta")),
+    AccessSecret(UserId("Greta")),
+]);
+
 #[test]
 fn regression1() {
     let ops = vec![
@@ -326,7 +1090,137 @@ fn regression1() {
         Fail("db.register".to_string()),
         Register(UserId("Greta".to_string()), Pass("T".to_string())),
     ];
-    assert!(run_simulator(ops).unwrap());
+    assert_passed(run_simulator(ops));
+}
+
+sim_regression!(replayed_waits_and_concurrent_registrations_run_as_a_test: [
+    ConcurrentRegister(UserId("Greta"), [Pass("A"), Pass("B"), Pass("C")]),
+    LoginWithCorrectPw(UserId("Greta")),
+    Wait(61s),
+    AccessSecret(UserId("Greta")),
+    Wait(1.5s),
+    LoginWithCorrectPw(UserId("Greta")),
+]);
+
+/// Without the checks between the ops, which would end the expired session first, the second
+/// login finds it still in the `Db`.
+#[test]
+fn logging_in_after_the_ttl_starts_a_fresh_session() {
+    let db = in_memory_db::init_db();
+    let mut model = ModelInvariants::default();
+    let user_id = UserId("Greta".to_string());
+    let ops = vec![
+        Register(user_id.clone(), Pass("pw".to_string())),
+        LoginWithCorrectPw(user_id.clone()),
+        Wait(SIM_SESSION_TTL + Duration::from_secs(1)),
+        LoginWithCorrectPw(user_id.clone()),
+        AccessSecret(user_id),
+    ];
+    for op in ops {
+        if let Err(e) = apply_op(&db, &mut model, op.clone()) {
+            panic!("{:?}: {:#}", op, e);
+        }
+    }
+}
+
+#[test]
+fn debug_durations_are_read_back() {
+    for duration in &[
+        Duration::from_secs(61),
+        Duration::from_millis(1500),
+        Duration::from_micros(7),
+        Duration::from_nanos(0),
+    ] {
+        assert_eq!(parse_debug_duration(&format!("{:?}", duration)), *duration);
+    }
+}
+
+sim_regression!(scheduled_register_failure_is_followed_by_a_successful_retry: [
+    FailSchedule("db.register", 1),
+    Register(UserId("Greta"), Pass("pw")),
+    Register(UserId("Greta"), Pass("pw")),
+    LoginWithCorrectPw(UserId("Greta")),
+]);
+
+/// The regression above also passes if the failure is never injected, this makes sure it is.
+#[test]
+fn scheduled_failures_are_injected_as_scheduled() {
+    let _scenario = fail::FailScenario::setup();
+    let db = FailDb::new(in_memory_db::init_db());
+    let mut model = ModelInvariants::default();
+    let greta = UserId("Greta".to_string());
+    take_injected();
+    model
+        .fail_schedules
+        .insert("db.register".to_string(), (1, 0));
+    fail::cfg("db.register", &fail_schedule_action(1)).unwrap();
+
+    apply_op(
+        &db,
+        &mut model,
+        Register(greta.clone(), Pass("pw".to_string())),
+    )
+    .unwrap();
+    model.check_injected().unwrap();
+    assert!(model.not_registered.contains(&greta), "{:?}", model);
+    assert_eq!(model.injected_by_schedule("db.register"), Some(1));
+
+    apply_op(
+        &db,
+        &mut model,
+        Register(greta.clone(), Pass("pw".to_string())),
+    )
+    .unwrap();
+    model.check_injected().unwrap();
+    assert!(model.registered.contains_key(&greta), "{:?}", model);
+    assert_eq!(model.injected_by_schedule("db.register"), Some(1));
+}
+
+#[test]
+fn coverage_records_the_failpoints_that_fired() {
+    take_coverage();
+    let ops = vec![Fail("db.get_pw".to_string()), LoginWithWrongPw(alice())];
+    assert_passed(run_simulator(ops));
+    let coverage = take_coverage();
+    assert!(
+        coverage.get("db.get_pw").copied().unwrap_or(0) > 0,
+        "{:?}",
+        coverage
+    );
+}
+
+#[test]
+fn sessions_expire_once_the_clock_passes_their_ttl() {
+    let ops = vec![
+        Register(alice(), Pass("pw".to_string())),
+        LoginWithCorrectPw(alice()),
+        AccessSecret(alice()),
+        Wait(SIM_SESSION_TTL + Duration::from_secs(1)),
+        AccessSecret(alice()),
+    ];
+    assert_passed(run_simulator(ops.clone()));
+
+    // Without the checks between ops, which log users in again
+    let db = in_memory_db::init_db();
+    let mut model = ModelInvariants::default();
+    for op in ops {
+        apply_op(&db, &mut model, op).unwrap();
+    }
+    assert!(model.sessions.is_empty());
+    assert!(!can_access_secret_with(&db, &model.config, &alice(), Role::User).unwrap());
+}
+
+#[test]
+fn racing_registrations_of_one_user_have_a_single_winner() {
+    let alice = UserId("Alice".to_string());
+    let passes = (0..10).map(|i| Pass(format!("pw{}", i))).collect();
+    let ops = vec![
+        ConcurrentRegister(alice.clone(), passes),
+        LoginWithCorrectPw(alice.clone()),
+        ConcurrentRegister(alice, vec![Pass("A".to_string()), Pass("B".to_string())]),
+    ];
+    assert_passed(run_simulator_on(FixedDb::default(), ops.clone()));
+    assert_passed(run_simulator(ops));
 }
 
 #[test]
@@ -343,5 +1237,405 @@ fn custom_bug() {
         LoginWithCorrectPw(UserId("David".to_string())),
         LoginWithCorrectPw(UserId("Erin".to_string())),
     ];
-    assert!(run_simulator(ops).unwrap());
+    assert_passed(run_simulator(ops));
+}
+
+/// Runs a random workload against a correct backend with pools of users around the sizes at
+/// which count-dependent code paths could change behavior.
+fn simulate_user_pool(users: usize) {
+    simulate_user_pool_on(FixedDb::default(), users);
+}
+
+fn simulate_user_pool_on(db: impl Db, users: usize) {
+    let config = SimConfig {
+        users,
+        faults: false,
+        ..SimConfig::default()
+    };
+    let ops = config.ops(&mut quickcheck::Gen::new(100), 2 * users.max(10));
+    match run_simulator_on(db, ops.clone()) {
+        SimOutcome::Passed => {}
+        outcome => panic!("{:?} with ops {:?}", outcome, ops),
+    }
+}
+
+/// Runs random op sequences of `config`, each against a fresh `Db`, until `duration` is over,
+/// to find bugs too rare for the fixed number of quickcheck runs. Returns how many ops ran, or
+/// the first sequence that didn't pass along with its outcome.
+///
+/// quickcheck's generator can't be seeded, so a failure is reported with its ops rather than a
+/// seed. They can be replayed with `sim_regression!`.
+fn soak<D: Db>(
+    duration: Duration,
+    config: &SimConfig,
+    new_db: impl Fn() -> D,
+) -> Result<usize, (Vec<Op>, SimOutcome)> {
+    let deadline = Instant::now() + duration;
+    let mut g = quickcheck::Gen::new(100);
+    let mut ops_run = 0;
+    while Instant::now() < deadline {
+        let len = 1 + usize::arbitrary(&mut g) % (4 * config.users.max(5));
+        let ops = config.ops(&mut g, len);
+        let outcome = run_simulator_on(new_db(), ops.clone());
+        if !outcome.passed() {
+            return Err((ops, outcome));
+        }
+        ops_run += len;
+    }
+    Ok(ops_run)
+}
+
+fn soak_without_faults(duration: Duration) {
+    let config = SimConfig {
+        faults: false,
+        ..SimConfig::default()
+    };
+    match soak(duration, &config, FixedDb::default) {
+        Ok(ops_run) => println!("soaked for {:?}, {} ops", duration, ops_run),
+        Err((ops, outcome)) => panic!("{:?} with ops {:?}", outcome, ops),
+    }
+}
+
+/// Keeps `soak` working, see `soak_for_sim_soak_secs` for actual soaking.
+#[test]
+fn soaks_briefly() {
+    soak_without_faults(Duration::from_secs(1));
+}
+
+/// `SIM_SOAK_SECS=3600 cargo test --test failpoints --features fail/failpoints soak -- --ignored`
+#[test]
+#[ignore]
+fn soak_for_sim_soak_secs() {
+    let secs = env::var("SIM_SOAK_SECS")
+        .expect("SIM_SOAK_SECS isn't set")
+        .parse()
+        .expect("SIM_SOAK_SECS isn't a number of seconds");
+    soak_without_faults(Duration::from_secs(secs));
+}
+
+#[test]
+fn weights_bias_the_generated_ops() {
+    let config = SimConfig {
+        faults: false,
+        weights: OpWeights {
+            register: 20,
+            ..OpWeights::default()
+        },
+        ..SimConfig::default()
+    };
+    let ops = config.ops(&mut quickcheck::Gen::new(100), 1000);
+    let registrations = ops.iter().filter(|op| matches!(op, Register(..))).count();
+    // 20 of 25 on average
+    assert!(registrations > 700, "{} registrations", registrations);
+    assert!(ops.iter().all(|op| !matches!(op, Fail(_))));
+}
+
+#[test]
+fn simulates_pool_of_2_users() {
+    simulate_user_pool(2);
+}
+
+#[test]
+fn simulates_pool_of_4_users() {
+    simulate_user_pool(4);
+}
+
+#[test]
+fn simulates_pool_of_8_users() {
+    simulate_user_pool(8);
+}
+
+#[test]
+fn simulates_pool_of_50_users() {
+    simulate_user_pool(50);
+}
+
+/// The in-memory backend overwrites a password whenever another user registers, which a single
+/// user can't trigger.
+#[test]
+fn simulates_pool_of_1_user_on_in_memory_db() {
+    simulate_user_pool_on(in_memory_db::init_db(), 1);
+}
+
+/// Two users are enough for random workloads to run into the overwrite bug of the in-memory
+/// backend, usually in the first few.
+#[test]
+fn pools_of_2_users_find_the_in_memory_overwrite_bug() {
+    let config = SimConfig {
+        users: 2,
+        faults: false,
+        ..SimConfig::default()
+    };
+    let mut g = quickcheck::Gen::new(100);
+    let found = (0..100).any(|_| {
+        let ops = config.ops(&mut g, 20);
+        !run_simulator_on(in_memory_db::init_db(), ops).passed()
+    });
+    assert!(found, "100 workloads missed the overwrite bug");
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn model_check_sled_up_to_two_ops() {
+    let bounds = ModelCheckBounds {
+        max_len: 2,
+        users: 2,
+        max_sequences: 1000,
+    };
+    match model_check(&bounds, || SledDb::temporary().unwrap()) {
+        Ok(checked) => assert_eq!(checked, 110),
+        Err((ops, outcome)) => panic!("{:?} with ops {:?}", outcome, ops),
+    }
+}
+
+#[cfg(feature = "sled")]
+#[test]
+fn simulates_pool_of_8_users_on_sled() {
+    simulate_user_pool_on(SledDb::temporary().unwrap(), 8);
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn model_check_concurrent_db_up_to_two_ops() {
+    let bounds = ModelCheckBounds {
+        max_len: 2,
+        users: 2,
+        max_sequences: 1000,
+    };
+    match model_check(&bounds, concurrent_db::init_db) {
+        Ok(checked) => assert_eq!(checked, 110),
+        Err((ops, outcome)) => panic!("{:?} with ops {:?}", outcome, ops),
+    }
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn simulates_pool_of_50_users_on_concurrent_db() {
+    simulate_user_pool_on(concurrent_db::init_db(), 50);
+}
+
+#[test]
+fn stacked_decorators_share_one_db() {
+    let shared: Arc<dyn Db> = Arc::new(in_memory_db::init_db());
+    let slow = Arc::new(LatencyDb::new(
+        Arc::clone(&shared),
+        Duration::from_millis(0),
+        Duration::from_millis(1),
+        42,
+    ));
+    let ops = vec![
+        Register(UserId("Alice".to_string()), Pass("A".to_string())),
+        Register(UserId("Bob".to_string()), Pass("B".to_string())),
+        LoginWithCorrectPw(UserId("Alice".to_string())),
+        AccessSecret(UserId("Alice".to_string())),
+        Logout(UserId("Alice".to_string())),
+    ];
+    assert_passed(run_simulator_on(FailDb::new(Arc::clone(&slow)), ops));
+
+    // Every handle on the stack sees the same users, whether owned, shared or borrowed.
+    let users = shared.count_users().unwrap();
+    assert_eq!(count_users(Arc::clone(&slow)), users);
+    assert_eq!(count_users(&*slow), users);
+}
+
+fn count_users(db: impl Db) -> usize {
+    db.count_users().unwrap()
+}
+
+#[async_std::test]
+async fn health_is_unavailable_while_the_db_is_down() {
+    use tide::http::{Method, Request, Response, StatusCode, Url};
+
+    let app = api::build_app(Arc::new(FailDb::new(in_memory_db::init_db())));
+    let health = || Request::new(Method::Get, Url::parse("http://localhost/health").unwrap());
+
+    // Keeps the simulations from running into the failpoint meanwhile
+    let _scenario = fail::FailScenario::setup();
+    fail::cfg("db.ping", "return").unwrap();
+    let res: Response = app.respond(health()).await.unwrap();
+    fail::remove("db.ping");
+    assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+
+    let res: Response = app.respond(health()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+}
+
+#[quickcheck]
+fn shadowing_a_correct_backend_never_diverges(ops: Vec<Op>) -> bool {
+    let db = ShadowDb::new(FixedDb::default(), FixedDb::default());
+    run_simulator_on(&db, ops).passed() && db.divergences().is_empty()
+}
+
+#[test]
+fn shadowing_catches_password_overwrite_bug() {
+    let db = ShadowDb::new(in_memory_db::init_db(), FixedDb::default());
+    let users = ["Alice", "Bob"]
+        .iter()
+        .map(|name| UserId(name.to_string()))
+        .collect::<Vec<_>>();
+    for user in &users {
+        register(&db, user.clone(), EnteredPassword::new(user.0.clone())).unwrap();
+    }
+    assert!(db.divergences().is_empty());
+
+    for user in &users {
+        db.get_pw(user).unwrap();
+    }
+    let divergences = db.divergences();
+    assert_eq!(divergences.len(), 1, "{:?}", divergences);
+    assert_eq!(divergences[0].method, "get_pw");
+}
+
+#[test]
+fn state_diffs_catch_drift_between_shadowed_dbs() {
+    let in_sync = ShadowDb::new(FixedDb::default(), FixedDb::default());
+    let primary = in_memory_db::init_db();
+    let desynced = ShadowDb::new(primary.clone(), FixedDb::default());
+    let bob = UserId("Bob".to_string());
+    for db in &[&in_sync as &dyn Db, &desynced] {
+        for user in &[alice(), bob.clone()] {
+            register(db, user.clone(), EnteredPassword::new(user.0.clone())).unwrap();
+        }
+        login(
+            db,
+            &AuthHeader::basic(&bob, &EnteredPassword::new(bob.0.clone())),
+        )
+        .unwrap();
+    }
+    assert_eq!(in_sync.diff_state().unwrap(), vec![]);
+
+    // Nothing returned differing results yet, the overwritten password only shows in the state
+    assert!(desynced.divergences().is_empty());
+    let carol = UserId("Carol".to_string());
+    primary.add_session(carol.clone()).unwrap();
+    assert_eq!(
+        desynced.diff_state().unwrap(),
+        vec![
+            StateMismatch::PasswordDiffers(alice()),
+            StateMismatch::SessionOnlyInA(carol),
+        ]
+    );
+}
+
+fn alice() -> UserId {
+    UserId("Alice".to_string())
+}
+
+fn assert_violation(result: anyhow::Result<()>, expected: &str) {
+    let e = result.expect_err("invariant violation wasn't detected");
+    assert!(e.to_string().contains(expected), "{}", e);
+}
+
+#[test]
+fn invariants_hold_for_a_consistent_model() {
+    let db = in_memory_db::init_db();
+    let mut model = ModelInvariants::default();
+    let pass = Pass("pw".to_string());
+    register(&db, alice(), pass.entered_password()).unwrap();
+    login(&db, &auth_header(&alice(), &pass)).unwrap();
+    model.registered.insert(alice(), pass);
+    model.sessions.insert(alice(), model.clock.now());
+
+    model.check_all(&db).unwrap();
+}
+
+#[test]
+fn invariants_catch_passwords_overwritten_by_other_registrations() {
+    let db = in_memory_db::init_db();
+    let mut model = ModelInvariants::default();
+    for (user, pass) in &[("Alice", "A"), ("Bob", "B")] {
+        let (user_id, pass) = (UserId(user.to_string()), Pass(pass.to_string()));
+        register(&db, user_id.clone(), pass.entered_password()).unwrap();
+        model.registered.insert(user_id, pass);
+    }
+
+    assert_violation(
+        model.check_stored(&db),
+        "has a different password stored than registered",
+    );
+}
+
+#[test]
+fn simulator_blames_the_registration_that_overwrote_a_password() {
+    let ops = vec![
+        Register(alice(), Pass("A".to_string())),
+        Register(UserId("Bob".to_string()), Pass("B".to_string())),
+    ];
+    match run_simulator(ops) {
+        SimOutcome::ModelViolation { detail, op_index } => {
+            assert_eq!(op_index, 1);
+            assert!(detail.contains("Alice"), "{}", detail);
+            assert!(
+                detail.contains("has a different password stored than registered"),
+                "{}",
+                detail
+            );
+        }
+        outcome => panic!("{:?}", outcome),
+    }
+}
+
+#[test]
+fn invariants_catch_registered_and_unregistered_at_once() {
+    let mut model = ModelInvariants::default();
+    model.registered.insert(alice(), Pass("pw".to_string()));
+    model.not_registered.insert(alice());
+
+    assert_violation(
+        model.check_registered(&in_memory_db::init_db()),
+        "in registered and unregistered at once",
+    );
+}
+
+#[test]
+fn invariants_catch_access_without_session() {
+    let db = in_memory_db::init_db();
+    let mut model = ModelInvariants::default();
+    let pass = Pass("pw".to_string());
+    register(&db, alice(), pass.entered_password()).unwrap();
+    login(&db, &auth_header(&alice(), &pass)).unwrap();
+    model.registered.insert(alice(), pass);
+
+    assert_violation(
+        model.check_registered(&db),
+        "has no session but can access secret",
+    );
+}
+
+#[test]
+fn invariants_catch_session_and_no_session_at_once() {
+    let mut model = ModelInvariants::default();
+    model.sessions.insert(alice(), model.clock.now());
+    model.no_session.insert(alice());
+
+    assert_violation(
+        model.check_sessions(&in_memory_db::init_db()),
+        "in session and no_session at once",
+    );
+}
+
+#[test]
+fn invariants_catch_session_without_registration() {
+    let mut model = ModelInvariants::default();
+    model.sessions.insert(alice(), model.clock.now());
+
+    assert_violation(
+        model.check_sessions(&in_memory_db::init_db()),
+        "in session but not registered",
+    );
+}
+
+#[test]
+fn invariants_catch_session_without_access() {
+    let db = in_memory_db::init_db();
+    let mut model = ModelInvariants::default();
+    let pass = Pass("pw".to_string());
+    register(&db, alice(), pass.entered_password()).unwrap();
+    model.registered.insert(alice(), pass);
+    model.sessions.insert(alice(), model.clock.now());
+
+    assert_violation(
+        model.check_sessions(&db),
+        "in session but can't access secret",
+    );
 }